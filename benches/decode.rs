@@ -0,0 +1,78 @@
+//! Measure the disassembler's hot paths, as baselines for changes to it: `cargo bench --bench decode`.
+//!
+//! The corpus is the course's listings, one after another.
+
+use std::fs;
+use std::hint::black_box;
+use std::io;
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use homework::disasm::{self, Labels, Targets, Unnamed};
+
+fn corpus() -> Vec<u8> {
+    let mut listings: Vec<_> = fs::read_dir("perfaware/part1")
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_none())
+        .collect();
+    listings.sort();
+    listings.iter().flat_map(|path| fs::read(path).unwrap()).collect()
+}
+
+/// Decode each instruction in `code`, and discard its text without formatting it.
+fn disassemble(code: &[u8], targets: &mut dyn Targets) -> usize {
+    disasm::disassemble(code, 0..code.len(), targets, &mut |text| {
+        black_box(text);
+    })
+}
+
+fn benchmarks(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decode");
+
+    // mov ax, r/m with every MOD and R/M, and any displacement.
+    let r_m: Vec<u8> = (0..=0b11_000_111u8)
+        .filter(|byte2| byte2 & 0b00_111_000 == 0)
+        .flat_map(|byte2| {
+            let displacement: &[u8] = match (byte2 >> 6, byte2 & 0b111) {
+                (0b00, 0b110) | (0b10, _) => &[0x34, 0x12],
+                (0b01, _) => &[0x12],
+                _ => &[],
+            };
+            [&[0b10001011, byte2][..], displacement].concat()
+        })
+        .collect();
+    group.throughput(Throughput::Bytes(r_m.len() as u64));
+    group.bench_function("r/m", |b| b.iter(|| disassemble(black_box(&r_m), &mut Unnamed)));
+
+    // Immediates to registers and memory, of each size, and sign-extended.
+    let immediate = [
+        &[0b10111000, 0x34, 0x12][..],           // mov ax, 4660
+        &[0b10110001, 0x12],                     // mov cl, 18
+        &[0b10000001, 0b11_000_011, 0x34, 0x12], // add bx, 4660
+        &[0b10000011, 0b11_101_011, 0xfe],       // sub bx, -2
+        &[0b11000111, 0b00_000_111, 0x34, 0x12], // mov word [bx], 4660
+        &[0b11000110, 0b00_000_111, 0x12],       // mov byte [bx], 18
+        &[0b00000100, 0x12],                     // add al, 18
+    ]
+    .concat()
+    .repeat(32);
+    group.throughput(Throughput::Bytes(immediate.len() as u64));
+    group.bench_function("immediate", |b| {
+        b.iter(|| disassemble(black_box(&immediate), &mut Unnamed))
+    });
+
+    let corpus = corpus();
+    group.throughput(Throughput::Bytes(corpus.len() as u64));
+    group.bench_function("dispatch", |b| b.iter(|| disassemble(black_box(&corpus), &mut Unnamed)));
+    group.bench_function("labels", |b| {
+        b.iter(|| disassemble(black_box(&corpus), &mut Labels::default()));
+    });
+    group.bench_function("format", |b| {
+        b.iter(|| disasm::run(black_box(&corpus), None, io::sink()))
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, benchmarks);
+criterion_main!(benches);
@@ -0,0 +1,53 @@
+//! Measure how many instructions per second the simulator executes, with instructions dispatched by matching on
+//! their operations and through cached handlers: `cargo bench --bench dispatch`.
+//!
+//! The program is a loop that adds memory to a register, increments an index, stores the sum and loops, like the
+//! inner loops of the course's listings.
+
+use std::time::{Duration, Instant};
+
+use homework::sim::{Dispatch, Error, Machine};
+
+fn main() {
+    let program = [
+        &[0b10111010, 50, 0][..],    // mov dx, 50
+        &[0b10111001, 0, 0],         // mov cx, 0
+        &[0b00000011, 0b00_000_000], // add ax, [bx+si]
+        &[0b01000110],               // inc si
+        &[0b10001001, 0b00_000_101], // mov [di], ax
+        &[0b11100010, 0xf9],         // loop $-5
+        &[0b01001010],               // dec dx
+        &[0b01110101, 0xf3],         // jnz $-11
+        &[0b11110100],               // hlt
+    ]
+    .concat();
+    for (name, dispatch) in [("match", Dispatch::Match), ("threaded", Dispatch::Threaded)] {
+        let (elapsed, count) = best(&program, dispatch);
+        #[allow(clippy::cast_precision_loss)]
+        let rate = count as f64 / elapsed.as_secs_f64() / 1e6;
+        println!("{name:>8}: {count} instructions in {elapsed:.2?}: {rate:.1} million per second");
+    }
+}
+
+/// The time and the number of instructions of the fastest of several runs, which is the least disturbed by other
+/// processes.
+fn best(program: &[u8], dispatch: Dispatch) -> (Duration, u64) {
+    (0..5)
+        .map(|_| {
+            let mut machine = Machine::new();
+            machine.dispatch = dispatch;
+            machine.load(0, program);
+            let start = Instant::now();
+            let mut count: u64 = 0;
+            loop {
+                match machine.step() {
+                    Ok(_) => count += 1,
+                    Err(Error::Halted) => break,
+                    Err(error) => panic!("{error}"),
+                }
+            }
+            (start.elapsed(), count)
+        })
+        .min()
+        .unwrap()
+}
@@ -0,0 +1,79 @@
+//! Measure how fast the simulator's trace is written, into a new `Vec` for each instruction or into one reused
+//! `Line` on the stack: `cargo bench --bench text`.
+//!
+//! The instructions are those of the course's listings, simulated once, so that only writing is measured.
+
+use std::fs;
+use std::hint::black_box;
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+use homework::instruction::Instruction;
+use homework::sim::{self, Machine, Registers};
+use homework::text::Line;
+
+// The number of times that every instruction is written in each run.
+const ROUNDS: usize = 2000;
+
+/// The best of several runs, which is the least disturbed by other processes.
+fn best(mut run: impl FnMut()) -> Duration {
+    (0..5)
+        .map(|_| {
+            let start = Instant::now();
+            run();
+            start.elapsed()
+        })
+        .min()
+        .unwrap()
+}
+
+fn main() {
+    let mut steps: Vec<(Instruction, Registers, Registers)> = vec![];
+    for listing in [
+        "0046_add_sub_cmp",
+        "0048_ip_register",
+        "0049_conditional_jumps",
+        "0047_challenge_flags",
+    ] {
+        let program = fs::read(format!("perfaware/part1/listing_{listing}")).unwrap();
+        let mut machine = Machine::new();
+        machine.load(0, &program);
+        while machine.instruction_address() < program.len() {
+            let before = machine.registers;
+            let instruction = machine.step().unwrap();
+            steps.push((instruction, before, machine.registers));
+        }
+    }
+
+    let allocated = best(|| {
+        for _ in 0..ROUNDS {
+            for (instruction, before, after) in &steps {
+                let mut line = vec![];
+                sim::write_trace(&mut line, instruction, before, after, true).unwrap();
+                black_box(&line);
+            }
+        }
+    });
+    let mut line = Line::new();
+    let stacked = best(|| {
+        for _ in 0..ROUNDS {
+            for (instruction, before, after) in &steps {
+                line.clear();
+                sim::write_trace(&mut line, instruction, before, after, true).unwrap();
+                black_box(line.as_bytes());
+            }
+        }
+    });
+
+    let count = steps.len() * ROUNDS;
+    let mut stdout = std::io::stdout().lock();
+    for (name, elapsed) in [("Vec per line", allocated), ("reused Line", stacked)] {
+        #[allow(clippy::cast_precision_loss)]
+        let rate = count as f64 / elapsed.as_secs_f64() / 1e6;
+        writeln!(
+            stdout,
+            "{name}: {count} lines in {elapsed:.2?}: {rate:.1} million per second"
+        )
+        .unwrap();
+    }
+}
@@ -6,7 +6,10 @@ use std::path::Path;
 use glob::glob;
 
 fn main() {
-    let path = Path::new(&env::var("OUT_DIR").unwrap()).join("main.include");
+    let out_dir = env::var("OUT_DIR").unwrap();
+
+    // Decode each listing, and reassemble it.
+    let path = Path::new(&out_dir).join("main.include");
     let mut file = File::create(path).unwrap();
 
     for entry in glob("perfaware/part1/*.asm").expect("Failed to read glob pattern") {
@@ -24,4 +27,26 @@ fn {name}() {{
         )
         .unwrap();
     }
+
+    // Simulate each listing that has a reference transcript, and compare.
+    let path = Path::new(&out_dir).join("transcript.include");
+    let mut file = File::create(path).unwrap();
+
+    for entry in glob("perfaware/part*/*.txt").expect("Failed to read glob pattern") {
+        let path = entry.unwrap();
+        let name = path.file_stem().unwrap().to_str().unwrap();
+        let program = path.with_extension("");
+
+        write!(
+            file,
+            r"
+#[test]
+fn {name}() {{
+    check({:?})
+}}
+",
+            program.to_str().unwrap()
+        )
+        .unwrap();
+    }
 }
@@ -0,0 +1,299 @@
+//! Log the memory accesses that a simulated program makes, and draw them as a heatmap.
+//!
+//! A log is a sequence of 8-byte records, little endian: the physical address that was accessed, with bit 31 set
+//! for a write and bit 30 set for a word, then the physical address of the instruction that made the access. Like
+//! the statistics, the log has the accesses of instructions, including to the stack and interrupt vectors, but not
+//! instruction fetches or the accesses of DOS and BIOS services.
+//!
+//! In a heatmap, each pixel is a byte of memory, in rows from a start address. Reads are green and writes are red,
+//! brighter for more accesses on a logarithmic scale, so a byte that is read once is still visible.
+
+use std::cell::{Cell, RefCell};
+use std::io::{self, ErrorKind, Read, Write};
+
+use crate::render::{self, Format, Region};
+
+const RECORD_SIZE: usize = 8;
+const WRITE: u32 = 1 << 31;
+const WIDE: u32 = 1 << 30;
+
+/// An access to a byte or word of memory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Access {
+    /// The physical address that was accessed.
+    pub address: usize,
+    /// The physical address of the instruction that made the access.
+    pub instruction: usize,
+    pub wide: bool,
+    pub write: bool,
+}
+
+impl Access {
+    #[must_use]
+    pub fn to_bytes(&self) -> [u8; RECORD_SIZE] {
+        // Physical addresses have 20 bits.
+        let mut address = self.address as u32;
+        if self.write {
+            address |= WRITE;
+        }
+        if self.wide {
+            address |= WIDE;
+        }
+        let mut bytes = [0; RECORD_SIZE];
+        bytes[..4].copy_from_slice(&address.to_le_bytes());
+        bytes[4..].copy_from_slice(&(self.instruction as u32).to_le_bytes());
+        bytes
+    }
+
+    #[must_use]
+    pub const fn from_bytes(bytes: [u8; RECORD_SIZE]) -> Self {
+        let address = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        Self {
+            address: (address & !(WRITE | WIDE)) as usize,
+            instruction: u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]) as usize,
+            wide: address & WIDE != 0,
+            write: address & WRITE != 0,
+        }
+    }
+}
+
+/// Write the accesses of a machine to a log.
+pub struct AccessLog {
+    out: RefCell<Box<dyn Write>>,
+    /// The physical address of the instruction being executed.
+    pub(crate) instruction: Cell<usize>,
+}
+
+impl AccessLog {
+    #[must_use]
+    pub fn new(out: Box<dyn Write>) -> Self {
+        Self {
+            out: RefCell::new(out),
+            instruction: Cell::new(0),
+        }
+    }
+
+    // Logging is best effort: a program shouldn't fail because the log can't be written.
+    pub(crate) fn log(&self, address: usize, wide: bool, write: bool) {
+        let access = Access {
+            address,
+            instruction: self.instruction.get(),
+            wide,
+            write,
+        };
+        let _ = self.out.borrow_mut().write_all(&access.to_bytes());
+    }
+
+    /// Flush the log.
+    ///
+    /// # Errors
+    ///
+    /// If writing fails.
+    pub fn flush(&self) -> io::Result<()> {
+        self.out.borrow_mut().flush()
+    }
+}
+
+/// Read the accesses in a log.
+///
+/// # Errors
+///
+/// If the log can't be read, or ends partway through an access.
+pub fn read(input: &mut impl Read) -> io::Result<Vec<Access>> {
+    let mut bytes = vec![];
+    input.read_to_end(&mut bytes)?;
+    if bytes.len() % RECORD_SIZE != 0 {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "log ends partway through an access",
+        ));
+    }
+    Ok(bytes
+        .chunks_exact(RECORD_SIZE)
+        .map(|chunk| {
+            let mut record = [0; RECORD_SIZE];
+            record.copy_from_slice(chunk);
+            Access::from_bytes(record)
+        })
+        .collect())
+}
+
+/// The reads and writes of each byte of memory.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Heatmap {
+    pub reads: Vec<u32>,
+    pub writes: Vec<u32>,
+}
+
+impl Heatmap {
+    /// Count the bytes that accesses read and wrote. A word at the end of memory wraps around to 0.
+    #[must_use]
+    pub fn new(accesses: &[Access], size: usize) -> Self {
+        let mut heatmap = Self {
+            reads: vec![0; size],
+            writes: vec![0; size],
+        };
+        for access in accesses {
+            let counts = if access.write {
+                &mut heatmap.writes
+            } else {
+                &mut heatmap.reads
+            };
+            for offset in 0..=usize::from(access.wide) {
+                let count = &mut counts[(access.address + offset) % size];
+                *count = count.saturating_add(1);
+            }
+        }
+        heatmap
+    }
+
+    /// The end of the last row that has an access, with rows of `width` bytes from `start`.
+    #[must_use]
+    pub fn end(&self, start: usize, width: usize) -> usize {
+        let last = (start..self.reads.len())
+            .rev()
+            .find(|&address| self.reads[address] != 0 || self.writes[address] != 0);
+        last.map_or(start, |last| {
+            (start + (last - start + 1).next_multiple_of(width)).min(self.reads.len())
+        })
+    }
+
+    /// Draw the bytes from `start` to `end` as an image, in rows of `width` bytes.
+    ///
+    /// # Errors
+    ///
+    /// If writing fails.
+    pub fn write<W: Write>(
+        &self,
+        out: &mut W,
+        start: usize,
+        end: usize,
+        width: usize,
+        format: Format,
+    ) -> io::Result<()> {
+        let scale = |counts: &[u32]| {
+            let max = counts[start..end].iter().copied().max().unwrap_or(0);
+            let max = f64::from(max).ln_1p();
+            move |count: u32| {
+                if count == 0 {
+                    0
+                } else {
+                    // At least 1 access is visible.
+                    (64.0 + 191.0 * f64::from(count).ln_1p() / max) as u8
+                }
+            }
+        };
+        let (red, green) = (scale(&self.writes), scale(&self.reads));
+        // Draw with the 4 bytes per pixel of the course's drawing listings.
+        let mut pixels = vec![];
+        for address in start..end {
+            pixels.extend_from_slice(&[red(self.writes[address]), green(self.reads[address]), 0, 255]);
+        }
+        let region = Region {
+            x: 0,
+            y: 0,
+            width,
+            height: (end - start).div_ceil(width),
+            stride: width * 4,
+        };
+        render::write(out, &pixels, &region, format)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{Seek, SeekFrom};
+
+    use crate::instruction::Register;
+    use crate::sim::Machine;
+
+    #[test]
+    fn round_trip() {
+        let accesses = [
+            Access {
+                address: 0xfffff,
+                instruction: 0x12345,
+                wide: true,
+                write: false,
+            },
+            Access {
+                address: 0x10,
+                instruction: 0,
+                wide: false,
+                write: true,
+            },
+        ];
+        let bytes: Vec<u8> = accesses.iter().flat_map(Access::to_bytes).collect();
+        assert_eq!(&bytes[..8], &[0xff, 0xff, 0x0f, 0x40, 0x45, 0x23, 0x01, 0]);
+        assert_eq!(read(&mut &bytes[..]).unwrap(), accesses);
+        assert!(read(&mut &bytes[..7]).is_err());
+    }
+
+    #[test]
+    fn log() {
+        let program = [
+            &[0b10111011, 0x00, 0x01][..], // mov bx, 256
+            &[0b10001011, 0b00_000_111],   // mov ax, [bx]
+            &[0b01010000],                 // push ax
+            &[0b10001000, 0b00_100_111],   // mov [bx], ah
+        ]
+        .concat();
+        let mut machine = Machine::new();
+        machine.load(0x10, &program);
+        machine.registers.ip = 0x10;
+        machine.registers.set(Register::Sp, 0x20);
+        let file = tempfile::tempfile().unwrap();
+        machine.accesses = Some(AccessLog::new(Box::new(file.try_clone().unwrap())));
+        for _ in 0..4 {
+            machine.step().unwrap();
+        }
+        machine.accesses.unwrap().flush().unwrap();
+        let access = |address, instruction, wide, write| Access {
+            address,
+            instruction,
+            wide,
+            write,
+        };
+        let mut file = file;
+        file.seek(SeekFrom::Start(0)).unwrap();
+        assert_eq!(
+            read(&mut file).unwrap(),
+            [
+                access(0x100, 0x13, true, false),
+                access(0x1e, 0x15, true, true),
+                access(0x100, 0x16, false, true),
+            ]
+        );
+    }
+
+    #[test]
+    fn heatmap() {
+        let access = |address, wide, write| Access {
+            address,
+            instruction: 0,
+            wide,
+            write,
+        };
+        let heatmap = Heatmap::new(
+            &[
+                access(1, true, false),
+                access(2, false, false),
+                access(2, false, true),
+                access(0xf, true, false),
+            ],
+            16,
+        );
+        assert_eq!(heatmap.reads, [1, 1, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(heatmap.writes[2], 1);
+        assert_eq!(heatmap.end(0, 4), 16);
+        assert_eq!(heatmap.end(4, 4), 16);
+
+        let heatmap = Heatmap::new(&[access(5, false, false)], 16);
+        assert_eq!(heatmap.end(0, 4), 8);
+        let mut out = vec![];
+        heatmap.write(&mut out, 4, 8, 2, Format::Ppm).unwrap();
+        assert_eq!(out, b"P6\n2 2\n255\n\0\0\0\0\xff\0\0\0\0\0\0\0");
+    }
+}
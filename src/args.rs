@@ -0,0 +1,86 @@
+//! Parse command-line arguments after the subcommand.
+
+use std::fmt::Display;
+use std::process;
+use std::str::FromStr;
+
+/// Print an error and exit with a usage error status.
+pub fn fail(message: impl Display) -> ! {
+    eprintln!("error: {message}");
+    process::exit(2);
+}
+
+/// Positional arguments, and options of the form `--name` or `--name value`.
+pub struct Args {
+    positionals: Vec<String>,
+    options: Vec<(String, Option<String>)>,
+}
+
+impl Args {
+    /// `switches` take no value. `options` take a value and can be repeated.
+    pub fn parse(args: impl IntoIterator<Item = String>, switches: &[&str], options: &[&str]) -> Self {
+        let mut positionals = vec![];
+        let mut parsed = vec![];
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let Some(name) = arg.strip_prefix("--") else {
+                positionals.push(arg);
+                continue;
+            };
+            if switches.contains(&name) {
+                parsed.push((name.to_string(), None));
+            } else if options.contains(&name) {
+                let value = args
+                    .next()
+                    .unwrap_or_else(|| fail(format!("--{name} requires a value")));
+                parsed.push((name.to_string(), Some(value)));
+            } else {
+                fail(format!("unknown option --{name}"));
+            }
+        }
+        Self {
+            positionals,
+            options: parsed,
+        }
+    }
+
+    /// The positional argument at an index, exiting if it is missing.
+    pub fn positional(&self, index: usize, name: &str) -> &str {
+        self.positionals
+            .get(index)
+            .unwrap_or_else(|| fail(format!("missing argument <{name}>")))
+    }
+
+    pub fn positionals(&self) -> &[String] {
+        &self.positionals
+    }
+
+    pub fn flag(&self, name: &str) -> bool {
+        self.options.iter().any(|(option, _)| option == name)
+    }
+
+    /// The values of an option, in order.
+    pub fn values<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> {
+        self.options
+            .iter()
+            .filter(move |(option, _)| option == name)
+            .filter_map(|(_, value)| value.as_deref())
+    }
+
+    /// The last value of an option.
+    pub fn value<'a>(&'a self, name: &'a str) -> Option<&'a str> {
+        self.values(name).last()
+    }
+
+    /// The last value of an option, parsed, exiting if it is invalid.
+    pub fn parsed<T: FromStr>(&self, name: &str) -> Option<T>
+    where
+        T::Err: Display,
+    {
+        self.value(name).map(|value| {
+            value
+                .parse()
+                .unwrap_or_else(|error| fail(format!("invalid --{name} {value:?}: {error}")))
+        })
+    }
+}
@@ -0,0 +1,2335 @@
+//! Assemble 8086 programs written in NASM's syntax into flat binaries, the inverse of the disassembler.
+//!
+//! The assembler accepts the subset of NASM that the disassembler writes and the course's listings use: the 8086's
+//! instructions, with `lock` and `rep` prefixes, labels, `bits 16`, and `db` and `dw` data. Operands are registers,
+//! constant expressions, effective addresses like `es:[bp + si + 4]` with optional `byte`, `word` and `far` sizes, and
+//! far addresses like `123:456`. Comments start with `;`.
+//!
+//! Expressions combine numbers, labels and `$`, the address of the line, with `+`, `-`, `*` and `<<`, and
+//! parentheses. Numbers are decimal, hexadecimal like `0x3a` or `3ah`, binary like `0b101`, or characters like `'a'`.
+//! `$$` is the address of the start of the program, which `org` sets. `name equ expression` defines a constant. Labels
+//! that start with `.` are local to the label before them, so `.loop` after `copy:` is `copy.loop`.
+//!
+//! Labels can be used before they are defined. Like NASM, the assembler picks the shortest encoding of each
+//! instruction, including short jumps where the target is in range, which moves the labels after it, so it makes
+//! passes over the program until the values of the labels settle. JMPs that are ever out of range stay near, so the
+//! passes converge.
+//!
+//! Writing the form overrides the shortest encoding: `strict` before an immediate keeps it a word rather than a
+//! sign-extended byte, as in `add bx, strict word 5`; `byte` or `word` inside the brackets sets the size of the
+//! displacement, as in `[byte bp + si]`; and `short` or `near` before a JMP's target picks its form.
+//!
+//! `times count` before an instruction or data repeats it, and `resb count` and `resw count` reserve zeroed bytes and
+//! words, so `times 510 - ($ - $$) db 0` pads a boot sector.
+//!
+//! `%include "file.inc"` reads the lines of a file in place of the line, looking for it in the directory of the file
+//! that includes it, then in the include paths in order, like NASM's `-i`.
+//!
+//! Programs are flat binaries, DOS .COM programs, which are loaded at 0x100, or DOS MZ .EXE programs, which are
+//! loaded at 0 in a segment that DOS picks. `seg label` is that segment, which DOS writes into the program as it loads
+//! it. An .EXE starts at the label `..start`, or at 0, and its stack ends at the label `..stack`, or 256 bytes after
+//! the program.
+//!
+//! Errors point at the columns of the line that they are about, and suggest fixes where they can, like the
+//! instruction or label that a misspelled name is closest to.
+//!
+//! [`builder`] builds programs from Rust, without their source.
+
+// Values are checked before they are truncated to bytes and words.
+#![allow(clippy::cast_possible_truncation, clippy::cast_sign_loss, clippy::cast_possible_wrap)]
+
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use crate::decode::decode;
+use crate::encode::{encode_with, Overrides};
+use crate::instruction::{Base, Instruction, Memory, Operand, Operation, Register, Rep};
+use crate::state::Target;
+use crate::table::{self, Slot};
+
+pub mod builder;
+
+// The most passes before giving up on the addresses of labels settling.
+const PASSES: usize = 20;
+
+// XCHG AX, AX.
+const NOP: u8 = 0b10010000;
+
+// The error for `seg` in expressions, or in bytes.
+const SEGMENT_ALONE: &str = "seg can only be a word on its own";
+
+// How deeply expressions can nest, so that parsing and evaluating them can't overflow the stack.
+const MAX_DEPTH: usize = 128;
+
+// The error for expressions that nest more than `MAX_DEPTH` deep.
+const TOO_DEEP: &str = "expression nests too deeply";
+
+// The size of an .EXE's stack, if the program doesn't end it with `..stack`.
+const STACK_SIZE: usize = 0x100;
+
+/// The kinds of programs that the assembler writes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Flat,
+    Com,
+    Exe,
+}
+
+/// Where `%include` looks for files.
+#[derive(Clone, Debug, Default)]
+pub struct Includes {
+    /// The directory of the source, or the current directory if it is empty.
+    pub directory: PathBuf,
+    /// The directories to look in after the directory of the file that includes the file.
+    pub paths: Vec<PathBuf>,
+}
+
+impl Format {
+    /// .COM or .EXE if the path ends in ".com" or ".exe", otherwise a flat binary.
+    #[must_use]
+    pub fn from_path(path: &str) -> Self {
+        let path = path.to_ascii_lowercase();
+        if path.ends_with(".com") {
+            Self::Com
+        } else if path.ends_with(".exe") {
+            Self::Exe
+        } else {
+            Self::Flat
+        }
+    }
+}
+
+// Alternative mnemonics, and the operations or prefixes that they are the same as.
+const ALIASES: [(&str, &str); 21] = [
+    ("jz", "je"),
+    ("jnz", "jne"),
+    ("jc", "jb"),
+    ("jnae", "jb"),
+    ("jnc", "jnb"),
+    ("jae", "jnb"),
+    ("jna", "jbe"),
+    ("ja", "jnbe"),
+    ("jpe", "jp"),
+    ("jpo", "jnp"),
+    ("jnge", "jl"),
+    ("jge", "jnl"),
+    ("jng", "jle"),
+    ("jg", "jnle"),
+    ("loope", "loopz"),
+    ("loopne", "loopnz"),
+    ("sal", "shl"),
+    ("xlatb", "xlat"),
+    ("repe", "rep"),
+    ("repz", "rep"),
+    ("repnz", "repne"),
+];
+
+/// An error in a line of the source.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Error {
+    /// The path of the included file that the error is in, or `None` if it is in the source.
+    pub file: Option<String>,
+    /// The line number, from 1.
+    pub line: usize,
+    /// The characters of the line that the error is about, from 0.
+    pub columns: Range<usize>,
+    pub message: String,
+    /// A suggestion for fixing the error.
+    pub help: Option<String>,
+    /// The text of the line.
+    pub text: String,
+}
+
+impl Error {
+    /// The error as compilers report them: the file, line and column, then the line with carets under the columns
+    /// that the error is about, then any suggestion. `path` is the path of the source.
+    #[must_use]
+    pub fn report(&self, path: &str) -> String {
+        let path = self.file.as_deref().unwrap_or(path);
+        let number = self.line.to_string();
+        let margin = " ".repeat(number.len());
+        // Tabs are kept, so that the carets line up with the text.
+        let indent: String = self
+            .text
+            .chars()
+            .take(self.columns.start)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let carets = "^".repeat(self.columns.len().max(1));
+        let mut report = format!("{path}:{}:{}: {}\n", self.line, self.columns.start + 1, self.message);
+        let _ = write!(report, "{number} | {}\n{margin} | {indent}{carets}", self.text);
+        if let Some(help) = &self.help {
+            let _ = write!(report, "\n{margin} = help: {help}");
+        }
+        report
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{file}, ")?;
+        }
+        write!(
+            f,
+            "line {}, column {}: {}",
+            self.line,
+            self.columns.start + 1,
+            self.message
+        )
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// An error at a span of a line's bytes, before it is located in the source.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Diagnostic {
+    message: String,
+    span: Range<usize>,
+    help: Option<String>,
+}
+
+impl Diagnostic {
+    fn new(message: impl Into<String>, span: Range<usize>) -> Self {
+        Self {
+            message: message.into(),
+            span,
+            help: None,
+        }
+    }
+
+    fn help(mut self, help: Option<String>) -> Self {
+        self.help = help;
+        self
+    }
+
+    /// Locate the error in a line of the source.
+    fn locate(self, line: usize, text: &str) -> Error {
+        let column = |byte: usize| text[..byte.min(text.len())].chars().count();
+        Error {
+            file: None,
+            line,
+            columns: column(self.span.start)..column(self.span.end),
+            message: self.message,
+            help: self.help,
+            text: text.to_string(),
+        }
+    }
+}
+
+/// The number of single-character edits that turn one name into another, where swapping two characters is one edit.
+fn distance(a: &str, b: &str) -> usize {
+    let (a, b): (Vec<char>, Vec<char>) = (a.chars().collect(), b.chars().collect());
+    // The distances between the prefixes of the names.
+    let mut d = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for i in 0..=a.len() {
+        for j in 0..=b.len() {
+            d[i][j] = if i == 0 || j == 0 {
+                i + j
+            } else {
+                let mut edits = (d[i - 1][j - 1] + usize::from(a[i - 1] != b[j - 1]))
+                    .min(d[i - 1][j] + 1)
+                    .min(d[i][j - 1] + 1);
+                if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                    edits = edits.min(d[i - 2][j - 2] + 1);
+                }
+                edits
+            };
+        }
+    }
+    d[a.len()][b.len()]
+}
+
+/// Suggest the candidate that a name is closest to, if it is close enough to be a misspelling.
+fn suggest<'a>(name: &str, candidates: impl IntoIterator<Item = &'a str>) -> Option<String> {
+    candidates
+        .into_iter()
+        .map(|candidate| (distance(name, candidate), candidate))
+        .filter(|&(distance, _)| distance <= 2 && distance < name.chars().count())
+        .min()
+        .map(|(_, candidate)| format!("did you mean {candidate:?}?"))
+}
+
+/// Every mnemonic, prefix and directive.
+fn mnemonics() -> Vec<String> {
+    let mut names = vec![];
+    for operation in Operation::ALL {
+        if operation.is_string() {
+            names.push(format!("{}b", operation.name()));
+            names.push(format!("{}w", operation.name()));
+        } else {
+            names.push(operation.name().to_string());
+        }
+    }
+    names.extend(ALIASES.iter().map(|(alias, _)| (*alias).to_string()));
+    names.extend(
+        [
+            "lock", "rep", "repne", "bits", "db", "dw", "equ", "org", "times", "resb", "resw", "nop",
+        ]
+        .map(String::from),
+    );
+    names
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Name(String),
+    Number(i64),
+    Text(Vec<u8>),
+    Symbol(char),
+}
+
+/// Parse a number like `42`, `0x2a`, `2ah`, `0b101010` or `0o52`.
+fn number(text: &str) -> Option<i64> {
+    let text = text.to_ascii_lowercase().replace('_', "");
+    let (digits, radix) = [("0x", 16), ("0b", 2), ("0o", 8)]
+        .into_iter()
+        .find_map(|(prefix, radix)| Some((text.strip_prefix(prefix)?, radix)))
+        .or_else(|| Some((text.strip_suffix('h')?, 16)))
+        .unwrap_or((&text, 10));
+    i64::from_str_radix(digits, radix).ok()
+}
+
+/// Split a line into tokens and their spans, up to any comment.
+fn tokenize(line: &str) -> Result<Vec<(Token, Range<usize>)>, Diagnostic> {
+    let mut tokens = vec![];
+    let mut chars = line.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        let mut end = start + c.len_utf8();
+        let mut take_while = |predicate: fn(char) -> bool| {
+            while let Some(&(i, c)) = chars.peek() {
+                if !predicate(c) {
+                    break;
+                }
+                end = i + c.len_utf8();
+                chars.next();
+            }
+            &line[start..end]
+        };
+        let token = match c {
+            ';' => break,
+            _ if c.is_whitespace() => continue,
+            _ if c.is_ascii_digit() => {
+                let text = take_while(|c| c.is_ascii_alphanumeric() || c == '_');
+                let span = start..start + text.len();
+                Token::Number(number(text).ok_or_else(|| Diagnostic::new(format!("invalid number {text:?}"), span))?)
+            }
+            _ if c.is_alphabetic() || c == '_' || c == '.' => {
+                let text = take_while(|c| c.is_alphanumeric() || matches!(c, '_' | '.' | '?' | '@'));
+                Token::Name(text.to_string())
+            }
+            '\'' | '"' | '`' => {
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some((i, quote)) if quote == c => {
+                            end = i + 1;
+                            break;
+                        }
+                        Some((_, other)) => text.push(other),
+                        None => return Err(Diagnostic::new("unterminated string", start..line.len())),
+                    }
+                }
+                Token::Text(text.into_bytes())
+            }
+            _ => Token::Symbol(c),
+        };
+        tokens.push((token, start..end));
+    }
+    Ok(tokens)
+}
+
+/// A constant expression, whose labels are resolved as the program is laid out.
+#[derive(Clone, Debug, PartialEq)]
+enum Expression {
+    Number(i64),
+    /// A label, and its span.
+    Label(String, Range<usize>),
+    /// `$`, the address of the line.
+    Here,
+    /// `$$`, the address of the start of the program.
+    Start,
+    /// `seg label`, the segment of a label, which is relocated when the program is loaded.
+    Segment(Box<Self>),
+    Negate(Box<Self>),
+    Add(Box<Self>, Box<Self>),
+    Subtract(Box<Self>, Box<Self>),
+    Multiply(Box<Self>, Box<Self>),
+    ShiftLeft(Box<Self>, Box<Self>),
+}
+
+/// The values of labels and constants, which are `None` until a pass lays them out, the address of the line, the
+/// address that the program is loaded at, and whether the program can be relocated.
+#[derive(Clone, Copy)]
+struct Context<'a> {
+    labels: &'a HashMap<String, Option<i64>>,
+    here: i64,
+    origin: i64,
+    relocatable: bool,
+}
+
+impl Context<'_> {
+    /// Check that the program can be relocated, for `seg` at a span.
+    fn relocate(&self, span: Range<usize>) -> Result<(), Diagnostic> {
+        if self.relocatable {
+            Ok(())
+        } else {
+            Err(Diagnostic::new("seg needs an .EXE, which DOS relocates", span)
+                .help(Some("write the program to a file that ends in .exe".to_string())))
+        }
+    }
+}
+
+impl Expression {
+    /// How many levels the expression has, counting itself.
+    fn depth(&self) -> usize {
+        match self {
+            Self::Segment(a) | Self::Negate(a) => 1 + a.depth(),
+            Self::Add(a, b) | Self::Subtract(a, b) | Self::Multiply(a, b) | Self::ShiftLeft(a, b) => {
+                1 + a.depth().max(b.depth())
+            }
+            Self::Number(_) | Self::Label(..) | Self::Here | Self::Start => 1,
+        }
+    }
+
+    /// Whether the expression uses `seg`.
+    fn has_segment(&self) -> bool {
+        match self {
+            Self::Segment(_) => true,
+            Self::Negate(a) => a.has_segment(),
+            Self::Add(a, b) | Self::Subtract(a, b) | Self::Multiply(a, b) | Self::ShiftLeft(a, b) => {
+                a.has_segment() || b.has_segment()
+            }
+            Self::Number(_) | Self::Label(..) | Self::Here | Self::Start => false,
+        }
+    }
+
+    /// The value of the expression, or `None` if a label isn't laid out yet.
+    fn evaluate(&self, context: &Context) -> Result<Option<i64>, Diagnostic> {
+        let binary = |a: &Self, b: &Self, operation: fn(i64, i64) -> i64| {
+            Ok(a.evaluate(context)?
+                .zip(b.evaluate(context)?)
+                .map(|(a, b)| operation(a, b)))
+        };
+        match self {
+            Self::Number(value) => Ok(Some(*value)),
+            Self::Label(name, span) => context.labels.get(name).copied().ok_or_else(|| {
+                Diagnostic::new(format!("undefined label {name:?}"), span.clone())
+                    .help(suggest(name, context.labels.keys().map(String::as_str)))
+            }),
+            Self::Here => Ok(Some(context.here)),
+            Self::Start => Ok(Some(context.origin)),
+            // Every label is in the program's segment, which is 0 until it is relocated.
+            Self::Segment(a) => Ok(a.evaluate(context)?.map(|_| 0)),
+            Self::Negate(a) => Ok(a.evaluate(context)?.map(i64::wrapping_neg)),
+            Self::Add(a, b) => binary(a, b, i64::wrapping_add),
+            Self::Subtract(a, b) => binary(a, b, i64::wrapping_sub),
+            Self::Multiply(a, b) => binary(a, b, i64::wrapping_mul),
+            Self::ShiftLeft(a, b) => binary(a, b, |a, b| {
+                u32::try_from(b).map_or(0, |b| a.checked_shl(b).unwrap_or(0))
+            }),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Argument {
+    Register(Register),
+    Memory {
+        segment: Option<Register>,
+        base: Base,
+        displacement: Expression,
+        /// Whether the displacement is a word, if it is written with `byte` or `word` inside the brackets.
+        size: Option<bool>,
+    },
+    Immediate(Expression),
+    /// A direct intersegment address: segment, offset.
+    Far(Expression, Expression),
+}
+
+/// An operand, with any size that it is written with.
+#[derive(Clone, Debug, PartialEq)]
+struct Parameter {
+    argument: Argument,
+    /// Whether the operand is a word, if it is written with `byte` or `word`, or a jump's target with `near`.
+    wide: Option<bool>,
+    far: bool,
+    /// Whether a jump's target is written with `short`.
+    short: bool,
+    /// Whether an immediate is written with `strict`, so its size isn't optimized.
+    strict: bool,
+    span: Range<usize>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Statement {
+    operation: Operation,
+    /// Whether a string operation is on words, from its suffix.
+    wide: Option<bool>,
+    lock: bool,
+    rep: Option<Rep>,
+    parameters: Vec<Parameter>,
+    /// The span of the instruction, from its prefixes to its last operand.
+    span: Range<usize>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Datum {
+    Expression(Expression, Range<usize>),
+    Text(Vec<u8>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Item {
+    Instruction(Statement),
+    Data {
+        wide: bool,
+        data: Vec<Datum>,
+    },
+    /// The value of the line's label, from `equ`.
+    Constant(Expression),
+    /// The address that the program is loaded at, from `org`, and the span of the directive.
+    Origin(Expression, Range<usize>),
+    /// An instruction or data repeated a number of times, from `times`, with the span of the count.
+    Times(Expression, Range<usize>, Box<Self>),
+    /// Zeroed bytes or words, from `resb` and `resw`, with the span of the count.
+    Reserve {
+        wide: bool,
+        count: Expression,
+        span: Range<usize>,
+    },
+}
+
+/// A line of the source, with any label and any instruction or data.
+#[derive(Clone, Debug, PartialEq)]
+struct Line {
+    /// The path of the included file that the line is in, or `None` if it is in the source.
+    file: Option<String>,
+    /// How many files deep the line is included.
+    depth: usize,
+    number: usize,
+    text: String,
+    /// The label, and its span.
+    label: Option<(String, Range<usize>)>,
+    item: Option<Item>,
+}
+
+impl Line {
+    /// Locate an error in the line.
+    fn locate(&self, diagnostic: Diagnostic) -> Error {
+        Error {
+            file: self.file.clone(),
+            ..diagnostic.locate(self.number, &self.text)
+        }
+    }
+
+    /// Where the line is, for errors on other lines.
+    fn place(&self, other: &Self) -> String {
+        match &self.file {
+            Some(file) if self.file != other.file => format!("line {} of {file}", self.number),
+            None if other.file.is_some() => format!("line {} of the source", self.number),
+            _ => format!("line {}", self.number),
+        }
+    }
+}
+
+/// The general or segment register that a name identifies.
+fn register(name: &str) -> Option<Register> {
+    match name.parse() {
+        Ok(Target::Register(register)) => Some(register),
+        _ => None,
+    }
+}
+
+/// The operation that a mnemonic identifies, and whether it is on words if it is a string operation.
+fn mnemonic(name: &str) -> Option<(Operation, Option<bool>)> {
+    let name = ALIASES
+        .iter()
+        .find(|(alias, _)| *alias == name)
+        .map_or(name, |(_, name)| name);
+    if let Ok(operation) = name.parse::<Operation>() {
+        return (!operation.is_string()).then_some((operation, None));
+    }
+    let (stem, wide) = if let Some(stem) = name.strip_suffix('b') {
+        (stem, false)
+    } else {
+        (name.strip_suffix('w')?, true)
+    };
+    let operation = stem.parse::<Operation>().ok()?;
+    operation.is_string().then_some((operation, Some(wide)))
+}
+
+/// A parser of a line's tokens.
+struct Parser {
+    tokens: Vec<Token>,
+    spans: Vec<Range<usize>>,
+    position: usize,
+    /// The length of the line, where errors at the end of the line point.
+    end: usize,
+    /// The last label that isn't local, which local labels belong to.
+    scope: String,
+    /// How many parentheses, signs and `seg`s the next term is inside.
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    /// The span of the next token, or the end of the line.
+    fn span(&self) -> Range<usize> {
+        self.spans.get(self.position).cloned().unwrap_or(self.end..self.end)
+    }
+
+    /// The span from the token at an index to the last token taken.
+    fn since(&self, start: usize) -> Range<usize> {
+        let end = self.spans[..self.position].last().map_or(self.end, |span| span.end);
+        self.spans.get(start).map_or(self.end, |span| span.start)..end
+    }
+
+    /// An error at the next token.
+    fn error(&self, message: impl Into<String>) -> Diagnostic {
+        Diagnostic::new(message, self.span())
+    }
+
+    /// Take a symbol if it is next.
+    fn eat(&mut self, symbol: char) -> bool {
+        let found = self.peek() == Some(&Token::Symbol(symbol));
+        if found {
+            self.position += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, symbol: char) -> Result<(), Diagnostic> {
+        if self.eat(symbol) {
+            Ok(())
+        } else {
+            Err(self.error(format!("expected {symbol:?}")))
+        }
+    }
+
+    /// Take a name if it is next and matches a predicate, lowercased.
+    fn keyword(&mut self, predicate: impl Fn(&str) -> bool) -> Option<String> {
+        let Some(Token::Name(name)) = self.peek() else {
+            return None;
+        };
+        let name = name.to_ascii_lowercase();
+        if predicate(&name) {
+            self.position += 1;
+            Some(name)
+        } else {
+            None
+        }
+    }
+
+    const fn at_end(&self) -> bool {
+        self.position >= self.tokens.len()
+    }
+
+    /// The full name of a label, with the label that it belongs to if it is local.
+    fn qualify(&self, name: String) -> String {
+        // Special labels like `..start` aren't local.
+        if name.starts_with('.') && !name.starts_with("..") {
+            format!("{}{name}", self.scope)
+        } else {
+            name
+        }
+    }
+
+    /// An expression that an operator at a span builds, unless it nests too deeply to evaluate.
+    fn nest(expression: Expression, span: Range<usize>) -> Result<Expression, Diagnostic> {
+        if expression.depth() > MAX_DEPTH {
+            Err(Diagnostic::new(TOO_DEEP, span))
+        } else {
+            Ok(expression)
+        }
+    }
+
+    /// Parse inside the parenthesis, sign or `seg` at a span, unless it nests too deeply to parse.
+    fn inside(
+        &mut self,
+        span: Range<usize>,
+        parse: impl FnOnce(&mut Self) -> Result<Expression, Diagnostic>,
+    ) -> Result<Expression, Diagnostic> {
+        if self.depth == MAX_DEPTH {
+            return Err(Diagnostic::new(TOO_DEEP, span));
+        }
+        self.depth += 1;
+        let expression = parse(self);
+        self.depth -= 1;
+        expression
+    }
+
+    /// `expression = sum {"<<" sum}`
+    fn expression(&mut self) -> Result<Expression, Diagnostic> {
+        let mut expression = self.sum()?;
+        while self.tokens.get(self.position..self.position + 2) == Some(&[Token::Symbol('<'), Token::Symbol('<')]) {
+            let span = self.span();
+            self.position += 2;
+            expression = Self::nest(Expression::ShiftLeft(Box::new(expression), Box::new(self.sum()?)), span)?;
+        }
+        Ok(expression)
+    }
+
+    /// `sum = product {("+" | "-") product}`
+    fn sum(&mut self) -> Result<Expression, Diagnostic> {
+        let mut expression = self.product()?;
+        loop {
+            let span = self.span();
+            if self.eat('+') {
+                expression = Self::nest(Expression::Add(Box::new(expression), Box::new(self.product()?)), span)?;
+            } else if self.eat('-') {
+                expression = Self::nest(
+                    Expression::Subtract(Box::new(expression), Box::new(self.product()?)),
+                    span,
+                )?;
+            } else {
+                return Ok(expression);
+            }
+        }
+    }
+
+    /// `product = term {"*" term}`
+    fn product(&mut self) -> Result<Expression, Diagnostic> {
+        let mut expression = self.term()?;
+        loop {
+            let span = self.span();
+            if !self.eat('*') {
+                return Ok(expression);
+            }
+            expression = Self::nest(Expression::Multiply(Box::new(expression), Box::new(self.term()?)), span)?;
+        }
+    }
+
+    /// `term = "-" term | "+" term | "(" expression ")" | number | character | label | "$"`
+    fn term(&mut self) -> Result<Expression, Diagnostic> {
+        let span = self.span();
+        match self.next() {
+            Some(Token::Symbol('-')) => {
+                let term = self.inside(span.clone(), Self::term)?;
+                Self::nest(Expression::Negate(Box::new(term)), span)
+            }
+            Some(Token::Symbol('+')) => self.inside(span, Self::term),
+            Some(Token::Symbol('(')) => self.inside(span, |parser| {
+                let expression = parser.expression()?;
+                parser.expect(')')?;
+                Ok(expression)
+            }),
+            Some(Token::Symbol('$')) if self.peek() == Some(&Token::Symbol('$')) && self.span().start == span.end => {
+                self.position += 1;
+                Ok(Expression::Start)
+            }
+            Some(Token::Symbol('$')) => Ok(Expression::Here),
+            Some(Token::Number(value)) => Ok(Expression::Number(value)),
+            // Characters are little-endian, like NASM's.
+            Some(Token::Text(text)) if !text.is_empty() && text.len() <= 2 => Ok(Expression::Number(
+                text.iter().rev().fold(0, |value, &byte| value << 8 | i64::from(byte)),
+            )),
+            Some(Token::Name(name))
+                if name.eq_ignore_ascii_case("seg") && matches!(self.peek(), Some(Token::Name(_))) =>
+            {
+                let term = self.inside(span.clone(), Self::term)?;
+                Self::nest(Expression::Segment(Box::new(term)), span)
+            }
+            Some(Token::Name(name)) if register(&name.to_ascii_lowercase()).is_none() => {
+                Ok(Expression::Label(self.qualify(name), span))
+            }
+            _ => Err(Diagnostic::new("expected an expression", span)),
+        }
+    }
+
+    /// The inside of an effective address, after `[`: registers and expressions added together, then `]`.
+    fn memory(&mut self, mut segment: Option<Register>) -> Result<Argument, Diagnostic> {
+        let start = self.position - 1;
+        if let Some(name) = self.keyword(|name| register(name).is_some_and(Register::is_segment)) {
+            self.expect(':')?;
+            segment = register(&name);
+        }
+        let size = self
+            .keyword(|name| matches!(name, "byte" | "word"))
+            .map(|name| name == "word");
+        let mut registers = vec![];
+        let mut displacement: Option<Expression> = None;
+        let mut negative = false;
+        loop {
+            let span = self.span();
+            if let Some(name) = self.keyword(|name| register(name).is_some()) {
+                if negative {
+                    return Err(Diagnostic::new("registers can't be subtracted from an address", span));
+                }
+                registers.extend(register(&name));
+            } else {
+                let term = self.product()?;
+                displacement = Some(Self::nest(
+                    match (displacement, negative) {
+                        (None, false) => term,
+                        (None, true) => Expression::Negate(Box::new(term)),
+                        (Some(a), false) => Expression::Add(Box::new(a), Box::new(term)),
+                        (Some(a), true) => Expression::Subtract(Box::new(a), Box::new(term)),
+                    },
+                    span,
+                )?);
+            }
+            if self.eat(']') {
+                break;
+            }
+            negative = if self.eat('+') {
+                false
+            } else if self.eat('-') {
+                true
+            } else {
+                return Err(self.error("expected \"+\", \"-\" or \"]\""));
+            };
+        }
+        let has = |register| registers.contains(&register);
+        let base = match registers.len() {
+            0 => Base::Direct,
+            1 if has(Register::Si) => Base::Si,
+            1 if has(Register::Di) => Base::Di,
+            1 if has(Register::Bp) => Base::Bp,
+            1 if has(Register::Bx) => Base::Bx,
+            2 if has(Register::Bx) && has(Register::Si) => Base::BxSi,
+            2 if has(Register::Bx) && has(Register::Di) => Base::BxDi,
+            2 if has(Register::Bp) && has(Register::Si) => Base::BpSi,
+            2 if has(Register::Bp) && has(Register::Di) => Base::BpDi,
+            _ => {
+                return Err(Diagnostic::new("invalid effective address", self.since(start))
+                    .help(Some("addresses add BX or BP, SI or DI, and a displacement".to_string())));
+            }
+        };
+        Ok(Argument::Memory {
+            segment,
+            base,
+            displacement: displacement.unwrap_or(Expression::Number(0)),
+            size,
+        })
+    }
+
+    /// An operand, up to the next `,` or the end of the line.
+    fn parameter(&mut self) -> Result<Parameter, Diagnostic> {
+        let start = self.position;
+        let mut wide = None;
+        let mut far = false;
+        let mut short = false;
+        let mut strict = false;
+        let sizes = |name: &str| matches!(name, "byte" | "word" | "far" | "near" | "short" | "strict");
+        while let Some(keyword) = self.keyword(sizes) {
+            match keyword.as_str() {
+                "byte" => wide = Some(false),
+                "word" | "near" => wide = Some(true),
+                "far" => far = true,
+                "short" => short = true,
+                _ => strict = true,
+            }
+        }
+        let argument = if self.eat('[') {
+            self.memory(None)?
+        } else if let Some(name) = self.keyword(|name| register(name).is_some()) {
+            let register = register(&name).unwrap_or(Register::Ax);
+            if register.is_segment() && self.eat(':') {
+                self.expect('[')?;
+                self.memory(Some(register))?
+            } else {
+                Argument::Register(register)
+            }
+        } else {
+            let expression = self.expression()?;
+            if self.eat(':') {
+                Argument::Far(expression, self.expression()?)
+            } else {
+                Argument::Immediate(expression)
+            }
+        };
+        Ok(Parameter {
+            argument,
+            wide,
+            far,
+            short,
+            strict,
+            span: self.since(start),
+        })
+    }
+
+    /// A line: an optional label, then an optional instruction, data or directive.
+    fn line(&mut self, number: usize, text: &str) -> Result<Line, Diagnostic> {
+        let mut line = Line {
+            file: None,
+            depth: 0,
+            number,
+            text: text.to_string(),
+            label: None,
+            item: None,
+        };
+        match &self.tokens[..] {
+            [Token::Name(name), Token::Symbol(':'), ..] => {
+                let label = self.qualify(name.clone());
+                if !name.starts_with('.') {
+                    self.scope.clone_from(&label);
+                }
+                line.label = Some((label, self.spans[0].clone()));
+                self.position = 2;
+            }
+            // Constants can be defined without a colon.
+            [Token::Name(name), Token::Name(equ), ..] if equ.eq_ignore_ascii_case("equ") => {
+                line.label = Some((self.qualify(name.clone()), self.spans[0].clone()));
+                self.position = 1;
+            }
+            _ => {}
+        }
+        if self.at_end() {
+            return Ok(line);
+        }
+        line.item = self.item(line.label.is_some())?;
+        if self.at_end() {
+            Ok(line)
+        } else {
+            Err(Diagnostic::new(
+                "unexpected text after the instruction",
+                self.span().start..self.end,
+            ))
+        }
+    }
+
+    /// An instruction, data or directive, after any label, or `None` for a directive that has no effect.
+    fn item(&mut self, labelled: bool) -> Result<Option<Item>, Diagnostic> {
+        let start = self.position;
+        let mut span = self.span();
+        let Some(mut name) = self.keyword(|_| true) else {
+            return Err(self.error("expected a label or an instruction"));
+        };
+
+        let mut lock = false;
+        let mut rep = None;
+        loop {
+            let name_or_alias = ALIASES
+                .iter()
+                .find(|(alias, _)| *alias == name)
+                .map_or(name.as_str(), |(_, name)| name);
+            match name_or_alias {
+                "lock" => lock = true,
+                "rep" => rep = Some(Rep::Rep),
+                "repne" => rep = Some(Rep::Repne),
+                _ => break,
+            }
+            span = self.span();
+            name = self
+                .keyword(|_| true)
+                .ok_or_else(|| self.error("expected an instruction after a prefix"))?;
+        }
+
+        let item = match name.as_str() {
+            "equ" => {
+                if !labelled {
+                    return Err(Diagnostic::new("equ needs a name before it", span));
+                }
+                Item::Constant(self.expression()?)
+            }
+            "bits" => {
+                let span = self.span();
+                if self.next() != Some(Token::Number(16)) {
+                    return Err(Diagnostic::new("only bits 16 is supported", span));
+                }
+                return Ok(None);
+            }
+            "org" => Item::Origin(self.expression()?, span),
+            "times" => {
+                let start = self.position;
+                let count = self.expression()?;
+                let count_span = self.since(start);
+                let span = self.span();
+                if self.at_end() {
+                    return Err(Diagnostic::new("expected an instruction or data to repeat", span));
+                }
+                match self.item(false)? {
+                    Some(Item::Constant(_) | Item::Origin(..)) | None => {
+                        return Err(Diagnostic::new("only instructions and data can be repeated", span));
+                    }
+                    Some(Item::Times(..)) => return Err(Diagnostic::new("times can't be nested", span)),
+                    Some(item) => Item::Times(count, count_span, Box::new(item)),
+                }
+            }
+            "resb" | "resw" => {
+                let start = self.position;
+                let count = self.expression()?;
+                Item::Reserve {
+                    wide: name == "resw",
+                    count,
+                    span: self.since(start),
+                }
+            }
+            "nop" => Item::Data {
+                wide: false,
+                data: vec![Datum::Text(vec![NOP])],
+            },
+            "db" | "dw" => {
+                let mut data = vec![];
+                loop {
+                    match self.peek() {
+                        Some(Token::Text(text)) if name == "db" || text.len() > 2 => {
+                            data.push(Datum::Text(text.clone()));
+                            self.position += 1;
+                        }
+                        _ => {
+                            let start = self.position;
+                            let expression = self.expression()?;
+                            data.push(Datum::Expression(expression, self.since(start)));
+                        }
+                    }
+                    if !self.eat(',') {
+                        break;
+                    }
+                }
+                Item::Data {
+                    wide: name == "dw",
+                    data,
+                }
+            }
+            _ => {
+                let (operation, wide) = mnemonic(&name).ok_or_else(|| {
+                    Diagnostic::new(format!("unknown instruction {name:?}"), span)
+                        .help(suggest(&name, mnemonics().iter().map(String::as_str)))
+                })?;
+                let mut parameters = vec![];
+                if !self.at_end() {
+                    loop {
+                        parameters.push(self.parameter()?);
+                        if !self.eat(',') {
+                            break;
+                        }
+                    }
+                }
+                if parameters.len() > 2 {
+                    return Err(Diagnostic::new(
+                        format!("too many operands for {name}"),
+                        parameters[2].span.start..self.since(start).end,
+                    ));
+                }
+                Item::Instruction(Statement {
+                    operation,
+                    wide,
+                    lock,
+                    rep,
+                    parameters,
+                    span: self.since(start),
+                })
+            }
+        };
+        Ok(Some(item))
+    }
+}
+
+/// Parse the lines of a program, and of the files that it includes.
+fn parse(source: &str, includes: &Includes) -> Result<Vec<Line>, Error> {
+    let mut reader = Reader {
+        includes,
+        lines: vec![],
+        scope: String::new(),
+        stack: vec![],
+    };
+    reader.read(source, None, &includes.directory)?;
+    Ok(reader.lines)
+}
+
+/// Parses the lines of the source and the files that it includes.
+struct Reader<'a> {
+    includes: &'a Includes,
+    lines: Vec<Line>,
+    /// The label that local labels belong to.
+    scope: String,
+    /// The canonical and displayed paths of the files being included, outermost first.
+    stack: Vec<(PathBuf, String)>,
+}
+
+impl Reader<'_> {
+    /// Parse the lines of a file, which is in a directory, and is included if it has a path.
+    fn read(&mut self, source: &str, file: Option<&str>, directory: &Path) -> Result<(), Error> {
+        for (index, text) in source.lines().enumerate() {
+            let number = index + 1;
+            let locate = |diagnostic: Diagnostic| Error {
+                file: file.map(str::to_string),
+                ..diagnostic.locate(number, text)
+            };
+            let tokens = tokenize(text).map_err(locate)?;
+            if let [(Token::Symbol('%'), start), (Token::Name(name), end), rest @ ..] = &tokens[..] {
+                if name.eq_ignore_ascii_case("include") && start.end == end.start {
+                    let [(Token::Text(name), span)] = rest else {
+                        return Err(locate(Diagnostic::new(
+                            "%include needs the name of a file in quotes",
+                            start.start..text.len(),
+                        )));
+                    };
+                    self.lines.push(Line {
+                        file: file.map(str::to_string),
+                        depth: self.stack.len(),
+                        number,
+                        text: text.to_string(),
+                        label: None,
+                        item: None,
+                    });
+                    self.include(&String::from_utf8_lossy(name), span, directory, locate)?;
+                    continue;
+                }
+            }
+            let (tokens, spans) = tokens.into_iter().unzip();
+            let mut parser = Parser {
+                tokens,
+                spans,
+                position: 0,
+                end: text.len(),
+                scope: std::mem::take(&mut self.scope),
+                depth: 0,
+            };
+            let mut line = parser.line(number, text).map_err(locate)?;
+            line.file = file.map(str::to_string);
+            line.depth = self.stack.len();
+            self.lines.push(line);
+            self.scope = parser.scope;
+        }
+        Ok(())
+    }
+
+    /// Parse the lines of a file that a file in `directory` includes. Errors are at the span of the `%include` line,
+    /// which `locate` places in the including file.
+    fn include(
+        &mut self,
+        name: &str,
+        span: &Range<usize>,
+        directory: &Path,
+        locate: impl Fn(Diagnostic) -> Error,
+    ) -> Result<(), Error> {
+        let directories: Vec<&Path> = std::iter::once(directory)
+            .chain(self.includes.paths.iter().map(PathBuf::as_path))
+            .collect();
+        let Some(path) = directories
+            .iter()
+            .map(|directory| directory.join(name))
+            .find(|path| path.is_file())
+        else {
+            let searched: Vec<_> = directories
+                .iter()
+                .map(|directory| {
+                    let directory = directory.display().to_string();
+                    if directory.is_empty() {
+                        ".".to_string()
+                    } else {
+                        directory
+                    }
+                })
+                .collect();
+            return Err(locate(
+                Diagnostic::new(format!("can't find {name:?}"), span.clone())
+                    .help(Some(format!("it isn't in {}", searched.join(", ")))),
+            ));
+        };
+        let canonical = fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+        let display = path.display().to_string();
+        if let Some(index) = self.stack.iter().position(|(other, _)| *other == canonical) {
+            let cycle: Vec<_> = self.stack[index..]
+                .iter()
+                .map(|(_, display)| display.as_str())
+                .chain([display.as_str()])
+                .collect();
+            return Err(locate(
+                Diagnostic::new(format!("{name:?} includes itself"), span.clone())
+                    .help(Some(format!("the includes go {}", cycle.join(" -> ")))),
+            ));
+        }
+        let source = fs::read_to_string(&path)
+            .map_err(|error| locate(Diagnostic::new(format!("can't read {name:?}: {error}"), span.clone())))?;
+        self.stack.push((canonical, display.clone()));
+        let result = self.read(&source, Some(&display), path.parent().unwrap_or(directory));
+        self.stack.pop();
+        result
+    }
+}
+
+/// Whether an operation jumps to a displacement from the next instruction.
+fn is_relative(operation: Operation) -> bool {
+    table::forms(operation)
+        .iter()
+        .any(|form| matches!(form.operands[0], Slot::Short | Slot::Near))
+}
+
+/// A byte or word of data, checking that it fits.
+fn datum(value: i64, wide: bool) -> Result<i64, String> {
+    let (low, high) = if wide { (-0x8000, 0xffff) } else { (-0x80, 0xff) };
+    if (low..=high).contains(&value) {
+        Ok(value)
+    } else {
+        Err(format!(
+            "{value} doesn't fit in a {}",
+            if wide { "word" } else { "byte" }
+        ))
+    }
+}
+
+impl Statement {
+    /// The span of the operands, or of the instruction if it has none.
+    fn operands(&self) -> Range<usize> {
+        match (self.parameters.first(), self.parameters.last()) {
+            (Some(first), Some(last)) => first.span.start..last.span.end,
+            _ => self.span.clone(),
+        }
+    }
+
+    /// The encodings that the operands are written to force.
+    fn overrides(&self) -> Overrides {
+        let displacement = self.parameters.iter().find_map(|parameter| match parameter.argument {
+            Argument::Memory { size, .. } => size,
+            _ => None,
+        });
+        // Relocated segments are words, even if they are 0 before they are relocated.
+        let strict = |parameter: &Parameter| {
+            parameter.strict
+                || matches!(&parameter.argument, Argument::Immediate(expression) if expression.has_segment())
+        };
+        Overrides {
+            strict: self.parameters.iter().any(strict),
+            displacement,
+        }
+    }
+
+    /// Whether the operation is on words, from its registers, sizes and suffix.
+    fn width(&self) -> Result<Option<bool>, Diagnostic> {
+        let mut widths = vec![];
+        widths.extend(self.wide);
+        for (index, parameter) in self.parameters.iter().enumerate() {
+            widths.extend(parameter.wide);
+            if let Argument::Register(register) = parameter.argument {
+                // The count of a shift or rotate, and the port of IN or OUT, don't have the operation's size.
+                let count = index == 1 && register == Register::Cl;
+                let port = matches!(self.operation, Operation::In | Operation::Out) && register == Register::Dx;
+                if !count && !port {
+                    widths.push(register.is_wide());
+                }
+            }
+        }
+        if widths.windows(2).any(|pair| pair[0] != pair[1]) {
+            return Err(Diagnostic::new("mismatched operand sizes", self.operands()));
+        }
+        Ok(widths.first().copied())
+    }
+
+    /// Build the instruction, given the address of the line. A JMP is short unless it is `long` or its target is out
+    /// of range, in which case `long` is set.
+    fn instruction(&self, context: &Context, long: &mut bool) -> Result<Instruction, Diagnostic> {
+        let operation = self.operation;
+        let prefixes = i64::from(self.lock) + i64::from(self.rep.is_some());
+        let mut far = false;
+        let mut segment = None;
+        let mut operands = [Operand::None; 2];
+        let mut wide = self.width()?;
+        for (operand, parameter) in operands.iter_mut().zip(&self.parameters) {
+            let at = |message| Diagnostic::new(message, parameter.span.clone());
+            far |= parameter.far;
+            *operand = match &parameter.argument {
+                Argument::Register(register) => Operand::Register(*register),
+                Argument::Memory {
+                    segment: prefix,
+                    base,
+                    displacement,
+                    size: _,
+                } => {
+                    if prefix.is_some() {
+                        segment = *prefix;
+                    }
+                    let displacement = datum(displacement.evaluate(context)?.unwrap_or(0), true).map_err(at)?;
+                    Operand::Memory(Memory {
+                        base: *base,
+                        displacement: displacement as i16,
+                    })
+                }
+                Argument::Immediate(expression) if is_relative(operation) => {
+                    let target = expression.evaluate(context)?;
+                    let distance = |size: i64| target.map_or(0, |target| target - context.here - size);
+                    let short = i8::try_from(distance(prefixes + 2));
+                    let near = parameter.wide == Some(true);
+                    if near && !matches!(operation, Operation::Jmp | Operation::Call) {
+                        return Err(at("the 8086's conditional jumps are always short".to_string()));
+                    }
+                    if parameter.short && operation == Operation::Call {
+                        return Err(at("calls can't be short".to_string()));
+                    }
+                    *long |= operation == Operation::Jmp && (near || !parameter.short && short.is_err());
+                    let displacement = if operation == Operation::Call || operation == Operation::Jmp && *long {
+                        distance(prefixes + 3) as u16 as i16
+                    } else {
+                        wide = Some(false);
+                        i16::from(short.map_err(|_| {
+                            at("short jump is out of range".to_string()).help(Some(format!(
+                                "the target is {} bytes from the next instruction, and short jumps reach -128 to 127",
+                                distance(prefixes + 2)
+                            )))
+                        })?)
+                    };
+                    Operand::Relative(displacement)
+                }
+                Argument::Immediate(expression) => {
+                    let value = expression.evaluate(context)?.unwrap_or(0);
+                    let value = datum(value, wide.unwrap_or(true)).map_err(at)?;
+                    Operand::Immediate(value as i32)
+                }
+                Argument::Far(segment, offset) => {
+                    let value = |expression: &Expression| {
+                        let value = expression.evaluate(context)?.unwrap_or(0);
+                        u16::try_from(value).map_err(|_| at(format!("{value} isn't a 16-bit address")))
+                    };
+                    Operand::Far(value(segment)?, value(offset)?)
+                }
+            };
+        }
+
+        let wide = match (wide, operands) {
+            (Some(wide), _) => wide,
+            // Jumps, calls, pushes and pops of memory are words.
+            (None, _)
+                if matches!(
+                    operation,
+                    Operation::Jmp | Operation::Call | Operation::Push | Operation::Pop
+                ) =>
+            {
+                true
+            }
+            (None, [Operand::Memory(_), Operand::None | Operand::Immediate(_)]) => {
+                return Err(
+                    Diagnostic::new("operation size not specified", self.parameters[0].span.clone())
+                        .help(Some("write byte or word before the operand".to_string())),
+                );
+            }
+            (None, _) => matches!(operation, Operation::Ret | Operation::Retf) && operands[0] != Operand::None,
+        };
+        let mut instruction = Instruction::new(operation, operands, wide);
+        instruction.far = far;
+        instruction.lock = self.lock;
+        instruction.rep = self.rep;
+        instruction.segment = segment;
+        Ok(instruction)
+    }
+}
+
+/// The count of `times` or `resb`, checking that it is positive and within the 8086's address space.
+fn count(expression: &Expression, span: &Range<usize>, context: &Context) -> Result<usize, Diagnostic> {
+    let count = expression.evaluate(context)?.unwrap_or(0);
+    usize::try_from(count)
+        .ok()
+        .filter(|&count| count <= 0x100000)
+        .ok_or_else(|| Diagnostic::new(format!("the count {count} isn't between 0 and 1048576"), span.clone()))
+}
+
+impl Item {
+    /// The bytes that a line takes up even if it can't be encoded, so that later lines are laid out as they will be
+    /// once it's fixed.
+    fn reserved(&self, long: bool) -> usize {
+        match self {
+            Self::Instruction(statement) if is_relative(statement.operation) => {
+                let prefixes = usize::from(statement.lock) + usize::from(statement.rep.is_some());
+                prefixes
+                    + if long || statement.operation == Operation::Call {
+                        3
+                    } else {
+                        2
+                    }
+            }
+            _ => 0,
+        }
+    }
+
+    /// Encode the instruction or data at an address, adding the offsets of the segments that `seg` wrote to
+    /// `relocations`.
+    fn encode(&self, context: &Context, long: &mut bool, relocations: &mut Vec<usize>) -> Result<Vec<u8>, Diagnostic> {
+        match self {
+            Self::Instruction(statement) => {
+                let instruction = statement.instruction(context, long)?;
+                let bytes = encode_with(&instruction, statement.overrides())
+                    .map_err(|message| Diagnostic::new(message, statement.operands()))?;
+                for parameter in &statement.parameters {
+                    let span = parameter.span.clone();
+                    match &parameter.argument {
+                        // The segment is the last word of the instruction.
+                        Argument::Immediate(Expression::Segment(_)) if instruction.wide => {
+                            context.relocate(span)?;
+                            relocations.push(bytes.len() - 2);
+                        }
+                        Argument::Far(Expression::Segment(_), offset) if !offset.has_segment() => {
+                            context.relocate(span)?;
+                            relocations.push(bytes.len() - 2);
+                        }
+                        Argument::Immediate(expression)
+                        | Argument::Memory {
+                            displacement: expression,
+                            ..
+                        } if expression.has_segment() => return Err(Diagnostic::new(SEGMENT_ALONE, span)),
+                        Argument::Far(segment, offset) if segment.has_segment() || offset.has_segment() => {
+                            return Err(Diagnostic::new(SEGMENT_ALONE, span));
+                        }
+                        _ => {}
+                    }
+                }
+                Ok(bytes)
+            }
+            Self::Data { wide, data } => {
+                let mut bytes = vec![];
+                for datum in data {
+                    match datum {
+                        Datum::Text(text) => {
+                            bytes.extend(text);
+                            if *wide && text.len() % 2 == 1 {
+                                bytes.push(0);
+                            }
+                        }
+                        Datum::Expression(expression, span) => {
+                            if matches!(expression, Expression::Segment(_)) && *wide {
+                                context.relocate(span.clone())?;
+                                relocations.push(bytes.len());
+                            } else if expression.has_segment() {
+                                return Err(Diagnostic::new(SEGMENT_ALONE, span.clone()));
+                            }
+                            let value = self::datum(expression.evaluate(context)?.unwrap_or(0), *wide)
+                                .map_err(|message| Diagnostic::new(message, span.clone()))?;
+                            if *wide {
+                                bytes.extend((value as u16).to_le_bytes());
+                            } else {
+                                bytes.push(value as u8);
+                            }
+                        }
+                    }
+                }
+                Ok(bytes)
+            }
+            Self::Times(count, span, item) => {
+                let mut bytes = vec![];
+                for _ in 0..self::count(count, span, context)? {
+                    let here = context.here + bytes.len() as i64;
+                    let mut repeated = vec![];
+                    let start = bytes.len();
+                    bytes.extend(item.encode(&Context { here, ..*context }, long, &mut repeated)?);
+                    relocations.extend(repeated.into_iter().map(|offset| start + offset));
+                    // Stop before the bytes fill the memory, like with `times 0x100000 resb 0x100000`.
+                    if bytes.len() > 0x100000 {
+                        return Err(Diagnostic::new("times writes more than 1048576 bytes", span.clone()));
+                    }
+                }
+                Ok(bytes)
+            }
+            Self::Reserve { wide, count, span } => {
+                Ok(vec![0; self::count(count, span, context)? << usize::from(*wide)])
+            }
+            Self::Constant(_) | Self::Origin(..) => Ok(vec![]),
+        }
+    }
+}
+
+/// Assemble a program into a flat binary, which is loaded at offset 0 unless `org` sets another.
+///
+/// # Errors
+///
+/// If a line is invalid, for example with an unknown instruction, invalid operands, an undefined label or a short
+/// jump that is out of range.
+pub fn assemble(source: &str) -> Result<Vec<u8>, Error> {
+    assemble_at(source, 0)
+}
+
+/// Assemble a program into a flat binary, which is loaded at an offset unless `org` sets another. Files that it
+/// includes are found from the current directory.
+///
+/// # Errors
+///
+/// If a line is invalid.
+pub fn assemble_at(source: &str, origin: u16) -> Result<Vec<u8>, Error> {
+    layout(parse(source, &Includes::default())?, origin, Format::Flat).map(|layout| layout.binary)
+}
+
+/// Assemble a program into a file of a format, finding the files that it includes in directories.
+///
+/// # Errors
+///
+/// If a line is invalid, an included file can't be read, or the program doesn't fit the format.
+pub fn assemble_as(source: &str, format: Format, includes: &Includes) -> Result<Vec<u8>, Error> {
+    build(parse(source, includes)?, format).map(|(file, _)| file)
+}
+
+/// Write code over the instructions of a binary at an offset, and return the number of bytes that it replaces.
+///
+/// If the code ends inside an instruction, it is padded with NOPs to the end of that instruction, so that the
+/// instructions after it still decode.
+///
+/// # Errors
+///
+/// If the code goes past the end of the binary.
+pub fn patch(binary: &mut [u8], offset: usize, code: &[u8]) -> Result<usize, String> {
+    let mut end = offset;
+    while end < offset + code.len() {
+        // Data that doesn't decode is overwritten as it is.
+        match binary.get(end..).and_then(decode) {
+            Some(instruction) => end += usize::from(instruction.size),
+            None => end = offset + code.len(),
+        }
+    }
+    if end > binary.len() {
+        return Err(format!(
+            "the patch ends at {end:#x}, past the end of the binary at {:#x}",
+            binary.len()
+        ));
+    }
+    binary[offset..offset + code.len()].copy_from_slice(code);
+    binary[offset + code.len()..end].fill(NOP);
+    Ok(end - offset)
+}
+
+/// Assemble a program, and list each line of its source beside its address and bytes, like NASM's `-l` listings.
+/// Lines of more than 9 bytes continue on the next lines of the listing, which end with `-`.
+///
+/// The addresses are offsets in the image, after any header of the format. The lines of included files follow the
+/// `%include`, marked with how deeply they are included, like `<1>`, and the listing numbers all the lines in order.
+///
+/// # Errors
+///
+/// If the program can't be assembled.
+pub fn listing(source: &str, format: Format, includes: &Includes) -> Result<(Vec<u8>, String), Error> {
+    let (file, layout) = build(parse(source, includes)?, format)?;
+    let mut listing = String::new();
+    for ((number, line), range) in (1..).zip(&layout.lines).zip(&layout.ranges) {
+        let text = if line.depth == 0 {
+            line.text.clone()
+        } else {
+            format!("<{}> {}", line.depth, line.text)
+        };
+        let bytes = &layout.binary[range.clone()];
+        if bytes.is_empty() {
+            let _ = writeln!(listing, "{number:6} {:8} {:24}{text}", "", "");
+        }
+        for (index, chunk) in bytes.chunks(9).enumerate() {
+            let address = range.start + 9 * index;
+            let mut hex = hex(chunk);
+            if address + chunk.len() < range.end {
+                hex.push('-');
+            }
+            if index == 0 {
+                let _ = writeln!(listing, "{number:6} {address:08X} {hex:24}{text}");
+            } else {
+                let _ = writeln!(listing, "{number:6} {address:08X} {hex}");
+            }
+        }
+    }
+    Ok((file, listing))
+}
+
+/// Bytes in hexadecimal, like NASM's listings.
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{byte:02X}");
+        hex
+    })
+}
+
+/// A line that assembles to different bytes than NASM assembles it to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mismatch {
+    /// The path of the included file that the line is in, or `None` if it is in the source.
+    pub file: Option<String>,
+    /// The line number, from 1.
+    pub line: usize,
+    pub text: String,
+    /// The offset of the line's bytes in NASM's binary.
+    pub offset: usize,
+    pub ours: Vec<u8>,
+    pub nasm: Vec<u8>,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{file}, ")?;
+        }
+        write!(
+            f,
+            "line {} at {:#06x}: {}: ours {}, NASM's {}",
+            self.line,
+            self.offset,
+            self.text.trim(),
+            hex(&self.ours),
+            hex(&self.nasm)
+        )
+    }
+}
+
+/// Assemble a program into a flat binary, and compare each line's bytes with the binary that NASM assembled from it.
+///
+/// Where NASM's encoding of an instruction is a different length, the lines after it are compared with NASM's bytes
+/// after the instruction that NASM's bytes decode to, so that each mismatch is reported once. Data is compared as
+/// long as it is.
+///
+/// # Errors
+///
+/// If the program can't be assembled.
+pub fn compare(source: &str, includes: &Includes, nasm: &[u8]) -> Result<Vec<Mismatch>, Error> {
+    let layout = layout(parse(source, includes)?, 0, Format::Flat)?;
+    let mut mismatches = vec![];
+    // Where NASM's bytes for the line start.
+    let mut offset = 0;
+    for (line, range) in layout.lines.iter().zip(&layout.ranges) {
+        if range.is_empty() {
+            continue;
+        }
+        let ours = &layout.binary[range.clone()];
+        let rest = nasm.get(offset..).unwrap_or_default();
+        let size = match &line.item {
+            Some(Item::Instruction(_)) => decode(rest).map_or(ours.len(), |instruction| usize::from(instruction.size)),
+            _ => ours.len(),
+        };
+        let theirs = &rest[..size.min(rest.len())];
+        if theirs != ours {
+            mismatches.push(Mismatch {
+                file: line.file.clone(),
+                line: line.number,
+                text: line.text.clone(),
+                offset,
+                ours: ours.to_vec(),
+                nasm: theirs.to_vec(),
+            });
+        }
+        offset += size;
+    }
+    Ok(mismatches)
+}
+
+/// Assemble the lines of a program into a file of a format, with its layout.
+fn build(lines: Vec<Line>, format: Format) -> Result<(Vec<u8>, Layout), Error> {
+    let origin = if format == Format::Com { 0x100 } else { 0 };
+    let layout = layout(lines, origin, format)?;
+    // The error for a program that doesn't fit, at the line that goes past the end.
+    let overflow = |message: &str, end: usize| {
+        let index = layout.ranges.iter().position(|range| range.end > end).unwrap_or(0);
+        let line = &layout.lines[index];
+        line.locate(Diagnostic::new(message, 0..line.text.len()))
+    };
+    match format {
+        Format::Flat => Ok((layout.binary.clone(), layout)),
+        Format::Com => {
+            // The stack starts at the top of the segment.
+            let end = 0xfffe - 0x100;
+            if layout.binary.len() > end {
+                return Err(overflow("the program is too large for a .COM file", end));
+            }
+            Ok((layout.binary.clone(), layout))
+        }
+        Format::Exe => {
+            // The header's offsets into the segment, like the entry point and the relocations, are words.
+            if layout.binary.len() > 0xffff {
+                return Err(overflow("the program doesn't fit in a segment", 0xffff));
+            }
+            let label = |name: &str| layout.labels.get(name).copied().flatten();
+            let entry = label("..start").unwrap_or(0);
+            let stack =
+                label("..stack").unwrap_or_else(|| (layout.binary.len() + STACK_SIZE).next_multiple_of(2) as i64);
+            if stack > 0x10000 {
+                return Err(overflow(
+                    "the program and its stack don't fit in a segment",
+                    0x10000 - STACK_SIZE,
+                ));
+            }
+            let extra = (stack as usize).saturating_sub(layout.binary.len());
+            let file = exe(&layout.binary, &layout.relocations, entry as u16, stack as u16, extra);
+            Ok((file, layout))
+        }
+    }
+}
+
+/// Write an MZ .EXE: a header with the relocations, the entry point and the stack, then the image, which is one
+/// segment, followed by `extra` bytes of memory.
+fn exe(image: &[u8], relocations: &[usize], entry: u16, stack: u16, extra: usize) -> Vec<u8> {
+    const RELOCATION_TABLE: usize = 0x1c;
+    let header = (RELOCATION_TABLE + 4 * relocations.len()).next_multiple_of(16);
+    let size = header + image.len();
+    let mut file = vec![0; header];
+    let mut write = |offset: usize, value: u16| file[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+    write(0x00, u16::from_le_bytes(*b"MZ"));
+    // The size in 512-byte pages, the last of which is partial.
+    write(0x02, (size % 512) as u16);
+    write(0x04, size.div_ceil(512) as u16);
+    write(0x06, relocations.len() as u16);
+    write(0x08, (header / 16) as u16);
+    // The least and most memory after the image, in paragraphs.
+    write(0x0a, extra.div_ceil(16) as u16);
+    write(0x0c, 0xffff);
+    // SS:SP and CS:IP, with segments relative to the image.
+    write(0x0e, 0);
+    write(0x10, stack);
+    write(0x14, entry);
+    write(0x16, 0);
+    write(0x18, RELOCATION_TABLE as u16);
+    // Each relocation is the offset and segment of a word.
+    for (index, &offset) in relocations.iter().enumerate() {
+        write(RELOCATION_TABLE + 4 * index, offset as u16);
+    }
+    file.extend(image);
+    file
+}
+
+/// An assembled program.
+struct Layout {
+    lines: Vec<Line>,
+    binary: Vec<u8>,
+    /// The range of the binary that each line produced.
+    ranges: Vec<Range<usize>>,
+    /// The offsets of the segments that `seg` wrote, which DOS relocates.
+    relocations: Vec<usize>,
+    labels: HashMap<String, Option<i64>>,
+}
+
+/// Assemble the lines of a program, which is loaded at an offset unless `org` sets another. Only flat binaries can
+/// set it.
+fn layout(lines: Vec<Line>, origin: u16, format: Format) -> Result<Layout, Error> {
+    let mut labels = HashMap::new();
+    let mut definitions = HashMap::new();
+    for line in &lines {
+        if let Some((label, span)) = &line.label {
+            if let Some(first) = definitions.insert(label.clone(), line) {
+                return Err(line.locate(
+                    Diagnostic::new(format!("label {label:?} is already defined"), span.clone())
+                        .help(Some(format!("it is first defined on {}", first.place(line)))),
+                ));
+            }
+            labels.insert(label.clone(), None);
+        }
+    }
+
+    // The program is loaded at the address that `org` sets, wherever it is.
+    let mut origins = lines.iter().filter_map(|line| match &line.item {
+        Some(Item::Origin(expression, span)) => Some((line, expression, span)),
+        _ => None,
+    });
+    let origin = match origins.next() {
+        Some((line, expression, span)) => {
+            let context = Context {
+                labels: &HashMap::new(),
+                here: 0,
+                origin: 0,
+                relocatable: false,
+            };
+            let value = expression
+                .evaluate(&context)
+                .map_err(|diagnostic| line.locate(diagnostic))?;
+            if format != Format::Flat && value != Some(i64::from(origin)) {
+                let name = if format == Format::Com { ".COM" } else { ".EXE" };
+                return Err(line.locate(Diagnostic::new(
+                    format!("the origin of a {name} program is {origin:#x}"),
+                    span.clone(),
+                )));
+            }
+            if let Some((again, _, span)) = origins.next() {
+                return Err(again.locate(
+                    Diagnostic::new("the origin is already set", span.clone())
+                        .help(Some(format!("it is set on {}", line.place(again)))),
+                ));
+            }
+            value.unwrap_or(0)
+        }
+        None => i64::from(origin),
+    };
+
+    // Whether each line is a JMP that is too far for a short jump.
+    let mut long = vec![false; lines.len()];
+    let mut moved = None;
+    for _ in 0..PASSES {
+        let mut output = vec![];
+        let mut ranges = Vec::with_capacity(lines.len());
+        let mut relocations = vec![];
+        let mut addresses = HashMap::new();
+        let mut error = None;
+        moved = None;
+        let mut settled = true;
+        for (line, long) in lines.iter().zip(&mut long) {
+            let start = output.len();
+            let here = origin + start as i64;
+            let context = Context {
+                labels: &labels,
+                here,
+                origin,
+                relocatable: format == Format::Exe,
+            };
+            if let Some((label, _)) = &line.label {
+                let value = match &line.item {
+                    Some(Item::Constant(expression)) => expression.evaluate(&context).unwrap_or_else(|diagnostic| {
+                        error.get_or_insert_with(|| line.locate(diagnostic));
+                        None
+                    }),
+                    _ => Some(here),
+                };
+                addresses.insert(label.clone(), value);
+                if labels.get(label) != Some(&value) {
+                    moved.get_or_insert(line);
+                }
+            }
+            if let Some(item) = &line.item {
+                let was_long = *long;
+                let mut offsets = vec![];
+                match item.encode(&context, long, &mut offsets) {
+                    Ok(bytes) => {
+                        output.extend(bytes);
+                        relocations.extend(offsets.into_iter().map(|offset| start + offset));
+                    }
+                    Err(diagnostic) => {
+                        error.get_or_insert_with(|| line.locate(diagnostic));
+                        output.resize(output.len() + item.reserved(*long), 0);
+                    }
+                }
+                settled &= *long == was_long;
+            }
+            ranges.push(start..output.len());
+        }
+        if settled && moved.is_none() {
+            // Constants that refer to each other never get values.
+            let circular = lines.iter().find_map(|line| {
+                let (label, span) = line.label.as_ref()?;
+                labels[label].is_none().then(|| {
+                    let message = format!("the value of {label:?} depends on itself");
+                    line.locate(Diagnostic::new(message, span.clone()))
+                })
+            });
+            if let Some(error) = error.or(circular) {
+                return Err(error);
+            }
+            return Ok(Layout {
+                lines,
+                binary: output,
+                ranges,
+                relocations,
+                labels,
+            });
+        }
+        labels = addresses;
+    }
+    let line = moved.unwrap_or(&lines[0]);
+    let span = line.label.as_ref().map_or(0..0, |(_, span)| span.clone());
+    Err(line.locate(Diagnostic::new("the address of the label doesn't settle", span)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    /// Assemble a listing, and compare it with NASM's binary.
+    fn check(path: &str) {
+        let source = fs::read_to_string(format!("{path}.asm")).unwrap();
+        let nasm = fs::read(path).unwrap();
+        let mismatches = compare(&source, &Includes::default(), &nasm).unwrap();
+        assert!(mismatches.is_empty(), "{}", mismatches[0]);
+        assert_eq!(assemble(&source).unwrap(), nasm);
+    }
+
+    include!(concat!(env!("OUT_DIR"), "/main.include"));
+
+    #[test]
+    fn labels() {
+        let source = "
+            start:
+                jmp end     ; forward, short
+                dec cx
+                jnz start   ; backward
+            end:
+                jmp start
+                call end
+        ";
+        assert_eq!(
+            assemble(source).unwrap(),
+            [
+                &[0b11101011, 3][..],      // jmp $+5
+                &[0b01001001],             // dec cx
+                &[0b01110101, 0xfb],       // jne $-3
+                &[0b11101011, 0xf9],       // jmp $-5
+                &[0b11101000, 0xfb, 0xff], // call $-2
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn expressions() {
+        let source = "
+            mov ax, 2 + 3 * 4       ; 14
+            mov bx, (2 + 3) * 4     ; 20
+            mov cx, 1 << 2 + 1      ; 8
+            mov dx, -2 * -3 - 1     ; 5
+            mov si, [bx + 2 * 3]
+        ";
+        assert_eq!(
+            assemble(source).unwrap(),
+            [
+                &[0b10111000, 14, 0][..],     // mov ax, 14
+                &[0b10111011, 20, 0],         // mov bx, 20
+                &[0b10111001, 8, 0],          // mov cx, 8
+                &[0b10111010, 5, 0],          // mov dx, 5
+                &[0b10001011, 0b01110111, 6], // mov si, [bx + 6]
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn constants() {
+        let source = "
+                mov cx, length      ; forward
+                mov dx, message
+                jmp copy.done
+            message:
+                db 'hello'
+            length equ $ - message
+            limit: EQU length * 2
+            copy:
+                cmp cx, limit
+            .done:
+                jmp .done
+            other:
+            .done:
+                jmp copy.done
+        ";
+        assert_eq!(
+            assemble(source).unwrap(),
+            [
+                &[0b10111001, 5, 0][..],       // mov cx, 5
+                &[0b10111010, 8, 0],           // mov dx, 8
+                &[0b11101011, 8],              // jmp $+10
+                b"hello",                      // db 'hello'
+                &[0b10000011, 0b11111001, 10], // cmp cx, 10
+                &[0b11101011, 0xfe],           // jmp $+0
+                &[0b11101011, 0xfc],           // jmp $-2
+            ]
+            .concat()
+        );
+        let located = |source| {
+            let error = assemble(source).unwrap_err();
+            (error.columns, error.message)
+        };
+        assert_eq!(located("equ 5"), (0..3, "equ needs a name before it".to_string()));
+        assert_eq!(
+            located("a equ b\nb equ a"),
+            (0..1, "the value of \"a\" depends on itself".to_string())
+        );
+    }
+
+    #[test]
+    fn overrides() {
+        let source = "
+            add bx, 5
+            add bx, strict word 5
+            add ax, strict word 5
+            mov cx, [bx]
+            mov cx, [byte bx]
+            mov cx, [word bx + 1]
+            jmp near next
+            next:
+            jmp short next
+        ";
+        assert_eq!(
+            assemble(source).unwrap(),
+            [
+                &[0b10000011, 0b11_000_011, 5][..], // add bx, 5
+                &[0b10000001, 0b11_000_011, 5, 0],  // add bx, 5
+                &[0b00000101, 5, 0],                // add ax, 5
+                &[0b10001011, 0b00_001_111],        // mov cx, [bx]
+                &[0b10001011, 0b01_001_111, 0],     // mov cx, [bx]
+                &[0b10001011, 0b10_001_111, 1, 0],  // mov cx, [bx+1]
+                &[0b11101001, 0, 0],                // jmp $+3
+                &[0b11101011, 0xfe],                // jmp $+0
+            ]
+            .concat()
+        );
+        let message = |source| assemble(source).unwrap_err().message;
+        assert_eq!(message("jmp short $+200"), "short jump is out of range");
+        assert_eq!(message("jz near $"), "the 8086's conditional jumps are always short");
+        assert_eq!(
+            message("mov cx, [byte bx + 300]"),
+            "300 doesn't fit in a byte displacement"
+        );
+    }
+
+    #[test]
+    fn directives() {
+        let source = "
+                org 0x100
+            start:
+                mov si, table
+                jmp start
+            table:
+                times 2 dw $
+                times 3 db 'ab'
+            buffer:
+                resw 2
+                dw buffer - $$
+                times 32 - ($ - $$) db 0xff
+        ";
+        assert_eq!(
+            assemble(source).unwrap(),
+            [
+                &[0b10111110, 0x05, 0x01][..], // mov si, 0x105
+                &[0b11101011, 0xfb],           // jmp $-3
+                &[0x05, 0x01, 0x07, 0x01],     // times 2 dw $
+                b"ababab",                     // times 3 db 'ab'
+                &[0; 4],                       // resw 2
+                &[0x0f, 0],                    // dw buffer - $$
+                &[0xff; 11],                   // times 32 - ($ - $$) db 0xff
+            ]
+            .concat()
+        );
+        let located = |source| {
+            let error = assemble(source).unwrap_err();
+            (error.line, error.columns, error.message)
+        };
+        assert_eq!(
+            located("org 1\norg 2"),
+            (2, 0..3, "the origin is already set".to_string())
+        );
+        assert_eq!(
+            located("times 1 - 2 hlt"),
+            (1, 6..11, "the count -1 isn't between 0 and 1048576".to_string())
+        );
+        assert_eq!(
+            located("times 2 bits 16"),
+            (1, 8..12, "only instructions and data can be repeated".to_string())
+        );
+        assert_eq!(
+            located("times 2 times 3 hlt"),
+            (1, 8..13, "times can't be nested".to_string())
+        );
+        assert_eq!(
+            located("times 0x100000 resb 0x100000"),
+            (1, 6..14, "times writes more than 1048576 bytes".to_string())
+        );
+    }
+
+    #[test]
+    fn formats() {
+        // .COM programs are loaded at 0x100.
+        assert_eq!(
+            assemble_as("mov si, data\ndata: db 1", Format::Com, &Includes::default()).unwrap(),
+            [0b10111110, 0x03, 0x01, 0x01]
+        );
+        let source = "
+            ..start:
+                mov ax, seg value
+                mov ds, ax
+                mov bx, [value]
+                hlt
+            value:
+                dw 0x1234
+                dw value, seg value
+        ";
+        let file = assemble_as(source, Format::Exe, &Includes::default()).unwrap();
+        assert_eq!(file.len(), 64);
+        let mut machine = crate::sim::Machine::new();
+        let range = crate::dos::load_exe(&mut machine, &file).unwrap();
+        assert_eq!(range, 0x10100..0x10110);
+        for _ in 0..3 {
+            machine.step().unwrap();
+        }
+        let registers = &machine.registers;
+        assert_eq!(registers.get(Register::Ax), 0x1010);
+        assert_eq!(registers.get(Register::Ds), 0x1010);
+        assert_eq!(registers.get(Register::Bx), 0x1234);
+        assert_eq!(registers.get(Register::Ss), 0x1010);
+        // The stack is after the image.
+        assert_eq!(registers.get(Register::Sp), 0x110);
+        assert_eq!(machine.memory[0x1010c..0x10110], [0x0a, 0, 0x10, 0x10]);
+
+        let located = |source, format| {
+            let error = assemble_as(source, format, &Includes::default()).unwrap_err();
+            (error.line, error.columns, error.message)
+        };
+        assert_eq!(
+            located("org 0\nhlt", Format::Com),
+            (1, 0..3, "the origin of a .COM program is 0x100".to_string())
+        );
+        assert_eq!(
+            located("hlt\nresb 0xff00", Format::Com),
+            (2, 0..11, "the program is too large for a .COM file".to_string())
+        );
+        assert_eq!(
+            located("..stack:\nresb 0xffff\n..start: mov ax, seg data\ndata:", Format::Exe),
+            (3, 0..25, "the program doesn't fit in a segment".to_string())
+        );
+        assert_eq!(
+            located("mov ax, seg data\ndata:", Format::Flat),
+            (1, 8..16, "seg needs an .EXE, which DOS relocates".to_string())
+        );
+        assert_eq!(
+            located("data: db seg data", Format::Exe),
+            (1, 9..17, SEGMENT_ALONE.to_string())
+        );
+    }
+
+    #[test]
+    fn includes() {
+        let directory = tempfile::tempdir().unwrap();
+        let library = directory.path().join("library");
+        fs::create_dir(&library).unwrap();
+        let write = |path: PathBuf, text: &str| fs::write(path, text).unwrap();
+        write(directory.path().join("regs.inc"), "count equ 3\n%include \"loop.inc\"");
+        write(library.join("loop.inc"), "again: dec cx\njnz again");
+        write(library.join("bad.inc"), "hlt\nmov ax, bl");
+        write(directory.path().join("a.inc"), "%include 'b.inc'");
+        write(directory.path().join("b.inc"), "%include 'a.inc'");
+        let includes = Includes {
+            directory: directory.path().to_path_buf(),
+            paths: vec![library.clone()],
+        };
+        let source = "mov cx, count\n%include \"regs.inc\"\nhlt";
+        let (binary, listing) = super::listing(source, Format::Flat, &includes).unwrap();
+        assert_eq!(
+            binary,
+            [
+                &[0b10111001, 3, 0][..], // mov cx, 3
+                &[0b01001001],           // dec cx
+                &[0b01110101, 0xfd],     // jnz $-1
+                &[0b11110100],           // hlt
+            ]
+            .concat()
+        );
+        assert_eq!(
+            listing.lines().collect::<Vec<_>>(),
+            [
+                "     1 00000000 B90300                  mov cx, count",
+                "     2                                  %include \"regs.inc\"",
+                "     3                                  <1> count equ 3",
+                "     4                                  <1> %include \"loop.inc\"",
+                "     5 00000003 49                      <2> again: dec cx",
+                "     6 00000004 75FD                    <2> jnz again",
+                "     7 00000006 F4                      hlt",
+            ]
+        );
+
+        let error = |source| assemble_as(source, Format::Flat, &includes).unwrap_err();
+        let bad = library.join("bad.inc").display().to_string();
+        let report = error("%include 'bad.inc'").report("main.asm");
+        assert!(
+            report.starts_with(&format!("{bad}:2:5: mismatched operand sizes\n")),
+            "{report}"
+        );
+        let missing = error("hlt\n%include \"missing.inc\"");
+        assert_eq!(
+            (missing.file, missing.line, missing.columns, missing.message),
+            (None, 2, 9..22, "can't find \"missing.inc\"".to_string())
+        );
+        assert_eq!(
+            missing.help.unwrap(),
+            format!("it isn't in {}, {}", directory.path().display(), library.display())
+        );
+        let cycle = error("%include 'a.inc'");
+        let [a, b] = ["a.inc", "b.inc"].map(|name| directory.path().join(name).display().to_string());
+        assert_eq!(
+            (cycle.file.unwrap(), cycle.message, cycle.help.unwrap()),
+            (
+                b.clone(),
+                "\"a.inc\" includes itself".to_string(),
+                format!("the includes go {a} -> {b} -> {a}")
+            )
+        );
+        let duplicate = error("count: hlt\n%include 'regs.inc'");
+        assert_eq!(duplicate.help.unwrap(), "it is first defined on line 1 of the source");
+        assert_eq!(
+            error("%include regs.inc").message,
+            "%include needs the name of a file in quotes"
+        );
+    }
+
+    #[test]
+    fn comparisons() {
+        let source = "
+            mov cx, 3
+            add bx, 5
+            jmp next
+        next:
+            db 1, 2
+            hlt
+        ";
+        let nasm = [
+            &[0b10111001, 3, 0][..],           // mov cx, 3
+            &[0b10000001, 0b11_000_011, 5, 0], // add bx, strict word 5
+            &[0b11101001, 0, 0],               // jmp near $+3
+            &[1, 3],                           // db 1, 3
+            &[0b11110100],                     // hlt
+        ]
+        .concat();
+        let mismatches = compare(source, &Includes::default(), &nasm).unwrap();
+        let found: Vec<_> = mismatches
+            .iter()
+            .map(|mismatch| (mismatch.line, mismatch.offset))
+            .collect();
+        // After NASM's longer encodings, the data and the HLT are compared with NASM's bytes after them.
+        assert_eq!(found, [(3, 3), (4, 7), (6, 10)]);
+        assert_eq!(
+            mismatches[0].to_string(),
+            "line 3 at 0x0003: add bx, 5: ours 83C305, NASM's 81C30500"
+        );
+        assert_eq!(mismatches[2].nasm, [1, 3]);
+    }
+
+    #[test]
+    fn patches() {
+        let mut binary = [
+            &[0b10111001, 3, 0][..],     // mov cx, 3
+            &[0b00000001, 0b11_001_011], // add bx, cx
+            &[0b11100010, 0xf9],         // loop $-5
+            &[0b11110100],               // hlt
+        ]
+        .concat();
+        let code = assemble_at("inc bx\njmp short 0", 3).unwrap();
+        assert_eq!(code, [0b01000011, 0b11101011, 0xfa]);
+        assert_eq!(patch(&mut binary, 3, &code), Ok(4));
+        assert_eq!(binary[3..], [0b01000011, 0b11101011, 0xfa, NOP, 0b11110100]);
+        assert_eq!(
+            patch(&mut binary, 7, &code),
+            Err("the patch ends at 0xa, past the end of the binary at 0x8".to_string())
+        );
+    }
+
+    #[test]
+    fn long_jumps() {
+        let source = format!("jmp end\n{}end: jmp $-300\n", "hlt\n".repeat(200));
+        let binary = assemble(&source).unwrap();
+        assert_eq!(binary[..3], [0b11101001, 200, 0]);
+        assert_eq!(binary[203..], [0b11101001, 0xd1, 0xfe]);
+        assert!(assemble(&format!("jz end\n{}end:", "hlt\n".repeat(200))).is_err());
+    }
+
+    #[test]
+    fn listing() {
+        let (binary, listing) = super::listing(
+            "bits 16\nstart: mov cx, bx\ndb 'abcdefghij'",
+            Format::Flat,
+            &Includes::default(),
+        )
+        .unwrap();
+        assert_eq!(binary.len(), 12);
+        assert_eq!(
+            listing,
+            [
+                "     1                                  bits 16\n",
+                "     2 00000000 89D9                    start: mov cx, bx\n",
+                "     3 00000002 616263646566676869-     db 'abcdefghij'\n",
+                "     3 0000000B 6A\n",
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn data() {
+        let source = "
+            bits 16
+            table: db 1, -1, 'ab', 0x7f
+                   dw 1000, table + 1, \"abc\"
+        ";
+        assert_eq!(
+            assemble(source).unwrap(),
+            [1, 0xff, b'a', b'b', 0x7f, 0xe8, 0x03, 1, 0, b'a', b'b', b'c', 0]
+        );
+    }
+
+    #[test]
+    fn operands() {
+        assert_eq!(
+            assemble("mov [bx + si + 4], byte 7").unwrap(),
+            [0b11000110, 0b01_000_000, 4, 7]
+        );
+        assert_eq!(
+            assemble("mov ax, es:[bp]").unwrap(),
+            [0b00100110, 0b10001011, 0b01_000_110, 0]
+        );
+        assert_eq!(assemble("mov al, [es:3ah]").unwrap(), [0b00100110, 0b10100000, 0x3a, 0]);
+        assert_eq!(assemble("rep stosw").unwrap(), [0b11110011, 0b10101011]);
+        assert_eq!(
+            assemble("lock xchg [100], al").unwrap(),
+            [0b11110000, 0b10000110, 0b00_000_110, 100, 0]
+        );
+        assert_eq!(assemble("mov cl, 'A'").unwrap(), [0b10110001, b'A']);
+        assert_eq!(
+            assemble("shl word [bp + 5], cl").unwrap(),
+            [0b11010011, 0b01_100_110, 5]
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let error = |source| assemble(source).unwrap_err();
+        assert_eq!(
+            error("bits 16\n  move ax, bx"),
+            Error {
+                file: None,
+                line: 2,
+                columns: 2..6,
+                message: "unknown instruction \"move\"".to_string(),
+                help: Some("did you mean \"mov\"?".to_string()),
+                text: "  move ax, bx".to_string(),
+            }
+        );
+        let located = |source| {
+            let error = error(source);
+            (error.columns, error.message)
+        };
+        assert_eq!(located("mov al, bx"), (4..10, "mismatched operand sizes".to_string()));
+        assert_eq!(located("inc [bx]"), (4..8, "operation size not specified".to_string()));
+        assert_eq!(
+            located("jmp nowhere"),
+            (4..11, "undefined label \"nowhere\"".to_string())
+        );
+        assert_eq!(
+            located("mov ax, [bx + bp]"),
+            (8..17, "invalid effective address".to_string())
+        );
+        assert_eq!(located("mov al, 256"), (8..11, "256 doesn't fit in a byte".to_string()));
+        assert_eq!(
+            located("mov ax, bx cx"),
+            (11..13, "unexpected text after the instruction".to_string())
+        );
+        assert_eq!(located("mov ax, 12q"), (8..11, "invalid number \"12q\"".to_string()));
+        assert_eq!(located("cbw ax"), (4..6, "invalid operands for cbw".to_string()));
+        assert_eq!(error("a:\na: hlt").line, 2);
+        assert_eq!(error("mvo ax, bx").help, Some("did you mean \"mov\"?".to_string()));
+        assert_eq!(error("x: jmp y").help, None);
+        assert_eq!(
+            error("start: jmp strat").help,
+            Some("did you mean \"start\"?".to_string())
+        );
+    }
+
+    #[test]
+    fn nesting() {
+        let message = |source: String| assemble(&source).unwrap_err().message;
+        let deepest = format!("mov ax, {}1{}", "(".repeat(MAX_DEPTH), ")".repeat(MAX_DEPTH));
+        assert_eq!(assemble(&deepest).unwrap(), [0b10111000, 1, 0]);
+        assert_eq!(
+            message(format!("mov ax, {}1{}", "(".repeat(10000), ")".repeat(10000))),
+            TOO_DEEP
+        );
+        assert_eq!(message(format!("mov ax, {}1", "-".repeat(10000))), TOO_DEEP);
+        assert_eq!(message(format!("mov ax, 1{}", "+1".repeat(10000))), TOO_DEEP);
+        assert_eq!(message(format!("mov ax, [bx{}]", "+1".repeat(10000))), TOO_DEEP);
+    }
+
+    #[test]
+    fn report() {
+        let error = assemble(&format!("start:\n\tjz end\n{}end:", "hlt\n".repeat(200))).unwrap_err();
+        assert_eq!(
+            error.report("test.asm"),
+            "test.asm:2:5: short jump is out of range
+2 | \tjz end
+  | \t   ^^^
+  = help: the target is 200 bytes from the next instruction, and short jumps reach -128 to 127"
+        );
+    }
+}
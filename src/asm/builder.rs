@@ -0,0 +1,628 @@
+//! Build 8086 programs from Rust, without writing their source, like
+//! `Asm::new().mov(AX, Imm(5)).jmp(start).assemble()`.
+//!
+//! A program is laid out like a source program, so jumps are short where their targets are in range, and labels can
+//! be used before they are bound. Errors are reported like the assembler's, with the line number of the instruction,
+//! label or data in the program, from 1, and the instruction written in NASM's syntax.
+
+use std::fmt;
+use std::ops::Range;
+
+use super::{build, layout, Argument, Datum, Error, Expression, Format, Item, Line, Parameter, Statement, NOP};
+use crate::instruction::{Base, Operation, Register, Rep};
+
+pub const AL: Register = Register::Al;
+pub const CL: Register = Register::Cl;
+pub const DL: Register = Register::Dl;
+pub const BL: Register = Register::Bl;
+pub const AH: Register = Register::Ah;
+pub const CH: Register = Register::Ch;
+pub const DH: Register = Register::Dh;
+pub const BH: Register = Register::Bh;
+pub const AX: Register = Register::Ax;
+pub const CX: Register = Register::Cx;
+pub const DX: Register = Register::Dx;
+pub const BX: Register = Register::Bx;
+pub const SP: Register = Register::Sp;
+pub const BP: Register = Register::Bp;
+pub const SI: Register = Register::Si;
+pub const DI: Register = Register::Di;
+pub const ES: Register = Register::Es;
+pub const CS: Register = Register::Cs;
+pub const SS: Register = Register::Ss;
+pub const DS: Register = Register::Ds;
+
+/// A position in a program, which is bound to an address with [`Asm::bind`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Label(usize);
+
+impl fmt::Display for Label {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "label{}", self.0)
+    }
+}
+
+/// An effective address, like `es:[bx + si + label + 4]`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Mem {
+    segment: Option<Register>,
+    base: Base,
+    label: Option<Label>,
+    /// The sum of the displacements, which is wider than they are so that adding them doesn't overflow.
+    displacement: i64,
+    wide: Option<bool>,
+    far: bool,
+}
+
+impl Mem {
+    /// The address in base registers.
+    #[must_use]
+    pub const fn new(base: Base) -> Self {
+        Self {
+            segment: None,
+            base,
+            label: None,
+            displacement: 0,
+            wide: None,
+            far: false,
+        }
+    }
+
+    /// A direct address.
+    #[must_use]
+    pub const fn direct(address: i32) -> Self {
+        Self::new(Base::Direct).plus(address)
+    }
+
+    /// The address of a label.
+    #[must_use]
+    pub const fn at(label: Label) -> Self {
+        let mut mem = Self::new(Base::Direct);
+        mem.label = Some(label);
+        mem
+    }
+
+    /// The address plus a displacement. Displacements that don't fit in a word are reported when the program is
+    /// assembled.
+    #[must_use]
+    pub const fn plus(mut self, displacement: i32) -> Self {
+        self.displacement = self.displacement.saturating_add(displacement as i64);
+        self
+    }
+
+    /// The address with a segment override.
+    #[must_use]
+    pub const fn segment(mut self, segment: Register) -> Self {
+        self.segment = Some(segment);
+        self
+    }
+
+    /// A byte at the address, for instructions whose other operand doesn't have a size.
+    #[must_use]
+    pub const fn byte(mut self) -> Self {
+        self.wide = Some(false);
+        self
+    }
+
+    /// A word at the address, for instructions whose other operand doesn't have a size.
+    #[must_use]
+    pub const fn word(mut self) -> Self {
+        self.wide = Some(true);
+        self
+    }
+
+    /// A far pointer at the address, for indirect intersegment jumps and calls.
+    #[must_use]
+    pub const fn far(mut self) -> Self {
+        self.far = true;
+        self
+    }
+}
+
+impl fmt::Display for Mem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.wide {
+            Some(false) => f.write_str("byte ")?,
+            Some(true) => f.write_str("word ")?,
+            None => {}
+        }
+        if self.far {
+            f.write_str("far ")?;
+        }
+        if let Some(segment) = self.segment {
+            write!(f, "{segment}:")?;
+        }
+        let mut terms: Vec<String> = self.base.registers().iter().map(ToString::to_string).collect();
+        terms.extend(self.label.map(|label| label.to_string()));
+        if self.displacement != 0 || terms.is_empty() {
+            terms.push(self.displacement.to_string());
+        }
+        write!(f, "[{}]", terms.join(" + ").replace("+ -", "- "))
+    }
+}
+
+/// An operand of an instruction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Arg {
+    Reg(Register),
+    /// Immediate data, or the target of a jump.
+    Imm(i32),
+    /// The address of a label, as immediate data or the target of a jump.
+    Label(Label),
+    Mem(Mem),
+    /// A direct intersegment address: segment, offset.
+    Far(u16, u16),
+}
+
+impl From<Register> for Arg {
+    fn from(register: Register) -> Self {
+        Self::Reg(register)
+    }
+}
+
+impl From<Label> for Arg {
+    fn from(label: Label) -> Self {
+        Self::Label(label)
+    }
+}
+
+impl From<Mem> for Arg {
+    fn from(mem: Mem) -> Self {
+        Self::Mem(mem)
+    }
+}
+
+impl fmt::Display for Arg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Reg(register) => write!(f, "{register}"),
+            Self::Imm(value) => write!(f, "{value}"),
+            Self::Label(label) => write!(f, "{label}"),
+            Self::Mem(mem) => write!(f, "{mem}"),
+            Self::Far(segment, offset) => write!(f, "{segment}:{offset}"),
+        }
+    }
+}
+
+impl Arg {
+    /// The operand as the assembler parses it, at a span of the line.
+    fn parameter(self, span: Range<usize>) -> Parameter {
+        let name = |label: Label| Expression::Label(label.to_string(), span.clone());
+        let (argument, wide, far) = match self {
+            Self::Reg(register) => (Argument::Register(register), None, false),
+            Self::Imm(value) => (Argument::Immediate(Expression::Number(value.into())), None, false),
+            Self::Label(label) => (Argument::Immediate(name(label)), None, false),
+            Self::Mem(mem) => {
+                let number = Expression::Number(mem.displacement);
+                let displacement = match mem.label {
+                    Some(label) => Expression::Add(Box::new(name(label)), Box::new(number)),
+                    None => number,
+                };
+                let argument = Argument::Memory {
+                    segment: mem.segment,
+                    base: mem.base,
+                    displacement,
+                    size: None,
+                };
+                (argument, mem.wide, mem.far)
+            }
+            Self::Far(segment, offset) => (
+                Argument::Far(Expression::Number(segment.into()), Expression::Number(offset.into())),
+                None,
+                false,
+            ),
+        };
+        Parameter {
+            argument,
+            wide,
+            far,
+            short: false,
+            strict: false,
+            span,
+        }
+    }
+}
+
+/// A program that is built an instruction at a time.
+#[derive(Clone, Debug, Default)]
+pub struct Asm {
+    lines: Vec<Line>,
+    labels: usize,
+    lock: bool,
+    rep: Option<Rep>,
+}
+
+impl Asm {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A new label, which isn't bound yet.
+    pub const fn label(&mut self) -> Label {
+        self.labels += 1;
+        Label(self.labels - 1)
+    }
+
+    /// Bind a label to the address of the next instruction or data.
+    pub fn bind(&mut self, label: Label) -> &mut Self {
+        let text = format!("{label}:");
+        let span = 0..text.len() - 1;
+        self.line(text, Some((label.to_string(), span)), None)
+    }
+
+    /// Set the address that the program is loaded at, like `org`.
+    pub fn org(&mut self, address: u16) -> &mut Self {
+        let text = format!("org {address}");
+        let span = 0..3;
+        self.line(text, None, Some(Item::Origin(Expression::Number(address.into()), span)))
+    }
+
+    /// Bytes of data.
+    pub fn db(&mut self, bytes: &[u8]) -> &mut Self {
+        let text = format!("db {}", join(bytes));
+        let data = vec![Datum::Text(bytes.to_vec())];
+        self.line(text, None, Some(Item::Data { wide: false, data }))
+    }
+
+    /// Words of data.
+    pub fn dw(&mut self, words: &[u16]) -> &mut Self {
+        let text = format!("dw {}", join(words));
+        let data = words
+            .iter()
+            .map(|&word| Datum::Expression(Expression::Number(word.into()), 3..text.len()))
+            .collect();
+        self.line(text, None, Some(Item::Data { wide: true, data }))
+    }
+
+    /// The address of a label, as a word of data.
+    pub fn address(&mut self, label: Label) -> &mut Self {
+        let text = format!("dw {label}");
+        let span = 3..text.len();
+        let data = vec![Datum::Expression(
+            Expression::Label(label.to_string(), span.clone()),
+            span,
+        )];
+        self.line(text, None, Some(Item::Data { wide: true, data }))
+    }
+
+    /// A one-byte NOP.
+    pub fn nop(&mut self) -> &mut Self {
+        let data = vec![Datum::Text(vec![NOP])];
+        self.line("nop".to_string(), None, Some(Item::Data { wide: false, data }))
+    }
+
+    /// Lock the bus during the next instruction.
+    pub const fn lock(&mut self) -> &mut Self {
+        self.lock = true;
+        self
+    }
+
+    /// Repeat the next string instruction, while ZF is set for CMPS and SCAS.
+    pub const fn rep(&mut self) -> &mut Self {
+        self.rep = Some(Rep::Rep);
+        self
+    }
+
+    /// Repeat the next string instruction while ZF is clear.
+    pub const fn repne(&mut self) -> &mut Self {
+        self.rep = Some(Rep::Repne);
+        self
+    }
+
+    /// An instruction with any operands, after any prefixes.
+    pub fn instruction(&mut self, operation: Operation, operands: &[Arg]) -> &mut Self {
+        self.statement(operation, None, operands)
+    }
+
+    /// Assemble the program into a flat binary.
+    ///
+    /// # Errors
+    ///
+    /// If an instruction's operands are invalid, a label isn't bound or is bound twice, or a prefix isn't followed by
+    /// an instruction.
+    pub fn assemble(&self) -> Result<Vec<u8>, Error> {
+        layout(self.lines()?, 0, Format::Flat).map(|layout| layout.binary)
+    }
+
+    /// Assemble the program into a file of a format.
+    ///
+    /// # Errors
+    ///
+    /// If the program can't be assembled, or doesn't fit the format.
+    pub fn assemble_as(&self, format: Format) -> Result<Vec<u8>, Error> {
+        build(self.lines()?, format).map(|(file, _)| file)
+    }
+
+    /// The lines of the program, checking that no prefix is left over.
+    fn lines(&self) -> Result<Vec<Line>, Error> {
+        if self.lock || self.rep.is_some() {
+            let text = prefixes(self.lock, self.rep);
+            return Err(Error {
+                file: None,
+                line: self.lines.len() + 1,
+                columns: 0..text.len(),
+                message: "expected an instruction after a prefix".to_string(),
+                help: None,
+                text,
+            });
+        }
+        Ok(self.lines.clone())
+    }
+
+    /// A string instruction on bytes or words.
+    fn string(&mut self, operation: Operation, wide: bool) -> &mut Self {
+        self.statement(operation, Some(wide), &[])
+    }
+
+    fn statement(&mut self, operation: Operation, wide: Option<bool>, operands: &[Arg]) -> &mut Self {
+        let mut text = prefixes(self.lock, self.rep);
+        text.push_str(operation.name());
+        if let Some(wide) = wide {
+            text.push(if wide { 'w' } else { 'b' });
+        }
+        let mut parameters = vec![];
+        for (index, operand) in operands.iter().enumerate() {
+            text.push_str(if index == 0 { " " } else { ", " });
+            let start = text.len();
+            text.push_str(&operand.to_string());
+            parameters.push(operand.parameter(start..text.len()));
+        }
+        let statement = Statement {
+            operation,
+            wide,
+            lock: self.lock,
+            rep: self.rep,
+            parameters,
+            span: 0..text.len(),
+        };
+        self.lock = false;
+        self.rep = None;
+        self.line(text, None, Some(Item::Instruction(statement)))
+    }
+
+    /// Append a line, with its text for errors.
+    fn line(&mut self, text: String, label: Option<(String, Range<usize>)>, item: Option<Item>) -> &mut Self {
+        self.lines.push(Line {
+            file: None,
+            depth: 0,
+            number: self.lines.len() + 1,
+            text,
+            label,
+            item,
+        });
+        self
+    }
+}
+
+/// The prefixes of an instruction, as they are written before it.
+fn prefixes(lock: bool, rep: Option<Rep>) -> String {
+    let mut text = String::new();
+    if lock {
+        text.push_str("lock ");
+    }
+    match rep {
+        Some(Rep::Rep) => text.push_str("rep "),
+        Some(Rep::Repne) => text.push_str("repne "),
+        None => {}
+    }
+    text
+}
+
+fn join(values: &[impl ToString]) -> String {
+    values.iter().map(ToString::to_string).collect::<Vec<_>>().join(", ")
+}
+
+macro_rules! instructions {
+    ($($method:ident => $operation:ident($($operand:ident),*),)*) => {
+        impl Asm {
+            $(
+                #[doc = concat!("`", stringify!($operation), "`", $(" `", stringify!($operand), "`",)*)]
+                pub fn $method(&mut self, $($operand: impl Into<Arg>),*) -> &mut Self {
+                    self.instruction(Operation::$operation, &[$($operand.into()),*])
+                }
+            )*
+        }
+    };
+}
+
+instructions! {
+    mov => Mov(destination, source),
+    push => Push(source),
+    pop => Pop(destination),
+    xchg => Xchg(destination, source),
+    in_ => In(destination, port),
+    out => Out(port, source),
+    xlat => Xlat(),
+    lea => Lea(destination, source),
+    lds => Lds(destination, source),
+    les => Les(destination, source),
+    lahf => Lahf(),
+    sahf => Sahf(),
+    pushf => Pushf(),
+    popf => Popf(),
+    add => Add(destination, source),
+    adc => Adc(destination, source),
+    inc => Inc(destination),
+    aaa => Aaa(),
+    daa => Daa(),
+    sub => Sub(destination, source),
+    sbb => Sbb(destination, source),
+    dec => Dec(destination),
+    neg => Neg(destination),
+    cmp => Cmp(destination, source),
+    aas => Aas(),
+    das => Das(),
+    mul => Mul(source),
+    imul => Imul(source),
+    aam => Aam(),
+    div => Div(source),
+    idiv => Idiv(source),
+    aad => Aad(),
+    cbw => Cbw(),
+    cwd => Cwd(),
+    not => Not(destination),
+    shl => Shl(destination, count),
+    shr => Shr(destination, count),
+    sar => Sar(destination, count),
+    rol => Rol(destination, count),
+    ror => Ror(destination, count),
+    rcl => Rcl(destination, count),
+    rcr => Rcr(destination, count),
+    and => And(destination, source),
+    test => Test(destination, source),
+    or => Or(destination, source),
+    xor => Xor(destination, source),
+    call => Call(target),
+    jmp => Jmp(target),
+    ret => Ret(),
+    retf => Retf(),
+    jo => Jo(target),
+    jno => Jno(target),
+    jb => Jb(target),
+    jnb => Jnb(target),
+    je => Je(target),
+    jne => Jne(target),
+    jbe => Jbe(target),
+    jnbe => Jnbe(target),
+    js => Js(target),
+    jns => Jns(target),
+    jp => Jp(target),
+    jnp => Jnp(target),
+    jl => Jl(target),
+    jnl => Jnl(target),
+    jle => Jle(target),
+    jnle => Jnle(target),
+    loopnz => Loopnz(target),
+    loopz => Loopz(target),
+    loop_ => Loop(target),
+    jcxz => Jcxz(target),
+    int => Int(number),
+    int3 => Int3(),
+    into_ => Into(),
+    iret => Iret(),
+    clc => Clc(),
+    cmc => Cmc(),
+    stc => Stc(),
+    cld => Cld(),
+    std => Std(),
+    cli => Cli(),
+    sti => Sti(),
+    hlt => Hlt(),
+    wait => Wait(),
+    // Alternative mnemonics.
+    jz => Je(target),
+    jnz => Jne(target),
+    jc => Jb(target),
+    jnc => Jnb(target),
+    jae => Jnb(target),
+    ja => Jnbe(target),
+    jge => Jnl(target),
+    jg => Jnle(target),
+}
+
+macro_rules! strings {
+    ($($bytes:ident, $words:ident => $operation:ident,)*) => {
+        impl Asm {
+            $(
+                #[doc = concat!("`", stringify!($operation), "` on bytes.")]
+                pub fn $bytes(&mut self) -> &mut Self {
+                    self.string(Operation::$operation, false)
+                }
+
+                #[doc = concat!("`", stringify!($operation), "` on words.")]
+                pub fn $words(&mut self) -> &mut Self {
+                    self.string(Operation::$operation, true)
+                }
+            )*
+        }
+    };
+}
+
+strings! {
+    movsb, movsw => Movs,
+    cmpsb, cmpsw => Cmps,
+    scasb, scasw => Scas,
+    lodsb, lodsw => Lods,
+    stosb, stosw => Stos,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::assemble;
+    use super::Arg::{Far, Imm};
+    use super::*;
+
+    #[test]
+    fn programs() {
+        let mut asm = Asm::new();
+        let (start, done, table) = (asm.label(), asm.label(), asm.label());
+        asm.mov(CX, Imm(3))
+            .mov(SI, table)
+            .bind(start)
+            .lodsb()
+            .add(Mem::new(Base::Bx).plus(2).byte(), AL)
+            .mov(Mem::at(table).plus(-1).segment(ES), AX)
+            .loop_(start)
+            .jcxz(done)
+            .jmp(Far(0x1234, 5))
+            .call(Mem::new(Base::BpDi).far())
+            .rep()
+            .stosw()
+            .bind(done)
+            .hlt()
+            .bind(table)
+            .db(b"ab")
+            .dw(&[0x1234])
+            .address(start);
+        let source = "
+            mov cx, 3
+            mov si, table
+        start:
+            lodsb
+            add byte [bx + 2], al
+            mov es:[table - 1], ax
+            loop start
+            jcxz done
+            jmp 4660:5
+            call far [bp + di]
+            rep stosw
+        done:
+            hlt
+        table:
+            db 'ab'
+            dw 0x1234
+            dw start
+        ";
+        assert_eq!(asm.assemble().unwrap(), assemble(source).unwrap());
+        // Chained calls build a program in one expression.
+        assert_eq!(
+            Asm::new().mov(AX, Imm(5)).int(Imm(0x21)).assemble().unwrap(),
+            [0b10111000, 5, 0, 0b11001101, 0x21]
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let mut asm = Asm::new();
+        let nowhere = asm.label();
+        let error = asm.mov(AX, BL).assemble().unwrap_err();
+        assert_eq!((error.line, error.text.as_str()), (1, "mov ax, bl"));
+        assert_eq!(error.message, "mismatched operand sizes");
+        let error = Asm::new().hlt().jmp(nowhere).assemble().unwrap_err();
+        assert_eq!(
+            (error.line, error.columns, error.message),
+            (2, 4..10, "undefined label \"label0\"".to_string())
+        );
+        let error = Asm::new().bind(nowhere).bind(nowhere).assemble().unwrap_err();
+        assert_eq!(error.help.unwrap(), "it is first defined on line 1");
+        let error = Asm::new().rep().assemble().unwrap_err();
+        assert_eq!(error.message, "expected an instruction after a prefix");
+        let error = Asm::new()
+            .mov(AX, Mem::direct(i32::MAX).plus(i32::MAX))
+            .assemble()
+            .unwrap_err();
+        assert_eq!(error.text, "mov ax, [4294967294]");
+        assert_eq!(error.message, "4294967294 doesn't fit in a word");
+    }
+}
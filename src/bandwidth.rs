@@ -0,0 +1,335 @@
+//! Loops that read or write memory in order as fast as they can, a number of bytes at a time, to measure how fast the
+//! machine moves memory at each level of its caches, like the course's bandwidth tests.
+//!
+//! The cache tests walk a working set a cache line at a time, either by following links between the lines in a random
+//! order, so that each load waits for the last and shows the latency, or in order with a stride, which shows the
+//! bandwidth. Sweeping the size of the working set shows where it stops fitting in each level of cache.
+//!
+//! Each access is volatile, so that it isn't combined with others or optimized away. 16-byte accesses use SSE2 and
+//! 32-byte accesses use AVX on x86-64; elsewhere, 16-byte accesses are of `u128`, and there are no 32-byte ones.
+//! Non-temporal writes, which bypass the caches through write-combining buffers, are only on x86-64, 8 bytes or more
+//! at a time.
+
+use std::fmt;
+use std::ptr;
+use std::str::FromStr;
+
+use crate::random::Random;
+
+/// The bytes of a cache line, which the cache tests access one of at a time.
+pub const CACHE_LINE: usize = 64;
+
+/// Whether a test reads memory or writes it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    /// Write without reading the lines into the caches.
+    NonTemporal,
+}
+
+impl FromStr for Access {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read" => Ok(Self::Read),
+            "write" => Ok(Self::Write),
+            "non-temporal" => Ok(Self::NonTemporal),
+            _ => Err("expected read, write or non-temporal".to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Read => "read",
+            Self::Write => "write",
+            Self::NonTemporal => "non-temporal",
+        })
+    }
+}
+
+/// How a cache test walks its working set.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Pattern {
+    /// Each access is to a line whose offset the last access loaded.
+    Dependent,
+    /// Each access is a stride after the last.
+    Strided,
+}
+
+impl FromStr for Pattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dependent" => Ok(Self::Dependent),
+            "strided" => Ok(Self::Strided),
+            _ => Err("expected dependent or strided".to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Dependent => "dependent",
+            Self::Strided => "strided",
+        })
+    }
+}
+
+/// The offset that a line of the buffer links to.
+fn next(buffer: &[u8], offset: usize) -> usize {
+    let mut bytes = [0; size_of::<usize>()];
+    bytes.copy_from_slice(&buffer[offset..offset + size_of::<usize>()]);
+    usize::from_ne_bytes(bytes)
+}
+
+fn set_next(buffer: &mut [u8], offset: usize, next: usize) {
+    buffer[offset..offset + size_of::<usize>()].copy_from_slice(&next.to_ne_bytes());
+}
+
+/// Link the cache lines of the buffer into one cycle in a random order from `seed`, for [`chase`]. Each line holds the
+/// offset of the next at its start.
+///
+/// # Errors
+///
+/// If the buffer is smaller than a cache line.
+pub fn link(buffer: &mut [u8], seed: u64) -> Result<(), String> {
+    let lines = buffer.len() / CACHE_LINE;
+    if lines == 0 {
+        return Err(format!("the buffer is smaller than a {CACHE_LINE}-byte line"));
+    }
+    for line in 0..lines {
+        set_next(buffer, line * CACHE_LINE, line * CACHE_LINE);
+    }
+    // Sattolo's shuffle, which makes one cycle through every line.
+    let mut random = Random::new(seed);
+    for line in (1..lines).rev() {
+        let other = usize::try_from(random.next_u64() % line as u64).unwrap_or(0);
+        let (a, b) = (line * CACHE_LINE, other * CACHE_LINE);
+        let (next_a, next_b) = (next(buffer, a), next(buffer, b));
+        set_next(buffer, a, next_b);
+        set_next(buffer, b, next_a);
+    }
+    Ok(())
+}
+
+/// Follow the links that [`link`] made from the first line `steps` times, each load waiting for the last, and return
+/// the offset that it ends at.
+#[must_use]
+pub fn chase(buffer: &[u8], steps: usize) -> usize {
+    let mut offset = 0;
+    for _ in 0..steps {
+        offset = next(buffer, offset);
+    }
+    offset
+}
+
+/// Read a `u64` every `stride` bytes of the buffer, `passes` times, and return the bytes that were passed over.
+///
+/// # Errors
+///
+/// If the stride is smaller than a `u64`, or the buffer isn't aligned to one.
+pub fn stride(buffer: &[u8], stride: usize, passes: usize) -> Result<usize, String> {
+    if stride < size_of::<u64>() || !stride.is_multiple_of(size_of::<u64>()) {
+        return Err(format!("the stride has to be a multiple of {} bytes", size_of::<u64>()));
+    }
+    if buffer.as_ptr().align_offset(size_of::<u64>()) != 0 {
+        return Err(format!("the buffer isn't aligned to {} bytes", size_of::<u64>()));
+    }
+    let count = buffer.len() / stride;
+    let base = buffer.as_ptr();
+    for _ in 0..passes {
+        for index in 0..count {
+            // SAFETY: The u64 is in the buffer and aligned.
+            unsafe { ptr::read_volatile(base.add(index * stride).cast::<u64>()) };
+        }
+    }
+    Ok(passes * count * stride)
+}
+
+/// The widths of accesses, in bytes, that this CPU can make.
+#[must_use]
+pub fn widths(access: Access) -> Vec<usize> {
+    let mut widths = match access {
+        Access::Read | Access::Write => vec![1, 8, 16],
+        Access::NonTemporal if cfg!(target_arch = "x86_64") => vec![8, 16],
+        Access::NonTemporal => return vec![],
+    };
+    #[cfg(target_arch = "x86_64")]
+    if is_x86_feature_detected!("avx") {
+        widths.push(32);
+    }
+    widths
+}
+
+/// Access each `T` of the buffer in order, and return the bytes that were accessed.
+///
+/// # Safety
+///
+/// `T` is an integer or a vector of integers, and the buffer is aligned to it.
+// Inlined, so that it's compiled with AVX in `sweep_avx`.
+#[allow(clippy::inline_always)]
+#[inline(always)]
+unsafe fn sweep<T: Copy>(buffer: &mut [u8], access: Access) -> usize {
+    let count = buffer.len() / size_of::<T>();
+    let base = buffer.as_mut_ptr().cast::<T>();
+    // SAFETY: Each T is in the buffer and aligned, and any bits are a valid T.
+    unsafe {
+        let zero = std::mem::zeroed::<T>();
+        for index in 0..count {
+            match access {
+                Access::Read => {
+                    ptr::read_volatile(base.add(index));
+                }
+                Access::Write | Access::NonTemporal => ptr::write_volatile(base.add(index), zero),
+            }
+        }
+    }
+    count * size_of::<T>()
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx")]
+unsafe fn sweep_avx(buffer: &mut [u8], access: Access) -> usize {
+    // SAFETY: The caller checks the alignment.
+    unsafe { sweep::<std::arch::x86_64::__m256i>(buffer, access) }
+}
+
+#[cfg(target_arch = "x86_64")]
+mod stream {
+    use std::arch::x86_64::{
+        __m128i, __m256i, _mm256_setzero_si256, _mm256_stream_si256, _mm_setzero_si128, _mm_sfence, _mm_stream_si128,
+        _mm_stream_si64,
+    };
+
+    // SAFETY for each: The buffer is aligned to the width, and the stores are fenced before it's used again.
+
+    pub unsafe fn stream64(buffer: &mut [u8]) -> usize {
+        let count = buffer.len() / 8;
+        let base = buffer.as_mut_ptr().cast::<i64>();
+        unsafe {
+            for index in 0..count {
+                _mm_stream_si64(base.add(index), 0);
+            }
+            _mm_sfence();
+        }
+        count * 8
+    }
+
+    pub unsafe fn stream128(buffer: &mut [u8]) -> usize {
+        let count = buffer.len() / 16;
+        let base = buffer.as_mut_ptr().cast::<__m128i>();
+        unsafe {
+            for index in 0..count {
+                _mm_stream_si128(base.add(index), _mm_setzero_si128());
+            }
+            _mm_sfence();
+        }
+        count * 16
+    }
+
+    #[target_feature(enable = "avx")]
+    pub unsafe fn stream256(buffer: &mut [u8]) -> usize {
+        let count = buffer.len() / 32;
+        let base = buffer.as_mut_ptr().cast::<__m256i>();
+        unsafe {
+            for index in 0..count {
+                _mm256_stream_si256(base.add(index), _mm256_setzero_si256());
+            }
+            _mm_sfence();
+        }
+        count * 32
+    }
+}
+
+/// Read or write the buffer `width` bytes at a time, and return the bytes that were accessed, which leave out any
+/// bytes at the end that are fewer than `width`. Writes write zeros.
+///
+/// # Errors
+///
+/// If the CPU can't access `width` bytes at a time, or the buffer isn't aligned to `width`.
+pub fn run(buffer: &mut [u8], width: usize, access: Access) -> Result<usize, String> {
+    if !widths(access).contains(&width) {
+        return Err(format!("can't {access} {width} bytes at a time"));
+    }
+    if buffer.as_ptr().align_offset(width) != 0 {
+        return Err(format!("the buffer isn't aligned to {width} bytes"));
+    }
+    // SAFETY: The width is supported and the buffer is aligned to it.
+    unsafe {
+        Ok(match (access, width) {
+            #[cfg(target_arch = "x86_64")]
+            (Access::NonTemporal, 8) => stream::stream64(buffer),
+            #[cfg(target_arch = "x86_64")]
+            (Access::NonTemporal, 16) => stream::stream128(buffer),
+            #[cfg(target_arch = "x86_64")]
+            (Access::NonTemporal, 32) => stream::stream256(buffer),
+            (_, 1) => sweep::<u8>(buffer, access),
+            (_, 8) => sweep::<u64>(buffer, access),
+            #[cfg(target_arch = "x86_64")]
+            (_, 16) => sweep::<std::arch::x86_64::__m128i>(buffer, access),
+            #[cfg(not(target_arch = "x86_64"))]
+            (_, 16) => sweep::<u128>(buffer, access),
+            #[cfg(target_arch = "x86_64")]
+            (_, 32) => sweep_avx(buffer, access),
+            _ => unreachable!(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::pages::{PageSize, Pages};
+
+    #[test]
+    fn sweeps() {
+        let mut buffer = Pages::new(4096 + 20, PageSize::Small).unwrap();
+        for width in widths(Access::Read) {
+            buffer.fill(1);
+            assert_eq!(run(&mut buffer, width, Access::Read), Ok(4096 + 20 / width * width));
+            assert!(buffer.iter().all(|&byte| byte == 1));
+            for access in [Access::Write, Access::NonTemporal] {
+                if !widths(access).contains(&width) {
+                    continue;
+                }
+                buffer.fill(1);
+                let bytes = run(&mut buffer, width, access).unwrap();
+                assert!(buffer[..bytes].iter().all(|&byte| byte == 0));
+                assert!(buffer[bytes..].iter().all(|&byte| byte == 1));
+            }
+        }
+        assert!(run(&mut buffer, 3, Access::Read).is_err());
+        assert!(run(&mut buffer, 1, Access::NonTemporal).is_err());
+        assert!(run(&mut buffer[1..], 8, Access::Read).unwrap_err().contains("aligned"));
+        assert_eq!("non-temporal".parse(), Ok(Access::NonTemporal));
+    }
+
+    #[test]
+    fn walks() {
+        let mut buffer = Pages::new(16 * CACHE_LINE, PageSize::Small).unwrap();
+        link(&mut buffer, 3).unwrap();
+        // One cycle through every line.
+        let mut seen = [false; 16];
+        let mut offset: usize = 0;
+        for _ in 0..16 {
+            assert!(offset.is_multiple_of(CACHE_LINE) && !seen[offset / CACHE_LINE]);
+            seen[offset / CACHE_LINE] = true;
+            offset = next(&buffer, offset);
+        }
+        assert_eq!(offset, 0);
+        assert_eq!(chase(&buffer, 16), 0);
+        assert_ne!(chase(&buffer, 5), 0);
+        assert!(link(&mut buffer[..CACHE_LINE - 1], 3).is_err());
+
+        assert_eq!(stride(&buffer, CACHE_LINE, 3), Ok(3 * 16 * CACHE_LINE));
+        assert!(stride(&buffer, 12, 1).is_err());
+        assert_eq!("strided".parse(), Ok(Pattern::Strided));
+    }
+}
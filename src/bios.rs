@@ -0,0 +1,134 @@
+//! Emulate the BIOS video services that print text, so boot sectors and BIOS-only programs can write to the host
+//! terminal.
+//!
+//! The screen is 80x25 text (mode 3). Teletype output goes to the console, and cursor positions are kept in the
+//! BIOS data area, like the BIOS, so programs that read them from memory see the same values.
+
+// Byte registers fit in their casts.
+#![allow(clippy::cast_possible_truncation)]
+
+use crate::instruction::{Instruction, Register};
+use crate::sim::{Error, Machine};
+
+const COLUMNS: u8 = 80;
+const ROWS: u8 = 25;
+const TEXT_MODE: u16 = 3;
+
+// Addresses in the BIOS data area.
+const CURSOR_POSITIONS: usize = 0x450; // Column and row for each of 8 pages.
+const CURSOR_SHAPE: usize = 0x460; // End and start scan lines.
+const ACTIVE_PAGE: usize = 0x462;
+
+/// Emulate INT 10h.
+///
+/// # Errors
+///
+/// If the function in AH isn't supported.
+pub(crate) fn video(machine: &mut Machine, instruction: &Instruction) -> Result<(), Error> {
+    let registers = &mut machine.registers;
+    let page = usize::from(registers.get(Register::Bh) & 0b111);
+    let position = CURSOR_POSITIONS + page * 2;
+    match registers.get(Register::Ah) {
+        // Set the cursor shape to the scan lines in CH and CL.
+        0x01 => {
+            let shape = [registers.get(Register::Cl), registers.get(Register::Ch)];
+            machine.store(CURSOR_SHAPE, shape[0] as u8);
+            machine.store(CURSOR_SHAPE + 1, shape[1] as u8);
+        }
+        // Set the cursor of page BH to row DH and column DL, kept on the screen.
+        0x02 => {
+            let (column, row) = (registers.get(Register::Dl) as u8, registers.get(Register::Dh) as u8);
+            machine.store(position, column.min(COLUMNS - 1));
+            machine.store(position + 1, row.min(ROWS - 1));
+        }
+        // Get the cursor of page BH into DH and DL, and the cursor shape into CH and CL.
+        0x03 => {
+            registers.set(Register::Dl, u16::from(machine.memory[position]));
+            registers.set(Register::Dh, u16::from(machine.memory[position + 1]));
+            registers.set(Register::Cl, u16::from(machine.memory[CURSOR_SHAPE]));
+            registers.set(Register::Ch, u16::from(machine.memory[CURSOR_SHAPE + 1]));
+        }
+        // Write the character in AL to page BH, and advance the cursor.
+        0x0e => {
+            let character = registers.get(Register::Al) as u8;
+            machine.output.push(character);
+            // Programs can write the BIOS data area directly, so the cursor might be off the screen.
+            let mut column = machine.memory[position].min(COLUMNS - 1);
+            let mut row = machine.memory[position + 1].min(ROWS - 1);
+            match character {
+                b'\r' => column = 0,
+                b'\n' => row += 1,
+                // Backspace moves the cursor without erasing.
+                0x08 => column = column.saturating_sub(1),
+                // The bell only beeps.
+                0x07 => {}
+                _ => {
+                    column += 1;
+                    if column >= COLUMNS {
+                        column = 0;
+                        row += 1;
+                    }
+                }
+            }
+            // The screen scrolls, rather than the cursor leaving it.
+            machine.store(position, column);
+            machine.store(position + 1, row.min(ROWS - 1));
+        }
+        // Get the mode into AL, the number of columns into AH, and the active page into BH.
+        0x0f => {
+            registers.set(Register::Al, TEXT_MODE);
+            registers.set(Register::Ah, u16::from(COLUMNS));
+            registers.set(Register::Bh, u16::from(machine.memory[ACTIVE_PAGE]));
+        }
+        _ => return Err(Error::Unsupported(*instruction)),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::instruction::{Operand, Operation};
+
+    fn call(machine: &mut Machine, ax: u16) {
+        machine.registers.set(Register::Ax, ax);
+        let instruction = Instruction::new(Operation::Int, [Operand::Immediate(0x10), Operand::None], false);
+        video(machine, &instruction).unwrap();
+    }
+
+    #[test]
+    fn teletype() {
+        let mut machine = Machine::new();
+        for &character in b"hi\r\nok\x08" {
+            call(&mut machine, 0x0e00 | u16::from(character));
+        }
+        assert_eq!(machine.output, b"hi\r\nok\x08");
+        call(&mut machine, 0x0300);
+        assert_eq!(machine.registers.get(Register::Dx), 0x0101);
+    }
+
+    #[test]
+    fn cursor() {
+        let mut machine = Machine::new();
+        machine.registers.set(Register::Dx, 0x184f);
+        call(&mut machine, 0x0200);
+        call(&mut machine, 0x0e00 | u16::from(b'x'));
+        call(&mut machine, 0x0300);
+        // The last column wraps to the next row, which scrolls the screen.
+        assert_eq!(machine.registers.get(Register::Dx), 0x1800);
+
+        // A cursor off the screen is kept on it.
+        machine.registers.set(Register::Dx, 0xffff);
+        call(&mut machine, 0x0200);
+        call(&mut machine, 0x0300);
+        assert_eq!(machine.registers.get(Register::Dx), 0x184f);
+        machine.memory[CURSOR_POSITIONS] = 0xff;
+        call(&mut machine, 0x0e00 | u16::from(b'x'));
+        call(&mut machine, 0x0300);
+        assert_eq!(machine.registers.get(Register::Dx), 0x1800);
+
+        call(&mut machine, 0x0f00);
+        assert_eq!(machine.registers.get(Register::Ax), 0x5003);
+    }
+}
@@ -0,0 +1,99 @@
+//! A loop that branches on each byte of a buffer, to measure how well the CPU predicts branches in patterns, like the
+//! course's branch prediction tests.
+//!
+//! A byte with its low bit set takes the branch. The branch can't be turned into a conditional move, because the code
+//! it skips is opaque to the compiler.
+
+use std::fmt;
+use std::hint::black_box;
+use std::str::FromStr;
+
+use crate::random::Random;
+
+/// Which branches are taken.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Pattern {
+    Always,
+    Never,
+    /// Taken, then not, and so on.
+    Alternating,
+    /// Taken at random, with some probability.
+    Random,
+}
+
+impl FromStr for Pattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(Self::Always),
+            "never" => Ok(Self::Never),
+            "alternating" => Ok(Self::Alternating),
+            "random" => Ok(Self::Random),
+            _ => Err("expected always, never, alternating or random".to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Always => "always",
+            Self::Never => "never",
+            Self::Alternating => "alternating",
+            Self::Random => "random",
+        })
+    }
+}
+
+/// Fill the buffer with the pattern, taking random branches with `probability` from `seed`.
+pub fn fill(buffer: &mut [u8], pattern: Pattern, probability: f64, seed: u64) {
+    let mut random = Random::new(seed);
+    for (index, byte) in buffer.iter_mut().enumerate() {
+        let taken = match pattern {
+            Pattern::Always => true,
+            Pattern::Never => false,
+            Pattern::Alternating => index % 2 == 0,
+            Pattern::Random => random.range(0.0, 1.0) < probability,
+        };
+        *byte = u8::from(taken);
+    }
+}
+
+/// Branch on each byte of the buffer, and return the branches that were taken.
+#[must_use]
+pub fn run(buffer: &[u8]) -> usize {
+    let mut taken = 0;
+    for &byte in buffer {
+        if byte & 1 != 0 {
+            taken = black_box(taken + 1);
+        }
+    }
+    taken
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn branches() {
+        let mut buffer = vec![0; 10_000];
+        for (pattern, taken) in [
+            (Pattern::Always, 10_000),
+            (Pattern::Never, 0),
+            (Pattern::Alternating, 5_000),
+        ] {
+            fill(&mut buffer, pattern, 0.5, 1);
+            assert_eq!(run(&buffer), taken);
+        }
+        fill(&mut buffer, Pattern::Random, 0.25, 1);
+        assert!((2_200..2_800).contains(&run(&buffer)));
+        let first = buffer.clone();
+        fill(&mut buffer, Pattern::Random, 0.25, 1);
+        assert_eq!(buffer, first);
+
+        assert_eq!("alternating".parse(), Ok(Pattern::Alternating));
+        assert!("sometimes".parse::<Pattern>().is_err());
+    }
+}
@@ -0,0 +1,268 @@
+//! Estimate the clocks that an executed instruction takes on the 8086, from the timing table in the 8086 family
+//! user's manual, which is in the [`table`] of forms.
+//!
+//! The estimate is the base clocks, plus the clocks to calculate the effective address, plus 4 clocks for each word
+//! transferred at an odd address. Multiplication and division take the least clocks in the manual's ranges, which
+//! depend on the operands. Prefetch queue and bus contention are not modeled, and string instructions don't pay
+//! for odd addresses.
+
+use crate::instruction::{Base, Instruction, Memory, Operand, Operation, Register};
+use crate::sim::Registers;
+use crate::table;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Register,
+    Memory,
+    Immediate,
+    Other,
+}
+
+impl From<Operand> for Kind {
+    fn from(operand: Operand) -> Self {
+        match operand {
+            Operand::Register(_) => Self::Register,
+            Operand::Memory(_) => Self::Memory,
+            Operand::Immediate(_) => Self::Immediate,
+            _ => Self::Other,
+        }
+    }
+}
+
+/// The clocks to calculate an effective address, including a segment override.
+#[must_use]
+pub fn effective_address(memory: Memory, segment_override: bool) -> u32 {
+    // [bp] is encoded with a displacement of 0.
+    let displacement = memory.displacement != 0 || memory.base == Base::Bp;
+    let clocks = match (memory.base, displacement) {
+        (Base::Direct, _) => 6,
+        (Base::Bx | Base::Bp | Base::Si | Base::Di, false) => 5,
+        (Base::Bx | Base::Bp | Base::Si | Base::Di, true) => 9,
+        (Base::BpDi | Base::BxSi, false) => 7,
+        (Base::BpSi | Base::BxDi, false) => 8,
+        (Base::BpDi | Base::BxSi, true) => 11,
+        (Base::BpSi | Base::BxDi, true) => 12,
+    };
+    clocks + if segment_override { 2 } else { 0 }
+}
+
+/// The clocks of an instruction, worked out once, so that each execution only adds the clocks that depend on the
+/// registers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Timing {
+    /// The clocks if a jump or loop isn't taken, a string instruction isn't repeated, a shift is by a count of 0, and
+    /// words are at even addresses.
+    pub fixed: u32,
+    /// The additional clocks if a jump or loop is taken.
+    taken: u32,
+    /// The clocks for each repetition of a string instruction.
+    repeated: u32,
+    /// The clocks for each bit of a shift by CL.
+    shifted: u32,
+    /// The words transferred at the memory operand's address, and on the stack, which each take 4 more clocks at an
+    /// odd address.
+    transfers: u32,
+    stack: u32,
+}
+
+impl Timing {
+    #[must_use]
+    pub fn new(instruction: &Instruction) -> Self {
+        // The clocks are linear in the repetitions and the shift count.
+        let fixed = base(instruction, false, 0, 0);
+        Self {
+            fixed,
+            taken: base(instruction, true, 0, 0) - fixed,
+            repeated: base(instruction, false, 1, 0) - fixed,
+            shifted: base(instruction, false, 0, 1) - fixed,
+            transfers: transfers(instruction),
+            stack: stack_transfers(instruction),
+        }
+    }
+
+    /// The clocks that the instruction took, given the registers before and after it executed, and whether its
+    /// memory operand was at an odd address.
+    #[must_use]
+    pub fn clocks(&self, instruction: &Instruction, before: &Registers, after: &Registers, odd: bool) -> u32 {
+        let mut clocks = self.fixed;
+        // Jumps and loops that weren't taken continue at the next instruction.
+        if after.ip != before.ip.wrapping_add(instruction.size) || after.segments != before.segments {
+            clocks += self.taken;
+        }
+        if self.repeated != 0 {
+            clocks += self.repeated * u32::from(before.get(Register::Cx).wrapping_sub(after.get(Register::Cx)));
+        }
+        if self.shifted != 0 {
+            clocks += self.shifted * u32::from(before.get(Register::Cl));
+        }
+        // Words at odd addresses take two bus cycles.
+        if odd {
+            clocks += 4 * self.transfers;
+        }
+        if before.get(Register::Sp) & 1 != 0 {
+            clocks += 4 * self.stack;
+        }
+        clocks
+    }
+
+    /// Whether the clocks depend on the address of the memory operand.
+    #[must_use]
+    pub const fn depends_on_address(&self) -> bool {
+        self.transfers != 0
+    }
+}
+
+/// The clocks that an instruction took, given the registers before and after it executed, and whether its memory
+/// operand was at an odd address.
+#[must_use]
+pub fn clocks(instruction: &Instruction, before: &Registers, after: &Registers, odd: bool) -> u32 {
+    Timing::new(instruction).clocks(instruction, before, after, odd)
+}
+
+/// The clocks of an instruction, given whether a jump or loop is taken, the repetitions of a string instruction, and
+/// the count of a shift by CL, but not the clocks for words at odd addresses. They are the clocks of the form that
+/// the instruction is encoded in.
+fn base(instruction: &Instruction, taken: bool, repetitions: u32, count: u32) -> u32 {
+    let clocks = table::find(instruction, false)
+        .map(|form| form.clocks)
+        .unwrap_or_default();
+    let memory = instruction
+        .operands
+        .iter()
+        .any(|operand| matches!(operand, Operand::Memory(_)));
+    let base = if instruction.rep.is_some() && clocks.repeated != 0 {
+        9 + clocks.repeated * repetitions
+    } else {
+        let mut base = if memory { clocks.memory } else { clocks.register };
+        if instruction.wide {
+            base += clocks.word;
+        }
+        if taken {
+            base += clocks.taken;
+        }
+        base + clocks.shifted * count
+    };
+
+    let memory = instruction.operands.iter().find_map(|operand| match operand {
+        Operand::Memory(memory) => Some(*memory),
+        _ => None,
+    });
+    let address = memory.map_or(0, |memory| {
+        // LEA's only cost is its effective address.
+        effective_address(
+            memory,
+            instruction.segment.is_some() && instruction.operation != Operation::Lea,
+        )
+    });
+    let lock = if instruction.lock { 2 } else { 0 };
+    base + address + lock
+}
+
+/// The words that an instruction transfers at the address of its memory operand.
+fn transfers(instruction: &Instruction) -> u32 {
+    let operation = instruction.operation;
+    let a = Kind::from(instruction.operands[0]);
+    let memory = instruction
+        .operands
+        .iter()
+        .any(|operand| matches!(operand, Operand::Memory(_)));
+    if memory && (instruction.wide || instruction.far || matches!(operation, Operation::Lds | Operation::Les)) {
+        match operation {
+            Operation::Lea => 0,
+            Operation::Mov
+            | Operation::Cmp
+            | Operation::Test
+            | Operation::Push
+            | Operation::Pop
+            | Operation::Mul
+            | Operation::Imul
+            | Operation::Div
+            | Operation::Idiv => 1,
+            Operation::Call | Operation::Jmp if !instruction.far => 1,
+            _ if a == Kind::Register && !matches!(operation, Operation::Xchg | Operation::Lds | Operation::Les) => 1,
+            _ => 2,
+        }
+    } else {
+        0
+    }
+}
+
+/// The clocks that an instruction takes, as far as they can be estimated without executing it.
+///
+/// A jump or loop isn't taken, a string instruction isn't repeated, a shift is by a count of 0, and words are at even
+/// addresses.
+#[must_use]
+pub fn estimate(instruction: &Instruction) -> u32 {
+    Timing::new(instruction).fixed
+}
+
+/// The number of words that an instruction pushes or pops.
+const fn stack_transfers(instruction: &Instruction) -> u32 {
+    match instruction.operation {
+        Operation::Call if instruction.far || matches!(instruction.operands[0], Operand::Far(..)) => 2,
+        Operation::Push | Operation::Pop | Operation::Pushf | Operation::Popf | Operation::Ret | Operation::Call => 1,
+        Operation::Retf => 2,
+        Operation::Int | Operation::Int3 | Operation::Iret => 3,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::decode::decode;
+
+    fn estimate(code: &[u8], odd: bool) -> u32 {
+        let instruction = decode(code).unwrap();
+        let registers = Registers::default();
+        let mut after = registers;
+        after.ip = instruction.size;
+        clocks(&instruction, &registers, &after, odd)
+    }
+
+    #[test]
+    fn effective_addresses() {
+        assert_eq!(estimate(&[0b10001011, 0b00_000_111], false), 8 + 5); // mov ax, [bx]
+        assert_eq!(estimate(&[0b10001011, 0b01_000_000, 4], false), 8 + 11); // mov ax, [bx+si+4]
+        assert_eq!(estimate(&[0b10001011, 0b01_000_110, 0], false), 8 + 9); // mov ax, [bp]
+        assert_eq!(estimate(&[0b00100110, 0b10001011, 0b00_000_001], false), 8 + 8 + 2); // mov ax, es:[bx+di]
+        assert_eq!(estimate(&[0b10100001, 0, 1], false), 10); // mov ax, [+256]
+    }
+
+    #[test]
+    fn transfers() {
+        assert_eq!(estimate(&[0b00000001, 0b00_000_111], false), 16 + 5); // add [bx], ax
+        assert_eq!(estimate(&[0b00000001, 0b00_000_111], true), 16 + 5 + 8);
+        assert_eq!(estimate(&[0b00000000, 0b00_000_111], true), 16 + 5); // add [bx], al
+    }
+
+    #[test]
+    fn branches() {
+        let instruction = decode(&[0b01110101, 0xfe]).unwrap(); // jne $+0
+        let before = Registers::default();
+        assert_eq!(clocks(&instruction, &before, &before, false), 16);
+        let mut after = before;
+        after.ip = 2;
+        assert_eq!(clocks(&instruction, &before, &after, false), 4);
+    }
+
+    #[test]
+    fn estimates() {
+        assert_eq!(super::estimate(&decode(&[0b01110101, 0xfe]).unwrap()), 4); // jne $+0
+        assert_eq!(super::estimate(&decode(&[0b11110011, 0b10100101]).unwrap()), 9); // rep movsw
+        assert_eq!(super::estimate(&decode(&[0b11010011, 0b11_100_000]).unwrap()), 8);
+        // shl ax, cl
+    }
+
+    #[test]
+    fn repetitions() {
+        let instruction = decode(&[0b11110011, 0b10100101]).unwrap(); // rep movsw
+        let mut before = Registers::default();
+        before.set(Register::Cx, 10);
+        let mut after = before;
+        after.set(Register::Cx, 0);
+        after.ip = 2;
+        assert_eq!(clocks(&instruction, &before, &after, false), 9 + 17 * 10);
+    }
+}
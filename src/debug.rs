@@ -0,0 +1,1156 @@
+//! An interactive debugger for the simulator, driven by text commands.
+//!
+//! Expressions add and subtract registers and numbers, like `bx+si+4`. Numbers are decimal, or hexadecimal with a
+//! "0x" prefix. Addresses are physical, or `segment:offset` with an expression on each side, like `ds:si`.
+//! Locations are addresses, or the labels that the disassembler gives to the targets of jumps and calls.
+//! Watchpoints are registers, bytes of memory like `[ds:si]`, or ranges of memory like `[0x200..0x210]`, whose end
+//! is excluded.
+//!
+//! Each executed instruction is recorded with the state that it changed, so the debugger can run backward. Output
+//! that was written and input that was read can't be taken back.
+
+// Offsets and counts fit in their casts.
+#![allow(clippy::cast_possible_truncation)]
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fmt;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::str::FromStr;
+
+use crate::decode::labels;
+use crate::instruction::{Instruction, Operand, Operation, Register};
+use crate::pic::Pic;
+use crate::sim::{self, physical, Error, Machine, Registers, MEMORY_SIZE};
+use crate::state::{self, Target};
+
+pub const HELP: &str = "\
+step [n]            execute n instructions, showing each (s)
+next [n]            like step, but run calls and interrupts until they return (n)
+continue            run until the program stops (c)
+until <location>    run until the instruction at an address or label, like a breakpoint that is then deleted (u)
+finish              run until the innermost call or interrupt returns (fin)
+regs                show the registers (r)
+mem <address> [n]   dump n bytes of memory, 64 by default (x)
+disasm [n]          disassemble n instructions from CS:IP, 8 by default (d)
+print <expression>  evaluate an expression, like bx+si+4 (p), or enter the expression alone
+break [location]    stop before the instruction at an address or label, or list breakpoints (b)
+delete <location>   remove a breakpoint
+watch [target]      stop when a register or memory changes, like bx or [0x200..0x210], or list watchpoints (w)
+unwatch <target>    remove a watchpoint
+bt                  show the calls and interrupts that haven't returned, innermost first
+reverse-step [n]    undo n instructions, 1 by default (rs)
+reverse-continue    undo instructions until a breakpoint or a watched change (rc)
+help                show this help (h)
+quit                exit the debugger (q)
+Addresses are physical, like 0x8000, or segment:offset, like ds:si. Locations are addresses or labels, like label3.
+";
+
+const BYTES_PER_LINE: usize = 16;
+const DEFAULT_DUMP: usize = 64;
+const DEFAULT_DISASSEMBLY: usize = 8;
+// The most bytes in an 8086 instruction that the disassembly shows, with a prefix.
+const INSTRUCTION_BYTES: usize = 7;
+
+/// A term in an expression.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Term {
+    Register(Target),
+    Number(u32),
+}
+
+/// Registers and numbers, added and subtracted.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Expression {
+    /// Each term, and whether it is subtracted.
+    terms: Vec<(bool, Term)>,
+}
+
+impl Expression {
+    #[must_use]
+    pub fn evaluate(&self, registers: &Registers) -> u32 {
+        self.terms.iter().fold(0u32, |sum, &(negative, term)| {
+            let value = match term {
+                Term::Register(target) => u32::from(target.get(registers)),
+                Term::Number(number) => number,
+            };
+            if negative {
+                sum.wrapping_sub(value)
+            } else {
+                sum.wrapping_add(value)
+            }
+        })
+    }
+}
+
+impl FromStr for Expression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let text: String = s.chars().filter(|c| !c.is_whitespace()).collect();
+        let mut terms = vec![];
+        let mut rest = text.as_str();
+        let mut negative = false;
+        if let Some(after) = rest.strip_prefix('-') {
+            (negative, rest) = (true, after);
+        }
+        loop {
+            let end = rest.find(['+', '-']).unwrap_or(rest.len());
+            let term = &rest[..end];
+            if term.is_empty() {
+                return Err(format!("invalid expression {s:?}"));
+            }
+            let term = if term.starts_with(|c: char| c.is_ascii_digit()) {
+                Term::Number(state::number(term)?)
+            } else {
+                Term::Register(term.parse()?)
+            };
+            terms.push((negative, term));
+            if end == rest.len() {
+                return Ok(Self { terms });
+            }
+            negative = rest[end..].starts_with('-');
+            rest = &rest[end + 1..];
+        }
+    }
+}
+
+/// A physical address, or a segment and an offset.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Address {
+    Physical(Expression),
+    Segmented(Expression, Expression),
+}
+
+impl Address {
+    #[must_use]
+    pub fn evaluate(&self, registers: &Registers) -> usize {
+        match self {
+            Self::Physical(address) => address.evaluate(registers) as usize % MEMORY_SIZE,
+            Self::Segmented(segment, offset) => {
+                physical(segment.evaluate(registers) as u16, offset.evaluate(registers) as u16)
+            }
+        }
+    }
+}
+
+impl FromStr for Address {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some((segment, offset)) => Ok(Self::Segmented(segment.parse()?, offset.parse()?)),
+            None => Ok(Self::Physical(s.parse()?)),
+        }
+    }
+}
+
+/// An address, or a label of the program.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Location {
+    Address(Address),
+    Label(String),
+}
+
+impl Location {
+    /// The physical address of the location, given the program's labels by physical address.
+    ///
+    /// # Errors
+    ///
+    /// If the label doesn't exist.
+    pub fn resolve(&self, labels: &BTreeMap<usize, String>, registers: &Registers) -> Result<usize, String> {
+        match self {
+            Self::Address(address) => Ok(address.evaluate(registers)),
+            Self::Label(label) => labels
+                .iter()
+                .find_map(|(&address, name)| (name == label).then_some(address))
+                .ok_or_else(|| format!("no label {label:?}")),
+        }
+    }
+}
+
+impl FromStr for Location {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(Self::Address).or_else(|error| {
+            if s.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+                && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+            {
+                Ok(Self::Label(s.to_string()))
+            } else {
+                Err(error)
+            }
+        })
+    }
+}
+
+/// A register, or a range of memory to evaluate.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WatchTarget {
+    Register(Target),
+    /// The start, and the end if it isn't the next byte.
+    Memory(Address, Option<Address>),
+}
+
+impl WatchTarget {
+    #[must_use]
+    pub fn evaluate(&self, registers: &Registers) -> Watch {
+        match self {
+            Self::Register(target) => Watch::Register(*target),
+            Self::Memory(start, end) => {
+                let start = start.evaluate(registers);
+                let end = end.as_ref().map_or(start + 1, |end| end.evaluate(registers));
+                Watch::Memory(start..end.max(start + 1))
+            }
+        }
+    }
+}
+
+impl FromStr for WatchTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some(memory) = s.strip_prefix('[').and_then(|s| s.strip_suffix(']')) else {
+            return s.parse().map(Self::Register);
+        };
+        match memory.split_once("..") {
+            Some((start, end)) => Ok(Self::Memory(start.parse()?, Some(end.parse()?))),
+            None => Ok(Self::Memory(memory.parse()?, None)),
+        }
+    }
+}
+
+/// A register, or physical addresses of memory.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Watch {
+    Register(Target),
+    Memory(Range<usize>),
+}
+
+impl fmt::Display for Watch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Register(target) => write!(f, "{target}"),
+            Self::Memory(range) if range.len() == 1 => write!(f, "[{:#07x}]", range.start),
+            Self::Memory(range) => write!(f, "[{:#07x}..{:#07x}]", range.start, range.end),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Command {
+    Step(usize),
+    Next(usize),
+    Continue,
+    Until(Location),
+    Finish,
+    Registers,
+    Memory(Address, usize),
+    Disassemble(usize),
+    Print(Expression),
+    Break(Option<Location>),
+    Delete(Location),
+    Watch(Option<WatchTarget>),
+    Unwatch(WatchTarget),
+    Backtrace,
+    ReverseStep(usize),
+    ReverseContinue,
+    Help,
+    Quit,
+}
+
+impl FromStr for Command {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        let name = words.next().ok_or("missing command")?;
+        let arguments: Vec<_> = words.collect();
+        let count = |index: usize, default: usize| {
+            arguments.get(index).map_or(Ok(default), |argument: &&str| {
+                state::number(argument).map(|count| count as usize)
+            })
+        };
+        let command = match name {
+            "step" | "s" => Self::Step(count(0, 1)?),
+            "next" | "n" => Self::Next(count(0, 1)?),
+            "continue" | "c" => Self::Continue,
+            "until" | "u" => Self::Until(arguments.first().ok_or("until requires a location")?.parse()?),
+            "finish" | "fin" => Self::Finish,
+            "regs" | "r" => Self::Registers,
+            "mem" | "x" => {
+                let address = arguments.first().ok_or("mem requires an address")?;
+                Self::Memory(address.parse()?, count(1, DEFAULT_DUMP)?)
+            }
+            "disasm" | "d" => Self::Disassemble(count(0, DEFAULT_DISASSEMBLY)?),
+            "print" | "p" => Self::Print(arguments.concat().parse()?),
+            "break" | "b" => Self::Break(arguments.first().map(|location| location.parse()).transpose()?),
+            "delete" => Self::Delete(arguments.first().ok_or("delete requires a location")?.parse()?),
+            "watch" | "w" => Self::Watch(arguments.first().map(|target| target.parse()).transpose()?),
+            "unwatch" => Self::Unwatch(arguments.first().ok_or("unwatch requires a target")?.parse()?),
+            "bt" | "backtrace" => Self::Backtrace,
+            "reverse-step" | "rs" => Self::ReverseStep(count(0, 1)?),
+            "reverse-continue" | "rc" => Self::ReverseContinue,
+            "help" | "h" => Self::Help,
+            "quit" | "q" => Self::Quit,
+            _ => Self::Print(
+                s.parse()
+                    .map_err(|_| format!("unknown command {name:?}, try \"help\""))?,
+            ),
+        };
+        Ok(command)
+    }
+}
+
+/// Whether to keep reading commands.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Control {
+    Continue,
+    Quit,
+}
+
+/// Why the program stopped running.
+#[derive(Debug)]
+enum Stop {
+    /// CS:IP left the program.
+    Left,
+    Exited(u8),
+    Halted,
+    Error(Error),
+    Breakpoint,
+    /// The instruction that `until` runs to.
+    Reached,
+    /// The innermost call or interrupt returned.
+    Returned,
+    /// The instruction at an address changed watched values, described with their old and new values.
+    Watchpoint(usize, Instruction, Vec<String>),
+    /// Running backward reached the first recorded instruction.
+    Start,
+}
+
+// The most instructions recorded. Older instructions are forgotten.
+const HISTORY: usize = 1 << 20;
+
+/// The state before an instruction, and the old values of the memory that it wrote.
+struct Record {
+    registers: Registers,
+    cycles: u64,
+    halted: bool,
+    undefined: u16,
+    pic: Pic,
+    frames: Tracked,
+    writes: Vec<(usize, u8)>,
+}
+
+/// How an instruction changed the frames. Only returns keep frames, so that the history doesn't grow with the depth
+/// of the calls.
+enum Tracked {
+    Unchanged,
+    Pushed,
+    /// The frames that a return popped, outermost first.
+    Popped(Vec<Frame>),
+}
+
+/// How a frame was entered, and so how it returns.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Call {
+    Near,
+    Far,
+    Interrupt(u8),
+}
+
+/// A call or interrupt that hasn't returned.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub call: Call,
+    /// The physical address that the call entered.
+    pub target: usize,
+    /// The segment and offset that it returns to.
+    pub return_to: (u16, u16),
+}
+
+pub struct Debugger {
+    pub machine: Machine,
+    /// The physical addresses of the program. The program ends when CS:IP leaves them.
+    pub program: Range<usize>,
+    /// The labels of the program, by physical address.
+    pub labels: BTreeMap<usize, String>,
+    /// The physical addresses of the breakpoints.
+    pub breakpoints: BTreeSet<usize>,
+    /// The watchpoints, with the values that they last had.
+    watchpoints: Vec<(Watch, Vec<u8>)>,
+    /// The calls and interrupts that haven't returned, outermost first.
+    pub frames: Vec<Frame>,
+    /// The executed instructions, oldest first.
+    history: VecDeque<Record>,
+}
+
+impl Debugger {
+    /// Debug a program that is loaded into a machine, labeling it like the disassembler.
+    #[must_use]
+    pub fn new(mut machine: Machine, program: Range<usize>) -> Self {
+        machine.journal = Some(vec![]);
+        let labels = labels(&machine.memory[program.clone()])
+            .into_iter()
+            .map(|(offset, label)| (program.start + offset, label))
+            .collect();
+        Self {
+            machine,
+            program,
+            labels,
+            breakpoints: BTreeSet::new(),
+            watchpoints: vec![],
+            frames: vec![],
+            history: VecDeque::new(),
+        }
+    }
+
+    /// Parse and run a command, writing its results and the program's output.
+    ///
+    /// # Errors
+    ///
+    /// If writing fails.
+    pub fn command(&mut self, line: &str, out: &mut impl Write) -> io::Result<Control> {
+        match line.parse() {
+            Ok(command) => self.run(&command, out),
+            Err(error) => {
+                writeln!(out, "error: {error}")?;
+                Ok(Control::Continue)
+            }
+        }
+    }
+
+    /// Run a command.
+    ///
+    /// # Errors
+    ///
+    /// If writing fails.
+    pub fn run(&mut self, command: &Command, out: &mut impl Write) -> io::Result<Control> {
+        let registers = self.machine.registers;
+        match command {
+            Command::Step(count) => self.step(*count, false, out)?,
+            Command::Next(count) => self.step(*count, true, out)?,
+            Command::Continue => loop {
+                if let Some(stop) = self.execute(false, out)? {
+                    self.report(&stop, out)?;
+                    break;
+                }
+            },
+            Command::Until(location) => match location.resolve(&self.labels, &registers) {
+                Ok(address) => {
+                    self.run_until(
+                        |debugger| debugger.machine.instruction_address() == address,
+                        &Stop::Reached,
+                        out,
+                    )?;
+                }
+                Err(error) => writeln!(out, "error: {error}")?,
+            },
+            Command::Finish => {
+                let depth = self.frames.len();
+                if depth == 0 {
+                    writeln!(out, "error: no call or interrupt to finish")?;
+                } else {
+                    self.run_until(|debugger| debugger.frames.len() < depth, &Stop::Returned, out)?;
+                }
+            }
+            Command::Registers => write_registers(out, &registers, self.machine.cycles)?,
+            Command::Memory(address, length) => self.dump(address.evaluate(&registers), *length, out)?,
+            Command::Disassemble(count) => self.disassemble(*count, out)?,
+            Command::Print(expression) => {
+                let value = expression.evaluate(&registers);
+                writeln!(out, "{value:#x} ({value})")?;
+            }
+            Command::Break(None) => {
+                for &address in &self.breakpoints {
+                    writeln!(out, "{}", self.describe(address))?;
+                }
+            }
+            Command::Break(Some(location)) => match location.resolve(&self.labels, &registers) {
+                Ok(address) => {
+                    self.breakpoints.insert(address);
+                    writeln!(out, "breakpoint at {}", self.describe(address))?;
+                }
+                Err(error) => writeln!(out, "error: {error}")?,
+            },
+            Command::Delete(location) => match location.resolve(&self.labels, &registers) {
+                Ok(address) if self.breakpoints.remove(&address) => {}
+                Ok(address) => writeln!(out, "error: no breakpoint at {}", self.describe(address))?,
+                Err(error) => writeln!(out, "error: {error}")?,
+            },
+            Command::Watch(None) => {
+                for (watch, _) in &self.watchpoints {
+                    writeln!(out, "{watch}")?;
+                }
+            }
+            Command::Watch(Some(target)) => {
+                let watch = target.evaluate(&registers);
+                if !self.watchpoints.iter().any(|(existing, _)| *existing == watch) {
+                    let values = self.values(&watch);
+                    self.watchpoints.push((watch, values));
+                }
+            }
+            Command::Unwatch(target) => {
+                let watch = target.evaluate(&registers);
+                let count = self.watchpoints.len();
+                self.watchpoints.retain(|(existing, _)| *existing != watch);
+                if self.watchpoints.len() == count {
+                    writeln!(out, "error: no watchpoint on {watch}")?;
+                }
+            }
+            Command::Backtrace => self.backtrace(out)?,
+            Command::ReverseStep(count) => {
+                for _ in 0..*count {
+                    if let Some(stop) = self.undo() {
+                        self.report(&stop, out)?;
+                        break;
+                    }
+                }
+                self.disassemble(1, out)?;
+            }
+            Command::ReverseContinue => loop {
+                if let Some(stop) = self.undo() {
+                    self.report(&stop, out)?;
+                    break;
+                }
+            },
+            Command::Help => out.write_all(HELP.as_bytes())?,
+            Command::Quit => return Ok(Control::Quit),
+        }
+        Ok(Control::Continue)
+    }
+
+    /// Execute instructions, showing each. To step over a call or interrupt, run until it returns.
+    fn step(&mut self, count: usize, over: bool, out: &mut impl Write) -> io::Result<()> {
+        for _ in 0..count {
+            let registers = self.machine.registers;
+            let (cs, ip, sp) = (registers.get(Register::Cs), registers.ip, registers.get(Register::Sp));
+            let next = self.machine.decode_at(cs, ip).and_then(|instruction| {
+                let returns = matches!(
+                    instruction.operation,
+                    Operation::Call | Operation::Int | Operation::Int3 | Operation::Into
+                );
+                returns.then(|| ip.wrapping_add(instruction.size))
+            });
+            if let Some(stop) = self.execute(true, out)? {
+                return self.report(&stop, out);
+            }
+            if let Some(next) = next.filter(|_| over) {
+                // The call returns when it reaches the next instruction, with its stack popped.
+                while {
+                    let registers = &self.machine.registers;
+                    (registers.get(Register::Cs), registers.ip) != (cs, next) || registers.get(Register::Sp) < sp
+                } {
+                    if let Some(stop) = self.execute(false, out)? {
+                        return self.report(&stop, out);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Execute instructions until `done` is true after one, and report `stop`, unless the program stops first.
+    fn run_until(&mut self, done: impl Fn(&Self) -> bool, stop: &Stop, out: &mut impl Write) -> io::Result<()> {
+        loop {
+            if let Some(stop) = self.execute(false, out)? {
+                return self.report(&stop, out);
+            }
+            if done(self) {
+                return self.report(stop, out);
+            }
+        }
+    }
+
+    /// Execute an instruction, showing it if `trace`, unless the program has stopped. Stop if the next instruction
+    /// has a breakpoint.
+    fn execute(&mut self, trace: bool, out: &mut impl Write) -> io::Result<Option<Stop>> {
+        if let Some(code) = self.machine.exit_code {
+            return Ok(Some(Stop::Exited(code)));
+        }
+        if !self.program.contains(&self.machine.instruction_address()) {
+            return Ok(Some(Stop::Left));
+        }
+        let before = self.machine.registers;
+        let address = self.machine.instruction_address();
+        let mut record = Record {
+            registers: before,
+            cycles: self.machine.cycles,
+            halted: self.machine.halted,
+            undefined: self.machine.undefined,
+            pic: self.machine.pic,
+            frames: Tracked::Unchanged,
+            writes: vec![],
+        };
+        let result = self.machine.step();
+        let writes = self.machine.journal.replace(vec![]).unwrap_or_default();
+        match result {
+            Ok(instruction) => {
+                record.writes = writes;
+                record.frames = self.track(&instruction, &before);
+                if self.history.len() == HISTORY {
+                    self.history.pop_front();
+                }
+                self.history.push_back(record);
+                out.write_all(&self.machine.output)?;
+                self.machine.output.clear();
+                if trace {
+                    sim::write_trace(out, &instruction, &before, &self.machine.registers, true)?;
+                }
+                let changes = self.changes();
+                if !changes.is_empty() {
+                    return Ok(Some(Stop::Watchpoint(address, instruction, changes)));
+                }
+                let breakpoint = self.breakpoints.contains(&self.machine.instruction_address());
+                Ok(breakpoint.then_some(Stop::Breakpoint))
+            }
+            Err(Error::Halted) => Ok(Some(Stop::Halted)),
+            Err(error) => Ok(Some(Stop::Error(error))),
+        }
+    }
+
+    /// Undo the last recorded instruction. Stop at a breakpoint, or if the instruction changed watched values.
+    fn undo(&mut self) -> Option<Stop> {
+        let Some(record) = self.history.pop_back() else {
+            return Some(Stop::Start);
+        };
+        let machine = &mut self.machine;
+        for &(address, value) in record.writes.iter().rev() {
+            machine.memory[address] = value;
+        }
+        machine.registers = record.registers;
+        machine.cycles = record.cycles;
+        machine.halted = record.halted;
+        machine.undefined = record.undefined;
+        machine.pic = record.pic;
+        machine.exit_code = None;
+        match record.frames {
+            Tracked::Unchanged => {}
+            Tracked::Pushed => {
+                self.frames.pop();
+            }
+            Tracked::Popped(frames) => self.frames.extend(frames),
+        }
+
+        let changes = self.changes();
+        let address = self.machine.instruction_address();
+        if !changes.is_empty() {
+            let registers = &self.machine.registers;
+            if let Some(instruction) = self.machine.decode_at(registers.get(Register::Cs), registers.ip) {
+                return Some(Stop::Watchpoint(address, instruction, changes));
+            }
+        }
+        self.breakpoints.contains(&address).then_some(Stop::Breakpoint)
+    }
+
+    /// Push a frame for a call or interrupt that entered a procedure or handler, or pop frames for a return, and
+    /// return how the frames changed.
+    fn track(&mut self, instruction: &Instruction, before: &Registers) -> Tracked {
+        let registers = &self.machine.registers;
+        let here = (registers.get(Register::Cs), registers.ip);
+        let next = (before.get(Register::Cs), before.ip.wrapping_add(instruction.size));
+        let call = match (instruction.operation, instruction.operands[0]) {
+            (Operation::Call, Operand::Far(..)) => Call::Far,
+            (Operation::Call, _) if instruction.far => Call::Far,
+            (Operation::Call, _) => Call::Near,
+            (Operation::Int, Operand::Immediate(number)) => Call::Interrupt(number as u8),
+            (Operation::Int3, _) => Call::Interrupt(3),
+            (Operation::Into, _) => Call::Interrupt(4),
+            (Operation::Ret | Operation::Retf | Operation::Iret, _) => {
+                // Pop to the frame that returns here, or else the innermost frame, in case the return address was
+                // changed.
+                let index = self.frames.iter().rposition(|frame| frame.return_to == here);
+                let length = index.unwrap_or_else(|| self.frames.len().saturating_sub(1));
+                let popped = self.frames.split_off(length);
+                return if popped.is_empty() {
+                    Tracked::Unchanged
+                } else {
+                    Tracked::Popped(popped)
+                };
+            }
+            _ => return Tracked::Unchanged,
+        };
+        // Emulated interrupts, and calls to the next instruction, don't enter anything.
+        if here == next {
+            return Tracked::Unchanged;
+        }
+        self.frames.push(Frame {
+            call,
+            target: self.machine.instruction_address(),
+            return_to: next,
+        });
+        Tracked::Pushed
+    }
+
+    /// Write the current address and the return address of each frame, innermost first, with the procedure or
+    /// handler that each is in.
+    ///
+    /// # Errors
+    ///
+    /// If writing fails.
+    pub fn backtrace(&self, out: &mut impl Write) -> io::Result<()> {
+        let registers = &self.machine.registers;
+        let mut addresses = vec![(registers.get(Register::Cs), registers.ip)];
+        addresses.extend(self.frames.iter().rev().map(|frame| frame.return_to));
+        for (depth, &(segment, offset)) in addresses.iter().enumerate() {
+            write!(out, "#{depth:<2} {segment:04x}:{offset:04x}")?;
+            if let Some(label) = self.labels.get(&physical(segment, offset)) {
+                write!(out, " ({label})")?;
+            }
+            // The frame that this address is in, if any.
+            if let Some(frame) = self.frames.len().checked_sub(depth + 1).map(|index| self.frames[index]) {
+                match frame.call {
+                    Call::Interrupt(number) => write!(out, " in interrupt {number:#04x} handler")?,
+                    call => {
+                        let procedure = self.labels.get(&frame.target).cloned();
+                        write!(
+                            out,
+                            " in {}",
+                            procedure.unwrap_or_else(|| format!("{:05x}", frame.target))
+                        )?;
+                        if call == Call::Far {
+                            write!(out, " (far)")?;
+                        }
+                    }
+                }
+            }
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+
+    /// The values of a watched register or memory.
+    fn values(&self, watch: &Watch) -> Vec<u8> {
+        match watch {
+            Watch::Register(target) => target.get(&self.machine.registers).to_le_bytes().to_vec(),
+            Watch::Memory(range) => range
+                .clone()
+                .map(|address| self.machine.memory[address % self.machine.memory.len()])
+                .collect(),
+        }
+    }
+
+    /// Describe the watched values that changed, and remember their new values.
+    fn changes(&mut self) -> Vec<String> {
+        let mut changes = vec![];
+        for i in 0..self.watchpoints.len() {
+            let values = self.values(&self.watchpoints[i].0);
+            let (watch, old) = &mut self.watchpoints[i];
+            if values == *old {
+                continue;
+            }
+            match watch {
+                Watch::Register(_) => {
+                    let [old, new] = [old, &values].map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]));
+                    changes.push(format!("{watch}: {old:#06x} -> {new:#06x}"));
+                }
+                Watch::Memory(range) => {
+                    for ((address, old), new) in range.clone().zip(old.iter()).zip(&values) {
+                        if old != new {
+                            changes.push(format!("[{address:#07x}]: {old:#04x} -> {new:#04x}"));
+                        }
+                    }
+                }
+            }
+            *old = values;
+        }
+        changes
+    }
+
+    fn report(&self, stop: &Stop, out: &mut impl Write) -> io::Result<()> {
+        let here = self.describe(self.machine.instruction_address());
+        match stop {
+            Stop::Left => writeln!(out, "program ended at {here}"),
+            Stop::Exited(code) => writeln!(out, "program exited with code {code}"),
+            Stop::Halted => writeln!(out, "halted at {here}"),
+            Stop::Error(error) => writeln!(out, "error at {here}: {error}"),
+            Stop::Start => writeln!(out, "reached the start of the recording"),
+            Stop::Watchpoint(address, instruction, changes) => {
+                writeln!(out, "watchpoint: {instruction} at {}", self.describe(*address))?;
+                for change in changes {
+                    writeln!(out, "    {change}")?;
+                }
+                Ok(())
+            }
+            Stop::Breakpoint | Stop::Reached | Stop::Returned => {
+                match stop {
+                    Stop::Breakpoint => writeln!(out, "breakpoint at {here}")?,
+                    Stop::Reached => writeln!(out, "stopped at {here}")?,
+                    _ => writeln!(out, "returned to {here}")?,
+                }
+                self.disassemble(1, out)?;
+                write_registers(out, &self.machine.registers, self.machine.cycles)
+            }
+        }
+    }
+
+    /// A physical address, as CS:IP if it is in the code segment, with its label.
+    fn describe(&self, address: usize) -> String {
+        let cs = self.machine.registers.get(Register::Cs);
+        let mut text = match address.checked_sub(physical(cs, 0)) {
+            Some(offset) if offset <= 0xffff => format!("{cs:04x}:{offset:04x}"),
+            _ => format!("{address:05x}"),
+        };
+        if let Some(label) = self.labels.get(&address) {
+            text = format!("{text} ({label})");
+        }
+        text
+    }
+
+    /// Write bytes of memory, with their characters.
+    ///
+    /// # Errors
+    ///
+    /// If writing fails.
+    pub fn dump(&self, address: usize, length: usize, out: &mut impl Write) -> io::Result<()> {
+        let addresses: Vec<_> = (address..address + length)
+            .map(|address| address % self.machine.memory.len())
+            .collect();
+        for line in addresses.chunks(BYTES_PER_LINE) {
+            write!(out, "{:05x} ", line[0])?;
+            for &address in line {
+                write!(out, " {:02x}", self.machine.memory[address])?;
+            }
+            let padding = 3 * (BYTES_PER_LINE - line.len());
+            let text: String = line
+                .iter()
+                .map(|&address| match self.machine.memory[address] {
+                    byte @ 0x20..=0x7e => char::from(byte),
+                    _ => '.',
+                })
+                .collect();
+            writeln!(out, "{:padding$}  |{text}|", "")?;
+        }
+        Ok(())
+    }
+
+    /// Write instructions from CS:IP, with their addresses, bytes and labels.
+    fn disassemble(&self, count: usize, out: &mut impl Write) -> io::Result<()> {
+        let cs = self.machine.registers.get(Register::Cs);
+        let mut ip = self.machine.registers.ip;
+        for i in 0..count {
+            let marker = if i == 0 { "=>" } else { "  " };
+            let instruction = self.machine.decode_at(cs, ip);
+            let size = instruction.map_or(1, |instruction| instruction.size);
+            if let Some(label) = self.labels.get(&physical(cs, ip)) {
+                writeln!(out, "{label}:")?;
+            }
+            write!(out, "{marker} {cs:04x}:{ip:04x} ")?;
+            for i in 0..size {
+                write!(
+                    out,
+                    " {:02x}",
+                    self.machine.memory[self.machine.address(cs, ip.wrapping_add(i))]
+                )?;
+            }
+            // The bytes are separated by spaces, and then padded.
+            let padding = 3 * INSTRUCTION_BYTES.saturating_sub(usize::from(size)) + 1;
+            write!(out, "{:padding$}", "")?;
+            let Some(instruction) = instruction else {
+                writeln!(out, "(invalid)")?;
+                break;
+            };
+            writeln!(out, "{instruction}")?;
+            ip = ip.wrapping_add(size);
+        }
+        Ok(())
+    }
+}
+
+/// Write all the registers, and the cycle count.
+///
+/// # Errors
+///
+/// If writing fails.
+pub fn write_registers(out: &mut impl Write, registers: &Registers, cycles: u64) -> io::Result<()> {
+    use Register::{Ax, Bp, Bx, Cs, Cx, Di, Ds, Dx, Es, Si, Sp, Ss};
+    for row in [[Ax, Bx, Cx, Dx], [Sp, Bp, Si, Di], [Es, Cs, Ss, Ds]] {
+        let row: Vec<_> = row
+            .iter()
+            .map(|&register| format!("{register} {:#06x}", registers.get(register)))
+            .collect();
+        writeln!(out, "{}", row.join("  "))?;
+    }
+    writeln!(
+        out,
+        "ip {:#06x}  flags {:#06x} {}  cycles {cycles}",
+        registers.ip,
+        registers.flags,
+        sim::Flags(registers.flags)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn debugger(program: &[u8]) -> Debugger {
+        let mut machine = Machine::new();
+        machine.load(0, program);
+        Debugger::new(machine, 0..program.len())
+    }
+
+    fn output(debugger: &mut Debugger, line: &str) -> String {
+        let mut out = vec![];
+        debugger.command(line, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn expressions() {
+        let mut registers = Registers::default();
+        registers.set(Register::Bx, 0x100);
+        registers.set(Register::Si, 2);
+        let expression = |text: &str| text.parse::<Expression>().unwrap().evaluate(&registers);
+        assert_eq!(expression("bx + si + 4"), 0x106);
+        assert_eq!(expression("-1+BX-0x10"), 0xef);
+        assert!("bx+".parse::<Expression>().is_err());
+        assert!("zz".parse::<Expression>().is_err());
+
+        registers.set(Register::Ds, 0x1000);
+        let address = "ds:bx+si".parse::<Address>().unwrap();
+        assert_eq!(address.evaluate(&registers), 0x10102);
+    }
+
+    #[test]
+    fn commands() {
+        assert_eq!("s".parse(), Ok(Command::Step(1)));
+        assert_eq!("next 0x10".parse(), Ok(Command::Next(16)));
+        assert!("mem".parse::<Command>().is_err());
+        assert!("frobnicate".parse::<Command>().is_err());
+        assert!(matches!("ax".parse(), Ok(Command::Print(_))));
+    }
+
+    #[test]
+    fn session() {
+        let program = [
+            &[0b10111011, 0x34, 0x12][..], // mov bx, 4660
+            &[0b11101000, 1, 0],           // call $+4
+            &[0b11110100],                 // hlt
+            &[0b01000011],                 // inc bx
+            &[0b11000011],                 // ret
+        ]
+        .concat();
+        let mut debugger = debugger(&program);
+        assert_eq!(
+            output(&mut debugger, "disasm 2"),
+            "=> 0000:0000  bb 34 12             mov bx, 4660\n   0000:0003  e8 01 00             call $+4\n"
+        );
+        assert_eq!(
+            output(&mut debugger, "s"),
+            "mov bx, 4660 ; bx:0x0->0x1234 ip:0x0->0x3 \n"
+        );
+        assert_eq!(output(&mut debugger, "p bx + 1"), "0x1235 (4661)\n");
+        // Next runs the whole call.
+        assert_eq!(output(&mut debugger, "n"), "call $+4 ; sp:0x0->0xfffe ip:0x3->0x7 \n");
+        assert_eq!(debugger.machine.registers.ip, 6);
+        assert_eq!(output(&mut debugger, "bx"), "0x1235 (4661)\n");
+        assert!(output(&mut debugger, "regs").starts_with("ax 0x0000  bx 0x1235"));
+        assert_eq!(output(&mut debugger, "c"), "halted at 0000:0007 (label0)\n");
+
+        assert_eq!(
+            output(&mut debugger, "x 0 4"),
+            "00000  bb 34 12 e8                                      |.4..|\n"
+        );
+        assert_eq!(debugger.command("q", &mut vec![]).unwrap(), Control::Quit);
+    }
+
+    #[test]
+    fn breakpoints() {
+        let program = [
+            &[0b10111001, 3, 0][..], // mov cx, 3
+            &[0b01000011],           // inc bx
+            &[0b11100010, 0xfd],     // loop $-1
+            &[0b11110100],           // hlt
+        ]
+        .concat();
+        let mut debugger = debugger(&program);
+        assert_eq!(output(&mut debugger, "b label0"), "breakpoint at 0000:0003 (label0)\n");
+        assert_eq!(output(&mut debugger, "b 6"), "breakpoint at 0000:0006\n");
+        assert_eq!(output(&mut debugger, "b label9"), "error: no label \"label9\"\n");
+        assert_eq!(output(&mut debugger, "b"), "0000:0003 (label0)\n0000:0006\n");
+
+        let stop = output(&mut debugger, "c");
+        assert!(stop.starts_with("breakpoint at 0000:0003 (label0)\nlabel0:\n=> 0000:0003  43"));
+        assert_eq!(debugger.machine.registers.get(Register::Bx), 0);
+        // Continuing from a breakpoint executes its instruction.
+        output(&mut debugger, "c");
+        assert_eq!(debugger.machine.registers.get(Register::Bx), 1);
+
+        output(&mut debugger, "delete label0");
+        assert!(output(&mut debugger, "c").starts_with("breakpoint at 0000:0006"));
+        assert_eq!(debugger.machine.registers.get(Register::Bx), 3);
+        assert_eq!(
+            output(&mut debugger, "delete cs:3"),
+            "error: no breakpoint at 0000:0003 (label0)\n"
+        );
+    }
+
+    #[test]
+    fn until_and_finish() {
+        let program = [
+            &[0b11101000, 1, 0][..], // call $+4
+            &[0b11110100],           // hlt
+            &[0b11101000, 1, 0],     // call $+4
+            &[0b11000011],           // ret
+            &[0b01000011],           // inc bx
+            &[0b01000011],           // inc bx
+            &[0b11000011],           // ret
+        ]
+        .concat();
+        let mut debugger = debugger(&program);
+        debugger.machine.registers.set(Register::Sp, 0x100);
+        assert_eq!(
+            output(&mut debugger, "finish"),
+            "error: no call or interrupt to finish\n"
+        );
+        assert!(output(&mut debugger, "until 9").starts_with("stopped at 0000:0009\n=> 0000:0009  43"));
+        assert_eq!(debugger.machine.registers.get(Register::Bx), 1);
+        assert_eq!(debugger.frames.len(), 2);
+        // Until doesn't leave a breakpoint behind.
+        assert!(debugger.breakpoints.is_empty());
+
+        assert!(output(&mut debugger, "fin").starts_with("returned to 0000:0007\n=> 0000:0007  c3"));
+        assert_eq!(debugger.machine.registers.get(Register::Bx), 2);
+        assert!(output(&mut debugger, "fin").starts_with("returned to 0000:0003\n"));
+        assert!(debugger.frames.is_empty());
+        assert_eq!(output(&mut debugger, "u label9"), "error: no label \"label9\"\n");
+        assert_eq!(output(&mut debugger, "until 0"), "halted at 0000:0004 (label0)\n");
+    }
+
+    #[test]
+    fn watchpoints() {
+        let program = [
+            &[0b10111011, 0, 2][..],           // mov bx, 512
+            &[0b11000110, 0b01_000_111, 1, 5], // mov byte [bx+1], 5
+            &[0b01000011],                     // inc bx
+            &[0b01000001],                     // inc cx
+        ]
+        .concat();
+        let mut debugger = debugger(&program);
+        output(&mut debugger, "watch [0x200..0x210]");
+        output(&mut debugger, "w cx");
+        assert_eq!(output(&mut debugger, "watch"), "[0x00200..0x00210]\ncx\n");
+        assert_eq!(
+            output(&mut debugger, "c"),
+            "watchpoint: mov byte [bx+1], 5 at 0000:0003\n    [0x00201]: 0x00 -> 0x05\n"
+        );
+        assert_eq!(
+            output(&mut debugger, "c"),
+            "watchpoint: inc cx at 0000:0008\n    cx: 0x0000 -> 0x0001\n"
+        );
+        assert_eq!(output(&mut debugger, "unwatch bx"), "error: no watchpoint on bx\n");
+        output(&mut debugger, "unwatch cx");
+        assert_eq!(output(&mut debugger, "watch"), "[0x00200..0x00210]\n");
+        assert!("watch [0x200..]".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn backtrace() {
+        let program = [
+            &[0b11101000, 1, 0][..],    // call $+4
+            &[0b11110100],              // hlt
+            &[0b10011010, 10, 0, 0, 0], // call 0:10
+            &[0b11000011],              // ret
+            &[0b11001101, 0x40],        // int 64
+            &[0b11001011],              // retf
+            &[0b11001111],              // iret
+        ]
+        .concat();
+        let mut debugger = debugger(&program);
+        // The handler is the IRET.
+        debugger.machine.load(0x40 * 4, &[13, 0, 0, 0]);
+        debugger.machine.registers.set(Register::Sp, 0x100);
+        output(&mut debugger, "s 3");
+        assert_eq!(
+            output(&mut debugger, "bt"),
+            "#0  0000:000d in interrupt 0x40 handler\n\
+             #1  0000:000c in 0000a (far)\n\
+             #2  0000:0009 in label0\n\
+             #3  0000:0003\n"
+        );
+        output(&mut debugger, "s 3");
+        assert_eq!(output(&mut debugger, "bt"), "#0  0000:0003\n");
+        assert!(debugger.frames.is_empty());
+    }
+
+    #[test]
+    fn reverse() {
+        let program = [
+            &[0b10111011, 0, 2][..],        // mov bx, 512
+            &[0b11000110, 0b00_000_111, 7], // mov byte [bx], 7
+            &[0b10111011, 5, 0],            // mov bx, 5
+            &[0b10111001, 1, 0],            // mov cx, 1
+            &[0b11110100],                  // hlt
+        ]
+        .concat();
+        let mut debugger = debugger(&program);
+        output(&mut debugger, "c");
+        assert_eq!(debugger.machine.memory[0x200], 7);
+
+        // When did BX become 5?
+        output(&mut debugger, "watch bx");
+        assert_eq!(
+            output(&mut debugger, "rc"),
+            "watchpoint: mov bx, 5 at 0000:0006\n    bx: 0x0005 -> 0x0200\n"
+        );
+        assert_eq!(debugger.machine.registers.get(Register::Cx), 0);
+        assert_eq!(
+            output(&mut debugger, "rs"),
+            "=> 0000:0003  c6 07 07             mov byte [bx], 7\n"
+        );
+        assert_eq!(debugger.machine.memory[0x200], 0);
+        output(&mut debugger, "unwatch bx");
+        assert!(output(&mut debugger, "rc").starts_with("reached the start of the recording\n"));
+        assert_eq!(debugger.machine.registers, Registers::default());
+        assert_eq!(debugger.machine.cycles, 0);
+
+        output(&mut debugger, "b 9");
+        assert!(output(&mut debugger, "c").starts_with("breakpoint at 0000:0009"));
+        output(&mut debugger, "c");
+        assert!(output(&mut debugger, "rc").starts_with("breakpoint at 0000:0009"));
+    }
+
+    #[test]
+    fn reverse_recursion() {
+        let program = [
+            &[0b11101000, 1, 0][..],   // call $+4
+            &[0b11110100],             // hlt
+            &[0b01001001],             // dec cx
+            &[0b01110100, 3],          // jz $+5
+            &[0b11101000, 0xfa, 0xff], // call $-6
+            &[0b11000011],             // ret
+        ]
+        .concat();
+        let mut debugger = debugger(&program);
+        debugger.machine.registers.set(Register::Sp, 0x1000);
+        debugger.machine.registers.set(Register::Cx, 300);
+        output(&mut debugger, "until 10");
+        assert_eq!(debugger.frames.len(), 300);
+        output(&mut debugger, "c");
+        assert!(debugger.frames.is_empty());
+        // Each frame is kept once, by the return that popped it, rather than by every instruction inside it.
+        let kept: usize = debugger
+            .history
+            .iter()
+            .map(|record| match &record.frames {
+                Tracked::Popped(frames) => frames.len(),
+                Tracked::Unchanged | Tracked::Pushed => 0,
+            })
+            .sum();
+        assert_eq!(kept, 300);
+
+        // Back past the HLT and the returns.
+        assert!(output(&mut debugger, "rs 301").ends_with("ret\n"));
+        let frames = debugger.frames.clone();
+        assert_eq!(frames.len(), 300);
+        assert!(frames.iter().all(|frame| frame.call == Call::Near));
+        assert_eq!(frames[0].return_to, (0, 3));
+        assert!(frames[1..]
+            .iter()
+            .all(|frame| frame.return_to == (0, 10) && frame.target == 4));
+        output(&mut debugger, "rc");
+        assert!(debugger.frames.is_empty());
+    }
+}
@@ -0,0 +1,429 @@
+//! Decode 8086 machine code into [`Instruction`]s, by the forms in the [`table`].
+
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+use crate::instruction::{Base, Instruction, Memory, Operand, Register, Rep};
+use crate::table::{self, ModRm, Slot};
+
+struct Cursor<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl Cursor<'_> {
+    fn u8(&mut self) -> Option<u8> {
+        let byte = *self.bytes.get(self.position)?;
+        self.position += 1;
+        Some(byte)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_le_bytes([self.u8()?, self.u8()?]))
+    }
+
+    fn i16(&mut self, w: bool) -> Option<i16> {
+        if w {
+            Some(i16::from_le_bytes([self.u8()?, self.u8()?]))
+        } else {
+            Some(i16::from(i8::from_le_bytes([self.u8()?])))
+        }
+    }
+
+    /// DATA | DATA if W = 1. 8-bit data is sign-extended if S = 1.
+    fn data(&mut self, w: bool, s: bool) -> Option<Operand> {
+        let value = if w {
+            i32::from(self.u16()?)
+        } else if s {
+            i32::from(i8::from_le_bytes([self.u8()?]))
+        } else {
+            i32::from(self.u8()?)
+        };
+        Some(Operand::Immediate(value))
+    }
+
+    /// The operand identified by the MOD and R/M fields, reading any displacement.
+    fn r_m(&mut self, w: bool, m0d: u8, r_m: u8) -> Option<Operand> {
+        let (base, displacement) = match m0d {
+            // Memory mode. No displacement follows.*
+            0b00 => {
+                // Direct address. "Except when R/M = 110, then 16-bit displacement follows."
+                if r_m == 0b110 {
+                    (Base::Direct, self.i16(true)?)
+                } else {
+                    (Base::from_field(r_m), 0)
+                }
+            }
+            // Memory mode. 8-bit displacement follows.
+            0b01 => (Base::from_field(r_m), self.i16(false)?),
+            // Memory mode. 16-bit displacement follows.
+            0b10 => (Base::from_field(r_m), self.i16(true)?),
+            // Register mode. No displacement follows.
+            _ => return Some(Operand::Register(Register::from_field(w, r_m))),
+        };
+        Some(Operand::Memory(Memory { base, displacement }))
+    }
+}
+
+/// Split a MOD REG R/M byte into its fields.
+const fn fields(byte2: u8) -> (u8, u8, u8) {
+    (byte2 >> 6, (byte2 >> 3) & 0b111, byte2 & 0b111)
+}
+
+/// Decode the instruction at the start of `code`, including any prefixes.
+///
+/// Returns `None` if the bytes are truncated or do not encode an 8086 instruction.
+#[must_use]
+pub fn decode(code: &[u8]) -> Option<Instruction> {
+    let mut cursor = Cursor {
+        bytes: code,
+        position: 0,
+    };
+    let mut lock = false;
+    let mut rep = None;
+    let mut segment = None;
+
+    let mut byte1 = cursor.u8()?;
+    loop {
+        match byte1 {
+            0b11110000 => lock = true,
+            0b11110010 => rep = Some(Rep::Repne),
+            0b11110011 => rep = Some(Rep::Rep),
+            // SEGMENT. 001 SR 110
+            0b001_00_110 | 0b001_01_110 | 0b001_10_110 | 0b001_11_110 => {
+                segment = Some(Register::segment(byte1 >> 3));
+            }
+            _ => break,
+        }
+        byte1 = cursor.u8()?;
+    }
+
+    // The MOD REG R/M byte, or the fixed second byte, picks between the forms that begin with the same byte.
+    let byte2 = code.get(cursor.position).copied();
+    let form = table::decoding(byte1).find(|form| match form.modrm {
+        ModRm::Op(op) => byte2.is_some_and(|byte2| byte2 >> 3 & 0b111 == op),
+        ModRm::Byte(byte) => byte2 == Some(byte),
+        _ => true,
+    })?;
+    let w = form.is_wide(form.w.is_some_and(|bit| byte1 >> bit & 1 == 1));
+    let (m0d, reg, r_m) = if form.modrm == ModRm::None {
+        (0, 0, 0)
+    } else {
+        fields(cursor.u8()?)
+    };
+    let mut operands = [Operand::None; 2];
+    for (operand, slot) in operands.iter_mut().zip(form.operands) {
+        *operand = match slot {
+            Slot::None => Operand::None,
+            Slot::Rm | Slot::Mem => cursor.r_m(w, m0d, r_m)?,
+            Slot::Reg => Operand::Register(Register::from_field(w, reg)),
+            Slot::Sreg => Operand::Register(Register::segment(reg)),
+            Slot::OpReg => Operand::Register(Register::from_field(w, byte1)),
+            Slot::OpSreg => Operand::Register(Register::segment(byte1 >> 3)),
+            Slot::Acc => Operand::Register(Register::from_field(w, 0)),
+            Slot::Ax => Operand::Register(Register::Ax),
+            Slot::Cl => Operand::Register(Register::Cl),
+            Slot::Dx => Operand::Register(Register::Dx),
+            Slot::One => Operand::Immediate(1),
+            Slot::Data => cursor.data(w, false)?,
+            Slot::Signed => cursor.data(false, true)?,
+            Slot::Byte => cursor.data(false, false)?,
+            Slot::Word => cursor.data(true, false)?,
+            Slot::Direct => Operand::Memory(Memory {
+                base: Base::Direct,
+                displacement: cursor.i16(true)?,
+            }),
+            Slot::Short => Operand::Relative(cursor.i16(false)?),
+            Slot::Near => Operand::Relative(cursor.i16(true)?),
+            Slot::Far => {
+                let ip = cursor.u16()?;
+                Operand::Far(cursor.u16()?, ip)
+            }
+        };
+    }
+
+    Some(Instruction {
+        operation: form.operation,
+        operands,
+        size: u16::try_from(cursor.position).ok()?,
+        wide: w,
+        far: form.far,
+        lock,
+        rep,
+        segment,
+    })
+}
+
+/// Name the targets of relative jumps and calls like the disassembler in `main.rs`.
+///
+/// Labels are "label0", "label1" and so on, in the order that they are first referenced. Keys are offsets in
+/// `code`. Invalid bytes are skipped.
+#[must_use]
+pub fn labels(code: &[u8]) -> BTreeMap<usize, String> {
+    let mut labels = BTreeMap::new();
+    let mut position = 0;
+    while position < code.len() {
+        let Some(instruction) = decode(&code[position..]) else {
+            position += 1;
+            continue;
+        };
+        position += usize::from(instruction.size);
+        if let Operand::Relative(displacement) = instruction.operands[0] {
+            if let Some(target) = position.checked_add_signed(isize::from(displacement)) {
+                let length = labels.len();
+                labels.entry(target).or_insert_with(|| format!("label{length}"));
+            }
+        }
+    }
+    labels
+}
+
+/// The most bytes after its prefixes that decoding an instruction reads: the opcode, MOD REG R/M, a 16-bit
+/// displacement and 16-bit data, and the byte after a 1-byte instruction, which is read to pick between forms.
+pub(crate) const READ_AHEAD: usize = 7;
+
+pub(crate) const fn is_prefix(byte: u8) -> bool {
+    matches!(byte, 0b11110000 | 0b11110010 | 0b11110011) || byte & 0b111_00_111 == 0b001_00_110
+}
+
+/// The instructions in some code, one after another, which are decoded again only where the code changes.
+///
+/// Each entry is the offset of an instruction, or of a byte that doesn't begin one, which is skipped like in
+/// [`labels`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Listing {
+    entries: Vec<(usize, Option<Instruction>)>,
+}
+
+impl Listing {
+    #[must_use]
+    pub fn new(code: &[u8]) -> Self {
+        let mut listing = Self::default();
+        listing.decode(code, 0, |_| false);
+        listing
+    }
+
+    #[must_use]
+    pub fn entries(&self) -> &[(usize, Option<Instruction>)] {
+        &self.entries
+    }
+
+    /// The instruction that begins at `offset`, if one does.
+    #[must_use]
+    pub fn get(&self, offset: usize) -> Option<&Instruction> {
+        let index = self
+            .entries
+            .binary_search_by_key(&offset, |&(position, _)| position)
+            .ok()?;
+        self.entries[index].1.as_ref()
+    }
+
+    /// Decode from `position` to the end of `code` or until `stop` returns true for an offset, appending entries.
+    fn decode(&mut self, code: &[u8], mut position: usize, mut stop: impl FnMut(usize) -> bool) -> usize {
+        while position < code.len() && !stop(position) {
+            let instruction = decode(&code[position..]);
+            self.entries.push((position, instruction));
+            position += instruction.map_or(1, |instruction| usize::from(instruction.size));
+        }
+        position
+    }
+
+    /// Decode again after the bytes in `changed` are overwritten in `code`, which is the same length, and return the
+    /// offsets that were decoded again.
+    ///
+    /// Decoding begins at the first instruction that read a changed byte, and stops where an instruction after the
+    /// change begins at the same offset as before, because the instructions from there on are the same.
+    pub fn update(&mut self, code: &[u8], changed: Range<usize>) -> Range<usize> {
+        // Instructions before the change read at most their size and the byte after. Invalid bytes read any prefixes
+        // after them and as many bytes as an instruction, so an instruction before a run of prefixes may read the
+        // change.
+        let mut first = self.entries.partition_point(|&(position, _)| position < changed.start);
+        for (index, &(position, instruction)) in self.entries[..first].iter().enumerate().rev() {
+            let reach = instruction.map_or_else(
+                || position + code[position..].iter().take_while(|&&byte| is_prefix(byte)).count() + READ_AHEAD,
+                |instruction| position + usize::from(instruction.size) + 1,
+            );
+            if reach > changed.start {
+                first = index;
+            } else if !is_prefix(code[position]) && position + READ_AHEAD <= changed.start {
+                break;
+            }
+        }
+
+        let start = self.entries.get(first).map_or(code.len(), |&(position, _)| position);
+        let old = self.entries.split_off(first);
+        let mut resumed = old.len();
+        let end = self.decode(code, start, |position| {
+            position >= changed.end
+                && old
+                    .binary_search_by_key(&position, |&(position, _)| position)
+                    .is_ok_and(|index| {
+                        resumed = index;
+                        true
+                    })
+        });
+        self.entries.extend_from_slice(&old[resumed..]);
+        start..end
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::fs;
+    use std::time::Instant;
+
+    use proptest::prelude::*;
+
+    fn text(bytes: &[u8]) -> String {
+        decode(bytes).unwrap().to_string()
+    }
+
+    #[test]
+    fn register_and_memory() {
+        assert_eq!(text(&[0b10001001, 0b11_011_001]), "mov cx, bx");
+        assert_eq!(text(&[0b10001010, 0b01_100_000, 4]), "mov ah, [bx+si+4]");
+        assert_eq!(text(&[0b10001011, 0b01_011_110, 0]), "mov bx, [bp]");
+        assert_eq!(text(&[0b10001001, 0b10_001_100, 0xd4, 0xfe]), "mov word [si-300], cx");
+        assert_eq!(text(&[0b10100001, 0xfb, 0x09]), "mov ax, [+2555]");
+    }
+
+    #[test]
+    fn immediates() {
+        assert_eq!(text(&[0b10111011, 0x03, 0xf0]), "mov bx, 61443");
+        assert_eq!(text(&[0b10000011, 0b11_000_001, 0xa6]), "add cx, -90");
+        assert_eq!(text(&[0b11000110, 0b00_000_011, 7]), "mov byte [bp+di], 7");
+    }
+
+    #[test]
+    fn jumps() {
+        assert_eq!(text(&[0b01110101, 0xf8]), "jne $-6");
+        assert_eq!(text(&[0b11100000, 0xed]), "loopnz $-17");
+        assert_eq!(text(&[0b10011010, 123, 0, 0x00, 0x7d]), "call 32000:123");
+    }
+
+    #[test]
+    fn labeled() {
+        let code = [
+            &[0b01110101, 0x02][..],   // jne $+4
+            &[0b11101000, 0xfb, 0xff], // call $-2
+            &[0b11100010, 0xf9],       // loop $-5
+        ]
+        .concat();
+        let labels = labels(&code);
+        assert_eq!(labels.len(), 2);
+        assert_eq!(labels[&4], "label0");
+        assert_eq!(labels[&0], "label1");
+    }
+
+    #[test]
+    fn prefixes() {
+        let instruction = decode(&[0b11110000, 0b10000110, 0b00_000_111]).unwrap();
+        assert!(instruction.lock);
+        assert_eq!(instruction.size, 3);
+        assert_eq!(instruction.to_string(), "lock xchg byte [bx], al");
+        assert_eq!(text(&[0b11110011, 0b10100101]), "rep movsw");
+        assert_eq!(text(&[0b00100110, 0b10001011, 0b00_000_111]), "mov ax, es:[bx]");
+    }
+
+    #[test]
+    fn truncated() {
+        assert_eq!(decode(&[0b10111011, 0x03]), None);
+        assert_eq!(decode(&[0b11110011]), None);
+    }
+
+    #[test]
+    fn listing() {
+        let mut code = [
+            &[0b10111011, 0x03, 0xf0][..],           // mov bx, 61443
+            &[0b10001001, 0b11_011_001],             // mov cx, bx
+            &[0b11110000, 0b10000110, 0b00_000_111], // lock xchg byte [bx], al
+            &[0b01110101, 0xf8],                     // jne $-6
+        ]
+        .concat();
+        let mut listing = Listing::new(&code);
+        assert_eq!(listing.entries().len(), 4);
+        assert_eq!(listing.get(3).unwrap().to_string(), "mov cx, bx");
+        assert_eq!(listing.get(4), None);
+
+        // An immediate changes only its instruction, and the byte before the next one.
+        code[2] = 0x12;
+        assert_eq!(listing.update(&code, 2..3), 0..3);
+        assert_eq!(listing.get(0).unwrap().to_string(), "mov bx, 4611");
+        assert_eq!(listing, Listing::new(&code));
+
+        // A shorter instruction leaves an invalid byte, and falls into step again at the xchg.
+        code[3] = 0b01000011; // inc bx
+        assert_eq!(listing.update(&code, 3..4), 0..5);
+        assert_eq!(listing.get(3).unwrap().to_string(), "inc bx");
+        assert_eq!(listing.entries()[2], (4, None));
+        assert_eq!(listing, Listing::new(&code));
+    }
+
+    proptest! {
+        #[test]
+        fn listing_updates(
+            // Prefixes are common, so that runs of them reach the change.
+            mut code in proptest::collection::vec(prop_oneof![any::<u8>(), Just(0b11110011), Just(0b00100110)], 1..64),
+            patch in proptest::collection::vec(any::<u8>(), 1..8),
+            at in any::<prop::sample::Index>(),
+        ) {
+            let mut listing = Listing::new(&code);
+            let start = at.index(code.len());
+            let end = (start + patch.len()).min(code.len());
+            code[start..end].copy_from_slice(&patch[..end - start]);
+            listing.update(&code, start..end);
+            prop_assert_eq!(listing, Listing::new(&code));
+        }
+    }
+
+    // The instructions per second of a release build on this machine, which the throughput test records on its first
+    // run, or if RECORD_THROUGHPUT is set. It isn't committed, because it depends on the machine.
+    const BASELINE: &str = "target/throughput.txt";
+
+    /// Fail if decoding is slower than a fraction of the baseline, which THROUGHPUT_FRACTION sets (0.5 by default).
+    #[test]
+    #[ignore = "measures a release build: cargo test --release -- --ignored throughput"]
+    fn throughput() {
+        let mut listings: Vec<_> = fs::read_dir("perfaware/part1")
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_none())
+            .collect();
+        listings.sort();
+        let corpus: Vec<u8> = listings.iter().flat_map(|path| fs::read(path).unwrap()).collect();
+        let corpus = corpus.repeat(1024);
+
+        // The best of several runs is the least disturbed by other processes.
+        let (elapsed, count) = (0..5)
+            .map(|_| {
+                let start = Instant::now();
+                let mut count = 0;
+                let mut position = 0;
+                while position < corpus.len() {
+                    position += decode(&corpus[position..]).map_or(1, |instruction| usize::from(instruction.size));
+                    count += 1;
+                }
+                (start.elapsed(), count)
+            })
+            .min()
+            .unwrap();
+        #[allow(clippy::cast_precision_loss)]
+        let rate = f64::from(count) / elapsed.as_secs_f64();
+
+        let recorded = fs::read_to_string(BASELINE).ok();
+        let Some(baseline) = recorded.filter(|_| env::var_os("RECORD_THROUGHPUT").is_none()) else {
+            fs::write(BASELINE, format!("{rate:.0}\n")).unwrap();
+            eprintln!("recorded a baseline of {rate:.0} instructions per second in {BASELINE}");
+            return;
+        };
+        let baseline: f64 = baseline.trim().parse().unwrap();
+        let fraction = env::var("THROUGHPUT_FRACTION").map_or(0.5, |text| text.parse().unwrap());
+        assert!(
+            rate >= fraction * baseline,
+            "{rate:.0} instructions per second, below {fraction} of the baseline of {baseline:.0}"
+        );
+    }
+}
@@ -0,0 +1,161 @@
+//! Compare the architectural state of two machines: the registers, memory, console output, and how the programs
+//! stopped.
+//!
+//! The programs themselves differ, so their bytes in memory aren't compared. IP isn't compared when programs are run
+//! to completion, because a rewritten program can end at a different address.
+
+use std::fmt;
+use std::ops::Range;
+
+use crate::instruction::Register;
+use crate::sim::Machine;
+use crate::state::Target;
+
+const REGISTERS: [Target; 14] = [
+    Target::Register(Register::Ax),
+    Target::Register(Register::Bx),
+    Target::Register(Register::Cx),
+    Target::Register(Register::Dx),
+    Target::Register(Register::Sp),
+    Target::Register(Register::Bp),
+    Target::Register(Register::Si),
+    Target::Register(Register::Di),
+    Target::Register(Register::Es),
+    Target::Register(Register::Cs),
+    Target::Register(Register::Ss),
+    Target::Register(Register::Ds),
+    Target::Ip,
+    Target::Flags,
+];
+
+/// A difference between two machines, with the first machine's value then the second's.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Difference {
+    Register(Target, u16, u16),
+    /// Bytes of memory that differ, from a physical address.
+    Memory(usize, Vec<u8>, Vec<u8>),
+    /// The console output, from the first byte that differs.
+    Output(usize, Vec<u8>, Vec<u8>),
+    ExitCode(Option<u8>, Option<u8>),
+    Halted(bool, bool),
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Register(target, a, b) => write!(f, "{target}: {a:#06x} != {b:#06x}"),
+            Self::Memory(address, a, b) => write!(f, "[{address:#07x}]: {} != {}", hex(a), hex(b)),
+            Self::Output(offset, a, b) => write!(
+                f,
+                "output from byte {offset}: {:?} != {:?}",
+                String::from_utf8_lossy(a),
+                String::from_utf8_lossy(b)
+            ),
+            Self::ExitCode(a, b) => write!(f, "exit code: {a:?} != {b:?}"),
+            Self::Halted(a, b) => write!(f, "halted: {a} != {b}"),
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// The differences between two machines, except in the memory at the `ignored` addresses, and in IP unless `ip`.
+#[must_use]
+pub fn differences(a: &Machine, b: &Machine, ignored: &[Range<usize>], ip: bool) -> Vec<Difference> {
+    let mut differences = vec![];
+    for target in REGISTERS {
+        let (x, y) = (target.get(&a.registers), target.get(&b.registers));
+        if x != y && (ip || target != Target::Ip) {
+            differences.push(Difference::Register(target, x, y));
+        }
+    }
+
+    // Memory is compared a slice at a time, between the ignored addresses. Runs of differing bytes are one difference.
+    let mut ignored = ignored.to_vec();
+    ignored.sort_by_key(|range| range.start);
+    let size = a.memory.len();
+    let mut start = 0;
+    for range in ignored.iter().chain([&(size..size)]) {
+        let end = range.start.clamp(start, size);
+        if a.memory[start..end] != b.memory[start..end] {
+            compare_memory(a, b, start..end, &mut differences);
+        }
+        start = range.end.clamp(start, size);
+    }
+
+    if a.output != b.output {
+        let offset = a.output.iter().zip(&b.output).take_while(|(x, y)| x == y).count();
+        differences.push(Difference::Output(
+            offset,
+            a.output[offset..].to_vec(),
+            b.output[offset..].to_vec(),
+        ));
+    }
+    if a.exit_code != b.exit_code {
+        differences.push(Difference::ExitCode(a.exit_code, b.exit_code));
+    }
+    if a.halted != b.halted {
+        differences.push(Difference::Halted(a.halted, b.halted));
+    }
+    differences
+}
+
+fn compare_memory(a: &Machine, b: &Machine, range: Range<usize>, differences: &mut Vec<Difference>) {
+    let mut address = range.start;
+    while address < range.end {
+        if a.memory[address] == b.memory[address] {
+            address += 1;
+            continue;
+        }
+        let start = address;
+        while address < range.end && a.memory[address] != b.memory[address] {
+            address += 1;
+        }
+        differences.push(Difference::Memory(
+            start,
+            a.memory[start..address].to_vec(),
+            b.memory[start..address].to_vec(),
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compare() {
+        let mut a = Machine::new();
+        let mut b = Machine::new();
+        assert!(differences(&a, &b, &[], true).is_empty());
+
+        a.registers.set(Register::Al, 1);
+        a.registers.ip = 2;
+        a.load(0x100, &[1, 2, 3]);
+        b.load(0x100, &[1, 5, 6]);
+        a.load(0x10, &[0xff]);
+        a.output.extend(b"hello");
+        b.output.extend(b"help");
+        b.exit_code = Some(0);
+        assert_eq!(
+            differences(&a, &b, &[0x10..0x11, 0x200..0x300], false),
+            [
+                Difference::Register(Target::Register(Register::Ax), 1, 0),
+                Difference::Memory(0x101, vec![2, 3], vec![5, 6]),
+                Difference::Output(3, b"lo".to_vec(), b"p".to_vec()),
+                Difference::ExitCode(None, Some(0)),
+            ]
+        );
+        assert_eq!(differences(&a, &b, &[], true)[1].to_string(), "ip: 0x0002 != 0x0000");
+        assert_eq!(
+            Difference::Memory(0x101, vec![2, 3], vec![5, 6]).to_string(),
+            "[0x00101]: 02 03 != 05 06"
+        );
+    }
+}
@@ -0,0 +1,878 @@
+//! Disassemble 8086 machine code into NASM's syntax, which the assembler reads back.
+//!
+//! Each first byte has a handler in a table, which decodes the bytes that follow it. The text of each instruction
+//! is written as it's decoded, without allocating. A first pass names the targets of jumps, and a second writes the
+//! text, with labels at the targets that are the starts of instructions.
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::io::Write;
+use std::ops::Range;
+
+mod chunks;
+
+// The shortest run of padding that's written as one line with `times`.
+const MIN_RUN: usize = 8;
+
+const REG_NAMES: [[&str; 8]; 2] = [
+    ["al", "cl", "dl", "bl", "ah", "ch", "dh", "bh"],
+    ["ax", "cx", "dx", "bx", "sp", "bp", "si", "di"],
+];
+const R_M_NAMES: [&str; 8] = ["bx + si", "bx + di", "bp + si", "bp + di", "si", "di", "bp", "bx"];
+const SEGMENT_NAMES: [&str; 4] = ["es", "cs", "ss", "ds"];
+
+// "N/A" indices are "(not used)" according to the manual.
+const ASCII_ADJUST_NAMES: [&str; 2] = ["aam", "aad"];
+const BINARY_NAMES: [&str; 8] = ["add", "or", "adc", "sbb", "and", "sub", "xor", "cmp"];
+const CALL_NAMES: [&str; 2] = ["call", "jmp"];
+const LOGIC_NAMES: [&str; 8] = ["rol", "ror", "rcl", "rcr", "shl", "shr", "N/A", "sar"];
+const STACK_NAMES: [&str; 2] = ["push", "pop"];
+const UNARY_NAMES: [&str; 4] = ["inc", "dec", "push", "pop"];
+// I don't know what unifies these two groups of instructions, other than their first byte.
+const NAMES_1111011W: [&str; 8] = ["test", "N/A", "not", "neg", "mul", "imul", "div", "idiv"];
+const NAMES_11111111: [&str; 8] = ["inc", "dec", "call", "call", "jmp", "jmp", "push", "N/A"];
+const JUMP2_NAMES: [&str; 4] = ["loopnz", "loopz", "loop", "jcxz"];
+const JUMP4_NAMES: [&str; 16] = [
+    "jo", "jno", "jb", "jnb", "je", "jne", "jbe", "jnbe", "js", "jns", "jp", "jnp", "jl", "jnl", "jle", "jnle",
+];
+
+/// How to disassemble the instructions that begin with a byte, with the fields that the byte encodes.
+#[derive(Clone, Copy)]
+enum Handler {
+    /// MOD REG R/M follows. `d` is whether REG identifies the destination, and `sreg` whether it's a segment register.
+    RegRm {
+        op: &'static str,
+        d: bool,
+        w: usize,
+        sreg: bool,
+    },
+    /// MOD OP R/M follows, where OP picks the operation from the group.
+    OpRm {
+        group: u8,
+        s_v: bool,
+        w: usize,
+    },
+    /// MOV Immediate to register.
+    MovImmediate {
+        reg: &'static str,
+        w: bool,
+    },
+    /// Data, an address or a port follows, and the other operand is the accumulator.
+    Accumulator {
+        op: &'static str,
+        e: bool,
+        w: bool,
+        mov: bool,
+        in_out: bool,
+    },
+    /// PUSH POP INC DEC Register.
+    Register {
+        op: &'static str,
+        reg: &'static str,
+    },
+    /// PUSH POP Segment register.
+    Stack {
+        op: &'static str,
+        sg: &'static str,
+    },
+    /// A segment override for the next instruction.
+    Segment(&'static str),
+    /// XCHG Accumulator.
+    Exchange(&'static str),
+    /// IN OUT Accumulator, with the port in DX.
+    Port {
+        out: bool,
+        acc: &'static str,
+    },
+    /// RET RETF, with data.
+    Return(&'static str),
+    Interrupt,
+    Repeat,
+    /// A jump with an 8-bit increment.
+    Short(&'static str),
+    /// A call or jump with a 16-bit increment.
+    Near(&'static str),
+    /// A call or jump with a segment and offset.
+    Far(&'static str),
+    /// AAM AAD, which have a fixed second byte.
+    AsciiAdjust(&'static str),
+    /// One fixed byte, including its newline.
+    Fixed(&'static str),
+    Lock,
+    Invalid,
+}
+
+/// The handler of each first byte, so that every byte is either handled or explicitly invalid.
+static HANDLERS: [Handler; 256] = {
+    let mut handlers = [Handler::Invalid; 256];
+    let mut byte = 0;
+    while byte < 256 {
+        handlers[byte] = handler(byte as u8);
+        byte += 1;
+    }
+    handlers
+};
+
+#[allow(clippy::too_many_lines)]
+const fn handler(byte1: u8) -> Handler {
+    let w = (byte1 & 1) as usize;
+    match byte1 {
+        // Next bytes are: MOD REG R/M | (DISP-LO) | (DISP-HI)
+          0b00_000_0_00..=0b00_000_0_11 // 00 ADD 0 D W
+        | 0b00_001_0_00..=0b00_001_0_11 // 00 OR  0 D W
+        | 0b00_010_0_00..=0b00_010_0_11 // 00 ADC 0 D W
+        | 0b00_011_0_00..=0b00_011_0_11 // 00 SBB 0 D W
+        | 0b00_100_0_00..=0b00_100_0_11 // 00 AND 0 D W
+        | 0b00_101_0_00..=0b00_101_0_11 // 00 SUB 0 D W
+        | 0b00_110_0_00..=0b00_110_0_11 // 00 XOR 0 D W
+        | 0b00_111_0_00..=0b00_111_0_11 // 00 CMP 0 D W
+        => Handler::RegRm { op: BINARY_NAMES[((byte1 >> 3) & 0b111) as usize], d: byte1 & 0b10 != 0, w, sreg: false },
+        0b1000010_0..=0b1000010_1 // 10 000 1 0 W TEST
+        => Handler::RegRm { op: "test", d: false, w, sreg: false },
+        0b1000011_0..=0b1000011_1 // 10 000 1 1 W XCHG
+        => Handler::RegRm { op: "xchg", d: true, w, sreg: false },
+        0b100010_00..=0b100010_11 // 10 001 0 D W MOV
+        => Handler::RegRm { op: "mov", d: byte1 & 0b10 != 0, w, sreg: false },
+        // MOV with a segment register is wide.
+        0b100011_00 | 0b100011_10 // 10 001 S D 0 MOV
+        => Handler::RegRm { op: "mov", d: byte1 & 0b10 != 0, w: 1, sreg: true },
+        // LES, LDS and LEA use REG for the destination and are wide.
+        0b11000100 => Handler::RegRm { op: "les", d: true, w: 1, sreg: false },
+        0b11000101 => Handler::RegRm { op: "lds", d: true, w: 1, sreg: false },
+        0b10001101 => Handler::RegRm { op: "lea", d: true, w: 1, sreg: false },
+
+        // Next bytes are: MOD OP R/M | (DISP-LO) | (DISP-HI) | (DATA) | (DATA if cond = 1)
+        //
+        // TEST "Immediate data and register/memory" has the same first byte as NEG, etc.
+        // so they all have the same handler - but the latter do not have DATA bytes.
+        //
+        // It's OK to treat AND, OR, XOR as having an S bit (of 0). 1 is "(not used)" according to the manual.
+          0b100000_00..=0b100000_11 // 100000 S W ADC, ADD, AND, CMP, OR, SBB, SUB, XOR
+        | 0b1100011_0..=0b1100011_1 // 110001 1 W MOV
+        // The following do not have DATA bytes, except TEST.
+        | 0b10001111                // 100011 1 1 POP
+        | 0b110100_00..=0b110100_11 // 110100 V W SHL SHR SAR ROL ROR RCL RCR
+        | 0b1111011_0..=0b1111011_1 // 111101 1 W NEG, MUL, IMUL, DIV, IDIV, NOT, TEST
+        | 0b1111111_0..=0b1111111_1 // 111111 1 W INC, DEC
+                                    // 111111 1 1 PUSH, CALL, JMP
+        => Handler::OpRm { group: byte1 >> 2, s_v: byte1 & 0b10 != 0, w },
+
+        // MOV Immediate to register. First byte: 1011 W REG
+        0b1011_0_000..=0b1011_1_111 => {
+            let w = ((byte1 >> 3) & 1) as usize;
+            Handler::MovImmediate { reg: REG_NAMES[w][(byte1 & 0b111) as usize], w: w == 1 }
+        }
+
+        // Accumulator. Next bytes are either: DATA | DATA if W = 1, ADDR-LO | ADDR-HI, DATA-8.
+          0b00_000_10_0..=0b00_000_10_1 // 00 ADD 1 0 W
+        | 0b00_001_10_0..=0b00_001_10_1 // 00 OR  1 0 W
+        | 0b00_010_10_0..=0b00_010_10_1 // 00 ADC 1 0 W
+        | 0b00_011_10_0..=0b00_011_10_1 // 00 SBB 1 0 W
+        | 0b00_100_10_0..=0b00_100_10_1 // 00 AND 1 0 W
+        | 0b00_101_10_0..=0b00_101_10_1 // 00 SUB 1 0 W
+        | 0b00_110_10_0..=0b00_110_10_1 // 00 XOR 1 0 W
+        | 0b00_111_10_0..=0b00_111_10_1 // 00 CMP 1 0 W
+        | 0b101000_00..=0b101000_11     // 101000 E W MOV
+        | 0b1010100_0..=0b1010100_1     // 101010 0 W TEST
+        | 0b111001_00..=0b111001_11     // 111001 E W IN, OUT
+        => {
+            let op = match byte1 >> 1 {
+                0b1010000 | 0b1010001 => "mov",
+                0b1010100 => "test",
+                0b1110010 => "in",
+                0b1110011 => "out",
+                _ => BINARY_NAMES[((byte1 >> 3) & 0b111) as usize],
+            };
+            Handler::Accumulator {
+                op,
+                // E is the opposite of D.
+                e: byte1 & 0b10 == 0,
+                w: w == 1,
+                mov: byte1 >> 2 == 0b101000,
+                in_out: byte1 >> 2 == 0b111001,
+            }
+        }
+
+        // PUSH POP INC DEC Register. One byte: 010 OP REG
+        0b010_00_000..=0b010_11_111 => Handler::Register {
+            op: UNARY_NAMES[((byte1 >> 3) & 0b11) as usize],
+            reg: REG_NAMES[1][(byte1 & 0b111) as usize],
+        },
+
+        // PUSH POP Segment register. One byte.
+          0b000_00_11_0..=0b000_00_11_1 // 000 ES 11 OP
+        | 0b000_01_11_0..=0b000_01_11_1 // 000 CS 11 OP
+        | 0b000_10_11_0..=0b000_10_11_1 // 000 SS 11 OP
+        | 0b000_11_11_0..=0b000_11_11_1 // 000 DS 11 OP
+        => Handler::Stack { op: STACK_NAMES[w], sg: SEGMENT_NAMES[((byte1 >> 3) & 0b11) as usize] },
+
+        // SEGMENT. One byte.
+          0b001_00_110 // 001 ES 110
+        | 0b001_01_110 // 001 CS 110
+        | 0b001_10_110 // 001 SS 110
+        | 0b001_11_110 // 001 DS 110
+        => Handler::Segment(SEGMENT_NAMES[((byte1 >> 3) & 0b11) as usize]),
+
+        // XCHG Accumulator. One byte: 10010 REG
+        0b10010_000..=0b10010_111 => Handler::Exchange(REG_NAMES[1][(byte1 & 0b111) as usize]),
+
+        // IN OUT Accumulator. One byte: 111011 OUT W
+        0b111011_00..=0b111011_11 => Handler::Port { out: byte1 & 0b10 != 0, acc: REG_NAMES[w][0] },
+
+        // RET RETF. Fixed byte plus i16 data.
+        0b11000010 => Handler::Return("ret"),
+        0b11001010 => Handler::Return("retf"),
+
+        // INT. Fixed byte plus u8 data.
+        0b11001101 => Handler::Interrupt,
+
+        // REP. Fixed byte plus lookup table.
+        0b11110011 => Handler::Repeat,
+
+        0b11101011 => Handler::Short("jmp"), // JMP Direct within segment-short
+        0b111000_00..=0b111000_11 => Handler::Short(JUMP2_NAMES[(byte1 & 0b11) as usize]), // 111000 OP JUMP
+        0b0111_0000..=0b0111_1111 => Handler::Short(JUMP4_NAMES[(byte1 & 0b1111) as usize]), // 0111 OP JUMP
+
+        // CALL JMP Direct within segment. 1110100 OP
+        0b1110100_0 | 0b1110100_1 => Handler::Near(CALL_NAMES[w]),
+
+        // CALL JMP Direct intersegment.
+        // LSB bit 5 also works to map 0 to CALL and 1 to JMP.
+        0b1_001_1010 | 0b1_110_1010 => Handler::Far(CALL_NAMES[((byte1 >> 6) & 1) as usize]),
+
+        // Two fixed bytes.
+        0b1101010_0 | 0b1101010_1 => Handler::AsciiAdjust(ASCII_ADJUST_NAMES[w]),
+
+        // One fixed byte.
+        0b11010111 => Handler::Fixed("xlat\n"),
+        0b10011111 => Handler::Fixed("lahf\n"),
+        0b10011110 => Handler::Fixed("sahf\n"),
+        0b10011100 => Handler::Fixed("pushf\n"),
+        0b10011101 => Handler::Fixed("popf\n"),
+        0b00110111 => Handler::Fixed("aaa\n"),
+        0b00100111 => Handler::Fixed("daa\n"),
+        0b00111111 => Handler::Fixed("aas\n"),
+        0b00101111 => Handler::Fixed("das\n"),
+        0b10011000 => Handler::Fixed("cbw\n"),
+        0b10011001 => Handler::Fixed("cwd\n"),
+        0b11000011 => Handler::Fixed("ret\n"),
+        0b11001011 => Handler::Fixed("retf\n"),
+        0b11001100 => Handler::Fixed("int3\n"),
+        0b11001110 => Handler::Fixed("into\n"),
+        0b11001111 => Handler::Fixed("iret\n"),
+        0b11111000 => Handler::Fixed("clc\n"),
+        0b11110101 => Handler::Fixed("cmc\n"),
+        0b11111001 => Handler::Fixed("stc\n"),
+        0b11111100 => Handler::Fixed("cld\n"),
+        0b11111101 => Handler::Fixed("std\n"),
+        0b11111010 => Handler::Fixed("cli\n"),
+        0b11111011 => Handler::Fixed("sti\n"),
+        0b11110100 => Handler::Fixed("hlt\n"),
+        0b10011011 => Handler::Fixed("wait\n"),
+        0b11110000 => Handler::Lock,
+
+        _ => Handler::Invalid,
+    }
+}
+
+/// Bytes that pad code, with the bytes in each unit of padding and its text. Zeros are `add [bx+si], al` in pairs,
+/// and are written as data.
+const fn padding(byte1: u8) -> Option<(usize, &'static str)> {
+    match byte1 {
+        0b10010000 => Some((1, "nop")),
+        0b11001100 => Some((1, "int3")),
+        0b00000000 => Some((2, "dw 0")),
+        _ => None,
+    }
+}
+
+/// A position in the bytes of a file, which are read as they are decoded.
+struct Cursor<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl Cursor<'_> {
+    /// The position and value of the next byte, if any.
+    fn next(&mut self) -> Option<(usize, u8)> {
+        let byte = *self.bytes.get(self.position)?;
+        self.position += 1;
+        Some((self.position - 1, byte))
+    }
+
+    /// The next byte of an instruction. The bytes of an instruction that's cut off by the end are 0.
+    fn u8(&mut self) -> u8 {
+        let byte = self.bytes.get(self.position).copied().unwrap_or(0);
+        self.position += 1;
+        byte
+    }
+
+    fn i8(&mut self) -> i8 {
+        i8::from_le_bytes([self.u8()])
+    }
+
+    fn i16(&mut self, w: bool) -> i16 {
+        if w {
+            i16::from_le_bytes([self.u8(), self.u8()])
+        } else {
+            i16::from(self.i8())
+        }
+    }
+}
+
+/// A register or memory operand, with any segment override, which is written without allocating.
+struct RM {
+    segment: &'static str,
+    mode: Mode,
+}
+
+enum Mode {
+    Register(&'static str),
+    Direct(i16),
+    Memory(&'static str, i16),
+}
+
+impl fmt::Display for RM {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.segment.is_empty() {
+            write!(f, "{}:", self.segment)?;
+        }
+        match self.mode {
+            Mode::Register(name) => f.write_str(name),
+            Mode::Direct(address) => write!(f, "[{address}]"),
+            Mode::Memory(base, disp) => match disp.cmp(&0) {
+                Ordering::Greater => write!(f, "[{base} + {disp}]"),
+                Ordering::Less => write!(f, "[{base} - {}]", -i32::from(disp)),
+                Ordering::Equal => write!(f, "[{base}]"),
+            },
+        }
+    }
+}
+
+fn disassemble_r_m(cursor: &mut Cursor, w: usize, m0d: u8, r_m: usize, segment: &'static str) -> RM {
+    let mode = match m0d {
+        // Memory mode. No displacement follows.*
+        0b00 => {
+            // Direct address. "Except when R/M = 110, then 16-bit displacement follows."
+            if r_m == 0b110 {
+                Mode::Direct(cursor.i16(true))
+            } else {
+                Mode::Memory(R_M_NAMES[r_m], 0)
+            }
+        }
+        // Memory mode. 8-bit displacement follows.
+        0b01 => Mode::Memory(R_M_NAMES[r_m], cursor.i16(false)),
+        // Memory mode. 16-bit displacement follows.
+        0b10 => Mode::Memory(R_M_NAMES[r_m], cursor.i16(true)),
+        // Register mode. No displacement follows.
+        0b11 => Mode::Register(REG_NAMES[w][r_m]),
+        _ => unreachable!(),
+    };
+    RM { segment, mode }
+}
+
+/// The targets of jumps, numbered in the order that they're first referenced, and whether each is the start of an
+/// instruction, which is labeled. Other targets are written as offsets. The targets are sorted, so that they're found
+/// by binary search, without hashing.
+#[derive(Default)]
+pub struct Labels(Vec<Label>);
+
+struct Label {
+    target: usize,
+    number: usize,
+    start: bool,
+}
+
+impl Labels {
+    /// The index of a target, or where to insert it.
+    fn search(&self, target: usize) -> Result<usize, usize> {
+        self.0.binary_search_by_key(&target, |label| label.target)
+    }
+}
+
+/// A reference to a target, written without allocating.
+#[derive(Clone, Copy)]
+pub enum Target {
+    Label(usize),
+    Offset(usize),
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Label(number) => write!(f, "label{number}"),
+            Self::Offset(offset) => write!(f, "{offset}"),
+        }
+    }
+}
+
+/// How a pass of the disassembler names the starts of instructions and the targets of jumps.
+pub trait Targets {
+    /// The label of the start of an instruction, if it's a target.
+    fn start(&mut self, position: usize) -> Option<Target>;
+
+    /// A reference to the target of a jump.
+    fn reference(&mut self, target: usize) -> Target;
+
+    /// An instruction begins at `position`, with no prefix pending, so that decoding from it agrees with decoding
+    /// from any earlier boundary.
+    fn boundary(&mut self, _position: usize) {}
+}
+
+impl Targets for Labels {
+    fn start(&mut self, position: usize) -> Option<Target> {
+        let index = self.search(position).ok()?;
+        let label = &mut self.0[index];
+        label.start = true;
+        Some(Target::Label(label.number))
+    }
+
+    // A target is labeled if it's the start of an instruction. Targets before the reference are known to be starts
+    // from the instructions so far, and targets after it from the first pass.
+    fn reference(&mut self, target: usize) -> Target {
+        let label = match self.search(target) {
+            Ok(index) => &self.0[index],
+            Err(index) => {
+                let number = self.0.len();
+                self.0.insert(
+                    index,
+                    Label {
+                        target,
+                        number,
+                        start: false,
+                    },
+                );
+                &self.0[index]
+            }
+        };
+        if label.start {
+            Target::Label(label.number)
+        } else {
+            Target::Offset(target)
+        }
+    }
+}
+
+/// Disassemble, writing each instruction as it's decoded. A first pass names the targets of jumps, so that memory is
+/// only needed for the labels. Larger inputs are disassembled in chunks, in parallel, unless that would need more
+/// than `max_memory` bytes.
+pub fn run<W: Write>(bytes: &[u8], max_memory: Option<usize>, mut stdout: W) {
+    if bytes.len() >= 2 * chunks::SIZE && max_memory.is_none_or(|max| chunks::memory(bytes.len()) <= max) {
+        chunks::run(bytes, chunks::SIZE, chunks::OVERLAP, stdout);
+        return;
+    }
+    let mut labels = Labels::default();
+    disassemble(bytes, 0..bytes.len(), &mut labels, &mut |_| {});
+    writeln!(stdout, "bits 16").unwrap();
+    disassemble(bytes, 0..bytes.len(), &mut labels, &mut |text| {
+        stdout.write_fmt(text).unwrap()
+    });
+}
+
+/// Targets that aren't named, to measure decoding alone.
+pub struct Unnamed;
+
+impl Targets for Unnamed {
+    fn start(&mut self, _position: usize) -> Option<Target> {
+        None
+    }
+
+    fn reference(&mut self, target: usize) -> Target {
+        Target::Offset(target)
+    }
+}
+
+/// Disassemble the instructions that begin in `range`, passing the text of each to `out`, preceded by its label, if
+/// any. The start of the range must be a boundary. Return the first boundary at or after its end, or the end of the
+/// bytes.
+pub fn disassemble(
+    bytes: &[u8],
+    range: Range<usize>,
+    labels: &mut dyn Targets,
+    out: &mut dyn FnMut(fmt::Arguments),
+) -> usize {
+    macro_rules! emit {
+        ($position:expr, $($text:tt)*) => {{
+            if let Some(label) = labels.start($position) {
+                out(format_args!("{label}:\n"));
+            }
+            out(format_args!($($text)*));
+        }};
+    }
+
+    let mut cursor = Cursor {
+        bytes,
+        position: range.start,
+    };
+    // Segment override.
+    let mut segment = "";
+    // Override the order of operands to avoid "instruction is not lockable".
+    let mut locked = false;
+    let mut release_lock = false;
+
+    while let Some((position, byte1)) = cursor.next() {
+        if segment.is_empty() && !locked {
+            if position >= range.end {
+                return position;
+            }
+            labels.boundary(position);
+
+            // A run of padding is written as one line, which is split only at labels. The run is decoded as one
+            // instruction, so that it's the same however much of it is in a range.
+            if let Some((width, text)) = padding(byte1) {
+                let units = bytes[position..].iter().take_while(|&&byte| byte == byte1).count() / width;
+                if units >= MIN_RUN {
+                    let mut first = 0;
+                    for unit in 1..=units {
+                        if unit == units || labels.start(position + unit * width).is_some() {
+                            emit!(position + first * width, "times {} {text}\n", unit - first);
+                            first = unit;
+                        }
+                    }
+                    cursor.position = position + units * width;
+                    continue;
+                }
+            }
+        }
+
+        if locked {
+            release_lock = true;
+        }
+
+        match HANDLERS[usize::from(byte1)] {
+            Handler::RegRm { op, d, w, sreg } => {
+                // MOD REG R/M
+                let byte2 = cursor.u8();
+                let m0d = byte2 >> 6; // mod
+                let reg = ((byte2 >> 3) & 0b111) as usize;
+                let r_m = (byte2 & 0b111) as usize;
+
+                // The 8086 ignores the high bit of a segment register field.
+                let reg_text = if sreg {
+                    SEGMENT_NAMES[reg & 0b11]
+                } else {
+                    REG_NAMES[w][reg]
+                };
+                let r_m_text = disassemble_r_m(&mut cursor, w, m0d, r_m, segment);
+                segment = "";
+
+                // 1 = the REG field identifies the destination operand.
+                // 0 = the REG field identifies the source operand.
+                if d && !locked {
+                    emit!(position, "{op} {reg_text}, {r_m_text}\n");
+                } else {
+                    emit!(position, "{op} {r_m_text}, {reg_text}\n");
+                }
+            }
+
+            Handler::OpRm { group, s_v, w } => {
+                // MOD OP R/M
+                let byte2 = cursor.u8();
+                let m0d = byte2 >> 6; // mod
+                let op = ((byte2 >> 3) & 0b111) as usize;
+                let r_m = (byte2 & 0b111) as usize;
+
+                let mov_test = group == 0b110001 || group == 0b111101 && (byte2 >> 3).trailing_zeros() >= 3;
+
+                let op_text = match group {
+                    0b100011 => "pop",
+                    0b110001 => "mov",
+                    0b100000 => BINARY_NAMES[op],
+                    0b110100 => LOGIC_NAMES[op],
+                    0b111101 => NAMES_1111011W[op],
+                    0b111111 => NAMES_11111111[op],
+                    _ => unreachable!(),
+                };
+                let unit_text = if w == 1 { "word" } else { "byte" };
+                let r_m_text = disassemble_r_m(&mut cursor, w, m0d, r_m, segment);
+                segment = "";
+
+                // Binary instructions (MOV, TEST, ADD, etc.) have DATA bytes.
+                if mov_test || group == 0b100000 {
+                    // data | data if w = 1 for MOV and TEST. data | data if sw = 01 for ADD, etc.
+                    let data = cursor.i16((mov_test || !s_v) && w == 1);
+                    emit!(position, "{op_text} {r_m_text}, {unit_text} {data}\n");
+                // Logic instructions.
+                } else if group == 0b110100 {
+                    // 0 = Shift/rotate count is one. 1 = Shift/rotate count is specified in CL register.
+                    let count = if s_v { "cl" } else { "1" };
+                    emit!(position, "{op_text} {unit_text} {r_m_text}, {count}\n");
+                // Jump instructions. "Indirect intersegment."
+                } else if byte1 == 0b11111111 && (op == 0b011 || op == 0b101) {
+                    emit!(position, "{op_text} {unit_text} far {r_m_text}\n");
+                } else {
+                    emit!(position, "{op_text} {unit_text} {r_m_text}\n");
+                }
+            }
+
+            Handler::MovImmediate { reg, w } => {
+                // data | data if w = 1
+                let data = cursor.i16(w);
+
+                emit!(position, "mov {reg}, {data}\n");
+            }
+
+            Handler::Accumulator { op, e, w, mov, in_out } => {
+                let data = if in_out {
+                    // data-8
+                    i16::from(cursor.u8())
+                } else {
+                    // addr-lo | addr-hi or data | data if w = 1
+                    cursor.i16(mov || w)
+                };
+
+                let acc_text = if w { "ax" } else { "al" };
+                // MOV does "memory to accumulator", others do "immediate to accumulator".
+                match (e, mov) {
+                    (true, true) => emit!(position, "{op} {acc_text}, [{data}]\n"),
+                    (true, false) => emit!(position, "{op} {acc_text}, {data}\n"),
+                    (false, true) => emit!(position, "{op} [{data}], {acc_text}\n"),
+                    (false, false) => emit!(position, "{op} {data}, {acc_text}\n"),
+                }
+            }
+
+            Handler::Register { op, reg } => emit!(position, "{op} {reg}\n"),
+
+            Handler::Stack { op, sg } => emit!(position, "{op} {sg}\n"),
+
+            Handler::Segment(sg) => segment = sg,
+
+            Handler::Exchange(reg) => emit!(position, "xchg ax, {reg}\n"),
+
+            Handler::Port { out, acc } => {
+                if out {
+                    emit!(position, "out dx, {acc}\n");
+                } else {
+                    emit!(position, "in {acc}, dx\n");
+                }
+            }
+
+            Handler::Return(op) => {
+                let data = cursor.i16(true);
+
+                emit!(position, "{op} {data}\n");
+            }
+
+            Handler::Interrupt => {
+                let data = cursor.u8();
+
+                emit!(position, "int {data}\n");
+            }
+
+            Handler::Repeat => {
+                // 1010 OP W
+                let byte2 = cursor.u8();
+                let op = (byte2 >> 1) & 0b111;
+                let w = byte2 & 1 == 1;
+
+                let op_text = match op {
+                    0b010 => "movs",
+                    0b011 => "cmps",
+                    0b101 => "stos",
+                    0b110 => "lods",
+                    0b111 => "scas",
+                    _ => "",
+                };
+                let unit_text = if w { "w" } else { "b" };
+
+                if byte2 >> 4 != 0b1010 || op_text.is_empty() {
+                    // The next byte is another instruction, and the prefix is written as data.
+                    cursor.position -= 1;
+                    emit!(position, "db {byte1}\n");
+                } else {
+                    emit!(position, "rep {op_text}{unit_text}\n");
+                }
+            }
+
+            Handler::Short(op) => {
+                let ip_inc8 = cursor.i8();
+
+                // This instruction is 2 bytes.
+                let target = position.wrapping_add_signed(2 + ip_inc8 as isize);
+                let label = labels.reference(target);
+
+                emit!(position, "{op} {label} ; {ip_inc8} short\n");
+            }
+
+            Handler::Near(op) => {
+                let ip_inc = cursor.i16(true);
+
+                // This instruction is 3 bytes.
+                let target = position.wrapping_add_signed(3 + ip_inc as isize);
+                let label = labels.reference(target);
+
+                emit!(position, "{op} {label} ; {ip_inc}\n");
+            }
+
+            Handler::Far(op) => {
+                let ip = cursor.i16(true);
+                let cs = cursor.i16(true);
+
+                emit!(position, "{op} {cs}:{ip}\n");
+            }
+
+            Handler::AsciiAdjust(op) => {
+                let byte2 = cursor.u8();
+
+                // The second byte is the base, which is 10 unless it's written.
+                if byte2 == 0b00001010 {
+                    emit!(position, "{op}\n");
+                } else {
+                    emit!(position, "{op} {byte2}\n");
+                }
+            }
+
+            Handler::Fixed(op) => emit!(position, "{op}"),
+
+            Handler::Lock => {
+                locked = true;
+                emit!(position, "lock ");
+            }
+
+            // Bytes that aren't instructions are written as data, like padding.
+            Handler::Invalid => emit!(position, "db {byte1}\n"),
+        }
+
+        if release_lock {
+            locked = false;
+            release_lock = false;
+        }
+    }
+    bytes.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::io;
+    use std::iter;
+    use std::process::Command;
+
+    use tempfile::tempdir;
+
+    use crate::asm;
+
+    /// Assemble with NASM, or return `None` if NASM isn't installed.
+    fn nasm(assembly: &str) -> Option<Vec<u8>> {
+        let dir = tempdir().unwrap();
+        let assembly_path = dir.path().join("test.asm");
+        let binary_path = dir.path().join("test");
+        fs::write(&assembly_path, assembly).unwrap();
+
+        let status = match Command::new("nasm")
+            .args(["-o", binary_path.to_str().unwrap(), assembly_path.to_str().unwrap()])
+            .status()
+        {
+            Ok(status) => status,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return None,
+            Err(error) => panic!("failed to execute process: {error}"),
+        };
+
+        assert!(status.success());
+        Some(fs::read(binary_path).unwrap())
+    }
+
+    /// Disassemble a listing, reassemble it, and compare. NASM also reassembles it, if it's installed.
+    fn check(test_path: &str) {
+        let mut assembly = vec![];
+        run(&fs::read(test_path).unwrap(), None, &mut assembly);
+        let assembly = String::from_utf8(assembly).unwrap();
+        println!("{assembly}");
+
+        let expected = fs::read(test_path).unwrap();
+        assert_eq!(asm::assemble(&assembly).unwrap(), expected);
+        if let Some(actual) = nasm(&assembly) {
+            assert_eq!(actual, expected);
+        }
+    }
+
+    include!(concat!(env!("OUT_DIR"), "/main.include"));
+
+    #[test]
+    fn padding() {
+        let bytes = [
+            &[0b11101011, 5][..], // jmp $+7
+            &[0b10010000; 20],
+            &[0; 17],
+            &[0b11001100; 9],
+            &[0b11110100],
+        ]
+        .concat();
+        let mut assembly = vec![];
+        run(&bytes, None, &mut assembly);
+        let assembly = String::from_utf8(assembly).unwrap();
+        let lines: Vec<_> = assembly.lines().skip(2).collect();
+        assert_eq!(
+            lines,
+            [
+                "times 5 nop",
+                "label0:",
+                "times 15 nop",
+                "times 8 dw 0",
+                "add ah, cl",
+                "times 8 int3",
+                "hlt"
+            ]
+        );
+        assert_eq!(asm::assemble(&assembly).unwrap(), bytes);
+        if let Some(actual) = nasm(&assembly) {
+            assert_eq!(actual, bytes);
+        }
+    }
+
+    #[test]
+    fn targets() {
+        // Jumps to the next instruction, so that label1 is a prefix of label10 and label11.
+        let bytes = [[0b11101011, 0].repeat(12), vec![0b11110100]].concat();
+        let mut assembly = vec![];
+        run(&bytes, None, &mut assembly);
+        let assembly = String::from_utf8(assembly).unwrap();
+        let lines: Vec<_> = assembly.lines().collect();
+        assert_eq!(lines[4..6], ["label1:", "jmp label2 ; 0 short"]);
+        assert_eq!(lines[22..26], ["label10:", "jmp label11 ; 0 short", "label11:", "hlt"]);
+        assert_eq!(asm::assemble(&assembly).unwrap(), bytes);
+    }
+
+    #[test]
+    fn invalid() {
+        let bytes = [
+            0b01100000, // an alias of jo
+            0b11110011, // rep
+            0b11111100, // cld
+            0b11110100, // hlt
+        ];
+        let mut assembly = vec![];
+        run(&bytes, None, &mut assembly);
+        let assembly = String::from_utf8(assembly).unwrap();
+        let lines: Vec<_> = assembly.lines().skip(1).collect();
+        assert_eq!(lines, ["db 96", "db 243", "cld", "hlt"]);
+        assert_eq!(asm::assemble(&assembly).unwrap(), bytes);
+        if let Some(actual) = nasm(&assembly) {
+            assert_eq!(actual, bytes);
+        }
+    }
+
+    #[test]
+    fn handlers() {
+        let invalid: Vec<u8> = (0..=255)
+            .filter(|&byte| matches!(HANDLERS[usize::from(byte)], Handler::Invalid))
+            .collect();
+        let expected: Vec<u8> = iter::empty()
+            // Aliases of the conditional jumps on the 8086, and other instructions on later processors.
+            .chain(0x60..=0x6f)
+            // String instructions, which are only disassembled after REP.
+            .chain([0xa4, 0xa5, 0xa6, 0xa7, 0xaa, 0xab, 0xac, 0xad, 0xae, 0xaf])
+            // Aliases of RET and RETF on the 8086.
+            .chain([0xc0, 0xc1, 0xc8, 0xc9])
+            // SALC, which is undocumented.
+            .chain([0xd6])
+            // ESC.
+            .chain(0xd8..=0xdf)
+            // An alias of LOCK on the 8086, and REPNE.
+            .chain([0xf1, 0xf2])
+            .collect();
+        assert_eq!(invalid, expected);
+    }
+}
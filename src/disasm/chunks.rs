@@ -0,0 +1,210 @@
+//! Disassemble large inputs in chunks, in parallel.
+//!
+//! A chunk's first instruction isn't known until the chunk before it is decoded, so each chunk is decoded from a little
+//! before its start. Decoding from the wrong byte usually falls into step with the instructions within a few bytes,
+//! because instructions are short. The instructions that precede the first boundary that a chunk agrees on with the
+//! chunk before it are decoded again, in order. Labels are then numbered across the chunks, and the chunks are written
+//! in parallel.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Write;
+use std::ops::Range;
+
+use rayon::prelude::*;
+
+use super::{disassemble, Target, Targets};
+use crate::text::Arena;
+
+/// The bytes in a chunk.
+pub const SIZE: usize = 1 << 20;
+/// The bytes before a chunk that are decoded to find its first instruction.
+pub const OVERLAP: usize = 64;
+
+/// The bytes of memory that disassembling in chunks needs for each byte of input, for its marks and for the text and
+/// the ends of its lines, which are kept until every chunk is written.
+const MEMORY_PER_BYTE: usize = 16;
+
+const START: u8 = 1;
+const BOUNDARY: u8 = 2;
+
+/// The starts of instructions, the boundaries and the references in a range of the bytes.
+struct Scan {
+    base: usize,
+    // START and BOUNDARY, for each byte after the base.
+    marks: Vec<u8>,
+    // The boundary before each reference, and its target.
+    references: Vec<(usize, usize)>,
+    boundary: usize,
+}
+
+impl Scan {
+    const fn new(base: usize) -> Self {
+        Self {
+            base,
+            marks: vec![],
+            references: vec![],
+            boundary: base,
+        }
+    }
+
+    fn mark(&mut self, position: usize, mark: u8) {
+        let index = position - self.base;
+        if index >= self.marks.len() {
+            self.marks.resize(index + 1, 0);
+        }
+        self.marks[index] |= mark;
+    }
+
+    fn is_boundary(&self, position: usize) -> bool {
+        self.marks
+            .get(position - self.base)
+            .is_some_and(|mark| mark & BOUNDARY != 0)
+    }
+
+    /// Add the starts and references in `range`, which is decoded correctly.
+    fn collect(&self, range: Range<usize>, starts: &mut [bool], references: &mut Vec<usize>) {
+        for (position, mark) in (self.base..).zip(&self.marks) {
+            if range.contains(&position) && mark & START != 0 {
+                starts[position] = true;
+            }
+        }
+        references.extend(
+            self.references
+                .iter()
+                .filter(|(boundary, _)| range.contains(boundary))
+                .map(|&(_, target)| target),
+        );
+    }
+}
+
+impl Targets for Scan {
+    fn start(&mut self, position: usize) -> Option<Target> {
+        self.mark(position, START);
+        None
+    }
+
+    fn reference(&mut self, target: usize) -> Target {
+        self.references.push((self.boundary, target));
+        Target::Offset(target)
+    }
+
+    fn boundary(&mut self, position: usize) {
+        self.mark(position, BOUNDARY);
+        self.boundary = position;
+    }
+}
+
+/// The numbers of the targets that are the starts of instructions.
+struct Resolved<'a>(&'a HashMap<usize, usize>);
+
+impl Targets for Resolved<'_> {
+    fn start(&mut self, position: usize) -> Option<Target> {
+        self.0.get(&position).map(|&number| Target::Label(number))
+    }
+
+    fn reference(&mut self, target: usize) -> Target {
+        self.start(target).unwrap_or(Target::Offset(target))
+    }
+}
+
+/// About the most bytes of memory that disassembling `len` bytes in chunks needs.
+pub const fn memory(len: usize) -> usize {
+    len.saturating_mul(MEMORY_PER_BYTE)
+}
+
+/// Disassemble in chunks of `size` bytes, each decoded from `overlap` bytes before it, writing the same text as
+/// disassembling in one pass.
+pub fn run<W: Write>(bytes: &[u8], size: usize, overlap: usize, mut stdout: W) {
+    let scans: Vec<(Scan, usize)> = (0..bytes.len())
+        .step_by(size)
+        .collect::<Vec<_>>()
+        .into_par_iter()
+        .map(|start| {
+            let begin = start.saturating_sub(overlap);
+            let mut scan = Scan::new(begin);
+            let end = disassemble(bytes, begin..start + size, &mut scan, &mut |_| {});
+            (scan, end)
+        })
+        .collect();
+
+    // The boundaries at which each chunk's instructions are written from, in order.
+    let mut entries = vec![];
+    let mut starts = vec![false; bytes.len()];
+    let mut references = vec![];
+    let mut position = 0;
+    for (scan, end) in &scans {
+        entries.push(position);
+        // Decode in order until the chunk agrees on a boundary.
+        let mut walk = Scan::new(position);
+        while position < *end && !scan.is_boundary(position) {
+            position = disassemble(bytes, position..position + 1, &mut walk, &mut |_| {});
+        }
+        walk.collect(walk.base..position, &mut starts, &mut references);
+        if position < *end {
+            scan.collect(position..*end, &mut starts, &mut references);
+            position = *end;
+        }
+    }
+    entries.push(position);
+
+    // Targets are numbered in the order that they're first referenced, and labeled if they're starts.
+    let mut numbers = HashMap::new();
+    for target in references {
+        let count = numbers.len();
+        numbers.entry(target).or_insert(count);
+    }
+    numbers.retain(|&target, _| starts.get(target) == Some(&true));
+
+    // Each chunk's text is written into an arena, so that the instructions don't allocate one by one.
+    let texts: Vec<Arena> = entries
+        .par_windows(2)
+        .map(|range| {
+            let mut text = Arena::default();
+            let mut out = |arguments: fmt::Arguments| {
+                text.push(arguments);
+            };
+            disassemble(bytes, range[0]..range[1], &mut Resolved(&numbers), &mut out);
+            text
+        })
+        .collect();
+    writeln!(stdout, "bits 16").unwrap();
+    for text in texts {
+        stdout.write_all(text.as_str().as_bytes()).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    #[test]
+    fn chunks() {
+        let mut listings: Vec<_> = fs::read_dir("perfaware/part1")
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_none())
+            .collect();
+        listings.sort();
+        let mut bytes: Vec<u8> = listings.iter().flat_map(|path| fs::read(path).unwrap()).collect();
+        // The chunks mostly begin inside instructions.
+        bytes.extend(listings.iter().rev().flat_map(|path| fs::read(path).unwrap()));
+        // Runs of padding are longer than some chunks.
+        bytes.extend([[0b10010000; 40], [0; 40], [0b11001100; 40]].concat());
+        bytes.extend(fs::read(&listings[0]).unwrap());
+
+        let mut expected = vec![];
+        crate::disasm::run(&bytes, None, &mut expected);
+        // Without an overlap, chunks are more often decoded again from their boundary with the chunk before.
+        for size in [1, 2, 3, 5, 16, 100, 1000] {
+            for overlap in [0, 1, OVERLAP] {
+                let mut actual = vec![];
+                run(&bytes, size, overlap, &mut actual);
+                let (actual, expected) = (String::from_utf8_lossy(&actual), String::from_utf8_lossy(&expected));
+                assert_eq!(actual, expected, "size {size}, overlap {overlap}");
+            }
+        }
+    }
+}
@@ -0,0 +1,361 @@
+//! Load .COM and .EXE programs, and emulate the DOS services that small programs use most.
+//!
+//! Only INT 20h (exit), and INT 21h functions 00h and 4Ch (exit), 02h and 09h (console output), and 3Fh and 40h
+//! (read and write standard handles) are supported. Standard output and standard error are both the console, as in DOS.
+
+// Byte registers and counts from CX fit in their casts.
+#![allow(clippy::cast_possible_truncation)]
+
+use std::io::{self, ErrorKind};
+use std::ops::Range;
+
+use crate::instruction::{Instruction, Register};
+use crate::sim::{physical, Error, Machine, CF};
+
+/// The segment of the program segment prefix (PSP), which precedes the program.
+pub const PSP_SEGMENT: u16 = 0x1000;
+const PSP_SIZE: u16 = 0x100;
+
+// DOS error codes.
+const INVALID_HANDLE: u16 = 6;
+const GENERAL_FAILURE: u16 = 0x1f;
+
+/// Load a .COM program after the PSP, with every segment register set to the PSP segment, like DOS.
+///
+/// Returns the physical addresses of the program.
+///
+/// # Errors
+///
+/// If the program doesn't fit in a segment, or the segment doesn't fit in memory.
+pub fn load_com(machine: &mut Machine, program: &[u8]) -> Result<Range<usize>, String> {
+    // The stack starts at the top of the segment.
+    if program.len() > usize::from(0xfffe - PSP_SIZE) {
+        return Err("program is too large for a .COM file".to_string());
+    }
+    if physical(PSP_SEGMENT, 0) + 0x10000 > machine.memory.len() {
+        return Err("memory is too small for a .COM program".to_string());
+    }
+    write_psp(machine);
+    let start = physical(PSP_SEGMENT, PSP_SIZE);
+    machine.load(start, program);
+    for register in [Register::Es, Register::Cs, Register::Ss, Register::Ds] {
+        machine.registers.set(register, PSP_SEGMENT);
+    }
+    machine.registers.ip = PSP_SIZE;
+    // A return from the program pops 0, where the PSP's INT 20h terminates it.
+    machine.registers.set(Register::Sp, 0xfffe);
+    Ok(start..start + program.len())
+}
+
+/// Load an MZ .EXE program after the PSP, relocating its segment references, and set the registers from its
+/// header. DS and ES are set to the PSP segment, like DOS.
+///
+/// Returns the physical addresses of the program.
+///
+/// # Errors
+///
+/// If the header is invalid, or the program doesn't fit in memory.
+pub fn load_exe(machine: &mut Machine, file: &[u8]) -> Result<Range<usize>, String> {
+    let word = |offset: usize| {
+        file.get(offset..offset + 2)
+            .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+            .ok_or_else(|| "truncated .EXE header".to_string())
+    };
+    if !file.starts_with(b"MZ") {
+        return Err("missing .EXE signature".to_string());
+    }
+    let last_page_size = usize::from(word(0x02)?);
+    let pages = usize::from(word(0x04)?);
+    let relocations = usize::from(word(0x06)?);
+    let header_size = usize::from(word(0x08)?) * 16;
+    let relocation_table = usize::from(word(0x18)?);
+
+    // The last 512-byte page is partial, unless its size is 0.
+    let mut end = pages * 512;
+    if last_page_size != 0 {
+        end = end.saturating_sub(512 - last_page_size);
+    }
+    let image = file
+        .get(header_size..end)
+        .ok_or_else(|| "truncated .EXE image".to_string())?;
+    let segment = PSP_SEGMENT + PSP_SIZE / 16;
+    let start = physical(segment, 0);
+    if start + image.len() > machine.memory.len() {
+        return Err("program does not fit in memory".to_string());
+    }
+    // Each relocation is the offset and segment of a word, relative to the image, to which the image's segment is
+    // added. They are all read before anything is loaded, so that an invalid table leaves the machine as it was.
+    let relocations = (0..relocations)
+        .map(|i| {
+            let entry = relocation_table + i * 4;
+            Ok((word(entry)?, segment.wrapping_add(word(entry + 2)?)))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    write_psp(machine);
+    machine.load(start, image);
+    for (offset, relocated) in relocations {
+        let address = [
+            machine.address(relocated, offset),
+            machine.address(relocated, offset.wrapping_add(1)),
+        ];
+        let value = u16::from_le_bytes(address.map(|address| machine.memory[address])).wrapping_add(segment);
+        for (address, byte) in address.into_iter().zip(value.to_le_bytes()) {
+            machine.memory[address] = byte;
+        }
+    }
+
+    machine.registers.set(Register::Es, PSP_SEGMENT);
+    machine.registers.set(Register::Ds, PSP_SEGMENT);
+    machine.registers.set(Register::Ss, segment.wrapping_add(word(0x0e)?));
+    machine.registers.set(Register::Sp, word(0x10)?);
+    machine.registers.set(Register::Cs, segment.wrapping_add(word(0x16)?));
+    machine.registers.ip = word(0x14)?;
+    Ok(start..start + image.len())
+}
+
+/// Write the parts of the PSP that programs commonly use: INT 20h at offset 0, the segment after the program's
+/// memory, and an empty command line.
+fn write_psp(machine: &mut Machine) {
+    let start = physical(PSP_SEGMENT, 0);
+    machine.load(start, &[0xcd, 0x20, 0x00, 0xa0]);
+    machine.load(start + 0x80, &[0, b'\r']);
+}
+
+/// Emulate INT 20h or INT 21h.
+///
+/// # Errors
+///
+/// If the function in AH isn't supported.
+pub(crate) fn interrupt(machine: &mut Machine, number: u8, instruction: &Instruction) -> Result<(), Error> {
+    if number == 0x20 {
+        machine.exit_code = Some(0);
+        return Ok(());
+    }
+    // Memory smaller than 1 MiB repeats through the address space.
+    let size = machine.memory.len();
+    let registers = &mut machine.registers;
+    let ds = registers.get(Register::Ds);
+    let dx = registers.get(Register::Dx);
+    match registers.get(Register::Ah) {
+        // Write the character in DL.
+        0x02 => {
+            let character = registers.get(Register::Dl);
+            machine.output.push(character as u8);
+            registers.set(Register::Al, character);
+        }
+        // Write the string at DS:DX, up to a "$". Without a "$", stop after the whole segment, since the loop runs
+        // within one instruction.
+        0x09 => {
+            let bytes = (0..=u16::MAX)
+                .map(|i| machine.memory[physical(ds, dx.wrapping_add(i)) % size])
+                .take_while(|&character| character != b'$');
+            machine.output.extend(bytes);
+            registers.set(Register::Al, u16::from(b'$'));
+        }
+        // Read or write up to CX bytes at DS:DX from or to the handle in BX. Set CF and AX to an error code on
+        // failure, or clear CF and set AX to the number of bytes.
+        function @ (0x3f | 0x40) => {
+            let handle = registers.get(Register::Bx);
+            let count = usize::from(registers.get(Register::Cx));
+            let result = match (function, handle) {
+                (0x3f, 0) => {
+                    let mut buffer = vec![0; count];
+                    // A key that the keyboard read, but the program didn't take, comes first.
+                    let waiting = match machine.keyboard.waiting.take() {
+                        Some(byte) if count > 0 => {
+                            buffer[0] = byte;
+                            1
+                        }
+                        _ => 0,
+                    };
+                    let result = read(&mut machine.input, &mut buffer[waiting..]).map(|count| count + waiting);
+                    result.inspect(|&count| {
+                        for (i, &byte) in (0..=u16::MAX).zip(&buffer[..count]) {
+                            machine.store(physical(ds, dx.wrapping_add(i)) % size, byte);
+                        }
+                    })
+                }
+                (0x40, 1 | 2) => {
+                    let bytes = (0..count as u16).map(|i| machine.memory[physical(ds, dx.wrapping_add(i)) % size]);
+                    machine.output.extend(bytes);
+                    Ok(count)
+                }
+                _ => Err(INVALID_HANDLE),
+            };
+            let registers = &mut machine.registers;
+            registers.set_flag(CF, result.is_err());
+            registers.set(Register::Ax, result.map_or_else(|code| code, |count| count as u16));
+        }
+        // Exit.
+        0x00 => machine.exit_code = Some(0),
+        // Exit with the code in AL.
+        0x4c => machine.exit_code = Some(registers.get(Register::Al) as u8),
+        _ => return Err(Error::Unsupported(*instruction)),
+    }
+    Ok(())
+}
+
+/// Read from the console, retrying if interrupted.
+fn read(input: &mut impl io::Read, buffer: &mut [u8]) -> Result<usize, u16> {
+    loop {
+        match input.read(buffer) {
+            Ok(count) => return Ok(count),
+            Err(error) if error.kind() == ErrorKind::Interrupted => {}
+            Err(_) => return Err(GENERAL_FAILURE),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    fn run(machine: &mut Machine) {
+        while machine.exit_code.is_none() {
+            machine.step().unwrap();
+        }
+    }
+
+    #[test]
+    fn com() {
+        let program = [
+            &[0b10110100, 0x09][..],   // mov ah, 9
+            &[0b10111010, 0x12, 0x01], // mov dx, 274
+            &[0b11001101, 0x21],       // int 33
+            &[0b10110100, 0x02],       // mov ah, 2
+            &[0b10110010, b'!'],       // mov dl, 33
+            &[0b11001101, 0x21],       // int 33
+            &[0b10111000, 0x03, 0x4c], // mov ax, 19459
+            &[0b11001101, 0x21],       // int 33
+            b"hi$",
+        ]
+        .concat();
+        let mut machine = Machine::new();
+        let range = load_com(&mut machine, &program).unwrap();
+        assert_eq!(range, 0x10100..0x10100 + program.len());
+        run(&mut machine);
+        assert_eq!(machine.output, b"hi!");
+        assert_eq!(machine.exit_code, Some(3));
+    }
+
+    #[test]
+    fn handles() {
+        let program = [
+            &[0b10110100, 0x3f][..],     // mov ah, 63
+            &[0b10111011, 0, 0],         // mov bx, 0
+            &[0b10111001, 10, 0],        // mov cx, 10
+            &[0b10111010, 0, 2],         // mov dx, 512
+            &[0b11001101, 0x21],         // int 33
+            &[0b10001001, 0b11_000_001], // mov cx, ax
+            &[0b10110100, 0x40],         // mov ah, 64
+            &[0b10111011, 1, 0],         // mov bx, 1
+            &[0b11001101, 0x21],         // int 33
+            &[0b10110100, 0x40],         // mov ah, 64
+            &[0b10111011, 9, 0],         // mov bx, 9
+            &[0b11001101, 0x21],         // int 33
+        ]
+        .concat();
+        let mut machine = Machine::new();
+        machine.input = Box::new(Cursor::new(b"abc\r\n"));
+        load_com(&mut machine, &program).unwrap();
+        for _ in 0..9 {
+            machine.step().unwrap();
+        }
+        assert_eq!(machine.output, b"abc\r\n");
+        assert_eq!(machine.registers.get(Register::Ax), 5);
+        assert!(!machine.registers.flag(CF));
+        for _ in 0..3 {
+            machine.step().unwrap();
+        }
+        assert_eq!(machine.registers.get(Register::Ax), INVALID_HANDLE);
+        assert!(machine.registers.flag(CF));
+    }
+
+    #[test]
+    fn wrap() {
+        let program = [
+            &[0b10110100, 0x40][..],   // mov ah, 64
+            &[0b10111011, 1, 0],       // mov bx, 1
+            &[0b10111001, 4, 0],       // mov cx, 4
+            &[0b10111010, 0xfe, 0xff], // mov dx, 65534
+            &[0b11001101, 0x21],       // int 33
+            &[0b10110100, 0x3f],       // mov ah, 63
+            &[0b10111011, 0, 0],       // mov bx, 0
+            &[0b11001101, 0x21],       // int 33
+        ]
+        .concat();
+        let mut machine = Machine::new();
+        machine.input = Box::new(Cursor::new(b"wxyz"));
+        load_com(&mut machine, &program).unwrap();
+        machine.load(physical(PSP_SEGMENT, 0xfffe), b"ab");
+        for _ in 0..5 {
+            machine.step().unwrap();
+        }
+        // The write continues at the start of the segment, with the PSP's INT 20h.
+        assert_eq!(machine.output, b"ab\xcd\x20");
+        assert_eq!(machine.registers.get(Register::Ax), 4);
+        for _ in 0..3 {
+            machine.step().unwrap();
+        }
+        let start = physical(PSP_SEGMENT, 0);
+        assert_eq!(&machine.memory[start + 0xfffe..start + 0x10000], b"wx");
+        assert_eq!(&machine.memory[start..start + 2], b"yz");
+    }
+
+    #[test]
+    fn unterminated() {
+        let program = [
+            &[0b10110100, 0x09][..], // mov ah, 9
+            &[0b11001101, 0x21],     // int 33
+        ]
+        .concat();
+        let mut machine = Machine::new();
+        load_com(&mut machine, &program).unwrap();
+        machine.step().unwrap();
+        machine.step().unwrap();
+        // Without a "$", the string ends after the segment.
+        assert_eq!(machine.output.len(), 0x10000);
+    }
+
+    #[test]
+    fn exe() {
+        let mut file = vec![0; 0x20];
+        for (offset, value) in [
+            (0x02, 0x23), // bytes on the last page
+            (0x04, 1),    // pages
+            (0x06, 1),    // relocations
+            (0x08, 2),    // header paragraphs
+            (0x0e, 0x10), // SS
+            (0x10, 0x80), // SP
+            (0x14, 0),    // IP
+            (0x16, 0),    // CS
+            (0x18, 0x1c), // relocation table
+            (0x1c, 1),    // relocation offset
+        ] {
+            file[offset..offset + 2].copy_from_slice(&u16::to_le_bytes(value));
+        }
+        file[..2].copy_from_slice(b"MZ");
+        // mov ax, seg+1
+        file.extend_from_slice(&[0b10111000, 1, 0]);
+
+        let mut machine = Machine::new();
+        let range = load_exe(&mut machine, &file).unwrap();
+        assert_eq!(range, 0x10100..0x10103);
+        assert_eq!(machine.registers.get(Register::Cs), 0x1010);
+        assert_eq!(machine.registers.get(Register::Ss), 0x1020);
+        assert_eq!(machine.registers.get(Register::Ds), PSP_SEGMENT);
+        machine.step().unwrap();
+        assert_eq!(machine.registers.get(Register::Ax), 0x1011);
+
+        assert!(load_exe(&mut machine, b"MZ").is_err());
+
+        // A relocation past the end of the file leaves the machine as it was.
+        file[0x18..0x1a].copy_from_slice(&u16::to_le_bytes(0x100));
+        let mut machine = Machine::new();
+        assert_eq!(load_exe(&mut machine, &file), Err("truncated .EXE header".to_string()));
+        assert_eq!(machine.memory, Machine::new().memory);
+    }
+}
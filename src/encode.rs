@@ -0,0 +1,535 @@
+//! Encode [`Instruction`]s into 8086 machine code, the inverse of [`decode`](crate::decode).
+//!
+//! Where an instruction has several encodings, the encoder picks NASM's, so that assembled programs match NASM's
+//! binaries: the shortest form, with the special forms for the accumulator and sign-extended immediates, and with
+//! register-to-register operations encoded from the R/M field to the REG field. A relative JMP is short unless the
+//! instruction is wide. Prefixes are LOCK, then REP, then the segment override.
+//!
+//! The encodings and the encoder's preferences are the forms in the [`table`].
+//!
+//! [`Overrides`] force longer encodings, to reproduce binaries that don't use the shortest forms.
+
+// Fields and immediates fit in their casts once they are checked.
+#![allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+
+use crate::instruction::{Base, Instruction, Memory, Operand, Operation, Register, Rep};
+use crate::table::{self, ModRm, Slot};
+
+/// Choices of encodings that override the shortest forms.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Overrides {
+    /// Encode word immediates as words, rather than as sign-extended bytes.
+    pub strict: bool,
+    /// Encode displacements as words, or as bytes even if they are 0, rather than in the fewest bytes.
+    pub displacement: Option<bool>,
+}
+
+/// Whether a value fits in a sign-extended byte.
+#[must_use]
+pub const fn is_sbyte(value: i32) -> bool {
+    -128 <= value && value <= 127
+}
+
+/// The bytes of immediate data: a byte, or a word if `wide`. Bytes can be signed or unsigned, and so can words.
+fn data(value: i32, wide: bool) -> Result<Vec<u8>, String> {
+    if wide {
+        if !(-0x8000..=0xffff).contains(&value) {
+            return Err(format!("{value} doesn't fit in a word"));
+        }
+        Ok((value as u16).to_le_bytes().to_vec())
+    } else {
+        if !(-0x80..=0xff).contains(&value) {
+            return Err(format!("{value} doesn't fit in a byte"));
+        }
+        Ok(vec![value as u8])
+    }
+}
+
+/// A MOD REG R/M byte, and any displacement, for a register or memory operand. The displacement is a word or a
+/// byte if `size` says so, and otherwise as short as it can be.
+fn mod_reg_r_m(reg: u8, r_m: Operand, size: Option<bool>) -> Result<Vec<u8>, String> {
+    let memory = match r_m {
+        Operand::Register(register) if !register.is_segment() => {
+            return Ok(vec![0b11_000_000 | reg << 3 | register.field()]);
+        }
+        Operand::Memory(memory) => memory,
+        _ => return Err("expected a register or memory operand".to_string()),
+    };
+    let Memory { base, displacement } = memory;
+    if base == Base::Direct {
+        let [low, high] = displacement.to_le_bytes();
+        return Ok(vec![reg << 3 | 0b110, low, high]);
+    }
+    let field = (0..8)
+        .find(|&field| Base::from_field(field) == base)
+        .unwrap_or_default();
+    let fits = is_sbyte(i32::from(displacement));
+    if size == Some(false) && !fits {
+        return Err(format!("{displacement} doesn't fit in a byte displacement"));
+    }
+    // [BP] has no encoding without a displacement, because its R/M field is the direct address's.
+    if displacement == 0 && base != Base::Bp && size.is_none() {
+        Ok(vec![reg << 3 | field])
+    } else if fits && size != Some(true) {
+        Ok(vec![0b01_000_000 | reg << 3 | field, displacement as u8])
+    } else {
+        let [low, high] = displacement.to_le_bytes();
+        Ok(vec![0b10_000_000 | reg << 3 | field, low, high])
+    }
+}
+
+/// Encode an instruction, without prefixes.
+fn opcode(instruction: &Instruction, overrides: Overrides) -> Result<Vec<u8>, String> {
+    let operation = instruction.operation;
+    let invalid = || Err(format!("invalid operands for {}", operation.name()));
+
+    // Registers have the operation's size, except the count of a shift or rotate and the port of IN or OUT. Only MOV,
+    // PUSH and POP take segment registers, which are words.
+    for (index, operand) in instruction.operands.into_iter().enumerate() {
+        let Operand::Register(register) = operand else {
+            continue;
+        };
+        if register.is_segment() && !matches!(operation, Operation::Mov | Operation::Push | Operation::Pop) {
+            return invalid();
+        }
+        let count = index == 1
+            && register == Register::Cl
+            && table::forms(operation).iter().any(|form| form.operands[1] == Slot::Cl);
+        let port = matches!(operation, Operation::In | Operation::Out) && register == Register::Dx;
+        if !count && !port && register.is_wide() != instruction.wide {
+            return Err("mismatched operand sizes".to_string());
+        }
+    }
+    // The 8086 decodes POP CS, but later processors don't.
+    if (operation, instruction.operands[0]) == (Operation::Pop, Operand::Register(Register::Cs)) {
+        return invalid();
+    }
+    let Some(form) = table::find(instruction, overrides.strict) else {
+        return invalid();
+    };
+
+    let w = form.is_wide(instruction.wide);
+    let mut opcode = form.opcode | form.w.map_or(0, |bit| u8::from(w) << bit);
+    let mut reg = 0;
+    let mut r_m = None;
+    for (slot, operand) in form.operands.into_iter().zip(instruction.operands) {
+        match (slot, operand) {
+            (Slot::OpReg, Operand::Register(register)) => opcode |= register.field(),
+            (Slot::OpSreg, Operand::Register(register)) => opcode |= register.field() << 3,
+            (Slot::Reg | Slot::Sreg, Operand::Register(register)) => reg = register.field(),
+            // LEA LDS LES and indirect intersegment jumps and calls take memory.
+            (Slot::Mem, Operand::Register(_)) => return invalid(),
+            (Slot::Rm | Slot::Mem, _) => r_m = Some(operand),
+            _ => {}
+        }
+    }
+    let mut bytes = vec![opcode];
+    match form.modrm {
+        ModRm::Byte(byte) => bytes.push(byte),
+        ModRm::Op(op) => reg = op,
+        ModRm::None | ModRm::Reg | ModRm::Any => {}
+    }
+    if let Some(r_m) = r_m {
+        bytes.extend(mod_reg_r_m(reg, r_m, overrides.displacement)?);
+    }
+    for (slot, operand) in form.operands.into_iter().zip(instruction.operands) {
+        match (slot, operand) {
+            (Slot::Data, Operand::Immediate(value)) => bytes.extend(data(value, w)?),
+            // Words are sign-extended from bytes where they can be, even for the accumulator.
+            (Slot::Signed, Operand::Immediate(value)) => {
+                data(value, true)?;
+                bytes.extend(data(i32::from(value as u16 as i16), false)?);
+            }
+            (Slot::Byte, Operand::Immediate(value)) => bytes.extend(data(value, false)?),
+            (Slot::Word, Operand::Immediate(value)) => bytes.extend(data(value, true)?),
+            (Slot::Direct, Operand::Memory(memory)) => bytes.extend(memory.displacement.to_le_bytes()),
+            (Slot::Short, Operand::Relative(displacement)) => {
+                if !is_sbyte(i32::from(displacement)) {
+                    return Err(format!("short jump of {displacement} is out of range"));
+                }
+                bytes.push(displacement as u8);
+            }
+            (Slot::Near, Operand::Relative(displacement)) => bytes.extend(displacement.to_le_bytes()),
+            (Slot::Far, Operand::Far(segment, offset)) => {
+                bytes.extend(offset.to_le_bytes());
+                bytes.extend(segment.to_le_bytes());
+            }
+            _ => {}
+        }
+    }
+    Ok(bytes)
+}
+
+/// Encode an instruction, with its prefixes, in its shortest form.
+///
+/// # Errors
+///
+/// If the operation has no encoding with the operands, or an immediate or displacement is out of range.
+pub fn encode(instruction: &Instruction) -> Result<Vec<u8>, String> {
+    encode_with(instruction, Overrides::default())
+}
+
+/// Encode an instruction, with its prefixes, in the form that overrides choose.
+///
+/// # Errors
+///
+/// If the operation has no encoding with the operands, or an immediate or displacement is out of range.
+pub fn encode_with(instruction: &Instruction, overrides: Overrides) -> Result<Vec<u8>, String> {
+    let mut bytes = vec![];
+    if instruction.lock {
+        bytes.push(0b11110000);
+    }
+    match instruction.rep {
+        Some(Rep::Rep) => bytes.push(0b11110011),
+        Some(Rep::Repne) => bytes.push(0b11110010),
+        None => {}
+    }
+    if let Some(segment) = instruction.segment {
+        bytes.push(0b001_00_110 | segment.field() << 3);
+    }
+    bytes.extend(opcode(instruction, overrides)?);
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use proptest::prelude::*;
+    use proptest::sample::select;
+
+    use crate::decode::decode;
+
+    fn register(wide: bool) -> impl Strategy<Value = Operand> {
+        (0..8u8).prop_map(move |field| Operand::Register(Register::from_field(wide, field)))
+    }
+
+    fn segment() -> impl Strategy<Value = Operand> {
+        (0..4u8).prop_map(|field| Operand::Register(Register::segment(field)))
+    }
+
+    fn memory() -> impl Strategy<Value = Operand> {
+        (0..9u8, any::<i16>()).prop_map(|(field, displacement)| {
+            let base = if field == 8 {
+                Base::Direct
+            } else {
+                Base::from_field(field)
+            };
+            Operand::Memory(Memory { base, displacement })
+        })
+    }
+
+    fn r_m(wide: bool) -> impl Strategy<Value = Operand> {
+        prop_oneof![register(wide), memory()]
+    }
+
+    /// Immediate data of a size, as the decoder reads it.
+    fn data(wide: bool) -> impl Strategy<Value = Operand> {
+        (0..=if wide { 0xffff } else { 0xff }).prop_map(Operand::Immediate)
+    }
+
+    /// Instructions as the decoder writes them, in each of the encodings that the encoder picks.
+    #[allow(clippy::too_many_lines)]
+    fn instruction() -> impl Strategy<Value = Instruction> {
+        use Operand::{Far, Immediate, Register as Reg, Relative};
+        use Operation as Op;
+
+        let binary = vec![Op::Add, Op::Or, Op::Adc, Op::Sbb, Op::And, Op::Sub, Op::Xor, Op::Cmp];
+        let logic = vec![Op::Rol, Op::Ror, Op::Rcl, Op::Rcr, Op::Shl, Op::Shr, Op::Sar];
+        let unary = vec![Op::Not, Op::Neg, Op::Mul, Op::Imul, Op::Div, Op::Idiv, Op::Inc, Op::Dec];
+        let fixed = [
+            Op::Xlat,
+            Op::Lahf,
+            Op::Sahf,
+            Op::Pushf,
+            Op::Popf,
+            Op::Aaa,
+            Op::Daa,
+            Op::Aas,
+            Op::Das,
+            Op::Cbw,
+            Op::Cwd,
+            Op::Ret,
+            Op::Retf,
+            Op::Int3,
+            Op::Into,
+            Op::Iret,
+            Op::Clc,
+            Op::Cmc,
+            Op::Stc,
+            Op::Cld,
+            Op::Std,
+            Op::Cli,
+            Op::Sti,
+            Op::Hlt,
+            Op::Wait,
+            Op::Aam,
+            Op::Aad,
+        ];
+        let short = vec![
+            Op::Jo,
+            Op::Jno,
+            Op::Jb,
+            Op::Jnb,
+            Op::Je,
+            Op::Jne,
+            Op::Jbe,
+            Op::Jnbe,
+            Op::Js,
+            Op::Jns,
+            Op::Jp,
+            Op::Jnp,
+            Op::Jl,
+            Op::Jnl,
+            Op::Jle,
+            Op::Jnle,
+            Op::Loopnz,
+            Op::Loopz,
+            Op::Loop,
+            Op::Jcxz,
+            Op::Jmp,
+        ];
+
+        let instruction = prop_oneof![
+            // REG to/from R/M. TEST is written R/M first, XCHG REG first, and XCHG with AX has AX first.
+            (
+                select([&binary[..], &[Op::Mov, Op::Test, Op::Xchg]].concat()),
+                any::<bool>(),
+                any::<bool>()
+            )
+                .prop_flat_map(|(operation, wide, reverse)| {
+                    (register(wide), r_m(wide)).prop_map(move |(reg, r_m)| {
+                        let reverse = match operation {
+                            Op::Test => true,
+                            Op::Xchg => r_m == Reg(Register::Ax),
+                            _ => reverse,
+                        };
+                        Instruction::new(operation, if reverse { [r_m, reg] } else { [reg, r_m] }, wide)
+                    })
+                }),
+            (segment(), r_m(true), any::<bool>()).prop_map(move |(sr, r_m, reverse)| {
+                Instruction::new(Op::Mov, if reverse { [r_m, sr] } else { [sr, r_m] }, true)
+            }),
+            (select(vec![Op::Lea, Op::Lds, Op::Les]), register(true), memory())
+                .prop_map(move |(operation, reg, memory)| Instruction::new(operation, [reg, memory], true)),
+            // Immediates. Word immediates of binary operations that fit in bytes are sign-extended.
+            (select(binary), any::<bool>()).prop_flat_map(|(operation, wide)| {
+                let data = if wide { -0x80..=0xff7f } else { 0..=0xff };
+                (r_m(wide), data)
+                    .prop_map(move |(r_m, value)| Instruction::new(operation, [r_m, Immediate(value)], wide))
+            }),
+            (select(vec![Op::Mov, Op::Test]), any::<bool>()).prop_flat_map(|(operation, wide)| {
+                (r_m(wide), data(wide)).prop_map(move |(r_m, data)| Instruction::new(operation, [r_m, data], wide))
+            }),
+            // R/M only.
+            (select(logic), any::<bool>()).prop_flat_map(move |(operation, wide)| {
+                let count = prop_oneof![Just(Immediate(1)), Just(Reg(Register::Cl))];
+                (r_m(wide), count).prop_map(move |(r_m, count)| Instruction::new(operation, [r_m, count], wide))
+            }),
+            (select(unary), any::<bool>()).prop_flat_map(move |(operation, wide)| {
+                r_m(wide).prop_map(move |r_m| Instruction::new(operation, [r_m, Operand::None], wide))
+            }),
+            (select(vec![Op::Push, Op::Pop]), prop_oneof![r_m(true), segment()])
+                .prop_filter("POP CS has no encoding", |&(operation, operand)| {
+                    (operation, operand) != (Op::Pop, Reg(Register::Cs))
+                })
+                .prop_map(move |(operation, operand)| Instruction::new(operation, [operand, Operand::None], true)),
+            (select(vec![Op::Call, Op::Jmp]), r_m(true), any::<bool>()).prop_map(move |(operation, r_m, far)| {
+                let mut instruction = Instruction::new(operation, [r_m, Operand::None], true);
+                instruction.far = far && matches!(r_m, Operand::Memory(_));
+                instruction
+            }),
+            // IN OUT
+            (
+                any::<bool>(),
+                any::<bool>(),
+                prop_oneof![data(false), Just(Reg(Register::Dx))]
+            )
+                .prop_map(|(out, wide, port)| {
+                    let accumulator = Reg(Register::from_field(wide, 0));
+                    if out {
+                        Instruction::new(Op::Out, [port, accumulator], wide)
+                    } else {
+                        Instruction::new(Op::In, [accumulator, port], wide)
+                    }
+                }),
+            // No operands, or fixed ones.
+            (
+                select(vec![Op::Movs, Op::Cmps, Op::Stos, Op::Lods, Op::Scas]),
+                any::<bool>()
+            )
+                .prop_map(|(operation, wide)| Instruction::new(operation, [Operand::None; 2], wide)),
+            select(fixed.to_vec()).prop_map(|operation| Instruction::new(operation, [Operand::None; 2], false)),
+            (select(vec![Op::Ret, Op::Retf]), data(true)).prop_map(move |(operation, data)| Instruction::new(
+                operation,
+                [data, Operand::None],
+                true
+            )),
+            data(false).prop_map(move |data| Instruction::new(Op::Int, [data, Operand::None], false)),
+            // Jumps.
+            (select(short), any::<i8>()).prop_map(move |(operation, displacement)| Instruction::new(
+                operation,
+                [Relative(displacement.into()), Operand::None],
+                false
+            )),
+            (select(vec![Op::Call, Op::Jmp]), any::<i16>()).prop_map(
+                move |(operation, displacement)| Instruction::new(
+                    operation,
+                    [Relative(displacement), Operand::None],
+                    true
+                )
+            ),
+            (select(vec![Op::Call, Op::Jmp]), any::<u16>(), any::<u16>()).prop_map(
+                move |(operation, segment, offset)| Instruction::new(
+                    operation,
+                    [Far(segment, offset), Operand::None],
+                    true
+                )
+            ),
+        ];
+        let prefixes = (
+            any::<bool>(),
+            select(vec![None, Some(Rep::Rep), Some(Rep::Repne)]),
+            0..5u8,
+        );
+        (instruction, prefixes).prop_map(|(mut instruction, (lock, rep, segment))| {
+            instruction.lock = lock;
+            instruction.rep = rep;
+            instruction.segment = (segment < 4).then(|| Register::segment(segment));
+            instruction
+        })
+    }
+
+    /// An instruction without the choices between its encodings: its size, the sign of its immediate, and the order of
+    /// XCHG's operands.
+    fn normalize(mut instruction: Instruction) -> Instruction {
+        instruction.size = 0;
+        let mask = if instruction.wide { 0xffff } else { 0xff };
+        for operand in &mut instruction.operands {
+            if let Operand::Immediate(value) = operand {
+                *value &= mask;
+            }
+        }
+        if instruction.operation == Operation::Xchg && instruction.operands[1] == Operand::Register(Register::Ax) {
+            instruction.operands.swap(0, 1);
+        }
+        instruction
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(2048))]
+
+        #[test]
+        fn decode_inverts_encode(instruction in instruction()) {
+            let bytes = encode(&instruction).map_err(TestCaseError::fail)?;
+            let size = bytes.len() as u16;
+            prop_assert_eq!(decode(&bytes), Some(Instruction { size, ..instruction }), "{:02x?}", bytes);
+        }
+
+        #[test]
+        fn encode_inverts_decode(bytes in proptest::collection::vec(any::<u8>(), 1..8)) {
+            let Some(instruction) = decode(&bytes) else {
+                return Ok(());
+            };
+            // These decode, but their effects aren't defined, and they have no assembly.
+            let undefined = match (instruction.operation, instruction.operands[0]) {
+                (Operation::Lea | Operation::Lds | Operation::Les, _) => {
+                    matches!(instruction.operands[1], Operand::Register(_))
+                }
+                (Operation::Pop, Operand::Register(Register::Cs)) => true,
+                (_, Operand::Register(_)) => instruction.far,
+                _ => false,
+            };
+            if undefined {
+                return Ok(());
+            }
+            let encoded = encode(&instruction).map_err(TestCaseError::fail)?;
+            // The encoder picks the shortest encoding.
+            prop_assert!(encoded.len() <= usize::from(instruction.size), "{:02x?}", encoded);
+            let again = decode(&encoded).map(normalize);
+            prop_assert_eq!(again, Some(normalize(instruction)), "{:02x?}", encoded);
+        }
+    }
+
+    /// Decode bytes, encode the instruction, and check that the bytes are the same.
+    fn round_trip(bytes: &[u8]) {
+        let instruction = decode(bytes).unwrap();
+        assert_eq!(encode(&instruction).unwrap(), bytes, "{instruction}");
+    }
+
+    #[test]
+    fn canonical() {
+        round_trip(&[0b10001001, 0b11_011_001]); // mov cx, bx
+        round_trip(&[0b10001010, 0b01_100_000, 4]); // mov ah, [bx+si+4]
+        round_trip(&[0b10001011, 0b01_011_110, 0]); // mov bx, [bp]
+        round_trip(&[0b10001001, 0b10_001_100, 0xd4, 0xfe]); // mov word [si-300], cx
+        round_trip(&[0b10100001, 0xfb, 0x09]); // mov ax, [+2555]
+        round_trip(&[0b10111011, 0x03, 0xf0]); // mov bx, 61443
+        round_trip(&[0b10000011, 0b11_000_001, 0xa6]); // add cx, -90
+        round_trip(&[0b00000101, 0xe8, 0x03]); // add ax, 1000
+        round_trip(&[0b11000110, 0b00_000_011, 7]); // mov byte [bp+di], 7
+        round_trip(&[0b01110101, 0xf8]); // jne $-6
+        round_trip(&[0b10011010, 123, 0, 0x00, 0x7d]); // call 32000:123
+        round_trip(&[0b11110000, 0b00101110, 0b11110110, 0b10_010_110, 0xb1, 0x26]); // lock not byte cs:[bp+9905]
+        round_trip(&[0b11110011, 0b10100101]); // rep movsw
+        round_trip(&[0b10010010]); // xchg ax, dx
+        round_trip(&[0b11111111, 0b00_101_101]); // jmp far [di]
+        round_trip(&[0b11010100, 0b00001010]); // aam
+    }
+
+    #[test]
+    fn overrides() {
+        let encoded = |bytes: &[u8], strict, displacement| {
+            encode_with(&decode(bytes).unwrap(), Overrides { strict, displacement }).unwrap()
+        };
+        let add = [0b10000011, 0b11_000_001, 0xa6]; // add cx, -90
+        assert_eq!(encoded(&add, true, None), [0b10000001, 0b11_000_001, 0xa6, 0xff]);
+        let add = [0b10000011, 0b11_000_000, 5]; // add ax, 5
+        assert_eq!(encoded(&add, true, None), [0b00000101, 5, 0]);
+        let mov = [0b10001011, 0b00_011_111]; // mov bx, [bx]
+        assert_eq!(encoded(&mov, false, Some(false)), [0b10001011, 0b01_011_111, 0]);
+        assert_eq!(encoded(&mov, false, Some(true)), [0b10001011, 0b10_011_111, 0, 0]);
+        let mov = [0b10001011, 0b10_011_111, 0x2c, 0x01]; // mov bx, [bx+300]
+        assert!(encode_with(
+            &decode(&mov).unwrap(),
+            Overrides {
+                strict: false,
+                displacement: Some(false)
+            }
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn invalid() {
+        let instruction = |operation, operands, wide| Instruction::new(operation, operands, wide);
+        let ax = Operand::Register(Register::Ax);
+        assert!(encode(&instruction(Operation::Mov, [Operand::Immediate(1), ax], true)).is_err());
+        assert!(encode(&instruction(Operation::Mov, [ax, Operand::Immediate(0x10000)], true)).is_err());
+        assert!(encode(&instruction(
+            Operation::Mov,
+            [ax, Operand::Register(Register::Bl)],
+            true
+        ))
+        .is_err());
+        assert!(encode(&instruction(
+            Operation::Add,
+            [ax, Operand::Register(Register::Ds)],
+            true
+        ))
+        .is_err());
+        assert!(encode(&instruction(Operation::Stos, [Operand::None, ax], true)).is_err());
+        assert!(encode(&instruction(
+            Operation::Pop,
+            [Operand::Register(Register::Cs), Operand::None],
+            true
+        ))
+        .is_err());
+        assert!(encode(&instruction(
+            Operation::Je,
+            [Operand::Relative(200), Operand::None],
+            false
+        ))
+        .is_err());
+    }
+}
@@ -0,0 +1,609 @@
+//! Generate the input of Part 2's haversine distance program: pairs of points on the Earth, as JSON.
+//!
+//! The file is `{"pairs":[{"x0":…, "y0":…, "x1":…, "y1":…}, …]}`, where x is a longitude from -180 to 180 degrees and
+//! y is a latitude from -90 to 90 degrees. The same seed generates the same file.
+//!
+//! The answers are the distance of each pair and then their average, as little-endian `f64`s, so that a program's
+//! results can be checked bit for bit. Coordinates are written with as many digits as round-trip, so that they're
+//! parsed as the values that the answers are computed from.
+//!
+//! [`stream`] computes the distances of pairs as they're parsed from a reader, for files that don't fit in memory.
+
+use std::io::{self, BufRead, Write};
+use std::panic;
+use std::str::FromStr;
+use std::thread;
+
+use crate::json::{self, Element};
+use crate::math::{Custom, Math, Std};
+use crate::profile;
+use crate::random::Random;
+use crate::simd::{self, Simd};
+use crate::{time_bandwidth, time_function};
+
+/// How points are spread over the Earth.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Mode {
+    /// Anywhere, so that the average distance is about the same for any seed.
+    #[default]
+    Uniform,
+    /// In clusters, each around a random center, so that the average distance varies with the seed.
+    Cluster,
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "uniform" => Ok(Self::Uniform),
+            "cluster" => Ok(Self::Cluster),
+            _ => Err("expected uniform or cluster".to_string()),
+        }
+    }
+}
+
+/// The radius of the Earth in kilometers, which the course uses.
+pub const EARTH_RADIUS: f64 = 6372.8;
+
+/// Two points, in degrees.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Pair {
+    pub x0: f64,
+    pub y0: f64,
+    pub x1: f64,
+    pub y1: f64,
+}
+
+impl Pair {
+    /// The distance between the points on the Earth.
+    #[must_use]
+    pub fn distance(self) -> f64 {
+        reference(self.x0, self.y0, self.x1, self.y1, EARTH_RADIUS)
+    }
+
+    /// The distance between the points on the Earth, with the math functions of `math`.
+    #[must_use]
+    pub fn distance_with<M: Math + ?Sized>(self, math: &M) -> f64 {
+        formula(math, self.x0, self.y0, self.x1, self.y1, EARTH_RADIUS)
+    }
+}
+
+// The pairs in each cluster, about.
+const CLUSTER_PAIRS: u64 = 64;
+
+/// The range of a coordinate, from -`max` to `max`, or from around a cluster's center.
+#[derive(Clone, Copy)]
+struct Span {
+    min: f64,
+    max: f64,
+}
+
+impl Span {
+    const fn whole(max: f64) -> Self {
+        Self { min: -max, max }
+    }
+
+    /// A random center, and a random radius around it, within the whole range.
+    fn around(random: &mut Random, max: f64) -> Self {
+        let center = random.range(-max, max);
+        let radius = random.range(0.0, max);
+        Self {
+            min: (center - radius).max(-max),
+            max: (center + radius).min(max),
+        }
+    }
+
+    fn sample(self, random: &mut Random) -> f64 {
+        random.range(self.min, self.max)
+    }
+}
+
+/// The distance between two points on a sphere of `radius`, like the course's reference implementation.
+#[must_use]
+pub fn reference(x0: f64, y0: f64, x1: f64, y1: f64, radius: f64) -> f64 {
+    formula(&Std, x0, y0, x1, y1, radius)
+}
+
+/// The distance between two points on a sphere of `radius`, with the math functions of `math`.
+#[must_use]
+// Without fused multiply-adds, like the reference, so that the answers are the same bit for bit.
+#[allow(clippy::suboptimal_flops)]
+pub fn formula<M: Math + ?Sized>(math: &M, x0: f64, y0: f64, x1: f64, y1: f64, radius: f64) -> f64 {
+    let d_lat = (y1 - y0).to_radians();
+    let d_lon = (x1 - x0).to_radians();
+    let (lat0, lat1) = (y0.to_radians(), y1.to_radians());
+    let a = math.sin(d_lat / 2.0).powi(2) + math.cos(lat0) * math.cos(lat1) * math.sin(d_lon / 2.0).powi(2);
+    radius * 2.0 * math.asin(math.sqrt(a))
+}
+
+/// Add a distance to an average of `count` distances, in the order that [`generate`] and [`average`] add them, so that
+/// they agree bit for bit.
+#[allow(clippy::cast_precision_loss)]
+fn accumulate(average: &mut f64, count: usize, distance: f64) {
+    *average += 1.0 / count as f64 * distance;
+}
+
+/// How distances are computed.
+#[derive(Clone, Copy)]
+pub enum Kernel<'a> {
+    /// One pair at a time, with math functions.
+    Scalar(&'a (dyn Math + Sync)),
+    /// Several pairs at a time, with SIMD instructions and the custom math functions.
+    Simd(Simd, &'a Custom),
+}
+
+impl Kernel<'_> {
+    /// The distances of the pairs, in order.
+    ///
+    /// # Panics
+    ///
+    /// If the CPU doesn't have the SIMD instructions.
+    #[must_use]
+    pub fn distances(self, pairs: &[Pair]) -> Vec<f64> {
+        match self {
+            Self::Scalar(math) => pairs.iter().map(|pair| pair.distance_with(math)).collect(),
+            Self::Simd(instructions, custom) => simd::distances(pairs, custom, instructions),
+        }
+    }
+}
+
+/// The distances of the pairs, in order, computed in a chunk on each of `threads` threads.
+fn distances(pairs: &[Pair], kernel: Kernel, threads: usize) -> Vec<f64> {
+    if threads <= 1 {
+        return kernel.distances(pairs);
+    }
+    let chunk = pairs.len().div_ceil(threads).max(1);
+    thread::scope(|scope| {
+        let workers: Vec<_> = pairs
+            .chunks(chunk)
+            .map(|chunk| {
+                scope.spawn(move || {
+                    let distances: Vec<f64> = {
+                        time_bandwidth!("compute thread", size_of_val(chunk));
+                        kernel.distances(chunk)
+                    };
+                    (distances, profile::take_thread())
+                })
+            })
+            .collect();
+        let mut distances = Vec::with_capacity(pairs.len());
+        for worker in workers {
+            let (chunk, times) = worker.join().unwrap_or_else(|payload| panic::resume_unwind(payload));
+            distances.extend(chunk);
+            profile::add_thread(times);
+        }
+        distances
+    })
+}
+
+/// The average distance of the pairs, computed by `kernel` on `threads` threads.
+///
+/// It computes the distances and then sums them in order, so that a profile shows the two apart and the average is the
+/// same for any number of threads.
+#[must_use]
+pub fn average(pairs: &[Pair], kernel: Kernel, threads: usize) -> f64 {
+    time_function!(size_of_val(pairs));
+    let distances = {
+        time_bandwidth!("compute", size_of_val(pairs));
+        distances(pairs, kernel, threads)
+    };
+    time_bandwidth!("sum", size_of_val(distances.as_slice()));
+    let mut average = 0.0;
+    for &distance in &distances {
+        accumulate(&mut average, distances.len(), distance);
+    }
+    average
+}
+
+/// Parse the pairs in the JSON that [`generate`] writes.
+///
+/// # Errors
+///
+/// If the JSON is invalid, or isn't an object of pairs.
+pub fn parse(json: &str) -> Result<Vec<Pair>, String> {
+    let mut pairs = Vec::new();
+    parse_into(json, &mut pairs)?;
+    Ok(pairs)
+}
+
+/// Parse the pairs in the JSON that [`generate`] writes into `pairs`, replacing what's there, to reuse its memory.
+///
+/// # Errors
+///
+/// If the JSON is invalid, or isn't an object of pairs.
+pub fn parse_into(json: &str, pairs: &mut Vec<Pair>) -> Result<(), String> {
+    let element = {
+        time_bandwidth!("parse", json.len());
+        json::parse(json)?
+    };
+    let elements = element
+        .get("pairs")
+        .and_then(Element::as_array)
+        .ok_or("expected an object with an array of \"pairs\"")?;
+    time_bandwidth!("convert", size_of::<Pair>() * elements.len());
+    pairs.clear();
+    pairs.reserve(elements.len());
+    for (index, element) in elements.iter().enumerate() {
+        pairs.push(pair(index, element)?);
+    }
+    Ok(())
+}
+
+/// The pair that's the `index`th element of the array of pairs.
+fn pair(index: usize, element: &Element) -> Result<Pair, String> {
+    let coordinate = |key: &str| {
+        element
+            .get(key)
+            .and_then(Element::as_f64)
+            .ok_or_else(|| format!("pair {index} needs a number {key:?}"))
+    };
+    Ok(Pair {
+        x0: coordinate("x0")?,
+        y0: coordinate("y0")?,
+        x1: coordinate("x1")?,
+        y1: coordinate("y1")?,
+    })
+}
+
+/// The pairs that [`stream`] computes the distances of at a time.
+const BATCH: usize = 4096;
+
+/// Parse the pairs in the JSON that [`generate`] writes from `reader`, and compute their distances as they're parsed.
+///
+/// Distances are computed with `kernel` a batch at a time, and `each` is called with each batch and its distances.
+/// Returns the number of pairs and their average.
+///
+/// Only a batch of pairs and what the JSON parser reads ahead are held at once. The average is the sum of the distances
+/// divided by their number at the end, because the number isn't known until then, so it can differ from [`average`]'s
+/// in its last bits.
+///
+/// # Errors
+///
+/// If reading fails, the JSON is invalid or isn't an object of pairs, or `each` fails.
+#[allow(clippy::cast_precision_loss)]
+pub fn stream(
+    reader: impl BufRead,
+    kernel: Kernel,
+    mut each: impl FnMut(&[Pair], &[f64]) -> Result<(), String>,
+) -> Result<(usize, f64), String> {
+    let mut batch = Vec::with_capacity(BATCH);
+    let mut sum = 0.0;
+    let mut flush = |batch: &mut Vec<Pair>| {
+        let distances = {
+            time_bandwidth!("compute", size_of_val(batch.as_slice()));
+            kernel.distances(batch)
+        };
+        sum += distances.iter().sum::<f64>();
+        each(batch, &distances)?;
+        batch.clear();
+        Ok::<_, String>(())
+    };
+    let mut index = 0;
+    let count = json::stream_array(reader, "pairs", |element| {
+        batch.push(pair(index, element)?);
+        index += 1;
+        if batch.len() == BATCH {
+            flush(&mut batch)?;
+        }
+        Ok(())
+    })?;
+    flush(&mut batch)?;
+    Ok((count, if count == 0 { 0.0 } else { sum / count as f64 }))
+}
+
+/// Parse answers that [`generate`] writes: the distance of each pair, and their average.
+///
+/// # Errors
+///
+/// If the answers aren't a whole number of `f64`s, or there are none.
+pub fn answers(bytes: &[u8]) -> Result<(Vec<f64>, f64), String> {
+    if bytes.is_empty() || !bytes.len().is_multiple_of(8) {
+        return Err(format!("expected a whole number of f64s, not {} bytes", bytes.len()));
+    }
+    let mut distances: Vec<f64> = bytes
+        .chunks_exact(8)
+        .map(|bytes| f64::from_le_bytes(bytes.try_into().unwrap_or_default()))
+        .collect();
+    let average = distances.pop().unwrap_or_default();
+    Ok((distances, average))
+}
+
+/// How the distances that were computed differ from the answers.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Validation {
+    /// The largest absolute difference of a pair's distance.
+    pub max: f64,
+    /// The mean absolute difference of the pairs' distances.
+    pub mean: f64,
+    /// The first pair whose distance isn't the same bit for bit, and its distance.
+    pub first: Option<(usize, f64)>,
+    /// The difference of the averages.
+    pub average: f64,
+}
+
+impl Validation {
+    /// Whether every distance and the average are the same bit for bit.
+    #[must_use]
+    pub fn is_exact(&self) -> bool {
+        self.first.is_none() && self.average == 0.0
+    }
+
+    /// Whether every distance and the average are within `tolerance` of the answers.
+    #[must_use]
+    pub fn is_within(&self, tolerance: f64) -> bool {
+        self.max <= tolerance && self.average.abs() <= tolerance
+    }
+}
+
+/// Compare the distances of the pairs, computed by `kernel`, and their average with the answers.
+///
+/// # Errors
+///
+/// If there are more or fewer answers than pairs.
+pub fn validate(
+    pairs: &[Pair],
+    kernel: Kernel,
+    distances: &[f64],
+    average: f64,
+    answer: f64,
+) -> Result<Validation, String> {
+    time_function!(size_of_val(pairs) + size_of_val(distances));
+    if pairs.len() != distances.len() {
+        return Err(format!("{} pairs, but {} answers", pairs.len(), distances.len()));
+    }
+    let mut validator = Validator::default();
+    validator.check(&kernel.distances(pairs), distances);
+    Ok(validator.finish(average, answer))
+}
+
+/// Compares distances with the answers a batch at a time, as [`stream`] computes them.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Validator {
+    validation: Validation,
+    // The sum of the absolute differences, and the number of distances.
+    total: f64,
+    count: usize,
+}
+
+impl Validator {
+    /// The number of distances that have been checked.
+    #[must_use]
+    pub const fn count(&self) -> usize {
+        self.count
+    }
+
+    /// Compare the distances of the next pairs with their answers.
+    pub fn check(&mut self, distances: &[f64], answers: &[f64]) {
+        for (index, (&distance, &expected)) in distances.iter().zip(answers).enumerate() {
+            if distance.to_bits() != expected.to_bits() && self.validation.first.is_none() {
+                self.validation.first = Some((self.count + index, distance));
+            }
+            let difference = (distance - expected).abs();
+            self.validation.max = self.validation.max.max(difference);
+            self.total += difference;
+        }
+        self.count += distances.len();
+    }
+
+    /// How the distances that were checked and their average differ from the answers.
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn finish(self, average: f64, answer: f64) -> Validation {
+        Validation {
+            mean: if self.count == 0 {
+                0.0
+            } else {
+                self.total / self.count as f64
+            },
+            average: average - answer,
+            ..self.validation
+        }
+    }
+}
+
+/// Write `pairs` pairs of points from `seed` as JSON to `out`, and their distances to `answers`. Returns the average
+/// distance.
+///
+/// # Errors
+///
+/// If writing fails.
+pub fn generate(out: &mut impl Write, answers: &mut impl Write, pairs: u64, seed: u64, mode: Mode) -> io::Result<f64> {
+    let mut random = Random::new(seed);
+    let (mut x, mut y) = (Span::whole(180.0), Span::whole(90.0));
+    let cluster = match mode {
+        Mode::Uniform => u64::MAX,
+        Mode::Cluster => CLUSTER_PAIRS,
+    };
+
+    let count = usize::try_from(pairs).map_err(|_| io::Error::other("too many pairs"))?;
+    let mut average = 0.0;
+    write!(out, "{{\"pairs\":[")?;
+    for index in 0..pairs {
+        if index % cluster == 0 {
+            (x, y) = (Span::around(&mut random, 180.0), Span::around(&mut random, 90.0));
+        }
+        let pair = Pair {
+            x0: x.sample(&mut random),
+            y0: y.sample(&mut random),
+            x1: x.sample(&mut random),
+            y1: y.sample(&mut random),
+        };
+        let separator = if index == 0 { "" } else { "," };
+        let Pair { x0, y0, x1, y1 } = pair;
+        write!(
+            out,
+            "{separator}\n    {{\"x0\":{x0}, \"y0\":{y0}, \"x1\":{x1}, \"y1\":{y1}}}"
+        )?;
+        let distance = pair.distance();
+        answers.write_all(&distance.to_le_bytes())?;
+        accumulate(&mut average, count, distance);
+    }
+    writeln!(out, "\n]}}")?;
+    answers.write_all(&average.to_le_bytes())?;
+    Ok(average)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::f64::consts::PI;
+    use std::io;
+
+    use crate::math::Custom;
+
+    fn points(pairs: u64, seed: u64, mode: Mode) -> Vec<Pair> {
+        let mut json = vec![];
+        generate(&mut json, &mut io::sink(), pairs, seed, mode).unwrap();
+        parse(std::str::from_utf8(&json).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn generates() {
+        for mode in [Mode::Uniform, Mode::Cluster] {
+            let pairs = points(200, 7, mode);
+            assert_eq!(pairs.len(), 200);
+            for Pair { x0, y0, x1, y1 } in &pairs {
+                assert!([x0, x1].iter().all(|x| (-180.0..=180.0).contains(*x)));
+                assert!([y0, y1].iter().all(|y| (-90.0..=90.0).contains(*y)));
+            }
+            assert_eq!(points(200, 7, mode), pairs);
+            assert_ne!(points(200, 8, mode), pairs);
+        }
+        assert!(points(0, 7, Mode::Uniform).is_empty());
+
+        // A seed writes the same file everywhere.
+        let mut json = vec![];
+        generate(&mut json, &mut io::sink(), 1, 1, Mode::Uniform).unwrap();
+        assert_eq!(
+            String::from_utf8(json).unwrap(),
+            r#"{"pairs":[
+    {"x0":8.99827409267994, "y0":79.28002063287028, "x1":125.27328307969381, "y1":68.43699255902132}
+]}
+"#
+        );
+    }
+
+    #[test]
+    fn distances() {
+        assert!((reference(0.0, 0.0, 0.0, 90.0, 1.0) - PI / 2.0).abs() < 1e-12);
+        assert!((reference(-90.0, 0.0, 90.0, 0.0, EARTH_RADIUS) - EARTH_RADIUS * PI).abs() < 1e-9);
+
+        let (mut json, mut answers) = (vec![], vec![]);
+        let average = generate(&mut json, &mut answers, 100, 3, Mode::Cluster).unwrap();
+        let answers: Vec<f64> = answers
+            .chunks_exact(8)
+            .map(|bytes| f64::from_le_bytes(bytes.try_into().unwrap()))
+            .collect();
+        assert_eq!(answers.len(), 101);
+        assert_eq!(answers[100].to_bits(), average.to_bits());
+
+        // The distances are those of the coordinates as they're parsed.
+        let mut sum = 0.0;
+        let pairs = parse(std::str::from_utf8(&json).unwrap()).unwrap();
+        for (pair, answer) in pairs.iter().zip(&answers) {
+            let distance = pair.distance();
+            assert_eq!(distance.to_bits(), answer.to_bits());
+            sum += distance / 100.0;
+        }
+        assert!((sum - average).abs() < 1e-9);
+    }
+
+    #[test]
+    fn computes() {
+        let (mut json, mut bytes) = (vec![], vec![]);
+        let expected = generate(&mut json, &mut bytes, 50, 11, Mode::Uniform).unwrap();
+        let pairs = parse(std::str::from_utf8(&json).unwrap()).unwrap();
+        assert_eq!(pairs.len(), 50);
+        assert!((average(&pairs, Kernel::Scalar(&Std), 1) - expected).abs() < 1e-9);
+        for threads in [2, 7, 64] {
+            assert_eq!(
+                average(&pairs, Kernel::Scalar(&Std), threads).to_bits(),
+                average(&pairs, Kernel::Scalar(&Std), 1).to_bits()
+            );
+        }
+        assert_eq!(average(&[], Kernel::Scalar(&Std), 4), 0.0);
+        let (distances, answer) = answers(&bytes).unwrap();
+        assert_eq!((distances.len(), answer.to_bits()), (50, expected.to_bits()));
+
+        let validation = validate(&pairs, Kernel::Scalar(&Std), &distances, expected, answer).unwrap();
+        assert!(validation.is_exact(), "{validation:?}");
+        let custom = Custom::default();
+        let validation = validate(
+            &pairs,
+            Kernel::Scalar(&custom),
+            &distances,
+            average(&pairs, Kernel::Scalar(&custom), 1),
+            answer,
+        )
+        .unwrap();
+        assert!(!validation.is_exact() && validation.is_within(1e-9), "{validation:?}");
+        let fastest = Kernel::Simd(Simd::Sse2, &custom);
+        assert_eq!(
+            validate(&pairs, fastest, &distances, average(&pairs, fastest, 3), answer),
+            Ok(validation)
+        );
+
+        let mut moved = pairs.clone();
+        moved[7].x1 += 1.0;
+        let validation = validate(
+            &moved,
+            Kernel::Scalar(&Std),
+            &distances,
+            average(&moved, Kernel::Scalar(&Std), 1),
+            answer,
+        )
+        .unwrap();
+        assert_eq!(validation.first.map(|(index, _)| index), Some(7));
+        assert!(validation.max > 0.0 && validation.mean > 0.0 && validation.average != 0.0);
+        assert!((validation.max - 50.0 * validation.mean).abs() < 1e-9);
+        assert!(validate(&pairs[1..], Kernel::Scalar(&Std), &distances, expected, answer).is_err());
+
+        assert!(parse("{\"pairs\":[{\"x0\":1}]}").unwrap_err().contains("pair 0"));
+        let mut reused = vec![Pair::default(); 80];
+        parse_into(std::str::from_utf8(&json).unwrap(), &mut reused).unwrap();
+        assert_eq!(reused, pairs);
+        assert!(parse("[]").is_err());
+        assert!(answers(&[0; 12]).is_err());
+    }
+
+    #[test]
+    fn streams() {
+        let (mut json, mut bytes) = (vec![], vec![]);
+        let expected = generate(&mut json, &mut bytes, 2 * BATCH as u64 + 5, 13, Mode::Cluster).unwrap();
+        let pairs = parse(std::str::from_utf8(&json).unwrap()).unwrap();
+        let (distances, answer) = answers(&bytes).unwrap();
+        let custom = Custom::default();
+        for kernel in [Kernel::Scalar(&Std), Kernel::Simd(Simd::Sse2, &custom)] {
+            let (mut streamed, mut validator) = (vec![], Validator::default());
+            let (count, average) = stream(
+                io::BufReader::with_capacity(100, json.as_slice()),
+                kernel,
+                |batch, batch_distances| {
+                    assert!(batch.len() <= BATCH);
+                    streamed.extend_from_slice(batch);
+                    validator.check(batch_distances, &distances[validator.count()..]);
+                    Ok(())
+                },
+            )
+            .unwrap();
+            assert_eq!((count, validator.count()), (pairs.len(), pairs.len()));
+            assert_eq!(streamed, pairs);
+            assert!((average - expected).abs() < 1e-9);
+            let validation = validator.finish(average, answer);
+            assert_eq!(
+                (validation.first, validation.max, validation.mean),
+                validate(&pairs, kernel, &distances, expected, answer)
+                    .map(|validation| (validation.first, validation.max, validation.mean))
+                    .unwrap()
+            );
+        }
+
+        let empty = stream(&b"{\"pairs\":[]}"[..], Kernel::Scalar(&Std), |_, _| Ok(()));
+        assert_eq!(empty, Ok((0, 0.0)));
+        let error = stream(&b"{\"pairs\":[{\"x0\":1}]}"[..], Kernel::Scalar(&Std), |_, _| Ok(()));
+        assert!(error.unwrap_err().contains("pair 0"));
+        assert!(stream(&b"{}"[..], Kernel::Scalar(&Std), |_, _| Ok(())).is_err());
+    }
+}
@@ -0,0 +1,435 @@
+//! Structured representation of a decoded 8086 instruction.
+//!
+//! The disassembler in `main.rs` writes text as it decodes. The simulator instead needs to inspect
+//! operands, so it decodes into these types. The `Display` impls follow the reference simulator's
+//! text format (`perfaware/sim86/sim86_text.cpp`), so traces compare against the `.txt` listings.
+
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Register {
+    Al,
+    Cl,
+    Dl,
+    Bl,
+    Ah,
+    Ch,
+    Dh,
+    Bh,
+    Ax,
+    Cx,
+    Dx,
+    Bx,
+    Sp,
+    Bp,
+    Si,
+    Di,
+    Es,
+    Cs,
+    Ss,
+    Ds,
+}
+
+// Indexed by W then by the REG or R/M field.
+const REGISTERS: [[Register; 8]; 2] = [
+    [
+        Register::Al,
+        Register::Cl,
+        Register::Dl,
+        Register::Bl,
+        Register::Ah,
+        Register::Ch,
+        Register::Dh,
+        Register::Bh,
+    ],
+    [
+        Register::Ax,
+        Register::Cx,
+        Register::Dx,
+        Register::Bx,
+        Register::Sp,
+        Register::Bp,
+        Register::Si,
+        Register::Di,
+    ],
+];
+const SEGMENTS: [Register; 4] = [Register::Es, Register::Cs, Register::Ss, Register::Ds];
+
+impl Register {
+    /// The register identified by a REG or R/M field.
+    #[must_use]
+    pub const fn from_field(w: bool, field: u8) -> Self {
+        REGISTERS[w as usize][(field & 0b111) as usize]
+    }
+
+    /// The segment register identified by an SR field.
+    #[must_use]
+    pub const fn segment(field: u8) -> Self {
+        SEGMENTS[(field & 0b11) as usize]
+    }
+
+    /// The REG, R/M or SR field that identifies the register.
+    #[must_use]
+    pub const fn field(self) -> u8 {
+        match self {
+            Self::Al | Self::Ax | Self::Es => 0,
+            Self::Cl | Self::Cx | Self::Cs => 1,
+            Self::Dl | Self::Dx | Self::Ss => 2,
+            Self::Bl | Self::Bx | Self::Ds => 3,
+            Self::Ah | Self::Sp => 4,
+            Self::Ch | Self::Bp => 5,
+            Self::Dh | Self::Si => 6,
+            Self::Bh | Self::Di => 7,
+        }
+    }
+
+    #[must_use]
+    pub const fn is_segment(self) -> bool {
+        matches!(self, Self::Es | Self::Cs | Self::Ss | Self::Ds)
+    }
+
+    #[must_use]
+    pub const fn is_wide(self) -> bool {
+        !matches!(
+            self,
+            Self::Al | Self::Cl | Self::Dl | Self::Bl | Self::Ah | Self::Ch | Self::Dh | Self::Bh
+        )
+    }
+
+    #[must_use]
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Al => "al",
+            Self::Cl => "cl",
+            Self::Dl => "dl",
+            Self::Bl => "bl",
+            Self::Ah => "ah",
+            Self::Ch => "ch",
+            Self::Dh => "dh",
+            Self::Bh => "bh",
+            Self::Ax => "ax",
+            Self::Cx => "cx",
+            Self::Dx => "dx",
+            Self::Bx => "bx",
+            Self::Sp => "sp",
+            Self::Bp => "bp",
+            Self::Si => "si",
+            Self::Di => "di",
+            Self::Es => "es",
+            Self::Cs => "cs",
+            Self::Ss => "ss",
+            Self::Ds => "ds",
+        }
+    }
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// The registers summed by an R/M field, or none for a direct address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Base {
+    BxSi,
+    BxDi,
+    BpSi,
+    BpDi,
+    Si,
+    Di,
+    Bp,
+    Bx,
+    Direct,
+}
+
+const BASES: [Base; 8] = [
+    Base::BxSi,
+    Base::BxDi,
+    Base::BpSi,
+    Base::BpDi,
+    Base::Si,
+    Base::Di,
+    Base::Bp,
+    Base::Bx,
+];
+
+impl Base {
+    /// The base identified by an R/M field in memory mode.
+    #[must_use]
+    pub const fn from_field(field: u8) -> Self {
+        BASES[(field & 0b111) as usize]
+    }
+
+    #[must_use]
+    pub const fn registers(self) -> &'static [Register] {
+        match self {
+            Self::BxSi => &[Register::Bx, Register::Si],
+            Self::BxDi => &[Register::Bx, Register::Di],
+            Self::BpSi => &[Register::Bp, Register::Si],
+            Self::BpDi => &[Register::Bp, Register::Di],
+            Self::Si => &[Register::Si],
+            Self::Di => &[Register::Di],
+            Self::Bp => &[Register::Bp],
+            Self::Bx => &[Register::Bx],
+            Self::Direct => &[],
+        }
+    }
+}
+
+/// An effective address: base registers plus a displacement.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Memory {
+    pub base: Base,
+    pub displacement: i16,
+}
+
+impl fmt::Display for Memory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("[")?;
+        for (i, register) in self.base.registers().iter().enumerate() {
+            if i > 0 {
+                f.write_str("+")?;
+            }
+            f.write_str(register.name())?;
+        }
+        if self.displacement != 0 {
+            write!(f, "{:+}", self.displacement)?;
+        }
+        f.write_str("]")
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Operand {
+    #[default]
+    None,
+    Register(Register),
+    Memory(Memory),
+    /// Immediate data. 16-bit and 8-bit data are zero-extended, unless the S bit sign-extends 8-bit data.
+    Immediate(i32),
+    /// A jump displacement, relative to the end of the instruction.
+    Relative(i16),
+    /// A direct intersegment address: segment, offset.
+    Far(u16, u16),
+}
+
+macro_rules! operations {
+    ($($variant:ident => $name:literal,)*) => {
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+        pub enum Operation {
+            $($variant,)*
+        }
+
+        impl Operation {
+            /// Every operation.
+            pub const ALL: &'static [Self] = &[$(Self::$variant,)*];
+
+            #[must_use]
+            pub const fn name(self) -> &'static str {
+                match self {
+                    $(Self::$variant => $name,)*
+                }
+            }
+        }
+
+        impl FromStr for Operation {
+            type Err = String;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                match s {
+                    $($name => Ok(Self::$variant),)*
+                    _ => Err(format!("unknown operation {s:?}")),
+                }
+            }
+        }
+    };
+}
+
+operations! {
+    Mov => "mov",
+    Push => "push",
+    Pop => "pop",
+    Xchg => "xchg",
+    In => "in",
+    Out => "out",
+    Xlat => "xlat",
+    Lea => "lea",
+    Lds => "lds",
+    Les => "les",
+    Lahf => "lahf",
+    Sahf => "sahf",
+    Pushf => "pushf",
+    Popf => "popf",
+    Add => "add",
+    Adc => "adc",
+    Inc => "inc",
+    Aaa => "aaa",
+    Daa => "daa",
+    Sub => "sub",
+    Sbb => "sbb",
+    Dec => "dec",
+    Neg => "neg",
+    Cmp => "cmp",
+    Aas => "aas",
+    Das => "das",
+    Mul => "mul",
+    Imul => "imul",
+    Aam => "aam",
+    Div => "div",
+    Idiv => "idiv",
+    Aad => "aad",
+    Cbw => "cbw",
+    Cwd => "cwd",
+    Not => "not",
+    Shl => "shl",
+    Shr => "shr",
+    Sar => "sar",
+    Rol => "rol",
+    Ror => "ror",
+    Rcl => "rcl",
+    Rcr => "rcr",
+    And => "and",
+    Test => "test",
+    Or => "or",
+    Xor => "xor",
+    Movs => "movs",
+    Cmps => "cmps",
+    Scas => "scas",
+    Lods => "lods",
+    Stos => "stos",
+    Call => "call",
+    Jmp => "jmp",
+    Ret => "ret",
+    Retf => "retf",
+    Jo => "jo",
+    Jno => "jno",
+    Jb => "jb",
+    Jnb => "jnb",
+    Je => "je",
+    Jne => "jne",
+    Jbe => "jbe",
+    Jnbe => "jnbe",
+    Js => "js",
+    Jns => "jns",
+    Jp => "jp",
+    Jnp => "jnp",
+    Jl => "jl",
+    Jnl => "jnl",
+    Jle => "jle",
+    Jnle => "jnle",
+    Loopnz => "loopnz",
+    Loopz => "loopz",
+    Loop => "loop",
+    Jcxz => "jcxz",
+    Int => "int",
+    Int3 => "int3",
+    Into => "into",
+    Iret => "iret",
+    Clc => "clc",
+    Cmc => "cmc",
+    Stc => "stc",
+    Cld => "cld",
+    Std => "std",
+    Cli => "cli",
+    Sti => "sti",
+    Hlt => "hlt",
+    Wait => "wait",
+}
+
+impl Operation {
+    /// Whether the operation is a string instruction, which takes a `b` or `w` suffix.
+    #[must_use]
+    pub const fn is_string(self) -> bool {
+        matches!(self, Self::Movs | Self::Cmps | Self::Scas | Self::Lods | Self::Stos)
+    }
+}
+
+/// A REP prefix. REP is also spelled REPE and REPZ for CMPS and SCAS.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Rep {
+    Rep,
+    Repne,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Instruction {
+    pub operation: Operation,
+    pub operands: [Operand; 2],
+    /// The number of bytes, including prefixes.
+    pub size: u16,
+    /// Whether the operation is on words rather than bytes.
+    pub wide: bool,
+    pub far: bool,
+    pub lock: bool,
+    pub rep: Option<Rep>,
+    pub segment: Option<Register>,
+}
+
+impl Instruction {
+    #[must_use]
+    pub const fn new(operation: Operation, operands: [Operand; 2], wide: bool) -> Self {
+        Self {
+            operation,
+            operands,
+            size: 0,
+            wide,
+            far: false,
+            lock: false,
+            rep: None,
+            segment: None,
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut operands = self.operands;
+        if self.lock {
+            // Assemblers expect the memory operand first in "lock xchg".
+            if self.operation == Operation::Xchg {
+                operands.swap(0, 1);
+            }
+            f.write_str("lock ")?;
+        }
+        match self.rep {
+            Some(Rep::Rep) => f.write_str("rep ")?,
+            Some(Rep::Repne) => f.write_str("repne ")?,
+            None => {}
+        }
+        f.write_str(self.operation.name())?;
+        if self.operation.is_string() {
+            f.write_str(if self.wide { "w" } else { "b" })?;
+        }
+
+        let mut separator = " ";
+        for operand in operands {
+            if operand == Operand::None {
+                continue;
+            }
+            f.write_str(separator)?;
+            separator = ", ";
+            match operand {
+                Operand::None => {}
+                Operand::Register(register) => f.write_str(register.name())?,
+                Operand::Memory(memory) => {
+                    if self.far {
+                        f.write_str("far ")?;
+                    } else if !matches!(operands[0], Operand::Register(_)) {
+                        f.write_str(if self.wide { "word " } else { "byte " })?;
+                    }
+                    if let Some(segment) = self.segment {
+                        f.write_str(segment.name())?;
+                        f.write_str(":")?;
+                    }
+                    memory.fmt(f)?;
+                }
+                Operand::Immediate(value) => write!(f, "{value}")?,
+                Operand::Relative(displacement) => write!(f, "${:+}", i32::from(displacement) + i32::from(self.size))?,
+                Operand::Far(segment, offset) => write!(f, "{segment}:{offset}")?,
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,734 @@
+//! A small JSON parser, written from scratch like the course's: a lexer that splits the text into tokens, and a
+//! parser that builds a tree of [`Element`]s from them.
+//!
+//! Strings borrow from the text unless they have escapes. Numbers are parsed by `str::parse`, which rounds correctly,
+//! so that they're the same values that were written.
+//!
+//! [`stream_array`] parses an array in an object from a reader one element at a time, for text that's too large to
+//! hold. It finds where each element ends, skipping over strings and the brackets inside it, before parsing it, so
+//! that an element that continues past what's been read is read further rather than being an error.
+
+use std::borrow::Cow;
+use std::io::BufRead;
+use std::iter::Peekable;
+
+/// A piece of JSON text.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Token<'a> {
+    OpenBrace,
+    CloseBrace,
+    OpenBracket,
+    CloseBracket,
+    Comma,
+    Colon,
+    String(Cow<'a, str>),
+    Number(f64),
+    True,
+    False,
+    Null,
+}
+
+/// A value, and the values in it.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Element<'a> {
+    /// Keys and values, in the order that they're written.
+    Object(Vec<(Cow<'a, str>, Self)>),
+    Array(Vec<Self>),
+    String(Cow<'a, str>),
+    Number(f64),
+    Bool(bool),
+    Null,
+}
+
+impl Element<'_> {
+    /// The value of the first `key`, if this is an object that has it.
+    #[must_use]
+    pub fn get(&self, key: &str) -> Option<&Self> {
+        match self {
+            Self::Object(members) => members.iter().find(|(name, _)| name == key).map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    #[must_use]
+    pub fn as_array(&self) -> Option<&[Self]> {
+        match self {
+            Self::Array(elements) => Some(elements),
+            _ => None,
+        }
+    }
+
+    #[must_use]
+    pub const fn as_f64(&self) -> Option<f64> {
+        match self {
+            Self::Number(number) => Some(*number),
+            _ => None,
+        }
+    }
+
+    #[must_use]
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::String(text) => Some(text),
+            _ => None,
+        }
+    }
+}
+
+/// The tokens of some JSON text, with the offset of each, or an error at the first invalid token.
+pub struct Lexer<'a> {
+    text: &'a str,
+    position: usize,
+    // The offset of the text in the whole, for the offsets of tokens and errors.
+    offset: usize,
+}
+
+impl<'a> Lexer<'a> {
+    #[must_use]
+    pub const fn new(text: &'a str) -> Self {
+        Self {
+            text,
+            position: 0,
+            offset: 0,
+        }
+    }
+
+    fn error<T>(&self, message: &str) -> Result<T, String> {
+        Err(format!("at byte {}: {message}", self.offset + self.position))
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.text.as_bytes().get(self.position).copied()
+    }
+
+    /// Skip a keyword, which begins at the current byte.
+    fn keyword(&mut self, keyword: &str, token: Token<'a>) -> Result<Token<'a>, String> {
+        if !self.text[self.position..].starts_with(keyword) {
+            return self.error("expected a value");
+        }
+        self.position += keyword.len();
+        Ok(token)
+    }
+
+    /// Skip digits, and return how many there were.
+    fn digits(&mut self) -> usize {
+        let count = self.text.as_bytes()[self.position..]
+            .iter()
+            .take_while(|byte| byte.is_ascii_digit())
+            .count();
+        self.position += count;
+        count
+    }
+
+    /// -?(0|[1-9][0-9]*)(.[0-9]+)?([eE][+-]?[0-9]+)?
+    fn number(&mut self) -> Result<Token<'a>, String> {
+        let start = self.position;
+        if self.peek() == Some(b'-') {
+            self.position += 1;
+        }
+        let integer = self.position;
+        if self.digits() == 0 || self.text.as_bytes()[integer] == b'0' && self.position - integer > 1 {
+            return self.error("invalid number");
+        }
+        if self.peek() == Some(b'.') {
+            self.position += 1;
+            if self.digits() == 0 {
+                return self.error("expected digits after the decimal point");
+            }
+        }
+        if matches!(self.peek(), Some(b'e' | b'E')) {
+            self.position += 1;
+            if matches!(self.peek(), Some(b'+' | b'-')) {
+                self.position += 1;
+            }
+            if self.digits() == 0 {
+                return self.error("expected digits in the exponent");
+            }
+        }
+        // The digits are valid, so they parse.
+        let number = self.text[start..self.position].parse().unwrap_or_default();
+        Ok(Token::Number(number))
+    }
+
+    /// Four hex digits of a \u escape.
+    fn hex(&mut self) -> Result<u32, String> {
+        let digits = self.text.as_bytes().get(self.position..self.position + 4);
+        let Some(digits) = digits.filter(|digits| digits.iter().all(u8::is_ascii_hexdigit)) else {
+            return self.error("expected 4 hex digits");
+        };
+        let code = digits.iter().fold(0, |code, &digit| {
+            code << 4 | char::from(digit).to_digit(16).unwrap_or(0)
+        });
+        self.position += 4;
+        Ok(code)
+    }
+
+    /// A string, after its opening quote.
+    fn string(&mut self) -> Result<Token<'a>, String> {
+        let start = self.position;
+        let mut unescaped = String::new();
+        // The start of the text that's not yet copied into `unescaped`.
+        let mut copied = start;
+        loop {
+            let Some(byte) = self.peek() else {
+                return self.error("unterminated string");
+            };
+            match byte {
+                b'"' => {
+                    let text = &self.text[copied..self.position];
+                    self.position += 1;
+                    if copied == start {
+                        return Ok(Token::String(Cow::Borrowed(text)));
+                    }
+                    unescaped.push_str(text);
+                    return Ok(Token::String(Cow::Owned(unescaped)));
+                }
+                b'\\' => {
+                    unescaped.push_str(&self.text[copied..self.position]);
+                    self.position += 1;
+                    let escaped = match self.peek() {
+                        Some(b'"') => '"',
+                        Some(b'\\') => '\\',
+                        Some(b'/') => '/',
+                        Some(b'b') => '\u{8}',
+                        Some(b'f') => '\u{c}',
+                        Some(b'n') => '\n',
+                        Some(b'r') => '\r',
+                        Some(b't') => '\t',
+                        Some(b'u') => {
+                            self.position += 1;
+                            let mut code = self.hex()?;
+                            // A surrogate pair.
+                            if (0xd800..0xdc00).contains(&code) && self.text[self.position..].starts_with("\\u") {
+                                self.position += 2;
+                                let low = self.hex()?;
+                                if !(0xdc00..0xe000).contains(&low) {
+                                    return self.error("expected a low surrogate");
+                                }
+                                code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                            }
+                            // Back up, because the escape's last byte is skipped below.
+                            self.position -= 1;
+                            match char::from_u32(code) {
+                                Some(character) => character,
+                                None => return self.error("invalid escape"),
+                            }
+                        }
+                        _ => return self.error("invalid escape"),
+                    };
+                    unescaped.push(escaped);
+                    self.position += 1;
+                    copied = self.position;
+                }
+                0..0x20 => return self.error("control character in string"),
+                _ => self.position += 1,
+            }
+        }
+    }
+}
+
+impl<'a> Iterator for Lexer<'a> {
+    type Item = Result<(usize, Token<'a>), String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let whitespace = self.text.as_bytes()[self.position..]
+            .iter()
+            .take_while(|byte| matches!(byte, b' ' | b'\t' | b'\n' | b'\r'))
+            .count();
+        self.position += whitespace;
+        let start = self.position;
+        let punctuation = match self.peek()? {
+            b'{' => Some(Token::OpenBrace),
+            b'}' => Some(Token::CloseBrace),
+            b'[' => Some(Token::OpenBracket),
+            b']' => Some(Token::CloseBracket),
+            b',' => Some(Token::Comma),
+            b':' => Some(Token::Colon),
+            _ => None,
+        };
+        let token = if let Some(token) = punctuation {
+            self.position += 1;
+            Ok(token)
+        } else {
+            match self.peek()? {
+                b'"' => {
+                    self.position += 1;
+                    self.string()
+                }
+                b'-' | b'0'..=b'9' => self.number(),
+                b't' => self.keyword("true", Token::True),
+                b'f' => self.keyword("false", Token::False),
+                b'n' => self.keyword("null", Token::Null),
+                _ => self.error("unexpected character"),
+            }
+        };
+        if token.is_err() {
+            // Stop after an error.
+            self.position = self.text.len();
+        }
+        Some(token.map(|token| (self.offset + start, token)))
+    }
+}
+
+type Tokens<'a> = Peekable<Lexer<'a>>;
+
+fn expected<T>(what: &str, token: Option<Result<(usize, Token), String>>) -> Result<T, String> {
+    match token {
+        Some(Ok((position, token))) => Err(format!("at byte {position}: expected {what}, not {token:?}")),
+        Some(Err(error)) => Err(error),
+        None => Err(format!("expected {what}, not the end")),
+    }
+}
+
+/// The most arrays and objects that can be inside each other. Elements are parsed recursively, so deeper nesting
+/// would overflow the stack.
+const MAX_DEPTH: usize = 128;
+
+/// The element that begins with the next token, which is inside `depth` arrays and objects.
+fn element<'a>(tokens: &mut Tokens<'a>, depth: usize) -> Result<Element<'a>, String> {
+    if let Some(Ok((position, Token::OpenBrace | Token::OpenBracket))) = tokens.peek() {
+        if depth == MAX_DEPTH {
+            return Err(format!("at byte {position}: nested more than {MAX_DEPTH} deep"));
+        }
+    }
+    match tokens.next() {
+        Some(Ok((_, Token::OpenBrace))) => {
+            let mut members = vec![];
+            if matches!(tokens.peek(), Some(Ok((_, Token::CloseBrace)))) {
+                tokens.next();
+                return Ok(Element::Object(members));
+            }
+            loop {
+                let key = match tokens.next() {
+                    Some(Ok((_, Token::String(key)))) => key,
+                    token => return expected("a key", token),
+                };
+                match tokens.next() {
+                    Some(Ok((_, Token::Colon))) => {}
+                    token => return expected("a colon", token),
+                }
+                members.push((key, element(tokens, depth + 1)?));
+                match tokens.next() {
+                    Some(Ok((_, Token::Comma))) => {}
+                    Some(Ok((_, Token::CloseBrace))) => return Ok(Element::Object(members)),
+                    token => return expected("a comma or a closing brace", token),
+                }
+            }
+        }
+        Some(Ok((_, Token::OpenBracket))) => {
+            let mut elements = vec![];
+            if matches!(tokens.peek(), Some(Ok((_, Token::CloseBracket)))) {
+                tokens.next();
+                return Ok(Element::Array(elements));
+            }
+            loop {
+                elements.push(element(tokens, depth + 1)?);
+                match tokens.next() {
+                    Some(Ok((_, Token::Comma))) => {}
+                    Some(Ok((_, Token::CloseBracket))) => return Ok(Element::Array(elements)),
+                    token => return expected("a comma or a closing bracket", token),
+                }
+            }
+        }
+        Some(Ok((_, Token::String(text)))) => Ok(Element::String(text)),
+        Some(Ok((_, Token::Number(number)))) => Ok(Element::Number(number)),
+        Some(Ok((_, Token::True))) => Ok(Element::Bool(true)),
+        Some(Ok((_, Token::False))) => Ok(Element::Bool(false)),
+        Some(Ok((_, Token::Null))) => Ok(Element::Null),
+        token => expected("a value", token),
+    }
+}
+
+/// Parse JSON text, which is one value.
+///
+/// # Errors
+///
+/// If the text isn't valid JSON. The error has the offset of the invalid token.
+pub fn parse(text: &str) -> Result<Element<'_>, String> {
+    crate::time_bandwidth!("lex and parse", text.len());
+    parse_at(text, 0)
+}
+
+/// Parse JSON text that's at `offset` in the whole.
+fn parse_at(text: &str, offset: usize) -> Result<Element<'_>, String> {
+    let mut tokens = Lexer {
+        text,
+        position: 0,
+        offset,
+    }
+    .peekable();
+    let element = element(&mut tokens, 0)?;
+    match tokens.next() {
+        None => Ok(element),
+        token => expected("the end", token),
+    }
+}
+
+/// The bytes that a stream reads at a time, at least, unless it's at the end.
+const CHUNK: usize = 1 << 20;
+
+/// JSON text that's read as it's parsed, and forgotten once it is.
+struct Source<R> {
+    reader: R,
+    text: String,
+    // The bytes at the end of what's been read that aren't a whole character yet.
+    partial: Vec<u8>,
+    // The position in the text that's parsed up to, and the offset of the text in the whole.
+    position: usize,
+    offset: usize,
+    end: bool,
+}
+
+impl<R: BufRead> Source<R> {
+    const fn new(reader: R) -> Self {
+        Self {
+            reader,
+            text: String::new(),
+            partial: Vec::new(),
+            position: 0,
+            offset: 0,
+            end: false,
+        }
+    }
+
+    fn error<T>(&self, position: usize, message: &str) -> Result<T, String> {
+        Err(format!("at byte {}: {message}", self.offset + position))
+    }
+
+    /// Read more text, forgetting what's been parsed, and return whether there was any. Positions in the text move
+    /// back by the change in [`offset`](Self::offset).
+    fn fill(&mut self) -> Result<bool, String> {
+        if self.end {
+            return Ok(false);
+        }
+        if self.position > self.text.len() / 2 {
+            self.text.drain(..self.position);
+            self.offset += self.position;
+            self.position = 0;
+        }
+        let mut read = 0;
+        while read < CHUNK {
+            let bytes = self.reader.fill_buf().map_err(|error| error.to_string())?;
+            if bytes.is_empty() {
+                self.end = true;
+                break;
+            }
+            let length = bytes.len();
+            self.partial.extend_from_slice(bytes);
+            self.reader.consume(length);
+            read += length;
+        }
+        let valid = match std::str::from_utf8(&self.partial) {
+            Ok(text) => text.len(),
+            // A character that continues in the next chunk.
+            Err(error) if error.error_len().is_none() && !self.end => error.valid_up_to(),
+            Err(error) => return self.error(self.text.len() + error.valid_up_to(), "invalid UTF-8"),
+        };
+        self.text
+            .push_str(std::str::from_utf8(&self.partial[..valid]).map_err(|error| error.to_string())?);
+        self.partial.drain(..valid);
+        Ok(read > 0)
+    }
+
+    /// The next byte that isn't whitespace, without skipping it, or `None` at the end.
+    fn peek(&mut self) -> Result<Option<u8>, String> {
+        loop {
+            let rest = &self.text.as_bytes()[self.position..];
+            if let Some(whitespace) = rest
+                .iter()
+                .position(|byte| !matches!(byte, b' ' | b'\t' | b'\n' | b'\r'))
+            {
+                self.position += whitespace;
+                return Ok(Some(self.text.as_bytes()[self.position]));
+            }
+            self.position = self.text.len();
+            if !self.fill()? {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Skip the next byte that isn't whitespace, and return it.
+    fn punctuation(&mut self, what: &str) -> Result<u8, String> {
+        match self.peek()? {
+            Some(byte) => {
+                self.position += 1;
+                Ok(byte)
+            }
+            None => Err(format!("expected {what}, not the end")),
+        }
+    }
+
+    /// Skip `byte`, which is the next that isn't whitespace.
+    fn expect(&mut self, byte: u8, what: &str) -> Result<(), String> {
+        match self.punctuation(what)? {
+            next if next == byte => Ok(()),
+            _ => self.error(self.position - 1, &format!("expected {what}")),
+        }
+    }
+
+    /// Parse the value or key before the next comma, colon, or closing brace or bracket that isn't inside it, skip
+    /// that punctuation, and return them, with the position of the punctuation.
+    fn value(&mut self, what: &str) -> Result<(Element<'_>, u8, usize), String> {
+        let (mut start, mut index) = (self.position, self.position);
+        let (mut depth, mut string, mut escaped) = (0_usize, false, false);
+        let delimiter = loop {
+            let bytes = self.text.as_bytes();
+            let found = bytes[index..].iter().position(|&byte| {
+                if string {
+                    if escaped {
+                        escaped = false;
+                    } else if byte == b'\\' {
+                        escaped = true;
+                    } else if byte == b'"' {
+                        string = false;
+                    }
+                    return false;
+                }
+                match byte {
+                    b'"' => string = true,
+                    b'{' | b'[' => depth += 1,
+                    b'}' | b']' if depth > 0 => depth -= 1,
+                    b',' | b':' if depth > 0 => {}
+                    b',' | b':' | b'}' | b']' => return true,
+                    _ => {}
+                }
+                false
+            });
+            if let Some(found) = found {
+                break index + found;
+            }
+            index = bytes.len();
+            let offset = self.offset;
+            if !self.fill()? {
+                return Err(format!("expected {what}, not the end"));
+            }
+            let forgotten = self.offset - offset;
+            (start, index) = (start - forgotten, index - forgotten);
+        };
+        let text = &self.text[start..delimiter];
+        if text.trim_ascii().is_empty() {
+            return self.error(delimiter, "expected a value");
+        }
+        let punctuation = self.text.as_bytes()[delimiter];
+        self.position = delimiter + 1;
+        Ok((
+            parse_at(text, self.offset + start)?,
+            punctuation,
+            self.offset + delimiter,
+        ))
+    }
+
+    /// Parse the array that's next, and call `each` with each of its elements. Returns the number of elements.
+    fn array(&mut self, each: &mut impl FnMut(&Element) -> Result<(), String>) -> Result<usize, String> {
+        self.expect(b'[', "an array")?;
+        if self.peek()? == Some(b']') {
+            self.position += 1;
+            return Ok(0);
+        }
+        let mut count = 0;
+        loop {
+            let (element, delimiter, position) = self.value("a comma or a closing bracket")?;
+            each(&element)?;
+            count += 1;
+            match delimiter {
+                b',' => {}
+                b']' => return Ok(count),
+                _ => return Err(format!("at byte {position}: expected a comma or a closing bracket")),
+            }
+        }
+    }
+}
+
+/// Parse the JSON object that `reader` has, calling `each` with each element of the array of its first `key`.
+///
+/// Each element is passed as soon as it's parsed, so that only it rather than the whole text is held. Returns the
+/// number of elements.
+///
+/// # Errors
+///
+/// If reading fails, the text isn't valid JSON or isn't an object with an array of `key`, or `each` fails.
+pub fn stream_array(
+    reader: impl BufRead,
+    key: &str,
+    mut each: impl FnMut(&Element) -> Result<(), String>,
+) -> Result<usize, String> {
+    let mut source = Source::new(reader);
+    let mut count = None;
+    source.expect(b'{', "an object")?;
+    if source.peek()? == Some(b'}') {
+        source.position += 1;
+    } else {
+        loop {
+            let (name, delimiter, position) = source.value("a colon")?;
+            let Element::String(name) = name else {
+                return Err(format!("at byte {position}: expected a key before it"));
+            };
+            if delimiter != b':' {
+                return Err(format!("at byte {position}: expected a colon"));
+            }
+            let delimiter = if count.is_none() && name == key {
+                count = Some(source.array(&mut each)?);
+                source.punctuation("a comma or a closing brace")?
+            } else {
+                source.value("a comma or a closing brace")?.1
+            };
+            match delimiter {
+                b',' => {}
+                b'}' => break,
+                _ => return source.error(source.position - 1, "expected a comma or a closing brace"),
+            }
+        }
+    }
+    if source.peek()?.is_some() {
+        return source.error(source.position, "expected the end");
+    }
+    count.ok_or_else(|| format!("expected an object with an array of {key:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(text: &str) -> Vec<Token<'_>> {
+        Lexer::new(text).map(|token| token.unwrap().1).collect()
+    }
+
+    #[test]
+    fn lexes() {
+        assert_eq!(
+            tokens(" {\"a\": [1, -2.5e3, true, false, null]}\n"),
+            [
+                Token::OpenBrace,
+                Token::String(Cow::Borrowed("a")),
+                Token::Colon,
+                Token::OpenBracket,
+                Token::Number(1.0),
+                Token::Comma,
+                Token::Number(-2500.0),
+                Token::Comma,
+                Token::True,
+                Token::Comma,
+                Token::False,
+                Token::Comma,
+                Token::Null,
+                Token::CloseBracket,
+                Token::CloseBrace,
+            ]
+        );
+        assert_eq!(
+            tokens(r#""tab\t \"quoted\" \u00e9 \ud83d\ude00""#),
+            [Token::String(Cow::Owned("tab\t \"quoted\" é 😀".to_string()))]
+        );
+        let positions: Vec<_> = Lexer::new("[1, 2]").map(|token| token.unwrap().0).collect();
+        assert_eq!(positions, [0, 1, 2, 4, 5]);
+    }
+
+    #[test]
+    fn parses() {
+        let element = parse(r#"{"pairs": [{"x0": 1.5, "y0": -0.25}, {}], "name": "a\/b", "empty": []}"#).unwrap();
+        let pairs = element.get("pairs").unwrap().as_array().unwrap();
+        assert_eq!(pairs.len(), 2);
+        assert_eq!(pairs[0].get("y0").unwrap().as_f64(), Some(-0.25));
+        assert_eq!(pairs[1], Element::Object(vec![]));
+        assert_eq!(element.get("name").unwrap().as_str(), Some("a/b"));
+        assert_eq!(element.get("empty"), Some(&Element::Array(vec![])));
+        assert_eq!(element.get("missing"), None);
+
+        // Numbers are exact.
+        for number in [0.1, 8.99827409267994, -1.7738807186997743, 1e300, 5e-324] {
+            assert_eq!(parse(&number.to_string()).unwrap(), Element::Number(number));
+        }
+    }
+
+    #[test]
+    fn errors() {
+        for (text, error) in [
+            ("", "expected a value, not the end"),
+            ("[1,]", "at byte 3: expected a value, not CloseBracket"),
+            ("{\"a\" 1}", "at byte 5: expected a colon"),
+            ("{1: 2}", "at byte 1: expected a key"),
+            ("[1] 2", "at byte 4: expected the end"),
+            ("\"abc", "at byte 4: unterminated string"),
+            ("01", "at byte 2: invalid number"),
+            ("1.", "at byte 2: expected digits after the decimal point"),
+            ("tru", "at byte 0: expected a value"),
+            ("\"\\x\"", "at byte 2: invalid escape"),
+            ("@", "at byte 0: unexpected character"),
+            (&"[".repeat(100_000), "at byte 128: nested more than 128 deep"),
+        ] {
+            let actual = parse(text).unwrap_err();
+            assert!(actual.starts_with(error), "{text:?}: {actual}");
+        }
+    }
+
+    /// Stream the array of "a" from the text, read a few bytes at a time.
+    fn stream(text: &str, capacity: usize) -> Result<Vec<Element<'static>>, String> {
+        let reader = std::io::BufReader::with_capacity(capacity, text.as_bytes());
+        let mut elements = vec![];
+        let count = stream_array(reader, "a", |element| {
+            elements.push(owned(element));
+            Ok(())
+        })?;
+        assert_eq!(count, elements.len());
+        Ok(elements)
+    }
+
+    fn owned(element: &Element) -> Element<'static> {
+        match element {
+            Element::Object(members) => Element::Object(
+                members
+                    .iter()
+                    .map(|(key, value)| (Cow::Owned(key.to_string()), owned(value)))
+                    .collect(),
+            ),
+            Element::Array(elements) => Element::Array(elements.iter().map(owned).collect()),
+            Element::String(text) => Element::String(Cow::Owned(text.to_string())),
+            Element::Number(number) => Element::Number(*number),
+            Element::Bool(bool) => Element::Bool(*bool),
+            Element::Null => Element::Null,
+        }
+    }
+
+    #[test]
+    fn streams() {
+        let text = r#" {"b": {"c": [1, "]"]}, "a": [{"x0": 1.5, "s": "\"é,}"}, [2, [3]], -4e1, "a\\", null],
+            "d": 1} "#;
+        let whole = parse(text).unwrap();
+        let expected = whole.get("a").unwrap().as_array().unwrap();
+        for capacity in [1, 2, 3, 7, 1024] {
+            assert_eq!(stream(text, capacity).unwrap(), expected, "{capacity}");
+        }
+        assert_eq!(stream(r#"{"a": []}"#, 1).unwrap(), []);
+        // Only the first array is streamed.
+        assert_eq!(stream(r#"{"a": [1], "a": [2, 3]}"#, 1).unwrap(), [Element::Number(1.0)]);
+
+        for (text, error) in [
+            ("", "expected an object, not the end"),
+            ("[]", "at byte 0: expected an object"),
+            ("{}", "expected an object with an array of \"a\""),
+            (r#"{"a": 1}"#, "at byte 6: expected an array"),
+            (r#"{"a": [1,]}"#, "at byte 9: expected a value"),
+            (r#"{"a": [1, 2"#, "expected a comma or a closing bracket, not the end"),
+            (r#"{"a": [1 2]}"#, "at byte 9: expected the end"),
+            (r#"{"a": [1]} 2"#, "at byte 11: expected the end"),
+            (r#"{"a" [1]}"#, "at byte 5: expected the end"),
+            (r#"{1: [1]}"#, "at byte 2: expected a key"),
+            (r#"{"a": [tru]}"#, "at byte 7: expected a value"),
+            (
+                &format!(r#"{{"a": [{}{}]}}"#, "[".repeat(100_000), "]".repeat(100_000)),
+                "at byte 135: nested more than 128 deep",
+            ),
+        ] {
+            let actual = stream(text, 2).unwrap_err();
+            assert!(actual.starts_with(error), "{text:?}: {actual}");
+        }
+        assert_eq!(
+            stream_array(&b"{\"a\": [1]}"[..], "a", |_| Err("stop".to_string())),
+            Err("stop".to_string())
+        );
+        assert!(stream_array(&b"{\"a\": [\"\xff\"]}"[..], "a", |_| Ok(()))
+            .unwrap_err()
+            .contains("invalid UTF-8"));
+    }
+}
@@ -0,0 +1,187 @@
+//! Emulate the keyboard, through the BIOS keyboard services (INT 16h) and the keyboard controller's ports.
+//!
+//! Programs that wait or poll for keys are driven by the host's standard input or a file of keys. Keys are the
+//! bytes of the machine's console input, which DOS services also read. Each byte is a key on a US keyboard: its
+//! ASCII code, with the scan code of the key that types it. "\n" and "\r" are Enter. Keys are read when the program
+//! asks for them, so IRQ 1 isn't raised.
+//!
+//! Port 0x64's bit 0 is set when a key is waiting, and reading port 0x60 takes the key and returns its scan code,
+//! like the PC/AT's keyboard controller. Reading port 0x60 with no key waiting returns the last scan code.
+
+use std::io::{ErrorKind, Read};
+
+use crate::instruction::{Instruction, Register};
+use crate::sim::{Error, Machine, ZF};
+
+const DATA: u16 = 0x60;
+const STATUS: u16 = 0x64;
+
+const ENTER: u8 = 0x1c;
+// Rows of the US keyboard: the scan code of the first key, and the characters without and with Shift.
+const ROWS: [(u8, &[u8], &[u8]); 4] = [
+    (0x02, b"1234567890-=", b"!@#$%^&*()_+"),
+    (0x10, b"qwertyuiop[]", b"QWERTYUIOP{}"),
+    (0x1e, b"asdfghjkl;'`", b"ASDFGHJKL:\"~"),
+    (0x2b, b"\\zxcvbnm,./", b"|ZXCVBNM<>?"),
+];
+
+/// The scan code and ASCII code of the key that types a byte, or a scan code of 0 if no key types it.
+#[must_use]
+pub fn key(byte: u8) -> (u8, u8) {
+    let scan_code = match byte {
+        0x1b => 0x01,
+        0x08 => 0x0e,
+        b'\t' => 0x0f,
+        b'\r' | b'\n' => return (ENTER, b'\r'),
+        b' ' => 0x39,
+        _ => ROWS
+            .iter()
+            .find_map(|(first, lower, upper)| {
+                let column = lower.iter().chain(upper.iter()).position(|&key| key == byte)?;
+                Some(first + (column % lower.len()) as u8)
+            })
+            .unwrap_or(0),
+    };
+    (scan_code, byte)
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Keyboard {
+    /// The next key, read from the input but not taken.
+    pub(crate) waiting: Option<u8>,
+    /// The scan code of the last key taken.
+    scan_code: u8,
+}
+
+impl Keyboard {
+    /// The next key, without taking it, or `None` if the input ended.
+    fn peek(&mut self, input: &mut dyn Read) -> Option<u8> {
+        if self.waiting.is_none() {
+            let mut byte = [0];
+            self.waiting = loop {
+                match input.read(&mut byte) {
+                    Ok(1) => break Some(byte[0]),
+                    Err(error) if error.kind() == ErrorKind::Interrupted => {}
+                    _ => break None,
+                }
+            };
+        }
+        self.waiting
+    }
+
+    /// Take the next key, or `None` if the input ended.
+    fn take(&mut self, input: &mut dyn Read) -> Option<u8> {
+        let byte = self.peek(input)?;
+        self.waiting = None;
+        self.scan_code = key(byte).0;
+        Some(byte)
+    }
+
+    /// Read a port, or `None` if the port isn't the keyboard controller's.
+    pub fn read(&mut self, port: u16, input: &mut dyn Read) -> Option<u8> {
+        match port {
+            DATA => {
+                self.take(input);
+                Some(self.scan_code)
+            }
+            STATUS => Some(u8::from(self.peek(input).is_some())),
+            _ => None,
+        }
+    }
+}
+
+/// Emulate INT 16h. Functions 00h and 10h wait for a key and take it, 01h and 11h clear ZF if a key is waiting,
+/// and 02h and 12h get the shift flags, which are always clear. AH is the scan code and AL the ASCII code.
+///
+/// # Errors
+///
+/// If the function in AH isn't supported, or the program waits for a key after the input ended.
+pub(crate) fn interrupt(machine: &mut Machine, instruction: &Instruction) -> Result<(), Error> {
+    let keyboard = &mut machine.keyboard;
+    let input = &mut *machine.input;
+    let registers = &mut machine.registers;
+    let word = |byte| {
+        let (scan_code, ascii) = key(byte);
+        u16::from_le_bytes([ascii, scan_code])
+    };
+    match registers.get(Register::Ah) {
+        0x00 | 0x10 => {
+            let byte = keyboard.take(input).ok_or(Error::InputEnded(*instruction))?;
+            registers.set(Register::Ax, word(byte));
+        }
+        0x01 | 0x11 => {
+            let byte = keyboard.peek(input);
+            registers.set_flag(ZF, byte.is_none());
+            if let Some(byte) = byte {
+                registers.set(Register::Ax, word(byte));
+            }
+        }
+        0x02 | 0x12 => registers.set(Register::Al, 0),
+        _ => return Err(Error::Unsupported(*instruction)),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    #[test]
+    fn keys() {
+        assert_eq!(key(b'a'), (0x1e, b'a'));
+        assert_eq!(key(b'A'), (0x1e, b'A'));
+        assert_eq!(key(b'!'), (0x02, b'!'));
+        assert_eq!(key(b'/'), (0x35, b'/'));
+        assert_eq!(key(b'\n'), (0x1c, b'\r'));
+        assert_eq!(key(0x1b), (0x01, 0x1b));
+        assert_eq!(key(0x80), (0, 0x80));
+    }
+
+    #[test]
+    fn services() {
+        let program = [
+            &[0b10110100, 0x01][..],     // mov ah, 1
+            &[0b11001101, 0x16],         // int 0x16
+            &[0b10110100, 0x00],         // mov ah, 0
+            &[0b11001101, 0x16],         // int 0x16
+            &[0b10001001, 0b11_000_011], // mov bx, ax
+            &[0b10110100, 0x01],         // mov ah, 1
+            &[0b11001101, 0x16],         // int 0x16
+            &[0b10110100, 0x00],         // mov ah, 0
+            &[0b11001101, 0x16],         // int 0x16
+        ]
+        .concat();
+        let mut machine = Machine::new();
+        machine.load(0, &program);
+        machine.input = Box::new(Cursor::new(b"q"));
+        for _ in 0..2 {
+            machine.step().unwrap();
+        }
+        assert!(!machine.registers.flag(ZF));
+        assert_eq!(machine.registers.get(Register::Ax), 0x1071);
+        for _ in 0..3 {
+            machine.step().unwrap();
+        }
+        assert_eq!(machine.registers.get(Register::Bx), 0x1071);
+        for _ in 0..2 {
+            machine.step().unwrap();
+        }
+        assert!(machine.registers.flag(ZF));
+        machine.step().unwrap();
+        assert!(matches!(machine.step(), Err(Error::InputEnded(_))));
+    }
+
+    #[test]
+    fn ports() {
+        let mut keyboard = Keyboard::default();
+        let mut input = Cursor::new(b"a\n");
+        assert_eq!(keyboard.read(STATUS, &mut input), Some(1));
+        assert_eq!(keyboard.read(DATA, &mut input), Some(0x1e));
+        assert_eq!(keyboard.read(DATA, &mut input), Some(0x1c));
+        assert_eq!(keyboard.read(STATUS, &mut input), Some(0));
+        assert_eq!(keyboard.read(DATA, &mut input), Some(0x1c));
+        assert_eq!(keyboard.read(0x61, &mut input), None);
+    }
+}
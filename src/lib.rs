@@ -0,0 +1,41 @@
+//! Homework for the [Performance-Aware Programming](https://computerenhance.com) series.
+
+pub mod access;
+pub mod asm;
+pub mod bandwidth;
+pub mod bios;
+pub mod branches;
+pub mod clocks;
+pub mod debug;
+pub mod decode;
+pub mod diff;
+pub mod disasm;
+pub mod dos;
+pub mod encode;
+pub mod haversine;
+pub mod instruction;
+pub mod json;
+pub mod keyboard;
+pub mod math;
+pub mod metrics;
+pub mod os_metrics;
+pub mod os_timer;
+pub mod pages;
+pub mod pic;
+pub mod ports;
+pub mod profile;
+pub mod random;
+pub mod render;
+pub mod repetition;
+pub mod script;
+pub mod sim;
+pub mod simd;
+pub mod snapshot;
+pub mod state;
+pub mod stats;
+#[cfg(feature = "async")]
+pub mod stream;
+pub mod table;
+pub mod text;
+pub mod transcript;
+pub mod video;
@@ -1,555 +1,1669 @@
-use std::cmp::Ordering;
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::env;
-use std::fs::File;
-use std::io::{self, BufReader, Bytes, Read, Write};
-use std::iter::Enumerate;
-use std::time::Instant;
-
-const REG_NAMES: [[&str; 8]; 2] = [
-    ["al", "cl", "dl", "bl", "ah", "ch", "dh", "bh"],
-    ["ax", "cx", "dx", "bx", "sp", "bp", "si", "di"],
-];
-const R_M_NAMES: [&str; 8] = ["bx + si", "bx + di", "bp + si", "bp + di", "si", "di", "bp", "bx"];
-const SEGMENT_NAMES: [&str; 4] = ["es", "cs", "ss", "ds"];
-
-// "N/A" indices are "(not used)" according to the manual.
-const ASCII_ADJUST_NAMES: [&str; 2] = ["aam", "aad"];
-const BINARY_NAMES: [&str; 8] = ["add", "or", "adc", "sbb", "and", "sub", "xor", "cmp"];
-const CALL_NAMES: [&str; 2] = ["call", "jmp"];
-const LOGIC_NAMES: [&str; 8] = ["rol", "ror", "rcl", "rcr", "shl", "shr", "N/A", "sar"];
-const STACK_NAMES: [&str; 2] = ["push", "pop"];
-const UNARY_NAMES: [&str; 4] = ["inc", "dec", "push", "pop"];
-// I don't know what unifies these two groups of instructions, other than their first byte.
-const NAMES_1111011W: [&str; 8] = ["test", "N/A", "not", "neg", "mul", "imul", "div", "idiv"];
-const NAMES_11111111: [&str; 8] = ["inc", "dec", "call", "call", "jmp", "jmp", "push", "N/A"];
-const JUMP2_NAMES: [&str; 4] = ["loopnz", "loopz", "loop", "jcxz"];
-const JUMP4_NAMES: [&str; 16] = [
-    "jo", "jno", "jb", "jnb", "je", "jne", "jbe", "jnbe", "js", "jns", "jp", "jnp", "jl", "jnl", "jle", "jnle",
+use std::fs::{self, File};
+use std::hint::black_box;
+use std::io::{self, BufReader, BufWriter, IsTerminal, Read, Write};
+use std::iter;
+use std::ops::{Deref, DerefMut, Range};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::time::{Duration, Instant};
+
+use homework::access::{self, AccessLog, Heatmap};
+use homework::asm;
+use homework::bandwidth::{self, Access, Pattern};
+use homework::branches;
+use homework::debug::{Control, Debugger, Location};
+use homework::decode;
+use homework::diff;
+use homework::disasm::{self, Labels, Target, Targets, Unnamed};
+use homework::dos;
+use homework::haversine::{self, Kernel};
+use homework::instruction::{Instruction, Register};
+use homework::math::{self, Library};
+use homework::metrics;
+use homework::pages::{PageSize, Pages};
+#[cfg(feature = "profile")]
+use homework::profile;
+use homework::render::{self, Format, Region};
+use homework::repetition::{self, Allocation};
+use homework::script::Script;
+use homework::sim::{self, Error, Machine, Registers, MEMORY_SIZE};
+use homework::simd::Simd;
+use homework::snapshot;
+use homework::state::{self, Assignment, Placement};
+use homework::stats::Statistics;
+use homework::text::Line;
+use homework::time_bandwidth;
+use homework::transcript::{self, Crlf, Reference};
+use homework::video::{self, Refresh};
+use memmap2::Mmap;
+use rayon::prelude::*;
+
+mod args;
+mod memory;
+mod tui;
+
+use args::{fail, Args};
+
+#[cfg(not(test))]
+#[global_allocator]
+static ALLOCATOR: memory::Tracking = memory::Tracking;
+
+const USAGE: &str = "usage:
+    homework <file> [--mmap] [--profile-self] [--max-memory SIZE] [--report-memory]
+    homework <file|directory>... --out directory [--mmap]
+    homework sim <file> [--trace [--no-ip]] [--compare-trace reference.txt]
+        [--render x,y,w,h,stride --out frame.ppm [--render-every N]] [--timer CLOCKS]
+        [--undefined-flags unchanged|hardware|strict] [--memory SIZE] [--addressing wrap|fault] [--keys keys.txt]
+        [--propagate-exit] [--max-instructions N] [--max-cycles N] [--state state.json] [--load file@address]...
+        [--set register=value]... [--snapshot-in snapshot.bin] [--snapshot-out snapshot.bin]
+        [--break address|label]... [--stats] [--hot-loops N] [--reconcile] [--access-log accesses.bin]
+        [--screen exit|live] [--script hooks.rhai]
+    homework debug <file> [--tui] [--timer CLOCKS] [--undefined-flags unchanged|hardware|strict] [--memory SIZE]
+        [--addressing wrap|fault] [--keys keys.txt] [--state state.json] [--load file@address]...
+        [--set register=value]... [--snapshot-in snapshot.bin]
+    homework sim-diff <a> <b> [--lockstep] [--max-instructions N] [--timer CLOCKS]
+        [--undefined-flags unchanged|hardware|strict] [--memory SIZE] [--addressing wrap|fault] [--keys keys.txt]
+        [--state state.json] [--load file@address]... [--set register=value]... [--snapshot-in snapshot.bin]
+    homework heatmap <accesses.bin> <out.ppm|out.bmp> [--width BYTES] [--start address] [--end address]
+    homework asm <file.asm> <out.bin|out.com|out.exe> [--listing out.lst] [--include directory]...
+    homework patch <file.bin> --at address --asm code [--origin address] [--out patched.bin]
+    homework asm-compare <file.asm|directory>... [--nasm program] [--prebuilt] [--include directory]...
+    homework bench <file> [--iterations N]
+    homework bench-branches [--count N] [--pattern always|never|alternating|random] [--probability P] [--seconds S]
+        [--exclude-disturbed]
+    homework bench-cache [--min SIZE] [--max SIZE] [--pattern dependent|strided] [--stride BYTES] [--seconds S]
+        [--out table.csv] [--exclude-disturbed]
+    homework bench-memory [--sizes SIZE,...] [--widths BYTES,...] [--access read|write|non-temporal]
+        [--seconds S] [--exclude-disturbed]
+    homework bench-read <file> [--seconds S] [--allocation fresh|reuse] [--large-pages] [--exclude-disturbed]
+    homework gen-haversine --pairs N --seed S [--mode uniform|cluster] [--out pairs.json]
+        [--answers answers.f64]
+    homework haversine <pairs.json> [answers.f64] [--math std|custom] [--degree N] [--simd sse2|avx]
+        [--threads N] [--stream] [--profile] [--profile-out report.csv|report.json]
+    homework math-check [--function sin|cos|asin|sqrt] [--degree N] [--intervals N] [--samples N]
+        [--out errors.csv]";
+
+// The options that set up a machine, for both simulating and debugging.
+const MACHINE_OPTIONS: [&str; 9] = [
+    "timer",
+    "undefined-flags",
+    "memory",
+    "addressing",
+    "keys",
+    "state",
+    "load",
+    "set",
+    "snapshot-in",
 ];
 
-fn next_u8(iterator: &mut Enumerate<Bytes<BufReader<File>>>) -> u8 {
-    let byte = iterator.next().unwrap().1.unwrap();
-    u8::from_le_bytes([byte])
+// The number of instructions shown when a simulation exceeds a limit.
+const RECENT_INSTRUCTIONS: usize = 10;
+// The number of differences shown when two simulations diverge.
+const SHOWN_DIFFERENCES: usize = 20;
+// The bytes per row of a heatmap, by default.
+const HEATMAP_WIDTH: usize = 256;
+// The number of instructions between checks for changes to the screen, when it is drawn live.
+const SCREEN_INTERVAL: usize = 1000;
+// The number of times that a file is disassembled to measure the disassembler, by default.
+const BENCH_ITERATIONS: usize = 10;
+
+// The seconds that the repetition tester keeps trying after a test's fastest trial, by default.
+const REPEAT_SECONDS: f64 = 10.0;
+// The same for the memory tests, which are many and short.
+const MEMORY_SECONDS: f64 = 1.0;
+// The sizes of the buffers that the memory tests read and write, by default, from the L1 cache to main memory.
+const MEMORY_SIZES: &str = "16K,256K,8M,1G";
+// The kilometers that distances with custom math functions can be from the answers.
+const MATH_TOLERANCE: f64 = 1e-6;
+// The intervals of each function's inputs that the math check reports, and the inputs that it tries in each, by default.
+const MATH_INTERVALS: u32 = 16;
+const MATH_SAMPLES: u32 = 100_000;
+// The branches of each trial of the branch prediction test, by default.
+const BRANCHES: usize = 1 << 20;
+// The smallest and largest working sets of the cache tests, by default.
+const CACHE_MIN: &str = "4K";
+const CACHE_MAX: &str = "256M";
+// The loads of each trial of the dependent cache test.
+const CHASE_STEPS: usize = 1 << 20;
+// The bytes, at least, that each trial of the strided cache test passes over.
+const STRIDE_BYTES: usize = 64 << 20;
+
+/// Read a file, or map it into memory if `mmap`, so that the operating system pages it in as it's disassembled.
+/// Files that can't be mapped are read.
+fn input(path: &Path, mmap: bool) -> io::Result<Box<dyn Deref<Target = [u8]>>> {
+    let mut file = File::open(path)?;
+    if mmap {
+        // SAFETY: The map is only read, and the file isn't expected to change while it's disassembled.
+        match unsafe { Mmap::map(&file) } {
+            Ok(map) => return Ok(Box::new(map)),
+            Err(error) => eprintln!("warning: can't map {}, so reading it: {error}", path.display()),
+        }
+    }
+    let mut bytes = vec![];
+    file.read_to_end(&mut bytes)?;
+    Ok(Box::new(bytes))
 }
 
-fn next_i8(iterator: &mut Enumerate<Bytes<BufReader<File>>>) -> i8 {
-    let byte = iterator.next().unwrap().1.unwrap();
-    i8::from_le_bytes([byte])
+/// Disassemble each file on a thread pool, to a file of the same name with an .asm extension in `out`, and write a
+/// line for each, in order, and a summary. Files with the same name, from different directories, fail, rather than
+/// overwrite each other. Return whether every file was disassembled.
+fn batch(paths: &[PathBuf], out: &Path, mmap: bool, summary: &mut impl Write) -> bool {
+    let start = Instant::now();
+    let mut names = HashMap::new();
+    for name in paths.iter().filter_map(|path| path.file_name()) {
+        *names.entry(name).or_insert(0) += 1;
+    }
+    let results: Vec<Result<(usize, PathBuf), String>> = paths
+        .par_iter()
+        .map(|path| {
+            let name = path.file_name().ok_or("not a file")?;
+            let mut asm = name.to_os_string();
+            asm.push(".asm");
+            let asm = out.join(asm);
+            if names[name] > 1 {
+                return Err(format!("{}: another file has the same name", asm.display()));
+            }
+            let bytes = input(path, mmap).map_err(|error| error.to_string())?;
+            let file = File::create(&asm).map_err(|error| format!("{}: {error}", asm.display()))?;
+            let mut writer = BufWriter::new(file);
+            disasm::run(&bytes, None, &mut writer);
+            writer.flush().map_err(|error| format!("{}: {error}", asm.display()))?;
+            Ok((bytes.len(), asm))
+        })
+        .collect();
+
+    let (mut size, mut failed) = (0, 0);
+    for (path, result) in paths.iter().zip(&results) {
+        match result {
+            Ok((bytes, asm)) => {
+                size += bytes;
+                writeln!(summary, "{}: {bytes} bytes -> {}", path.display(), asm.display()).unwrap();
+            }
+            Err(error) => {
+                failed += 1;
+                writeln!(summary, "{}: error: {error}", path.display()).unwrap();
+            }
+        }
+    }
+    let elapsed = start.elapsed();
+    write!(summary, "{} files, {size} bytes in {elapsed:.2?}", paths.len()).unwrap();
+    if failed > 0 {
+        write!(summary, ", {failed} failed").unwrap();
+    }
+    writeln!(summary).unwrap();
+    failed == 0
 }
 
-fn next_i16(iterator: &mut Enumerate<Bytes<BufReader<File>>>, w: bool) -> i16 {
-    let byte = iterator.next().unwrap().1.unwrap();
-    if w {
-        i16::from_le_bytes([byte, iterator.next().unwrap().1.unwrap()])
-    } else {
-        i16::from(i8::from_le_bytes([byte]))
+/// The files to disassemble: the files given, and the binaries in the directories given, which have no extension or
+/// a .com or .bin extension.
+fn binaries(paths: &[String]) -> Vec<PathBuf> {
+    let mut binaries = vec![];
+    for path in paths {
+        if !Path::new(path).is_dir() {
+            binaries.push(PathBuf::from(path));
+            continue;
+        }
+        let entries = fs::read_dir(path).unwrap_or_else(|error| fail(format!("{path}: {error}")));
+        let mut files: Vec<PathBuf> = entries
+            .filter_map(|entry| Some(entry.ok()?.path()))
+            .filter(|path| path.is_file())
+            .filter(|path| {
+                path.extension().is_none_or(|extension| {
+                    extension.eq_ignore_ascii_case("com") || extension.eq_ignore_ascii_case("bin")
+                })
+            })
+            .collect();
+        files.sort();
+        binaries.extend(files);
     }
+    binaries
 }
 
-fn disassemble_r_m(iterator: &mut Enumerate<Bytes<BufReader<File>>>, w: usize, m0d: u8, r_m: usize) -> String {
-    let disp = match m0d {
-        // Memory mode. No displacement follows.*
-        0b00 => {
-            // Direct address. "Except when R/M = 110, then 16-bit displacement follows."
-            if r_m == 0b110 {
-                return format!("[{}]", next_i16(iterator, true));
-            }
-            0
-        }
-        // Memory mode. 8-bit displacement follows.
-        0b01 => next_i16(iterator, false),
-        // Memory mode. 16-bit displacement follows.
-        0b10 => next_i16(iterator, true),
-        // Register mode. No displacement follows.
-        0b11 => return REG_NAMES[w][r_m].to_string(),
-        _ => unreachable!(),
+/// Disassemble a file in one thread, like [`run`], and write how long each phase took to `report`. Decoding is
+/// measured by a pass that only decodes. The passes that name targets and write the text decode again, so their times
+/// include it.
+fn profile<W: Write>(path: &Path, mmap: bool, stdout: W, report: &mut impl Write) -> io::Result<()> {
+    let start = Instant::now();
+    let bytes = input(path, mmap)?;
+    let read = start.elapsed();
+
+    let start = Instant::now();
+    disasm::disassemble(&bytes, 0..bytes.len(), &mut Unnamed, &mut |_| {});
+    let decode = start.elapsed();
+
+    let start = Instant::now();
+    let mut labels = Labels::default();
+    disasm::disassemble(&bytes, 0..bytes.len(), &mut labels, &mut |_| {});
+    let first = start.elapsed();
+
+    let start = Instant::now();
+    let mut writer = BufWriter::new(stdout);
+    writeln!(writer, "bits 16")?;
+    disasm::disassemble(&bytes, 0..bytes.len(), &mut labels, &mut |text| {
+        writer.write_fmt(text).unwrap()
+    });
+    let second = start.elapsed();
+
+    let start = Instant::now();
+    writer.flush()?;
+    let flush = start.elapsed();
+
+    let phases = [
+        ("read", read),
+        ("decode", decode),
+        ("labels", first),
+        ("format and write", second),
+        ("flush", flush),
+    ];
+    let total = phases.iter().map(|(_, elapsed)| *elapsed).sum::<Duration>();
+    for (name, elapsed) in phases {
+        let percent = 100.0 * elapsed.as_secs_f64() / total.as_secs_f64();
+        writeln!(report, "{name:>16}: {elapsed:>10.2?} {percent:5.1}%")?;
+    }
+    writeln!(report, "{:>16}: {total:>10.2?}", "total")
+}
+
+fn simulate(args: &Args) {
+    let region = args.parsed::<Region>("render");
+    let out = args.value("out");
+    let every = args.parsed::<usize>("render-every");
+    if region.is_some() && out.is_none() {
+        fail("--render requires --out");
+    }
+    if every.is_some() && region.is_none() {
+        fail("--render-every requires --render");
+    }
+    if every == Some(0) {
+        fail("--render-every must be positive");
+    }
+    let max_instructions = args.parsed::<usize>("max-instructions");
+    let max_cycles = args.parsed::<u64>("max-cycles");
+    let refresh = args.parsed::<Refresh>("screen");
+    let render = |machine: &Machine, path: &str| {
+        if let Some(region) = region {
+            let mut file = File::create(path).unwrap_or_else(|error| fail(format!("{path}: {error}")));
+            render::write(&mut file, &machine.memory, &region, Format::from_path(path)).unwrap();
+        }
     };
 
-    match disp.cmp(&0) {
-        Ordering::Greater => format!("[{} + {}]", R_M_NAMES[r_m], disp),
-        Ordering::Less => format!("[{} - {}]", R_M_NAMES[r_m], -disp),
-        Ordering::Equal => format!("[{}]", R_M_NAMES[r_m]),
+    let (mut machine, program) = load(args, args.positional(0, "file"));
+    if args.value("keys").is_none() {
+        machine.input = Box::new(io::stdin());
     }
-}
+    let hot_loops = args.parsed::<usize>("hot-loops");
+    if args.flag("stats") || hot_loops.is_some() || args.flag("reconcile") {
+        machine.statistics = Some(Statistics::default());
+    }
+    if let Some(path) = args.value("access-log") {
+        let file = File::create(path).unwrap_or_else(|error| fail(format!("{path}: {error}")));
+        machine.accesses = Some(AccessLog::new(Box::new(BufWriter::new(file))));
+    }
+    let flush_accesses = |machine: &Machine| {
+        if let (Some(accesses), Some(path)) = (&machine.accesses, args.value("access-log")) {
+            accesses
+                .flush()
+                .unwrap_or_else(|error| fail(format!("{path}: {error}")));
+        }
+    };
+    let mut script = args.value("script").map(|path| {
+        let source = fs::read_to_string(path).unwrap_or_else(|error| fail(format!("{path}: {error}")));
+        Script::new(&source).unwrap_or_else(|error| fail(format!("{path}: {error}")))
+    });
+    let labels: BTreeMap<_, _> = decode::labels(&machine.memory[program.clone()])
+        .into_iter()
+        .map(|(offset, label)| (program.start + offset, label))
+        .collect();
+    let breakpoints: Vec<_> = args
+        .values("break")
+        .map(|location| {
+            location
+                .parse::<Location>()
+                .and_then(|location| location.resolve(&labels, &machine.registers))
+                .unwrap_or_else(|error| fail(format!("invalid --break {location:?}: {error}")))
+        })
+        .collect();
+
+    let mut reference = args.value("compare-trace").map(|path| {
+        let text = fs::read_to_string(path).unwrap_or_else(|error| fail(format!("{path}: {error}")));
+        Reference::new(&text)
+    });
+    // Transcripts leave out IP like the reference, if there is one.
+    let ip = reference.as_ref().map_or(!args.flag("no-ip"), |reference| reference.ip);
+    let limited = max_instructions.is_some() || max_cycles.is_some();
+    let transcribe = args.flag("trace") || reference.is_some() || limited;
+    let mut compare = |output: &[u8]| {
+        if let Some(reference) = &mut reference {
+            reference
+                .compare(output)
+                .unwrap_or_else(|mismatch| mismatched(&mismatch));
+        }
+    };
 
-fn run<W: Write>(filename: &str, mut stdout: W) {
-    let file = BufReader::new(File::open(filename).unwrap());
-    let mut iterator = file.bytes().enumerate();
-    // Insert assembly instructions at byte indices.
-    let mut instructions = BTreeMap::new();
-    // Track the byte index of each label.
-    let mut labels = HashMap::new();
-    // Segment override.
-    let mut segment = "";
-    // Override the order of operands to avoid "instruction is not lockable".
-    let mut locked = false;
-    let mut release_lock = false;
-
-    while let Some((position, Ok(byte1))) = iterator.next() {
-        // Uncomment to just print bytes.
-        // println!("{byte1:8b}");
-        // continue;
-
-        if locked {
-            release_lock = true;
-        }
-
-        // Next bytes are: MOD REG R/M | (DISP-LO) | (DISP-HI)
-        match byte1 {
-              0b00_000_0_00..=0b00_000_0_11 // 00 ADD 0 D W
-            | 0b00_001_0_00..=0b00_001_0_11 // 00 OR  0 D W
-            | 0b00_010_0_00..=0b00_010_0_11 // 00 ADC 0 D W
-            | 0b00_011_0_00..=0b00_011_0_11 // 00 SBB 0 D W
-            | 0b00_100_0_00..=0b00_100_0_11 // 00 AND 0 D W
-            | 0b00_101_0_00..=0b00_101_0_11 // 00 SUB 0 D W
-            | 0b00_110_0_00..=0b00_110_0_11 // 00 XOR 0 D W
-            | 0b00_111_0_00..=0b00_111_0_11 // 00 CMP 0 D W
-            | 0b1000010_0..=0b1000010_1     // 10 000 1 0 W TEST
-            | 0b1000011_0..=0b1000011_1     // 10 000 1 1 W XCHG
-            | 0b100010_00..=0b100010_11     // 10 001 0 D W MOV
-            | 0b100011_00 | 0b100011_10     // 10 001 S D 0 MOV
-            | 0b11000100                    // LES
-            | 0b11000101                    // LDS
-            | 0b10001101                    // LEA
-            => {
-                let (d, w) = match byte1 {
-                    // LES, LDS and LEA use REG for the destination and are wide.
-                    0b11000100 | 0b11000101 | 0b10001101 => (1, 1),
-                    // MOV with a segment register is wide.
-                    0b100011_00 | 0b100011_10 => ((byte1 >> 1) & 1, 1),
-                    _ => ((byte1 >> 1) & 1, (byte1 & 1) as usize),
-                };
+    let mut stdout = io::stdout().lock();
+    if args.flag("trace") {
+        let filename = args.positional(0, "file");
+        let name = Path::new(filename)
+            .file_stem()
+            .map_or_else(|| filename.into(), |stem| stem.to_string_lossy());
+        transcript::write_header(&mut Crlf(&mut stdout), &name).unwrap();
+    }
+    let mut count = 0;
+    let mut frame = 0;
+    let mut line = Line::new();
+    let mut recent = VecDeque::with_capacity(RECENT_INSTRUCTIONS);
+    let mut limit = None;
+    let mut shown = vec![0; video::SIZE];
+    let mut redraw = |machine: &Machine, stdout: &mut io::StdoutLock| {
+        let screen = video::screen(machine);
+        if screen != shown {
+            video::redraw(stdout, &screen).unwrap();
+            shown = screen;
+        }
+    };
+    // Stop when CS:IP leaves the program, the program exits, or HLT stops the processor for good.
+    while program.contains(&machine.instruction_address()) && machine.exit_code.is_none() {
+        if max_instructions.is_some_and(|max| count >= max) {
+            limit = Some(format!("{count} instructions"));
+            break;
+        }
+        if max_cycles.is_some_and(|max| machine.cycles >= max) {
+            limit = Some(format!("{} cycles", machine.cycles));
+            break;
+        }
+        let address = machine.instruction_address();
+        if breakpoints.contains(&address) {
+            let (cs, ip) = (machine.registers.get(Register::Cs), machine.registers.ip);
+            match labels.get(&address) {
+                Some(label) => writeln!(stdout, "breakpoint at {cs:04x}:{ip:04x} ({label})").unwrap(),
+                None => writeln!(stdout, "breakpoint at {cs:04x}:{ip:04x}").unwrap(),
+            }
+            break;
+        }
 
-                // MOD REG R/M
-                let byte2 = iterator.next().unwrap().1.unwrap();
-                let m0d = byte2 >> 6; // mod
-                let reg = ((byte2 >> 3) & 0b111) as usize;
-                let r_m = (byte2 & 0b111) as usize;
-
-                let op_text = match byte1 {
-                    0b11000100 => "les",
-                    0b11000101 => "lds",
-                    0b10001101 => "lea",
-                    0b1000010_0..=0b1000010_1 => "test",
-                    0b1000011_0..=0b1000011_1 => "xchg",
-                    0b100010_00..=0b100010_11 | 0b100011_00 | 0b100011_10 => "mov",
-                    _ => BINARY_NAMES[((byte1 >> 3) & 0b111) as usize],
-                };
-                let reg_text = if byte1 == 0b100011_00 || byte1 == 0b100011_10 {
-                    SEGMENT_NAMES[reg]
-                } else {
-                    REG_NAMES[w][reg]
-                };
-                let mut r_m_text = disassemble_r_m(&mut iterator, w, m0d, r_m);
-                if !segment.is_empty() {
-                    r_m_text = format!("{segment}:{r_m_text}");
-                    segment = "";
+        let before = machine.registers;
+        let result = match &mut script {
+            Some(script) => script.step(&mut machine),
+            None => machine.step(),
+        };
+        match result {
+            Ok(instruction) => {
+                if !machine.output.is_empty() {
+                    stdout.write_all(&machine.output).unwrap();
+                    stdout.flush().unwrap();
+                    machine.output.clear();
                 }
-
-                // 1 = the REG field identifies the destination operand.
-                // 0 = the REG field identifies the source operand.
-                if d == 1 && !locked {
-                    instructions.insert(position, format!("{op_text} {reg_text}, {r_m_text}\n"));
-                } else {
-                    instructions.insert(position, format!("{op_text} {r_m_text}, {reg_text}\n"));
+                if transcribe {
+                    line.clear();
+                    sim::write_trace(&mut line, &instruction, &before, &machine.registers, ip).unwrap();
+                    if args.flag("trace") {
+                        Crlf(&mut stdout).write_all(line.as_bytes()).unwrap();
+                    }
+                    compare(line.as_bytes());
+                    if limited {
+                        // Reuse the oldest line's buffer, once there are enough.
+                        let mut kept = if recent.len() == RECENT_INSTRUCTIONS {
+                            recent.pop_front().unwrap()
+                        } else {
+                            vec![]
+                        };
+                        kept.clear();
+                        kept.extend_from_slice(line.as_bytes());
+                        recent.push_back(kept);
+                    }
                 }
-            },
-
-            // Next bytes are: MOD OP R/M | (DISP-LO) | (DISP-HI) | (DATA) | (DATA if cond = 1)
-            //
-            // TEST "Immediate data and register/memory" has the same first byte as NEG, etc.
-            // so we need to put them all in this branch - but the latter do not have DATA bytes.
-            //
-            // It's OK to treat AND, OR, XOR as having an S bit (of 0). 1 is "(not used)" according to the manual.
-              0b100000_00..=0b100000_11 // 100000 S W ADC, ADD, AND, CMP, OR, SBB, SUB, XOR
-            | 0b1100011_0..=0b1100011_1 // 110001 1 W MOV
-            // The following do not have DATA bytes, except TEST.
-            | 0b10001111                // 100011 1 1 POP
-            | 0b110100_00..=0b110100_11 // 110100 V W SHL SHR SAR ROL ROR RCL RCR
-            | 0b1111011_0..=0b1111011_1 // 111101 1 W NEG, MUL, IMUL, DIV, IDIV, NOT, TEST
-            | 0b1111111_0..=0b1111111_1 // 111111 1 W INC, DEC
-                                        // 111111 1 1 PUSH, CALL, JMP
-            => {
-                let group = byte1 >> 2;
-                let s_v = (byte1 >> 1) & 1; // s or v
-                let w = (byte1 & 1) as usize;
-
-                // MOD OP R/M
-                let byte2 = iterator.next().unwrap().1.unwrap();
-                let m0d = byte2 >> 6; // mod
-                let op = ((byte2 >> 3) & 0b111) as usize;
-                let r_m = (byte2 & 0b111) as usize;
-
-                let mov_test = group == 0b110001 || group == 0b111101 && (byte2 >> 3).trailing_zeros() >= 3;
-
-                let op_text = match group {
-                    0b100011 => "pop",
-                    0b110001 => "mov",
-                    0b100000 => BINARY_NAMES[op],
-                    0b110100 => LOGIC_NAMES[op],
-                    0b111101 => NAMES_1111011W[op],
-                    0b111111 => NAMES_11111111[op],
-                    _ => unreachable!(),
-                };
-                let unit_text = if w == 1 { "word" } else { "byte" };
-                let mut r_m_text = disassemble_r_m(&mut iterator, w, m0d, r_m);
-                if !segment.is_empty() {
-                    r_m_text = format!("{segment}:{r_m_text}");
-                    segment = "";
+                count += 1;
+                if refresh == Some(Refresh::Live) && count % SCREEN_INTERVAL == 0 {
+                    redraw(&machine, &mut stdout);
                 }
-
-                // Binary instructions (MOV, TEST, ADD, etc.) have DATA bytes.
-                if mov_test || group == 0b100000 {
-                    // data | data if w = 1 for MOV and TEST. data | data if sw = 01 for ADD, etc.
-                    let data = next_i16(&mut iterator, (mov_test || s_v == 0) && w == 1);
-                    instructions.insert(position, format!("{op_text} {r_m_text}, {unit_text} {data}\n"));
-                // Logic instructions.
-                } else if group == 0b110100 {
-                    // 0 = Shift/rotate count is one. 1 = Shift/rotate count is specified in CL register.
-                    let count = if s_v == 0 { "1" } else { "cl" };
-                    instructions.insert(position, format!("{op_text} {unit_text} {r_m_text}, {count}\n"));
-                // Jump instructions. "Indirect intersegment."
-                } else if byte1 == 0b11111111 && (op == 0b011 || op == 0b101) {
-                    instructions.insert(position, format!("{op_text} {unit_text} far {r_m_text}\n"));
-                } else {
-                    instructions.insert(position, format!("{op_text} {unit_text} {r_m_text}\n"));
+                if let (Some(every), Some(out)) = (every, out) {
+                    if count % every == 0 {
+                        render(&machine, &render::frame_path(out, frame));
+                        frame += 1;
+                    }
                 }
-            },
-
-            // MOV Immediate to register. First byte: 1011 W REG
-            0b1011_0_000..=0b1011_1_111 => {
-                let w = ((byte1 >> 3) & 1) as usize;
-                let reg = (byte1 & 0b111) as usize;
-
-                // data | data if w = 1
-                let data = next_i16(&mut iterator, w == 1);
-
-                let reg_text = REG_NAMES[w][reg];
-
-                instructions.insert(position, format!("mov {reg_text}, {data}\n"));
-            },
-
-            // Accumulator. Next bytes are either: DATA | DATA if W = 1, ADDR-LO | ADDR-HI, DATA-8.
-              0b00_000_10_0..=0b00_000_10_1 // 00 ADD 1 0 W
-            | 0b00_001_10_0..=0b00_001_10_1 // 00 OR  1 0 W
-            | 0b00_010_10_0..=0b00_010_10_1 // 00 ADC 1 0 W
-            | 0b00_011_10_0..=0b00_011_10_1 // 00 SBB 1 0 W
-            | 0b00_100_10_0..=0b00_100_10_1 // 00 AND 1 0 W
-            | 0b00_101_10_0..=0b00_101_10_1 // 00 SUB 1 0 W
-            | 0b00_110_10_0..=0b00_110_10_1 // 00 XOR 1 0 W
-            | 0b00_111_10_0..=0b00_111_10_1 // 00 CMP 1 0 W
-            | 0b101000_00..=0b101000_11     // 101000 E W MOV
-            | 0b1010100_0..=0b1010100_1     // 101010 0 W TEST
-            | 0b111001_00..=0b111001_11     // 111001 E W IN, OUT
-            => {
-                let mov = byte1 >> 2 == 0b101000;
-                let in_out = byte1 >> 2 == 0b111001;
-                let e = (byte1 >> 1) & 1 == 0; // opposite of d
-                let w = byte1 & 1 == 1;
-
-                let data = if in_out {
-                    // data-8
-                    i16::from(next_u8(&mut iterator))
-                } else {
-                    // addr-lo | addr-hi or data | data if w = 1
-                    next_i16(&mut iterator, mov || w)
-                };
-
-                let op_text = match byte1 >> 1 {
-                    0b1010000 | 0b1010001 => "mov",
-                    0b1010100 => "test",
-                    0b1110010 => "in",
-                    0b1110011 => "out",
-                    _ => BINARY_NAMES[((byte1 >> 3) & 0b111) as usize],
-                };
-                let acc_text = if w { "ax" } else { "al" };
-                // MOV does "memory to accumulator", others do "immediate to accumulator".
-                let data_text = if mov { format!("[{data}]") } else { data.to_string() };
-
-                if e {
-                    instructions.insert(position, format!("{op_text} {acc_text}, {data_text}\n"));
-                } else {
-                    instructions.insert(position, format!("{op_text} {data_text}, {acc_text}\n"));
+                if script.as_ref().is_some_and(Script::stopped) {
+                    let (cs, ip) = (machine.registers.get(Register::Cs), machine.registers.ip);
+                    writeln!(stdout, "stopped by the script at {cs:04x}:{ip:04x}").unwrap();
+                    break;
                 }
-            },
-
-            // PUSH POP INC DEC Register. One byte: 010 OP REG
-            0b010_00_000..=0b010_11_111 => {
-                let op = ((byte1 >> 3) & 0b11) as usize;
-                let reg = (byte1 & 0b111) as usize;
-
-                let op_text = UNARY_NAMES[op];
-                let reg_text = REG_NAMES[1][reg];
-
-                instructions.insert(position, format!("{op_text} {reg_text}\n"));
-            },
-
-            // PUSH POP Segment register. One byte.
-              0b000_00_11_0..=0b000_00_11_1 // 000 ES 11 OP
-            | 0b000_01_11_0..=0b000_01_11_1 // 000 CS 11 OP
-            | 0b000_10_11_0..=0b000_10_11_1 // 000 SS 11 OP
-            | 0b000_11_11_0..=0b000_11_11_1 // 000 DS 11 OP
-            => {
-                let sg = ((byte1 >> 3) & 0b11) as usize;
-                let op = (byte1 & 1) as usize;
-
-                let sg_text = SEGMENT_NAMES[sg];
-                let op_text = STACK_NAMES[op];
-
-                instructions.insert(position, format!("{op_text} {sg_text}\n"));
-            },
+            }
+            Err(Error::Halted) => break,
+            Err(error) => {
+                flush_accesses(&machine);
+                eprintln!("error: {error}");
+                process::exit(1);
+            }
+        }
+    }
+    flush_accesses(&machine);
+    match refresh {
+        Some(Refresh::Exit) => {
+            let colors = stdout.is_terminal();
+            video::write(&mut stdout, &video::screen(&machine), colors).unwrap();
+        }
+        Some(Refresh::Live) => redraw(&machine, &mut stdout),
+        None => {}
+    }
 
-            // SEGMENT. One byte.
-              0b001_00_110 // 001 ES 110
-            | 0b001_01_110 // 001 CS 110
-            | 0b001_10_110 // 001 SS 110
-            | 0b001_11_110 // 001 DS 110
-            => {
-                let sg = ((byte1 >> 3) & 0b11) as usize;
+    // A snapshot saved at a limit resumes where the simulation stopped.
+    if let Some(path) = args.value("snapshot-out") {
+        let file = File::create(path).unwrap_or_else(|error| fail(format!("{path}: {error}")));
+        let mut out = BufWriter::new(file);
+        snapshot::save(&machine, &mut out)
+            .and_then(|()| out.flush())
+            .unwrap_or_else(|error| fail(format!("{path}: {error}")));
+    }
+    // Statistics are written before the registers at a limit, which are written to standard error.
+    let statistics = |stdout: &mut io::StdoutLock| {
+        if let Some(statistics) = &machine.statistics {
+            if args.flag("stats") {
+                writeln!(stdout).unwrap();
+                statistics.write(stdout, &labels).unwrap();
+            }
+            if let Some(count) = hot_loops {
+                writeln!(stdout).unwrap();
+                statistics.write_loops(stdout, &labels, count).unwrap();
+            }
+            if args.flag("reconcile") {
+                writeln!(stdout).unwrap();
+                statistics.write_reconciliation(stdout, &labels).unwrap();
+            }
+        }
+    };
+    if let Some(limit) = limit {
+        statistics(&mut stdout);
+        stdout.flush().unwrap();
+        exceeded(&limit, &recent, &machine.registers);
+    }
 
-                segment = SEGMENT_NAMES[sg];
-            },
+    let mut registers = vec![];
+    sim::write_registers(&mut registers, &machine.registers, ip).unwrap();
+    if args.flag("trace") {
+        // A transcript ends with a blank line.
+        let mut out = Crlf(&mut stdout);
+        writeln!(out).unwrap();
+        out.write_all(&registers).unwrap();
+        writeln!(out).unwrap();
+    } else {
+        stdout.write_all(&registers).unwrap();
+    }
+    if let Some(reference) = &mut reference {
+        reference
+            .compare(b"\n")
+            .and_then(|()| reference.compare(&registers))
+            .and_then(|()| reference.finish())
+            .unwrap_or_else(|mismatch| mismatched(&mismatch));
+    }
+    statistics(&mut stdout);
+
+    if let Some(out) = out {
+        match every {
+            // Render the final state, unless the last frame already did.
+            Some(every) if count % every != 0 => render(&machine, &render::frame_path(out, frame)),
+            Some(_) => {}
+            None => render(&machine, out),
+        }
+    }
 
-            // XCHG Accumulator. One byte: 10010 REG
-            0b10010_000..=0b10010_111 => {
-                let reg = (byte1 & 0b111) as usize;
+    if args.flag("propagate-exit") {
+        stdout.flush().unwrap();
+        process::exit(machine.exit_code.map_or(0, i32::from));
+    }
+}
 
-                let reg_text = REG_NAMES[1][reg];
+/// Report that a simulation exceeded a limit, with its last instructions and its registers, and exit.
+fn exceeded(limit: &str, recent: &VecDeque<Vec<u8>>, registers: &Registers) -> ! {
+    let mut stderr = io::stderr().lock();
+    writeln!(
+        stderr,
+        "error: stopped after {limit}, with the last {} instructions:",
+        recent.len()
+    )
+    .unwrap();
+    for line in recent {
+        stderr.write_all(line).unwrap();
+    }
+    writeln!(stderr).unwrap();
+    sim::write_registers(&mut stderr, registers, true).unwrap();
+    process::exit(1);
+}
 
-                instructions.insert(position, format!("xchg ax, {reg_text}\n"));
-            },
+/// Report where a simulation's transcript differs from the reference, and exit.
+fn mismatched(mismatch: &transcript::Mismatch) -> ! {
+    io::stdout().flush().unwrap();
+    eprintln!("error: {mismatch}");
+    process::exit(1);
+}
 
-            // IN OUT Accumulator. One byte: 111011 OUT W
-            0b111011_00..=0b111011_11 => {
-                let out = (byte1 >> 1) & 1 == 1;
-                let w = byte1 & 1 == 1;
+/// Load a program into a new machine, and set its state from the options. Returns the program's addresses.
+fn load(args: &Args, filename: &str) -> (Machine, Range<usize>) {
+    let timer = args.parsed::<u64>("timer");
+    if timer == Some(0) {
+        fail("--timer must be positive");
+    }
+    let file = fs::read(filename).unwrap_or_else(|error| fail(format!("{filename}: {error}")));
+    let size = args.value("memory").map_or(MEMORY_SIZE, |text| {
+        sim::memory_size(text).unwrap_or_else(|error| fail(format!("invalid --memory: {error}")))
+    });
+    let mut machine = Machine::with_memory(size);
+    // .COM and .EXE files are loaded like DOS. Other files are loaded at address 0.
+    let extension = filename
+        .rsplit_once('.')
+        .map(|(_, extension)| extension.to_ascii_lowercase());
+    let program = match extension.as_deref() {
+        Some("com") => dos::load_com(&mut machine, &file),
+        Some("exe") => dos::load_exe(&mut machine, &file),
+        _ if file.len() > machine.memory.len() => Err("program does not fit in memory".to_string()),
+        _ => {
+            machine.load(0, &file);
+            Ok(0..file.len())
+        }
+    }
+    .unwrap_or_else(|error| fail(format!("{filename}: {error}")));
+    // A snapshot replaces the loaded program's state, and the program's addresses still say where to stop.
+    if let Some(path) = args.value("snapshot-in") {
+        let file = File::open(path).unwrap_or_else(|error| fail(format!("{path}: {error}")));
+        snapshot::restore(&mut machine, &mut BufReader::new(file))
+            .unwrap_or_else(|error| fail(format!("{path}: {error}")));
+    }
+    // The state file comes first, so that --load and --set can override it.
+    if let Some(path) = args.value("state") {
+        let json = fs::read_to_string(path).unwrap_or_else(|error| fail(format!("{path}: {error}")));
+        let directory = Path::new(path).parent().unwrap_or_else(|| Path::new(""));
+        state::apply_json(&mut machine, &json, directory).unwrap_or_else(|error| fail(format!("{path}: {error}")));
+    }
+    for placement in args.values("load") {
+        let placement = placement
+            .parse::<Placement>()
+            .unwrap_or_else(|error| fail(format!("invalid --load: {error}")));
+        placement.apply(&mut machine).unwrap_or_else(|error| fail(error));
+    }
+    for assignment in args.values("set") {
+        let assignment = assignment
+            .parse::<Assignment>()
+            .unwrap_or_else(|error| fail(format!("invalid --set: {error}")));
+        assignment.apply(&mut machine.registers);
+    }
+    machine.timer = timer;
+    machine.undefined_flags = args.parsed("undefined-flags").unwrap_or_default();
+    machine.addressing = args.parsed("addressing").unwrap_or_default();
+    // The keys are the console input, instead of standard input.
+    if let Some(path) = args.value("keys") {
+        let file = File::open(path).unwrap_or_else(|error| fail(format!("{path}: {error}")));
+        machine.input = Box::new(BufReader::new(file));
+    }
+    (machine, program)
+}
 
-                let acc_text = if w { "ax" } else { "al" };
+/// Read debugger commands from standard input. An empty line repeats the last command.
+fn debug(args: &Args) {
+    let (machine, program) = load(args, args.positional(0, "file"));
+    let mut debugger = Debugger::new(machine, program);
+    if args.flag("tui") {
+        tui::run(debugger).unwrap_or_else(|error| fail(error));
+        return;
+    }
+    let mut stdout = io::stdout().lock();
+    let mut last = String::new();
+    let mut lines = io::stdin().lines();
+    loop {
+        write!(stdout, "(sim) ").unwrap();
+        stdout.flush().unwrap();
+        let Some(line) = lines.next() else {
+            writeln!(stdout).unwrap();
+            break;
+        };
+        let line = line.unwrap_or_else(|error| fail(error));
+        if !line.trim().is_empty() {
+            last = line;
+        }
+        if last.is_empty() {
+            continue;
+        }
+        if debugger.command(&last, &mut stdout).unwrap() == Control::Quit {
+            break;
+        }
+    }
+}
 
-                if out {
-                    instructions.insert(position, format!("out dx, {acc_text}\n"));
-                } else {
-                    instructions.insert(position, format!("in {acc_text}, dx\n"));
+/// Run two programs with the same options, and report the first difference in their state: after each instruction
+/// if `--lockstep`, or else when both stop.
+fn sim_diff(args: &Args) {
+    let names = [args.positional(0, "a"), args.positional(1, "b")];
+    let max_instructions = args.parsed::<usize>("max-instructions");
+    let [(mut a, a_program), (mut b, b_program)] = names.map(|name| load(args, name));
+    let ignored = [a_program.clone(), b_program.clone()];
+    let mut stdout = io::stdout().lock();
+
+    if args.flag("lockstep") {
+        let mut count = 0;
+        loop {
+            if max_instructions.is_some_and(|max| count >= max) {
+                writeln!(stdout, "no differences after {count} instructions").unwrap();
+                return;
+            }
+            let instructions = [
+                advance(names[0], &mut a, &a_program),
+                advance(names[1], &mut b, &b_program),
+            ];
+            if instructions == [None, None] {
+                break;
+            }
+            count += 1;
+            let differences = diff::differences(&a, &b, &ignored, true);
+            if !differences.is_empty() {
+                writeln!(stdout, "diverged at instruction {count}:").unwrap();
+                for (name, instruction) in names.iter().zip(instructions) {
+                    match instruction {
+                        Some(instruction) => writeln!(stdout, "{name}: {instruction}").unwrap(),
+                        None => writeln!(stdout, "{name}: (stopped)").unwrap(),
+                    }
                 }
-            },
-
-            // RET RETF. Fixed byte plus i16 data.
-            0b11000010 | 0b11001010 => {
-                let retf = (byte1 >> 3) & 1 == 1;
-                let data = next_i16(&mut iterator, true);
-
-                let op_text = if retf { "retf" } else { "ret" };
-
-                instructions.insert(position, format!("{op_text} {data}\n"));
-            },
-
-            // INT. Fixed byte plus u8 data.
-            0b11001101 => {
-                let data = next_u8(&mut iterator);
-
-                instructions.insert(position, format!("int {data}\n"));
-            },
-
-            // REP. Fixed byte plus lookup table.
-            0b11110011 => {
-                // 1010 OP W
-                let byte2 = iterator.next().unwrap().1.unwrap();
-                let op = (byte2 >> 1) & 0b111;
-                let w = byte2 & 1 == 1;
-
-                let op_text = match op {
-                    0b010 => "movs",
-                    0b011 => "cmps",
-                    0b101 => "stos",
-                    0b110 => "lods",
-                    0b111 => "scas",
-                    _ => unreachable!(),
-                };
-                let unit_text = if w { "w" } else { "b" };
+                diverged(&mut stdout, &differences);
+            }
+        }
+        writeln!(stdout, "no differences after {count} instructions").unwrap();
+        return;
+    }
 
-                instructions.insert(position, format!("rep {op_text}{unit_text}\n"));
-            },
+    for (name, machine, program) in [(names[0], &mut a, &a_program), (names[1], &mut b, &b_program)] {
+        let mut count = 0;
+        loop {
+            if max_instructions.is_some_and(|max| count >= max) {
+                eprintln!("error: {name}: stopped after {count} instructions");
+                process::exit(1);
+            }
+            if advance(name, machine, program).is_none() {
+                break;
+            }
+            count += 1;
+        }
+        writeln!(stdout, "{name}: {count} instructions, {} cycles", machine.cycles).unwrap();
+    }
+    let differences = diff::differences(&a, &b, &ignored, false);
+    if !differences.is_empty() {
+        diverged(&mut stdout, &differences);
+    }
+    writeln!(stdout, "no differences").unwrap();
+}
 
-              0b11101011                // JMP Direct within segment-short
-            | 0b111000_00..=0b111000_11 // 111000 OP JUMP
-            | 0b0111_0000..=0b0111_1111 // 0111   OP JUMP
-            => {
-                let group = byte1 >> 2;
+/// Execute an instruction, unless the program has stopped. Exit if the instruction fails.
+fn advance(name: &str, machine: &mut Machine, program: &Range<usize>) -> Option<Instruction> {
+    if !program.contains(&machine.instruction_address()) || machine.exit_code.is_some() {
+        return None;
+    }
+    match machine.step() {
+        Ok(instruction) => Some(instruction),
+        Err(Error::Halted) => None,
+        Err(error) => {
+            eprintln!("error: {name}: {error}");
+            process::exit(1);
+        }
+    }
+}
 
-                let ip_inc8 = next_i8(&mut iterator);
+/// Report the differences between two simulations, and exit.
+fn diverged(stdout: &mut io::StdoutLock, differences: &[diff::Difference]) -> ! {
+    for difference in differences.iter().take(SHOWN_DIFFERENCES) {
+        writeln!(stdout, "  {difference}").unwrap();
+    }
+    if differences.len() > SHOWN_DIFFERENCES {
+        writeln!(stdout, "  and {} more", differences.len() - SHOWN_DIFFERENCES).unwrap();
+    }
+    stdout.flush().unwrap();
+    process::exit(1);
+}
 
-                let op_text = match group {
-                    0b111010 => "jmp",
-                    0b111000 => JUMP2_NAMES[(byte1 & 0b11) as usize],
-                    _ => JUMP4_NAMES[(byte1 & 0b1111) as usize],
-                };
+/// Draw the memory accesses in a log as an image. By default, the image ends at the last row with an access.
+fn heatmap(args: &Args) {
+    let path = args.positional(0, "accesses.bin");
+    let out = args.positional(1, "out");
+    let file = File::open(path).unwrap_or_else(|error| fail(format!("{path}: {error}")));
+    let accesses = access::read(&mut BufReader::new(file)).unwrap_or_else(|error| fail(format!("{path}: {error}")));
+    let width = args.parsed::<usize>("width").unwrap_or(HEATMAP_WIDTH);
+    if width == 0 {
+        fail("--width must be positive");
+    }
+    let address = |name| {
+        args.value(name).map(|text| {
+            state::address_of(text)
+                .ok()
+                .filter(|&address| address <= MEMORY_SIZE)
+                .unwrap_or_else(|| fail(format!("invalid --{name} {text:?}")))
+        })
+    };
+    let heatmap = Heatmap::new(&accesses, MEMORY_SIZE);
+    let start = address("start").unwrap_or(0);
+    let end = address("end").unwrap_or_else(|| heatmap.end(start, width));
+    if end <= start {
+        fail("no accesses to draw");
+    }
+    let file = File::create(out).unwrap_or_else(|error| fail(format!("{out}: {error}")));
+    let mut writer = BufWriter::new(file);
+    heatmap
+        .write(&mut writer, start, end, width, Format::from_path(out))
+        .and_then(|()| writer.flush())
+        .unwrap_or_else(|error| fail(format!("{out}: {error}")));
+}
 
-                // This instruction is 2 bytes.
-                let target = position.checked_add_signed(2 + ip_inc8 as isize).unwrap();
-                let length = labels.len();
-                let label = labels.entry(target).or_insert_with(|| format!("label{length}"));
+/// Generate the pairs of points for the haversine program, to a file or to stdout, and their distances to a file.
+fn generate(args: &Args) {
+    let Some(pairs) = args.parsed::<u64>("pairs") else {
+        fail("--pairs is needed");
+    };
+    let Some(seed) = args.parsed::<u64>("seed") else {
+        fail("--seed is needed");
+    };
+    let mode = args.parsed::<haversine::Mode>("mode").unwrap_or_default();
+    let create = |path: &str| {
+        let file = File::create(path).unwrap_or_else(|error| fail(format!("{path}: {error}")));
+        BufWriter::new(file)
+    };
+    let mut json: BufWriter<Box<dyn Write>> = match args.value("out") {
+        Some(out) => BufWriter::new(Box::new(create(out))),
+        None => BufWriter::new(Box::new(io::stdout().lock())),
+    };
+    let mut answers: Box<dyn Write> = match args.value("answers") {
+        Some(path) => Box::new(create(path)),
+        None => Box::new(io::sink()),
+    };
+    let average = haversine::generate(&mut json, &mut answers, pairs, seed, mode)
+        .and_then(|average| json.flush().and(answers.flush()).map(|()| average))
+        .unwrap_or_else(|error| fail(error));
+    eprintln!("mode: {mode:?}, seed: {seed}, pairs: {pairs}, average: {average}");
+}
 
-                instructions.insert(position, format!("{op_text} {label} ; {ip_inc8} short\n"));
-            },
+/// Compute the average haversine distance of the pairs in a file, like the course's reference program, and compare it
+/// with the answers, if any.
+///
+/// With `--profile` or `--profile-out`, report how long each part took: reading the file, parsing the JSON, converting
+/// it to pairs, computing their distances on `--threads` threads, and summing them. What the threads took together
+/// shows how well computing scales.
+///
+/// `--stream` computes the distances a batch at a time as the file is read and parsed, for files that don't fit in
+/// memory.
+///
+/// Exits with an error status if any distance differs from its answer. With `--math custom` or `--simd`, which computes
+/// several distances at a time with the custom functions, or with `--stream`, which sums the average differently,
+/// distances can be up to a millimeter from their answers.
+fn compute(args: &Args) {
+    let custom = math::Custom::new(args.parsed::<u32>("degree").unwrap_or(math::DEFAULT_DEGREE));
+    let simd = args.parsed::<Simd>("simd");
+    if let Some(simd) = simd.filter(|simd| !simd.is_available()) {
+        fail(format!("this CPU doesn't have {simd}"));
+    }
+    // SIMD needs the custom functions.
+    let library = args
+        .parsed::<Library>("math")
+        .unwrap_or(if simd.is_some() { Library::Custom } else { Library::Std });
+    let (kernel, mut tolerance) = match (library, simd) {
+        (Library::Std, None) => (Kernel::Scalar(&math::Std), 0.0),
+        (Library::Std, Some(_)) => fail("--simd computes with the custom math functions, not std"),
+        (Library::Custom, None) => (Kernel::Scalar(&custom), MATH_TOLERANCE),
+        (Library::Custom, Some(simd)) => (Kernel::Simd(simd, &custom), MATH_TOLERANCE),
+    };
+    let threads = args.parsed::<usize>("threads").unwrap_or(1);
+    if threads == 0 {
+        fail("--threads has to be positive");
+    }
+    let streaming = args.flag("stream");
+    if streaming {
+        if threads > 1 {
+            fail("--stream computes on one thread");
+        }
+        tolerance = MATH_TOLERANCE;
+    }
+    let profiling = args.flag("profile") || args.value("profile-out").is_some();
+    #[cfg(not(feature = "profile"))]
+    if profiling {
+        fail("profiling needs homework to be built with the profile feature");
+    }
+    #[cfg(feature = "profile")]
+    if profiling {
+        profile::begin();
+    }
+    let path = args.positional(0, "pairs.json");
+    let answers = args.positionals().get(1).map(|path| {
+        let bytes = fs::read(path).unwrap_or_else(|error| fail(format!("{path}: {error}")));
+        let (distances, answer) = haversine::answers(&bytes).unwrap_or_else(|error| fail(format!("{path}: {error}")));
+        (path, distances, answer)
+    });
+    let (size, count, average, validation) = if streaming {
+        let file = File::open(path).unwrap_or_else(|error| fail(format!("{path}: {error}")));
+        let size = file.metadata().map_or(0, |metadata| metadata.len());
+        let mut validator = haversine::Validator::default();
+        let (count, average) = {
+            time_bandwidth!("stream", size);
+            haversine::stream(BufReader::new(file), kernel, |_, distances| {
+                // Pairs without answers are counted, and reported at the end.
+                if let Some((_, expected, _)) = &answers {
+                    validator.check(distances, expected.get(validator.count()..).unwrap_or_default());
+                }
+                Ok(())
+            })
+            .unwrap_or_else(|error| fail(format!("{path}: {error}")))
+        };
+        let validation = answers.as_ref().map(|(path, distances, answer)| {
+            if count != distances.len() {
+                fail(format!("{path}: {count} pairs, but {} answers", distances.len()));
+            }
+            validator.finish(average, *answer)
+        });
+        (size, count, average, validation)
+    } else {
+        let json = {
+            time_bandwidth!("read", fs::metadata(path).map_or(0, |metadata| metadata.len()));
+            fs::read_to_string(path).unwrap_or_else(|error| fail(format!("{path}: {error}")))
+        };
+        let pairs = haversine::parse(&json).unwrap_or_else(|error| fail(format!("{path}: {error}")));
+        let average = haversine::average(&pairs, kernel, threads);
+        let validation = answers.as_ref().map(|(path, distances, answer)| {
+            haversine::validate(&pairs, kernel, distances, average, *answer)
+                .unwrap_or_else(|error| fail(format!("{path}: {error}")))
+        });
+        (json.len() as u64, pairs.len(), average, validation)
+    };
+    println!("Input size: {size}");
+    println!("Pair count: {count}");
+    println!("Haversine sum: {average:.16}");
+
+    // Whether the results are close enough to the answers.
+    let mut exact = true;
+    if let (Some((_, distances, answer)), Some(validation)) = (&answers, validation) {
+        println!();
+        println!("Validation:");
+        println!("Reference sum: {answer:.16}");
+        println!("Difference: {:.16}", validation.average);
+        println!(
+            "Pair differences: max {:.16}, mean {:.16}",
+            validation.max, validation.mean
+        );
+        if let Some((index, distance)) = validation.first {
+            println!(
+                "First different pair: {index} ({distance:.16}, not {:.16})",
+                distances[index]
+            );
+        }
+        exact = validation.is_exact() || validation.is_within(tolerance);
+    }
+    #[cfg(feature = "profile")]
+    if profiling {
+        let report = profile::end();
+        if args.flag("profile") {
+            println!();
+            print!("{report}");
+        }
+        if let Some(out) = args.value("profile-out") {
+            let mut writer = BufWriter::new(File::create(out).unwrap_or_else(|error| fail(format!("{out}: {error}"))));
+            report
+                .export(&mut writer, profile::Export::from_path(out))
+                .and_then(|()| writer.flush())
+                .unwrap_or_else(|error| fail(format!("{out}: {error}")));
+        }
+    }
+    if !exact {
+        process::exit(1);
+    }
+}
 
-            // CALL JMP Direct within segment. 1110100 OP
-            0b1110100_0 | 0b1110100_1 => {
-                let op = (byte1 & 1) as usize;
+/// Compare the custom math functions with the standard library's over the inputs that the haversine formula gives
+/// them, and write the largest errors in each interval as CSV, to a file or to stdout, and the largest of each function.
+fn math_check(args: &Args) {
+    let custom = math::Custom::new(args.parsed::<u32>("degree").unwrap_or(math::DEFAULT_DEGREE));
+    let intervals = args.parsed::<u32>("intervals").unwrap_or(MATH_INTERVALS);
+    let samples = args.parsed::<u32>("samples").unwrap_or(MATH_SAMPLES);
+    if intervals == 0 || samples < 2 {
+        fail("--intervals has to be positive, and --samples at least 2");
+    }
+    let functions = args
+        .parsed::<math::Function>("function")
+        .map_or_else(|| math::Function::ALL.to_vec(), |function| vec![function]);
+    let mut out: BufWriter<Box<dyn Write>> = match args.value("out") {
+        Some(path) => BufWriter::new(Box::new(
+            File::create(path).unwrap_or_else(|error| fail(format!("{path}: {error}"))),
+        )),
+        None => BufWriter::new(Box::new(io::stdout().lock())),
+    };
 
-                let ip_inc = next_i16(&mut iterator, true);
+    let mut rows = vec!["function,start,end,max_ulps,max_error,worst".to_string()];
+    for function in functions {
+        let precisions = math::check(&custom, &math::Std, function, intervals, samples);
+        for precision in &precisions {
+            rows.push(format!(
+                "{function},{},{},{},{:e},{}",
+                precision.start, precision.end, precision.max_ulps, precision.max_error, precision.worst
+            ));
+        }
+        let worst = precisions
+            .iter()
+            .max_by_key(|precision| precision.max_ulps)
+            .copied()
+            .unwrap_or_default();
+        let max_error = precisions
+            .iter()
+            .map(|precision| precision.max_error)
+            .fold(0.0, f64::max);
+        eprintln!(
+            "{function}: max {} ulps at {}, max error {max_error:e}",
+            worst.max_ulps, worst.worst
+        );
+    }
+    writeln!(out, "{}", rows.join("\n"))
+        .and_then(|()| out.flush())
+        .unwrap_or_else(|error| fail(error));
+}
 
-                let op_text = CALL_NAMES[op];
+/// Targets that aren't named, counting the instructions as they're decoded. A run of padding counts as one.
+struct Counted(usize);
 
-                // This instruction is 3 bytes.
-                let target = position.checked_add_signed(3 + ip_inc as isize).unwrap();
-                let length = labels.len();
-                let label = labels.entry(target).or_insert_with(|| format!("label{length}"));
+impl Targets for Counted {
+    fn start(&mut self, _position: usize) -> Option<Target> {
+        None
+    }
 
-                instructions.insert(position, format!("{op_text} {label} ; {ip_inc}\n"));
-            },
+    fn reference(&mut self, target: usize) -> Target {
+        Target::Offset(target)
+    }
 
-            // CALL JMP Direct intersegment.
-            0b1_001_1010 | 0b1_110_1010 => {
-                // LSB bit 5 also works to map 0 to CALL and 1 to JMP.
-                let op = ((byte1 >> 6) & 1) as usize;
+    fn boundary(&mut self, _position: usize) {
+        self.0 += 1;
+    }
+}
 
-                let ip = next_i16(&mut iterator, true);
-                let cs = next_i16(&mut iterator, true);
+/// Disassemble a file repeatedly, like without options but without writing the text, and print the times and the
+/// throughput.
+fn bench(args: &Args) {
+    let path = args.positional(0, "file");
+    let bytes = fs::read(path).unwrap_or_else(|error| fail(format!("{path}: {error}")));
+    let iterations = args.parsed::<usize>("iterations").unwrap_or(BENCH_ITERATIONS);
+    if iterations == 0 {
+        fail("--iterations must be positive");
+    }
+    let mut counted = Counted(0);
+    disasm::disassemble(&bytes, 0..bytes.len(), &mut counted, &mut |_| {});
+    let count = counted.0;
+    let seconds: Vec<f64> = (0..iterations)
+        .map(|_| {
+            let start = Instant::now();
+            disasm::run(black_box(&bytes), None, io::sink());
+            start.elapsed().as_secs_f64()
+        })
+        .collect();
+
+    #[allow(clippy::cast_precision_loss)]
+    let (size, count, n) = (bytes.len() as f64, count as f64, iterations as f64);
+    let min = seconds.iter().copied().fold(f64::INFINITY, f64::min);
+    let average = seconds.iter().sum::<f64>() / n;
+    let variance = seconds.iter().map(|s| (s - average).powi(2)).sum::<f64>() / n;
+    println!("{path}: {size} bytes, {count} instructions, {iterations} iterations");
+    println!(
+        "time: min {:.3}ms, average {:.3}ms, variance {:.6}ms²",
+        min * 1e3,
+        average * 1e3,
+        variance * 1e6
+    );
+    println!(
+        "throughput: best {:.1} MB/s, average {:.1} MB/s",
+        size / min / 1e6,
+        size / average / 1e6
+    );
+    println!(
+        "instructions: best {:.2} million/s, average {:.2} million/s",
+        count / min / 1e6,
+        count / average / 1e6
+    );
+}
 
-                let op_text = CALL_NAMES[op];
+/// Repeat reading a file, and parsing it if it's haversine pairs, until each test stops getting faster, and print
+/// their times, bandwidths and page faults, with a fresh buffer for each trial and with one that's reused, unless
+/// `--allocation` picks one. With `--large-pages`, files are read into buffers of 4k pages and of large pages. Trials
+/// that the operating system switched out or moved to another CPU are counted, or left out with `--exclude-disturbed`,
+/// like in the other benchmarks.
+fn bench_read(args: &Args) {
+    let path = args.positional(0, "file");
+    let seconds = args.parsed::<f64>("seconds").unwrap_or(REPEAT_SECONDS);
+    let try_for = Duration::try_from_secs_f64(seconds).unwrap_or_else(|error| fail(format!("--seconds: {error}")));
+    let allocations = args.parsed::<Allocation>("allocation").map_or_else(
+        || vec![Allocation::Fresh, Allocation::Reuse],
+        |allocation| vec![allocation],
+    );
+    let size = fs::metadata(path)
+        .unwrap_or_else(|error| fail(format!("{path}: {error}")))
+        .len();
+    let length = usize::try_from(size).unwrap_or_else(|_| fail(format!("{path}: too large")));
+    let frequency = metrics::estimate_cpu_frequency(metrics::CALIBRATION);
+    let tester = repetition::Tester {
+        exclude_disturbed: args.flag("exclude-disturbed"),
+        ..repetition::Tester::new(size, frequency, try_for)
+    };
+    let run = |name: &str, allocation: Allocation, test: &mut dyn FnMut(&mut repetition::Trial)| {
+        let results = tester
+            .run(test)
+            .unwrap_or_else(|error| fail(format!("{name}: {error}")));
+        println!("--- {name}, {allocation} buffer ({} trials) ---", results.count);
+        print!("{results}");
+    };
 
-                instructions.insert(position, format!("{op_text} {cs}:{ip}\n"));
-            },
+    // With large pages, the buffers that are read into are mapped in pages of each size, to compare them.
+    let page_sizes = if args.flag("large-pages") {
+        vec![Some(PageSize::Small), Some(PageSize::Large)]
+    } else {
+        vec![None]
+    };
+    let allocate = |page_size: Option<PageSize>| -> Box<dyn DerefMut<Target = [u8]>> {
+        match page_size {
+            None => Box::new(vec![0; length]),
+            Some(page_size) => Box::new(
+                Pages::new(length, page_size).unwrap_or_else(|error| fail(format!("{page_size} pages: {error}"))),
+            ),
+        }
+    };
 
-            // Two fixed bytes.
-            0b1101010_0 | 0b1101010_1 => {
-                let op = (byte1 & 1) as usize;
+    let json = fs::read_to_string(path)
+        .ok()
+        .filter(|json| haversine::parse(json).is_ok());
+    for allocation in allocations {
+        // It always allocates.
+        if allocation == Allocation::Fresh {
+            run("fs::read", allocation, &mut |trial| {
+                trial.begin();
+                let bytes = fs::read(path).unwrap_or_else(|error| fail(format!("{path}: {error}")));
+                trial.end();
+                trial.count_bytes(bytes.len());
+            });
+        }
+        for &page_size in &page_sizes {
+            let name = page_size.map_or_else(
+                || "File::read_exact".to_string(),
+                |page_size| format!("File::read_exact into {page_size} pages"),
+            );
+            let mut reused = allocate(page_size);
+            run(&name, allocation, &mut |trial| {
+                let mut file = File::open(path).unwrap_or_else(|error| fail(format!("{path}: {error}")));
+                // Its pages of zeros aren't written until it's read into.
+                let mut fresh;
+                let buffer = match allocation {
+                    Allocation::Fresh => {
+                        fresh = allocate(page_size);
+                        &mut fresh
+                    }
+                    Allocation::Reuse => &mut reused,
+                };
+                trial.begin();
+                file.read_exact(buffer)
+                    .unwrap_or_else(|error| fail(format!("{path}: {error}")));
+                trial.end();
+                trial.count_bytes(buffer.len());
+            });
+        }
+        if let Some(json) = &json {
+            let mut reused = Vec::new();
+            run("haversine::parse", allocation, &mut |trial| {
+                let mut fresh = Vec::new();
+                let pairs = match allocation {
+                    Allocation::Fresh => &mut fresh,
+                    Allocation::Reuse => &mut reused,
+                };
+                trial.begin();
+                haversine::parse_into(json, pairs).unwrap_or_else(|error| fail(format!("{path}: {error}")));
+                trial.end();
+                black_box(pairs);
+                trial.count_bytes(json.len());
+            });
+        }
+    }
+}
 
-                let byte2 = iterator.next().unwrap().1.unwrap();
+/// Repeat reading and writing buffers of each size, a number of bytes at a time, with regular and with non-temporal
+/// writes, until each test stops getting faster, and print their times and bandwidths.
+fn bench_memory(args: &Args) {
+    let seconds = args.parsed::<f64>("seconds").unwrap_or(MEMORY_SECONDS);
+    let try_for = Duration::try_from_secs_f64(seconds).unwrap_or_else(|error| fail(format!("--seconds: {error}")));
+    let list = |name, default: &str| -> Vec<usize> {
+        let text = args.value(name).unwrap_or(default);
+        text.split(',')
+            .map(|item| memory::parse(item).unwrap_or_else(|error| fail(format!("invalid --{name}: {error}"))))
+            .collect()
+    };
+    let sizes = list("sizes", MEMORY_SIZES);
+    let widths = args.value("widths").map(|_| list("widths", ""));
+    let accesses = args.parsed::<Access>("access").map_or_else(
+        || vec![Access::Read, Access::Write, Access::NonTemporal],
+        |access| vec![access],
+    );
+    let frequency = metrics::estimate_cpu_frequency(metrics::CALIBRATION);
+
+    for size in sizes {
+        let mut buffer =
+            Pages::new(size, PageSize::Small).unwrap_or_else(|error| fail(format!("{size} bytes: {error}")));
+        // Fault the pages in, so that the tests only measure the memory.
+        buffer.fill(1);
+        for &access in &accesses {
+            // The widths that were asked for, that this access can be made in.
+            let supported = bandwidth::widths(access);
+            let widths = widths.as_ref().map_or(supported.clone(), |widths| {
+                widths
+                    .iter()
+                    .copied()
+                    .filter(|width| supported.contains(width))
+                    .collect()
+            });
+            for width in widths {
+                let bytes = bandwidth::run(&mut buffer, width, access).unwrap_or_else(|error| fail(error));
+                let tester = repetition::Tester {
+                    exclude_disturbed: args.flag("exclude-disturbed"),
+                    ..repetition::Tester::new(bytes as u64, frequency, try_for)
+                };
+                let results = tester
+                    .run(|trial| {
+                        trial.begin();
+                        let bytes = bandwidth::run(&mut buffer, width, access).unwrap_or_else(|error| fail(error));
+                        trial.end();
+                        trial.count_bytes(bytes);
+                    })
+                    .unwrap_or_else(|error| fail(error));
+                println!(
+                    "--- {access} {size} bytes, {width} at a time ({} trials) ---",
+                    results.count
+                );
+                print!("{results}");
+            }
+        }
+    }
+}
 
-                let op_text = ASCII_ADJUST_NAMES[op];
+/// Walk working sets of each power of two from `--min` to `--max` bytes a cache line at a time, until each stops
+/// getting faster, and write each size's fastest latency and bandwidth as CSV.
+fn bench_cache(args: &Args) {
+    let seconds = args.parsed::<f64>("seconds").unwrap_or(MEMORY_SECONDS);
+    let try_for = Duration::try_from_secs_f64(seconds).unwrap_or_else(|error| fail(format!("--seconds: {error}")));
+    let size = |name, default| {
+        memory::parse(args.value(name).unwrap_or(default))
+            .unwrap_or_else(|error| fail(format!("invalid --{name}: {error}")))
+    };
+    let (min, max) = (
+        size("min", CACHE_MIN).max(bandwidth::CACHE_LINE),
+        size("max", CACHE_MAX),
+    );
+    let pattern = args.parsed::<Pattern>("pattern").unwrap_or(Pattern::Dependent);
+    let stride = args.parsed::<usize>("stride").unwrap_or(bandwidth::CACHE_LINE);
+    if stride == 0 || stride > min.next_power_of_two() {
+        fail("--stride has to be positive, and no more than --min");
+    }
+    let frequency = metrics::estimate_cpu_frequency(metrics::CALIBRATION);
+    let mut out: BufWriter<Box<dyn Write>> = match args.value("out") {
+        Some(path) => BufWriter::new(Box::new(
+            File::create(path).unwrap_or_else(|error| fail(format!("{path}: {error}"))),
+        )),
+        None => BufWriter::new(Box::new(io::stdout().lock())),
+    };
 
-                if byte2 == 0b00001010 {
-                    instructions.insert(position, format!("{op_text}\n"));
-                } else {
-                    unreachable!();
-                }
-            },
-
-            // One fixed byte.
-            _ => {
-                let op_text = match byte1 {
-                    0b11010111 => "xlat\n",
-                    0b10011111 => "lahf\n",
-                    0b10011110 => "sahf\n",
-                    0b10011100 => "pushf\n",
-                    0b10011101 => "popf\n",
-                    0b00110111 => "aaa\n",
-                    0b00100111 => "daa\n",
-                    0b00111111 => "aas\n",
-                    0b00101111 => "das\n",
-                    0b10011000 => "cbw\n",
-                    0b10011001 => "cwd\n",
-                    0b11000011 => "ret\n",
-                    0b11001011 => "retf\n",
-                    0b11001100 => "int3\n",
-                    0b11001110 => "into\n",
-                    0b11001111 => "iret\n",
-                    0b11111000 => "clc\n",
-                    0b11110101 => "cmc\n",
-                    0b11111001 => "stc\n",
-                    0b11111100 => "cld\n",
-                    0b11111101 => "std\n",
-                    0b11111010 => "cli\n",
-                    0b11111011 => "sti\n",
-                    0b11110100 => "hlt\n",
-                    0b10011011 => "wait\n",
-                    0b11110000 => {
-                        locked = true;
-                        "lock "
+    let mut buffer = Pages::new(max, PageSize::Small).unwrap_or_else(|error| fail(format!("{max} bytes: {error}")));
+    buffer.fill(1);
+    let mut rows = vec!["size,pattern,accesses,ticks,ns_per_access,gb_per_s".to_string()];
+    let mut size = min.next_power_of_two();
+    while size <= max {
+        let working = &mut buffer[..size];
+        // Enough accesses that the smallest sizes take long enough to time.
+        let passes = (STRIDE_BYTES / size).max(1);
+        let (accesses, bytes) = match pattern {
+            Pattern::Dependent => {
+                bandwidth::link(working, size as u64).unwrap_or_else(|error| fail(error));
+                (CHASE_STEPS, CHASE_STEPS * bandwidth::CACHE_LINE)
+            }
+            Pattern::Strided => (passes * (size / stride), passes * (size / stride) * stride),
+        };
+        let tester = repetition::Tester {
+            exclude_disturbed: args.flag("exclude-disturbed"),
+            ..repetition::Tester::new(bytes as u64, frequency, try_for)
+        };
+        let results = tester
+            .run(|trial| {
+                trial.begin();
+                match pattern {
+                    Pattern::Dependent => {
+                        black_box(bandwidth::chase(working, CHASE_STEPS));
+                    }
+                    Pattern::Strided => {
+                        bandwidth::stride(working, stride, passes).unwrap_or_else(|error| fail(error));
                     }
-                    _ => "",
-                };
-                if op_text.is_empty() {
-                    instructions.insert(position, format!("; {byte1:8b}\n")); // debugging
-                } else {
-                    instructions.insert(position, op_text.to_string());
                 }
-            }
+                trial.end();
+                trial.count_bytes(bytes);
+            })
+            .unwrap_or_else(|error| fail(error));
+        let seconds = metrics::seconds(results.min.ticks, frequency);
+        #[allow(clippy::cast_precision_loss)]
+        let (nanoseconds, gigabytes) = (
+            seconds * 1e9 / accesses as f64,
+            bytes as f64 / seconds / (1024.0 * 1024.0 * 1024.0),
+        );
+        rows.push(format!(
+            "{size},{pattern},{accesses},{},{nanoseconds:.3},{gigabytes:.3}",
+            results.min.ticks
+        ));
+        size *= 2;
+    }
+    writeln!(out, "{}", rows.join("\n"))
+        .and_then(|()| out.flush())
+        .unwrap_or_else(|error| fail(error));
+}
+
+/// Repeat branching on a buffer of bytes in each pattern until each stops getting faster, and print how long each
+/// branch took.
+fn bench_branches(args: &Args) {
+    let seconds = args.parsed::<f64>("seconds").unwrap_or(MEMORY_SECONDS);
+    let try_for = Duration::try_from_secs_f64(seconds).unwrap_or_else(|error| fail(format!("--seconds: {error}")));
+    let count = args.parsed::<usize>("count").unwrap_or(BRANCHES);
+    let probability = args.parsed::<f64>("probability").unwrap_or(0.5);
+    if !(0.0..=1.0).contains(&probability) {
+        fail("--probability has to be from 0 to 1");
+    }
+    let patterns = args.parsed::<branches::Pattern>("pattern").map_or_else(
+        || {
+            use branches::Pattern::{Alternating, Always, Never, Random};
+            vec![Always, Never, Alternating, Random]
+        },
+        |pattern| vec![pattern],
+    );
+    let frequency = metrics::estimate_cpu_frequency(metrics::CALIBRATION);
+    let tester = repetition::Tester {
+        exclude_disturbed: args.flag("exclude-disturbed"),
+        ..repetition::Tester::new(count as u64, frequency, try_for)
+    };
+
+    let mut buffer = vec![0; count];
+    for pattern in patterns {
+        branches::fill(&mut buffer, pattern, probability, count as u64);
+        let results = tester
+            .run(|trial| {
+                trial.begin();
+                black_box(branches::run(&buffer));
+                trial.end();
+                trial.count_bytes(buffer.len());
+            })
+            .unwrap_or_else(|error| fail(error));
+        let name = match pattern {
+            branches::Pattern::Random => format!("random with probability {probability}"),
+            pattern => pattern.to_string(),
         };
+        println!("--- {name}, {count} branches ({} trials) ---", results.count);
+        print!("{results}");
+        #[allow(clippy::cast_precision_loss)]
+        let ticks = results.min.ticks as f64 / count.max(1) as f64;
+        println!(
+            "Per branch: {ticks:.3} ticks ({:.3}ns)",
+            ticks * 1e9 / frequency.max(1) as f64
+        );
+    }
+}
 
-        if release_lock {
-            locked = false;
-            release_lock = false;
-        }
+fn assemble(args: &Args) {
+    let path = args.positional(0, "file.asm");
+    let out = args.positional(1, "out.bin");
+    let source = fs::read_to_string(path).unwrap_or_else(|error| fail(format!("{path}: {error}")));
+    let format = asm::Format::from_path(out);
+    // Included files are found beside the source first.
+    let includes = asm::Includes {
+        directory: Path::new(path).parent().unwrap_or(Path::new("")).to_path_buf(),
+        paths: args.values("include").map(PathBuf::from).collect(),
+    };
+    let binary = if let Some(listing_path) = args.value("listing") {
+        let (binary, listing) =
+            asm::listing(&source, format, &includes).unwrap_or_else(|error| fail(error.report(path)));
+        fs::write(listing_path, listing).unwrap_or_else(|error| fail(format!("{listing_path}: {error}")));
+        binary
+    } else {
+        asm::assemble_as(&source, format, &includes).unwrap_or_else(|error| fail(error.report(path)))
+    };
+    fs::write(out, binary).unwrap_or_else(|error| fail(format!("{out}: {error}")));
+}
+
+fn patch(args: &Args) {
+    let path = args.positional(0, "file.bin");
+    let number = |name| {
+        args.value(name)
+            .map(|text| state::number(text).unwrap_or_else(|error| fail(format!("--{name}: {error}"))))
+    };
+    let at = number("at").unwrap_or_else(|| fail("patch requires --at"));
+    // Lines of the code can be separated with "\n", for shells that can't write newlines in arguments.
+    let source = args
+        .value("asm")
+        .unwrap_or_else(|| fail("patch requires --asm"))
+        .replace("\\n", "\n");
+    let origin = number("origin").unwrap_or(0);
+    let Some(offset) = at.checked_sub(origin) else {
+        fail(format!("--at {at:#x} is before --origin {origin:#x}"));
+    };
+    let address = u16::try_from(at).unwrap_or_else(|_| fail(format!("--at {at:#x} isn't a 16-bit offset")));
+    let code = asm::assemble_at(&source, address).unwrap_or_else(|error| fail(error.report("--asm")));
+    let mut binary = fs::read(path).unwrap_or_else(|error| fail(format!("{path}: {error}")));
+    let replaced =
+        asm::patch(&mut binary, offset as usize, &code).unwrap_or_else(|error| fail(format!("{path}: {error}")));
+    if replaced != code.len() {
+        eprintln!(
+            "warning: the code is {} bytes, and replaces {replaced} bytes of instructions, so it is padded with NOPs",
+            code.len()
+        );
     }
+    let out = args.value("out").unwrap_or(path);
+    fs::write(out, binary).unwrap_or_else(|error| fail(format!("{out}: {error}")));
+}
 
-    let mut unlabeled = HashMap::new();
-    for (target, label) in &labels {
-        if !instructions.contains_key(target) {
-            unlabeled.insert(format!("{label} "), target);
+/// Assemble a source with NASM, or read the binary beside it that NASM assembled, and compare it with the assembler's.
+fn compare_with_nasm(path: &Path, nasm: Option<&str>, include_paths: &[PathBuf]) -> Result<Vec<asm::Mismatch>, String> {
+    let name = path.display().to_string();
+    let source = fs::read_to_string(path).map_err(|error| format!("{name}: {error}"))?;
+    let directory = path.parent().unwrap_or(Path::new(""));
+    let binary = if let Some(nasm) = nasm {
+        let out = env::temp_dir().join(format!("homework-{}.bin", process::id()));
+        let mut command = Command::new(nasm);
+        command.args(["-f", "bin", "-o"]).arg(&out);
+        // NASM joins include paths to names as they are written, so they end with a separator.
+        for directory in iter::once(directory).chain(include_paths.iter().map(PathBuf::as_path)) {
+            command.arg(format!("-i{}", directory.join("").display()));
         }
-    }
+        let output = command.arg(path).output().map_err(|error| format!("{nasm}: {error}"))?;
+        if !output.status.success() {
+            return Err(format!(
+                "{name}: NASM failed\n{}",
+                String::from_utf8_lossy(&output.stderr).trim_end()
+            ));
+        }
+        let binary = fs::read(&out).map_err(|error| format!("{}: {error}", out.display()));
+        let _ = fs::remove_file(&out);
+        binary?
+    } else {
+        let prebuilt = path.with_extension("");
+        fs::read(&prebuilt).map_err(|error| format!("{}: {error}", prebuilt.display()))?
+    };
+    let includes = asm::Includes {
+        directory: directory.to_path_buf(),
+        paths: include_paths.to_vec(),
+    };
+    asm::compare(&source, &includes, &binary).map_err(|error| error.report(&name))
+}
 
-    writeln!(stdout, "bits 16").unwrap();
-    for (position, string) in &mut instructions {
-        if let Some(label) = labels.get(position) {
-            writeln!(stdout, "{label}:").unwrap();
+fn compare(args: &Args) {
+    let mut paths = vec![];
+    for path in args.positionals() {
+        if !Path::new(path).is_dir() {
+            paths.push(PathBuf::from(path));
+            continue;
         }
-        for (label, target) in &unlabeled {
-            if string.contains(label) {
-                *string = string.replacen(label, &format!("{target}"), 1);
+        let entries = fs::read_dir(path).unwrap_or_else(|error| fail(format!("{path}: {error}")));
+        let mut sources: Vec<PathBuf> = entries
+            .filter_map(|entry| Some(entry.ok()?.path()))
+            .filter(|path| path.extension().is_some_and(|extension| extension == "asm"))
+            .collect();
+        sources.sort();
+        paths.extend(sources);
+    }
+    if paths.is_empty() {
+        fail("missing argument <file.asm|directory>");
+    }
+    // The course's listings come with the binaries that NASM assembled.
+    let nasm = (!args.flag("prebuilt")).then(|| args.value("nasm").unwrap_or("nasm"));
+    let include_paths: Vec<PathBuf> = args.values("include").map(PathBuf::from).collect();
+    let mut matches = 0;
+    for path in &paths {
+        match compare_with_nasm(path, nasm, &include_paths) {
+            Ok(mismatches) if mismatches.is_empty() => matches += 1,
+            Ok(mismatches) => {
+                for mismatch in mismatches {
+                    println!("{}: {mismatch}", path.display());
+                }
             }
+            Err(error) => eprintln!("error: {error}"),
         }
-        write!(stdout, "{string}").unwrap();
+    }
+    println!("{matches} of {} files match NASM", paths.len());
+    if matches < paths.len() {
+        process::exit(1);
     }
 }
 
 fn main() {
-    let now = Instant::now();
-    let filename = env::args().nth(1).unwrap();
-    run(&filename, &mut io::stdout().lock());
-    eprintln!("{}ms", now.elapsed().as_micros());
+    let mut args = env::args().skip(1);
+    let Some(command) = args.next() else {
+        fail(USAGE);
+    };
+
+    match command.as_str() {
+        "sim" => simulate(&Args::parse(
+            args,
+            &["trace", "no-ip", "propagate-exit", "stats", "reconcile"],
+            &[
+                &MACHINE_OPTIONS[..],
+                &[
+                    "render",
+                    "out",
+                    "render-every",
+                    "max-instructions",
+                    "max-cycles",
+                    "snapshot-out",
+                    "break",
+                    "hot-loops",
+                    "access-log",
+                    "compare-trace",
+                    "screen",
+                    "script",
+                ],
+            ]
+            .concat(),
+        )),
+        "debug" => debug(&Args::parse(args, &["tui"], &MACHINE_OPTIONS)),
+        "sim-diff" => sim_diff(&Args::parse(
+            args,
+            &["lockstep"],
+            &[&MACHINE_OPTIONS[..], &["max-instructions"]].concat(),
+        )),
+        "heatmap" => heatmap(&Args::parse(args, &[], &["width", "start", "end"])),
+        "asm" => assemble(&Args::parse(args, &[], &["listing", "include"])),
+        "patch" => patch(&Args::parse(args, &[], &["at", "asm", "origin", "out"])),
+        "asm-compare" => compare(&Args::parse(args, &["prebuilt"], &["nasm", "include"])),
+        "bench" => bench(&Args::parse(args, &[], &["iterations"])),
+        "bench-branches" => bench_branches(&Args::parse(
+            args,
+            &["exclude-disturbed"],
+            &["count", "pattern", "probability", "seconds"],
+        )),
+        "bench-cache" => bench_cache(&Args::parse(
+            args,
+            &["exclude-disturbed"],
+            &["min", "max", "pattern", "stride", "seconds", "out"],
+        )),
+        "bench-memory" => bench_memory(&Args::parse(
+            args,
+            &["exclude-disturbed"],
+            &["sizes", "widths", "access", "seconds"],
+        )),
+        "bench-read" => bench_read(&Args::parse(
+            args,
+            &["large-pages", "exclude-disturbed"],
+            &["seconds", "allocation"],
+        )),
+        "haversine" => compute(&Args::parse(
+            args,
+            &["profile", "stream"],
+            &["profile-out", "math", "degree", "simd", "threads"],
+        )),
+        "math-check" => math_check(&Args::parse(
+            args,
+            &[],
+            &["function", "degree", "intervals", "samples", "out"],
+        )),
+        "gen-haversine" => generate(&Args::parse(args, &[], &["pairs", "seed", "mode", "out", "answers"])),
+        filename => {
+            let switches = ["mmap", "profile-self", "report-memory"];
+            let options = ["out", "max-memory"];
+            let args = Args::parse(iter::once(filename.to_string()).chain(args), &switches, &options);
+            let max_memory = args
+                .value("max-memory")
+                .map(|text| memory::parse(text).unwrap_or_else(|error| fail(format!("invalid --max-memory: {error}"))));
+            if let [path] = args.positionals() {
+                if args.flag("profile-self") {
+                    profile(
+                        Path::new(path),
+                        args.flag("mmap"),
+                        io::stdout().lock(),
+                        &mut io::stderr(),
+                    )
+                    .unwrap_or_else(|error| fail(format!("{path}: {error}")));
+                    return;
+                }
+                if !Path::new(path).is_dir() {
+                    // A file that's larger than the cap is mapped rather than read onto the heap.
+                    let larger = max_memory
+                        .is_some_and(|max| fs::metadata(path).is_ok_and(|metadata| metadata.len() > max as u64));
+                    let bytes = input(Path::new(path), args.flag("mmap") || larger)
+                        .unwrap_or_else(|error| fail(format!("{path}: {error}")));
+                    let before = memory::reset();
+                    let now = Instant::now();
+                    disasm::run(&bytes, max_memory, &mut io::stdout().lock());
+                    eprintln!("{}ms", now.elapsed().as_micros());
+                    if args.flag("report-memory") {
+                        eprintln!("peak memory: {} bytes", memory::peak() - before);
+                    }
+                    return;
+                }
+            }
+            let paths = binaries(args.positionals());
+            let Some(out) = args.value("out") else {
+                fail("--out is needed to disassemble several files");
+            };
+            fs::create_dir_all(out).unwrap_or_else(|error| fail(format!("{out}: {error}")));
+            if !batch(&paths, Path::new(out), args.flag("mmap"), &mut io::stdout().lock()) {
+                process::exit(1);
+            }
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    use std::fs::File;
-    use std::process::Command;
+    use std::alloc::{GlobalAlloc, Layout};
+    use std::cell::Cell;
 
     use tempfile::tempdir;
 
-    fn check(test_path: &str) {
-        let dir = tempdir().unwrap();
-        let assembly_path = dir.path().join("test.asm");
-        let binary_path = dir.path().join("test");
-        let mut assembly_file = File::create(assembly_path.clone()).unwrap();
+    thread_local! {
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
 
-        run(test_path, &mut assembly_file);
+    /// Count the allocations on each thread, so that tests running in parallel don't count each other's.
+    struct Counting;
+
+    // SAFETY: The tracking allocator does the allocating.
+    unsafe impl GlobalAlloc for Counting {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            // The count is gone while a thread exits.
+            let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+            unsafe { memory::Tracking.alloc(layout) }
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            unsafe { memory::Tracking.dealloc(ptr, layout) }
+        }
+    }
 
-        let mut text = vec![];
-        File::open(assembly_path.clone())
-            .unwrap()
-            .read_to_end(&mut text)
-            .unwrap();
-        println!("{}", String::from_utf8(text).unwrap());
+    #[global_allocator]
+    static ALLOCATOR: Counting = Counting;
 
-        let status = Command::new("nasm")
-            .args(["-o", binary_path.to_str().unwrap(), assembly_path.to_str().unwrap()])
-            .status()
-            .expect("failed to execute process");
+    #[test]
+    fn batch() {
+        let dir = tempdir().unwrap();
+        let mut paths = binaries(&["perfaware/part1".to_string()]);
+        assert!(paths.iter().all(|path| path.extension().is_none()));
+        paths.push(PathBuf::from("perfaware/part1/missing"));
+
+        let mut summary = vec![];
+        assert!(!super::batch(&paths, dir.path(), false, &mut summary));
+        let summary = String::from_utf8(summary).unwrap();
+        assert_eq!(summary.lines().count(), paths.len() + 1);
+        assert!(summary.contains("perfaware/part1/missing: error: "));
+        assert!(summary.contains(&format!("{} files, ", paths.len())));
+        assert!(summary.trim_end().ends_with(", 1 failed"));
+
+        for path in &paths[..paths.len() - 1] {
+            let mut expected = vec![];
+            disasm::run(&fs::read(path).unwrap(), None, &mut expected);
+            let mut name = path.file_name().unwrap().to_os_string();
+            name.push(".asm");
+            assert_eq!(fs::read(dir.path().join(name)).unwrap(), expected);
+        }
+    }
 
-        assert!(status.success());
+    #[test]
+    fn batch_names() {
+        let dir = tempdir().unwrap();
+        let (a, b, out) = (dir.path().join("a"), dir.path().join("b"), dir.path().join("out"));
+        for path in [&a, &b, &out] {
+            fs::create_dir(path).unwrap();
+        }
+        fs::write(a.join("foo"), [0b11110100]).unwrap();
+        fs::write(b.join("foo"), [0b10010000]).unwrap();
+        fs::write(b.join("BAR.BIN"), [0b11110100]).unwrap();
+        fs::write(b.join("notes.txt"), "").unwrap();
+
+        let paths = binaries(&[a.display().to_string(), b.display().to_string()]);
+        assert_eq!(paths, [a.join("foo"), b.join("BAR.BIN"), b.join("foo")]);
+
+        // Files with the same name fail, rather than one overwriting the other.
+        let mut summary = vec![];
+        assert!(!super::batch(&paths, &out, false, &mut summary));
+        let summary = String::from_utf8(summary).unwrap();
+        assert_eq!(summary.matches("another file has the same name").count(), 2);
+        assert!(summary.trim_end().ends_with(", 2 failed"));
+        assert!(!out.join("foo.asm").exists());
+        assert!(out.join("BAR.BIN.asm").exists());
+    }
 
-        let mut actual = vec![];
-        File::open(binary_path).unwrap().read_to_end(&mut actual).unwrap();
+    #[test]
+    fn profiled() {
+        let path = Path::new("perfaware/part1/listing_0042_completionist_decode");
+        let (mut assembly, mut report) = (vec![], vec![]);
+        profile(path, false, &mut assembly, &mut report).unwrap();
         let mut expected = vec![];
-        File::open(test_path).unwrap().read_to_end(&mut expected).unwrap();
-        assert_eq!(actual, expected);
+        disasm::run(&fs::read(path).unwrap(), None, &mut expected);
+        assert_eq!(assembly, expected);
+
+        let report = String::from_utf8(report).unwrap();
+        let names: Vec<_> = report
+            .lines()
+            .map(|line| line.split(':').next().unwrap().trim())
+            .collect();
+        assert_eq!(
+            names,
+            ["read", "decode", "labels", "format and write", "flush", "total"]
+        );
     }
 
-    include!(concat!(env!("OUT_DIR"), "/main.include"));
+    #[test]
+    fn allocations() {
+        let bytes = [
+            fs::read("perfaware/part1/listing_0042_completionist_decode").unwrap(),
+            fs::read("perfaware/part1/listing_0050_challenge_jumps").unwrap(),
+        ]
+        .concat();
+        let before = ALLOCATIONS.with(Cell::get);
+        disasm::run(&bytes, None, io::sink());
+        let allocations = ALLOCATIONS.with(Cell::get) - before;
+        // The instructions are written without allocating, and only the table of labels allocates as it grows.
+        assert!(allocations <= 4, "{allocations} allocations");
+
+        // Without labels, nothing allocates: operands, immediates and displacements are formatted straight into the
+        // writer.
+        let mut text = Line::new();
+        let before = ALLOCATIONS.with(Cell::get);
+        disasm::disassemble(&bytes, 0..bytes.len(), &mut Unnamed, &mut |arguments| {
+            text.clear();
+            text.write_fmt(arguments).unwrap();
+        });
+        assert_eq!(ALLOCATIONS.with(Cell::get) - before, 0);
+    }
 }
@@ -0,0 +1,393 @@
+//! The functions that the haversine formula needs, from the standard library or written here, like the course's math
+//! replacement exercise.
+//!
+//! The custom functions reduce their argument to a small range and evaluate a polynomial of a configurable degree on
+//! it, so that they don't call libm. Sine and cosine reduce to [-π/4, π/4] by quadrant. Arcsine reduces to [0, 1/2]
+//! by `asin(x) = π/2 - 2 asin(√((1 - x) / 2))`, and then to about [0, 0.26] by halving the angle. The polynomials are
+//! the Taylor series, which converge quickly on those ranges; with the default degree, the functions are within a few
+//! units in the last place. Square roots use the CPU's instruction.
+//!
+//! They don't use fused multiply-adds, which call libm unless the FMA target feature is enabled.
+//!
+//! [`check`] measures how far they are from a reference over the inputs that the haversine formula gives them, like
+//! the course's precision tests.
+
+use std::f64::consts::{FRAC_2_PI, FRAC_PI_2, PI};
+use std::fmt;
+use std::str::FromStr;
+
+/// The degree of the custom polynomials, by default.
+pub const DEFAULT_DEGREE: u32 = 25;
+
+// π/2 split in two, like fdlibm's: the high part has 33 bits, so that its product with a quadrant under 2^20 is exact.
+pub(crate) const FRAC_PI_2_HIGH: f64 = 1.570_796_326_734_125_6;
+pub(crate) const FRAC_PI_2_LOW: f64 = 6.077_100_506_506_192e-11;
+
+/// The functions that the haversine formula needs.
+pub trait Math {
+    fn sin(&self, x: f64) -> f64;
+    fn cos(&self, x: f64) -> f64;
+    fn asin(&self, x: f64) -> f64;
+    fn sqrt(&self, x: f64) -> f64;
+}
+
+/// Which functions to use.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Library {
+    #[default]
+    Std,
+    Custom,
+}
+
+impl FromStr for Library {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "std" => Ok(Self::Std),
+            "custom" => Ok(Self::Custom),
+            _ => Err("expected std or custom".to_string()),
+        }
+    }
+}
+
+/// The standard library's functions.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Std;
+
+impl Math for Std {
+    fn sin(&self, x: f64) -> f64 {
+        x.sin()
+    }
+
+    fn cos(&self, x: f64) -> f64 {
+        x.cos()
+    }
+
+    fn asin(&self, x: f64) -> f64 {
+        x.asin()
+    }
+
+    fn sqrt(&self, x: f64) -> f64 {
+        x.sqrt()
+    }
+}
+
+/// Polynomial approximations of a degree.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Custom {
+    // The coefficients of the powers of x², from the lowest: sin(x) = x P(x²), cos(x) = Q(x²) and asin(x) = x A(x²).
+    pub(crate) sin: Vec<f64>,
+    pub(crate) cos: Vec<f64>,
+    pub(crate) asin: Vec<f64>,
+}
+
+/// Evaluate a polynomial by Horner's rule.
+#[allow(clippy::suboptimal_flops)]
+fn polynomial(coefficients: &[f64], x: f64) -> f64 {
+    coefficients
+        .iter()
+        .rev()
+        .fold(0.0, |sum, &coefficient| sum * x + coefficient)
+}
+
+/// `x` to the nearest integer, without libm's `round`.
+#[allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+fn nearest(x: f64) -> i64 {
+    if x < 0.0 {
+        (x - 0.5) as i64
+    } else {
+        (x + 0.5) as i64
+    }
+}
+
+impl Custom {
+    /// Polynomials of at most `degree`, which is at least 1.
+    #[must_use]
+    pub fn new(degree: u32) -> Self {
+        let degree = degree.max(1);
+        let (mut sin, mut cos, mut asin) = (vec![], vec![], vec![]);
+        // The Taylor series: (-1)^k / (2k + 1)!, (-1)^k / (2k)! and (2k)! / (4^k (k!)² (2k + 1)).
+        let (mut factorial, mut central) = (1.0, 1.0);
+        for k in 0..=degree / 2 {
+            let n = f64::from(2 * k);
+            if k > 0 {
+                factorial *= (n - 1.0) * n;
+                central *= (n - 1.0) / n;
+            }
+            let alternating = if k % 2 == 0 { 1.0 } else { -1.0 };
+            cos.push(alternating / factorial);
+            if 2 * k < degree {
+                sin.push(alternating / (factorial * (n + 1.0)));
+                asin.push(central / (n + 1.0));
+            }
+        }
+        Self { sin, cos, asin }
+    }
+
+    /// The remainder of `x` after its nearest multiple of π/2, and which multiple it is, modulo 4.
+    #[allow(clippy::cast_precision_loss, clippy::suboptimal_flops)]
+    fn reduce(x: f64) -> (f64, i64) {
+        let quadrant = nearest(x * FRAC_2_PI);
+        let k = quadrant as f64;
+        (x - k * FRAC_PI_2_HIGH - k * FRAC_PI_2_LOW, quadrant & 3)
+    }
+
+    fn reduced_sin(&self, r: f64) -> f64 {
+        r * polynomial(&self.sin, r * r)
+    }
+
+    fn reduced_cos(&self, r: f64) -> f64 {
+        polynomial(&self.cos, r * r)
+    }
+}
+
+impl Default for Custom {
+    fn default() -> Self {
+        Self::new(DEFAULT_DEGREE)
+    }
+}
+
+impl Math for Custom {
+    fn sin(&self, x: f64) -> f64 {
+        let (r, quadrant) = Self::reduce(x);
+        match quadrant {
+            0 => self.reduced_sin(r),
+            1 => self.reduced_cos(r),
+            2 => -self.reduced_sin(r),
+            _ => -self.reduced_cos(r),
+        }
+    }
+
+    fn cos(&self, x: f64) -> f64 {
+        let (r, quadrant) = Self::reduce(x);
+        match quadrant {
+            0 => self.reduced_cos(r),
+            1 => -self.reduced_sin(r),
+            2 => -self.reduced_cos(r),
+            _ => self.reduced_sin(r),
+        }
+    }
+
+    #[allow(clippy::suboptimal_flops)]
+    fn asin(&self, x: f64) -> f64 {
+        let y = x.abs();
+        let (y, outer) = if y > 0.5 {
+            (self.sqrt((1.0 - y) / 2.0), true)
+        } else {
+            (y, false)
+        };
+        // sin(θ/2) = sin θ / √(2 (1 + cos θ)).
+        let half = y / self.sqrt(2.0 * (1.0 + self.sqrt(1.0 - y * y)));
+        let mut angle = 2.0 * half * polynomial(&self.asin, half * half);
+        if outer {
+            angle = FRAC_PI_2 - 2.0 * angle;
+        }
+        angle.copysign(x)
+    }
+
+    fn sqrt(&self, x: f64) -> f64 {
+        #[cfg(target_arch = "x86_64")]
+        {
+            use std::arch::x86_64::{_mm_cvtsd_f64, _mm_set_sd, _mm_sqrt_sd};
+            // SAFETY: Every x86-64 processor has SSE2.
+            unsafe { _mm_cvtsd_f64(_mm_sqrt_sd(_mm_set_sd(x), _mm_set_sd(x))) }
+        }
+        #[cfg(not(target_arch = "x86_64"))]
+        {
+            x.sqrt()
+        }
+    }
+}
+
+/// One of the functions of [`Math`], to check.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Function {
+    Sin,
+    Cos,
+    Asin,
+    Sqrt,
+}
+
+impl Function {
+    pub const ALL: [Self; 4] = [Self::Sin, Self::Cos, Self::Asin, Self::Sqrt];
+
+    /// The inputs that the haversine formula gives the function, from half of a difference of longitudes to the
+    /// haversine of the central angle.
+    #[must_use]
+    pub const fn domain(self) -> (f64, f64) {
+        match self {
+            Self::Sin | Self::Cos => (-PI, PI),
+            Self::Asin | Self::Sqrt => (0.0, 1.0),
+        }
+    }
+
+    /// The function of `math` at `x`.
+    pub fn call<M: Math + ?Sized>(self, math: &M, x: f64) -> f64 {
+        match self {
+            Self::Sin => math.sin(x),
+            Self::Cos => math.cos(x),
+            Self::Asin => math.asin(x),
+            Self::Sqrt => math.sqrt(x),
+        }
+    }
+}
+
+impl FromStr for Function {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sin" => Ok(Self::Sin),
+            "cos" => Ok(Self::Cos),
+            "asin" => Ok(Self::Asin),
+            "sqrt" => Ok(Self::Sqrt),
+            _ => Err("expected sin, cos, asin or sqrt".to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Sin => "sin",
+            Self::Cos => "cos",
+            Self::Asin => "asin",
+            Self::Sqrt => "sqrt",
+        })
+    }
+}
+
+/// The largest error of a function over an interval of its domain. Near the zeros of a function, where the doubles are
+/// densest, the units in the last place can be many even if the difference is tiny.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Precision {
+    pub start: f64,
+    pub end: f64,
+    /// The most units in the last place that a result was from the reference.
+    pub max_ulps: u64,
+    /// The largest difference from the reference.
+    pub max_error: f64,
+    /// The input with the most units in the last place of error.
+    pub worst: f64,
+}
+
+/// The number of doubles from `a` to `b`, counting zero once, or `u64::MAX` if only one is NaN.
+#[must_use]
+pub const fn ulps(a: f64, b: f64) -> u64 {
+    // Doubles are ordered like their bits as sign and magnitude.
+    #[allow(clippy::cast_possible_wrap)]
+    const fn ordered(x: f64) -> i64 {
+        let magnitude = (x.to_bits() & !(1 << 63)) as i64;
+        if x.is_sign_negative() {
+            -magnitude
+        } else {
+            magnitude
+        }
+    }
+    match (a.is_nan(), b.is_nan()) {
+        (true, true) => 0,
+        (false, false) => ordered(a).abs_diff(ordered(b)),
+        _ => u64::MAX,
+    }
+}
+
+/// Compare `function` of `math` with that of `reference` at `samples` evenly spaced inputs, including the ends, in
+/// each of `intervals` equal parts of its domain.
+#[must_use]
+#[allow(clippy::suboptimal_flops)]
+pub fn check<M: Math + ?Sized, R: Math + ?Sized>(
+    math: &M,
+    reference: &R,
+    function: Function,
+    intervals: u32,
+    samples: u32,
+) -> Vec<Precision> {
+    let (start, end) = function.domain();
+    let (intervals, samples) = (intervals.max(1), samples.max(2));
+    let width = (end - start) / f64::from(intervals);
+    (0..intervals)
+        .map(|interval| {
+            let start = start + width * f64::from(interval);
+            let mut precision = Precision {
+                start,
+                end: start + width,
+                ..Precision::default()
+            };
+            for sample in 0..samples {
+                let x = start + width * f64::from(sample) / f64::from(samples - 1);
+                let (result, expected) = (function.call(math, x), function.call(reference, x));
+                let distance = ulps(result, expected);
+                if distance > precision.max_ulps {
+                    precision.max_ulps = distance;
+                    precision.worst = x;
+                }
+                precision.max_error = precision.max_error.max((result - expected).abs());
+            }
+            precision
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::f64::consts::PI;
+
+    #[test]
+    fn approximates() {
+        let custom = Custom::default();
+        for step in -400..=400 {
+            let x = f64::from(step) * PI / 100.0;
+            assert!((custom.sin(x) - x.sin()).abs() < 1e-15, "sin {x}");
+            assert!((custom.cos(x) - x.cos()).abs() < 1e-15, "cos {x}");
+        }
+        for step in -1000..=1000 {
+            let x = f64::from(step) / 1000.0;
+            assert!((custom.asin(x) - x.asin()).abs() < 1e-15, "asin {x}");
+            assert_eq!(custom.sqrt(x.abs()), x.abs().sqrt());
+        }
+        assert_eq!(custom.asin(1.0), FRAC_PI_2);
+        assert!(custom.asin(1.5).is_nan());
+
+        // Lower degrees are worse.
+        let rough = Custom::new(5);
+        assert!((rough.sin(0.7) - 0.7f64.sin()).abs() > 1e-7);
+        assert!((rough.sin(0.7) - 0.7f64.sin()).abs() < 1e-4);
+        assert_eq!("custom".parse(), Ok(Library::Custom));
+    }
+
+    #[test]
+    fn checks() {
+        assert_eq!(ulps(1.0, 1.0), 0);
+        assert_eq!(ulps(1.0, 1.0 + f64::EPSILON), 1);
+        assert_eq!(ulps(-0.0, 0.0), 0);
+        assert_eq!(ulps(-f64::from_bits(1), f64::from_bits(2)), 3);
+        assert_eq!(ulps(f64::NAN, 0.0), u64::MAX);
+
+        let rough = Custom::new(5);
+        for function in Function::ALL {
+            let exact = check(&Std, &Std, function, 4, 100);
+            assert_eq!(exact.len(), 4);
+            assert_eq!(exact[0].start, function.domain().0);
+            assert!((exact[3].end - function.domain().1).abs() < 1e-15);
+            assert!(exact
+                .iter()
+                .all(|precision| precision.max_ulps == 0 && precision.max_error == 0.0));
+
+            let close = check(&Custom::default(), &Std, function, 4, 1000);
+            assert!(close.iter().all(|precision| precision.max_error < 1e-15), "{function}");
+            assert_eq!(function.to_string().parse(), Ok(function));
+        }
+        // Lower degrees are worse, most of all at the edges of the quadrants.
+        for precision in check(&rough, &Std, Function::Sin, 4, 1001) {
+            assert!(
+                precision.max_error > 1e-7 && precision.max_ulps > 1_000,
+                "{precision:?}"
+            );
+            let quadrant = precision.worst / FRAC_PI_2;
+            assert!((quadrant - quadrant.round()).abs() > 0.4, "{precision:?}");
+        }
+    }
+}
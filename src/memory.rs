@@ -0,0 +1,105 @@
+//! Count the bytes on the heap, and the most that were on it at once, to report and cap how much disassembling uses.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+/// The system allocator, counting the bytes that it allocates.
+pub struct Tracking;
+
+impl Tracking {
+    fn grow(size: usize) {
+        let current = CURRENT.fetch_add(size, Ordering::Relaxed) + size;
+        PEAK.fetch_max(current, Ordering::Relaxed);
+    }
+
+    fn shrink(size: usize) {
+        CURRENT.fetch_sub(size, Ordering::Relaxed);
+    }
+}
+
+// SAFETY: The system allocator does the allocating.
+unsafe impl GlobalAlloc for Tracking {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { System.alloc(layout) };
+        if !ptr.is_null() {
+            Self::grow(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { System.alloc_zeroed(layout) };
+        if !ptr.is_null() {
+            Self::grow(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) };
+        Self::shrink(layout.size());
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new = unsafe { System.realloc(ptr, layout, new_size) };
+        if !new.is_null() {
+            Self::grow(new_size);
+            Self::shrink(layout.size());
+        }
+        new
+    }
+}
+
+/// Start measuring the peak from the bytes on the heap now, and return them.
+pub fn reset() -> usize {
+    let current = CURRENT.load(Ordering::Relaxed);
+    PEAK.store(current, Ordering::Relaxed);
+    current
+}
+
+/// The most bytes that were on the heap at once since [`reset`].
+pub fn peak() -> usize {
+    PEAK.load(Ordering::Relaxed)
+}
+
+/// Parse a size in bytes, or with a "K", "M" or "G" suffix for KiB, MiB or GiB, like [`sim::memory_size`].
+///
+/// [`sim::memory_size`]: homework::sim::memory_size
+pub fn parse(text: &str) -> Result<usize, String> {
+    let upper = text.to_ascii_uppercase();
+    let (digits, unit) = [("K", 1 << 10), ("M", 1 << 20), ("G", 1 << 30)]
+        .into_iter()
+        .find_map(|(suffix, unit)| upper.strip_suffix(suffix).map(|digits| (digits, unit)))
+        .unwrap_or((&upper, 1));
+    digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|size| size.checked_mul(unit))
+        .ok_or_else(|| format!("invalid size {text:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses() {
+        assert_eq!(parse("4096"), Ok(4096));
+        assert_eq!(parse("64K"), Ok(64 << 10));
+        assert_eq!(parse("2m"), Ok(2 << 20));
+        assert_eq!(parse("1G"), Ok(1 << 30));
+        assert!(parse("M").is_err());
+        assert!(parse("-1").is_err());
+    }
+
+    #[test]
+    fn peaks() {
+        reset();
+        let bytes = vec![0u8; 1 << 20];
+        // Other tests allocate too, so the peak is only known to include these bytes.
+        assert!(peak() >= bytes.len());
+    }
+}
@@ -0,0 +1,85 @@
+//! Measure time in the CPU's ticks, which are cheaper to read and finer than the operating system's timer.
+//!
+//! The counter is the timestamp counter on x86-64, read by RDTSC, and the virtual counter on `AArch64`, read from
+//! `CNTVCT_EL0`. How fast it counts isn't reported portably, so it's estimated against the operating system's timer.
+
+use std::time::Duration;
+
+use crate::os_timer::{self, read_os_timer};
+
+/// How long the frequency is estimated over, by default. Longer is more precise.
+pub const CALIBRATION: Duration = Duration::from_millis(100);
+
+/// The CPU's counter, which counts up at a fixed rate.
+#[cfg(target_arch = "x86_64")]
+#[must_use]
+pub fn read_cpu_timer() -> u64 {
+    // SAFETY: Every x86-64 processor has RDTSC.
+    unsafe { std::arch::x86_64::_rdtsc() }
+}
+
+/// The CPU's counter, which counts up at a fixed rate.
+#[cfg(target_arch = "aarch64")]
+#[must_use]
+pub fn read_cpu_timer() -> u64 {
+    let ticks: u64;
+    // SAFETY: The virtual counter is readable at EL0 on the operating systems that Rust supports.
+    unsafe { std::arch::asm!("mrs {}, cntvct_el0", out(reg) ticks, options(nomem, nostack)) };
+    ticks
+}
+
+/// The operating system's timer, on processors without a counter.
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+#[must_use]
+pub fn read_cpu_timer() -> u64 {
+    read_os_timer()
+}
+
+/// Estimate the ticks per second of [`read_cpu_timer`], by counting them while the operating system's timer measures
+/// `wait`.
+#[must_use]
+pub fn estimate_cpu_frequency(wait: Duration) -> u64 {
+    let os_frequency = os_timer::os_timer_frequency();
+    let os_wait = u128::from(os_frequency) * wait.as_nanos() / 1_000_000_000;
+    let cpu_start = read_cpu_timer();
+    let os_start = read_os_timer();
+    let mut os_elapsed = 0;
+    while u128::from(os_elapsed) < os_wait {
+        os_elapsed = read_os_timer().wrapping_sub(os_start);
+    }
+    let ticks = read_cpu_timer().wrapping_sub(cpu_start);
+    let frequency = u128::from(ticks) * u128::from(os_frequency) / u128::from(os_elapsed.max(1));
+    u64::try_from(frequency).unwrap_or(u64::MAX)
+}
+
+/// The seconds of `ticks` at `frequency` ticks per second.
+#[must_use]
+#[allow(clippy::cast_precision_loss)]
+pub fn seconds(ticks: u64, frequency: u64) -> f64 {
+    ticks as f64 / frequency as f64
+}
+
+/// The ticks in `duration` at `frequency` ticks per second.
+#[must_use]
+pub fn ticks(duration: Duration, frequency: u64) -> u64 {
+    u64::try_from(duration.as_nanos() * u128::from(frequency) / 1_000_000_000).unwrap_or(u64::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts() {
+        let first = read_cpu_timer();
+        let second = read_cpu_timer();
+        assert!(second >= first);
+
+        let frequency = estimate_cpu_frequency(Duration::from_millis(10));
+        assert!(frequency > 0);
+        let duration = Duration::from_millis(250);
+        assert!((seconds(ticks(duration, frequency), frequency) - 0.25).abs() < 1e-6);
+        assert_eq!(ticks(Duration::from_secs(2), 3_000_000_000), 6_000_000_000);
+        assert!((seconds(1_500_000, 3_000_000) - 0.5).abs() < f64::EPSILON);
+    }
+}
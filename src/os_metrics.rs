@@ -0,0 +1,156 @@
+//! Counters that the operating system keeps for this process: `GetProcessMemoryInfo` on Windows, and `getrusage`
+//! elsewhere.
+//!
+//! Windows doesn't count a process's context switches, so they're only counted elsewhere.
+
+#[cfg(windows)]
+mod platform {
+    use std::ffi::c_void;
+
+    // PROCESS_MEMORY_COUNTERS.
+    #[repr(C)]
+    #[derive(Default)]
+    struct Counters {
+        size: u32,
+        page_faults: u32,
+        peak_working_set: usize,
+        working_set: usize,
+        peak_paged_pool: usize,
+        paged_pool: usize,
+        peak_non_paged_pool: usize,
+        non_paged_pool: usize,
+        page_file: usize,
+        peak_page_file: usize,
+    }
+
+    #[link(name = "kernel32")]
+    unsafe extern "system" {
+        fn GetCurrentProcess() -> *mut c_void;
+        fn K32GetProcessMemoryInfo(process: *mut c_void, counters: *mut Counters, size: u32) -> i32;
+        fn GetCurrentProcessorNumber() -> u32;
+    }
+
+    pub fn page_faults() -> u64 {
+        let mut counters = Counters {
+            size: u32::try_from(size_of::<Counters>()).unwrap_or(0),
+            ..Counters::default()
+        };
+        // SAFETY: The counters are written to a valid struct of the size that's passed. The handle of the current
+        // process is a constant that needn't be closed.
+        let ok = unsafe { K32GetProcessMemoryInfo(GetCurrentProcess(), &raw mut counters, counters.size) };
+        if ok == 0 {
+            0
+        } else {
+            u64::from(counters.page_faults)
+        }
+    }
+
+    pub const fn context_switches() -> u64 {
+        0
+    }
+
+    pub fn cpu() -> Option<u32> {
+        // SAFETY: It has no arguments.
+        Some(unsafe { GetCurrentProcessorNumber() })
+    }
+}
+
+#[cfg(unix)]
+mod platform {
+    fn usage() -> Option<libc::rusage> {
+        // SAFETY: An all-zero rusage is valid.
+        let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+        // SAFETY: The usage is written to a valid rusage.
+        (unsafe { libc::getrusage(libc::RUSAGE_SELF, &raw mut usage) } == 0).then_some(usage)
+    }
+
+    pub fn page_faults() -> u64 {
+        // Minor faults map a page that's already in memory, like a new page of zeros. Major faults read from disk.
+        usage().map_or(0, |usage| u64::try_from(usage.ru_minflt + usage.ru_majflt).unwrap_or(0))
+    }
+
+    pub fn context_switches() -> u64 {
+        // Voluntary switches wait for something, like a read. Involuntary ones are preempted by another thread.
+        usage().map_or(0, |usage| u64::try_from(usage.ru_nvcsw + usage.ru_nivcsw).unwrap_or(0))
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn cpu() -> Option<u32> {
+        // SAFETY: It has no arguments.
+        u32::try_from(unsafe { libc::sched_getcpu() }).ok()
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub const fn cpu() -> Option<u32> {
+        None
+    }
+}
+
+#[cfg(not(any(windows, unix)))]
+mod platform {
+    pub const fn page_faults() -> u64 {
+        0
+    }
+
+    pub const fn context_switches() -> u64 {
+        0
+    }
+
+    pub const fn cpu() -> Option<u32> {
+        None
+    }
+}
+
+/// The page faults of this process so far, or 0 if the operating system doesn't count them.
+#[must_use]
+// It's only constant on some platforms.
+#[allow(clippy::missing_const_for_fn)]
+pub fn read_page_faults() -> u64 {
+    platform::page_faults()
+}
+
+/// The context switches of this process so far, when it waited or was preempted, or 0 if the operating system doesn't
+/// count them.
+#[must_use]
+// It's only constant on some platforms.
+#[allow(clippy::missing_const_for_fn)]
+pub fn read_context_switches() -> u64 {
+    platform::context_switches()
+}
+
+/// The CPU that this thread is running on, if the operating system says.
+#[must_use]
+// It's only constant on some platforms.
+#[allow(clippy::missing_const_for_fn)]
+pub fn read_cpu() -> Option<u32> {
+    platform::cpu()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(any(windows, unix))]
+    #[test]
+    fn faults() {
+        let before = read_page_faults();
+        // Fresh pages of zeros fault when they're first written.
+        let mut pages = vec![0u8; 64 << 20];
+        for page in pages.chunks_mut(4096) {
+            page[0] = 1;
+        }
+        std::hint::black_box(&pages);
+        assert!(read_page_faults() > before);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn switches() {
+        let before = read_context_switches();
+        // Sleeping waits, which switches to another thread.
+        std::thread::sleep(std::time::Duration::from_millis(1));
+        assert!(read_context_switches() > before);
+        #[cfg(target_os = "linux")]
+        assert!(read_cpu().is_some());
+    }
+}
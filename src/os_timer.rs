@@ -0,0 +1,128 @@
+//! The operating system's monotonic timer as raw ticks, with its frequency and resolution: `QueryPerformanceCounter`
+//! on Windows, and `clock_gettime` elsewhere.
+//!
+//! [`Instant`](std::time::Instant) wraps the same timers, but doesn't expose the ticks, which the CPU's counter in
+//! [`metrics`](crate::metrics) is calibrated against.
+
+use std::time::Duration;
+
+#[cfg(windows)]
+mod platform {
+    use std::time::Duration;
+
+    #[link(name = "kernel32")]
+    unsafe extern "system" {
+        fn QueryPerformanceCounter(count: *mut i64) -> i32;
+        fn QueryPerformanceFrequency(frequency: *mut i64) -> i32;
+    }
+
+    pub fn read() -> u64 {
+        let mut count = 0;
+        // SAFETY: The count is written to a valid i64. It can't fail on Windows XP and later.
+        unsafe { QueryPerformanceCounter(&raw mut count) };
+        count.unsigned_abs()
+    }
+
+    pub fn frequency() -> u64 {
+        let mut frequency = 0;
+        // SAFETY: The frequency is written to a valid i64. It can't fail on Windows XP and later.
+        unsafe { QueryPerformanceFrequency(&raw mut frequency) };
+        frequency.unsigned_abs()
+    }
+
+    pub fn resolution() -> Duration {
+        Duration::from_nanos(1_000_000_000 / frequency().max(1))
+    }
+}
+
+#[cfg(unix)]
+mod platform {
+    use std::time::Duration;
+
+    fn timespec(get: unsafe extern "C" fn(libc::clockid_t, *mut libc::timespec) -> libc::c_int) -> Duration {
+        let mut time = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+        // SAFETY: The time is written to a valid timespec. CLOCK_MONOTONIC is always supported.
+        unsafe { get(libc::CLOCK_MONOTONIC, &raw mut time) };
+        let seconds = u64::try_from(time.tv_sec).unwrap_or(0);
+        let nanos = u32::try_from(time.tv_nsec).unwrap_or(0);
+        Duration::new(seconds, nanos)
+    }
+
+    pub fn read() -> u64 {
+        u64::try_from(timespec(libc::clock_gettime).as_nanos()).unwrap_or(u64::MAX)
+    }
+
+    pub const fn frequency() -> u64 {
+        1_000_000_000
+    }
+
+    pub fn resolution() -> Duration {
+        timespec(libc::clock_getres)
+    }
+}
+
+#[cfg(not(any(windows, unix)))]
+mod platform {
+    use std::sync::OnceLock;
+    use std::time::{Duration, Instant};
+
+    pub fn read() -> u64 {
+        static START: OnceLock<Instant> = OnceLock::new();
+        u64::try_from(START.get_or_init(Instant::now).elapsed().as_nanos()).unwrap_or(u64::MAX)
+    }
+
+    pub const fn frequency() -> u64 {
+        1_000_000_000
+    }
+
+    pub const fn resolution() -> Duration {
+        Duration::from_nanos(1)
+    }
+}
+
+/// The ticks of the operating system's monotonic timer, from an arbitrary start.
+#[must_use]
+pub fn read_os_timer() -> u64 {
+    platform::read()
+}
+
+/// The ticks per second of [`read_os_timer`].
+#[must_use]
+// It's only constant on some platforms.
+#[allow(clippy::missing_const_for_fn)]
+pub fn os_timer_frequency() -> u64 {
+    platform::frequency()
+}
+
+/// The shortest time that the timer can measure, as the operating system reports it.
+#[must_use]
+pub fn resolution() -> Duration {
+    platform::resolution()
+}
+
+/// The time in `ticks` of the timer.
+#[must_use]
+pub fn duration(ticks: u64) -> Duration {
+    let nanos = u128::from(ticks) * 1_000_000_000 / u128::from(os_timer_frequency());
+    Duration::from_nanos(u64::try_from(nanos).unwrap_or(u64::MAX))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Instant;
+
+    #[test]
+    fn times() {
+        assert!(os_timer_frequency() > 0);
+        assert!(resolution() > Duration::ZERO && resolution() < Duration::from_millis(20));
+
+        let instant = Instant::now();
+        let start = read_os_timer();
+        while instant.elapsed() < Duration::from_millis(5) {}
+        let elapsed = duration(read_os_timer() - start);
+        assert!(elapsed >= Duration::from_millis(4), "{elapsed:?}");
+        assert!(elapsed < Duration::from_secs(1), "{elapsed:?}");
+    }
+}
@@ -0,0 +1,324 @@
+//! Memory that's mapped from the operating system rather than allocated from the heap, in pages of 4k or in large
+//! pages, so that benchmarks can measure what faulting its pages in costs.
+//!
+//! Large pages are transparent huge pages on Linux, which the kernel backs with 2M pages where it can, and
+//! `MEM_LARGE_PAGES` on Windows, which needs the "Lock pages in memory" right.
+
+use std::fmt;
+use std::io;
+use std::ops::{Deref, DerefMut};
+use std::ptr::NonNull;
+use std::str::FromStr;
+
+/// The size of the pages of a mapping.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PageSize {
+    Small,
+    Large,
+}
+
+impl FromStr for PageSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "4k" => Ok(Self::Small),
+            "large" => Ok(Self::Large),
+            _ => Err("expected 4k or large".to_string()),
+        }
+    }
+}
+
+impl fmt::Display for PageSize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Small => "4k",
+            Self::Large => "large",
+        })
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use std::ffi::c_void;
+    use std::io;
+    use std::ptr;
+
+    type Handle = *mut c_void;
+
+    #[repr(C)]
+    struct Luid {
+        low: u32,
+        high: i32,
+    }
+
+    // TOKEN_PRIVILEGES with one privilege.
+    #[repr(C)]
+    struct TokenPrivileges {
+        count: u32,
+        luid: Luid,
+        attributes: u32,
+    }
+
+    const TOKEN_ADJUST_PRIVILEGES: u32 = 0x20;
+    const SE_PRIVILEGE_ENABLED: u32 = 2;
+    const MEM_COMMIT: u32 = 0x1000;
+    const MEM_RESERVE: u32 = 0x2000;
+    const MEM_RELEASE: u32 = 0x8000;
+    const MEM_LARGE_PAGES: u32 = 0x2000_0000;
+    const PAGE_READWRITE: u32 = 4;
+
+    #[link(name = "kernel32")]
+    unsafe extern "system" {
+        fn GetCurrentProcess() -> Handle;
+        fn CloseHandle(handle: Handle) -> i32;
+        fn GetLargePageMinimum() -> usize;
+        fn VirtualAlloc(address: *mut c_void, size: usize, kind: u32, protect: u32) -> *mut c_void;
+        fn VirtualFree(address: *mut c_void, size: usize, kind: u32) -> i32;
+    }
+
+    #[link(name = "advapi32")]
+    unsafe extern "system" {
+        fn OpenProcessToken(process: Handle, access: u32, token: *mut Handle) -> i32;
+        fn LookupPrivilegeValueW(system: *const u16, name: *const u16, luid: *mut Luid) -> i32;
+        fn AdjustTokenPrivileges(
+            token: Handle,
+            disable_all: i32,
+            privileges: *const TokenPrivileges,
+            length: u32,
+            previous: *mut TokenPrivileges,
+            returned: *mut u32,
+        ) -> i32;
+    }
+
+    pub fn large_page_size() -> Option<usize> {
+        // SAFETY: It has no arguments.
+        Some(unsafe { GetLargePageMinimum() }).filter(|&size| size > 0)
+    }
+
+    /// Enable the right to lock pages in memory, which the account has to have been granted, for large pages.
+    fn lock_pages() {
+        let name: Vec<u16> = "SeLockMemoryPrivilege".encode_utf16().chain([0]).collect();
+        let mut token = ptr::null_mut();
+        // SAFETY: The token and LUID are written to valid locals, the name ends in 0, and the token is closed.
+        unsafe {
+            if OpenProcessToken(GetCurrentProcess(), TOKEN_ADJUST_PRIVILEGES, &raw mut token) == 0 {
+                return;
+            }
+            let mut privileges = TokenPrivileges {
+                count: 1,
+                luid: Luid { low: 0, high: 0 },
+                attributes: SE_PRIVILEGE_ENABLED,
+            };
+            if LookupPrivilegeValueW(ptr::null(), name.as_ptr(), &raw mut privileges.luid) != 0 {
+                AdjustTokenPrivileges(token, 0, &raw const privileges, 0, ptr::null_mut(), ptr::null_mut());
+            }
+            CloseHandle(token);
+        }
+    }
+
+    pub fn map(size: usize, large: bool) -> io::Result<*mut u8> {
+        let kind = if large {
+            lock_pages();
+            MEM_COMMIT | MEM_RESERVE | MEM_LARGE_PAGES
+        } else {
+            MEM_COMMIT | MEM_RESERVE
+        };
+        // SAFETY: It maps new memory, which isn't aliased.
+        let address = unsafe { VirtualAlloc(ptr::null_mut(), size, kind, PAGE_READWRITE) };
+        if address.is_null() {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(address.cast())
+        }
+    }
+
+    pub unsafe fn unmap(address: *mut u8, _size: usize) {
+        // SAFETY: The caller passes memory from `map`, which isn't used again.
+        unsafe { VirtualFree(address.cast(), 0, MEM_RELEASE) };
+    }
+}
+
+#[cfg(unix)]
+mod platform {
+    use std::io;
+    use std::ptr;
+
+    #[cfg(target_os = "linux")]
+    // It's None on other platforms.
+    #[allow(clippy::unnecessary_wraps)]
+    pub const fn large_page_size() -> Option<usize> {
+        // The size of a huge page on x86-64 and on AArch64 with 4k pages, which transparent huge pages use.
+        Some(2 << 20)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub const fn large_page_size() -> Option<usize> {
+        None
+    }
+
+    unsafe fn mmap(size: usize) -> io::Result<*mut u8> {
+        // SAFETY: It maps new memory, which isn't aliased.
+        let address = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if address == libc::MAP_FAILED {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(address.cast())
+        }
+    }
+
+    pub fn map(size: usize, large: bool) -> io::Result<*mut u8> {
+        let Some(align) = large_page_size().filter(|_| large) else {
+            // SAFETY: It maps new memory.
+            return unsafe { mmap(size) };
+        };
+        // Huge pages have to be aligned, so map more than enough and unmap the ends that aren't needed.
+        // SAFETY: The ends are in the mapping, and aren't used.
+        unsafe {
+            let mapped = mmap(size + align)?;
+            let head = mapped.align_offset(align);
+            let address = mapped.add(head);
+            if head > 0 {
+                libc::munmap(mapped.cast(), head);
+            }
+            if align - head > 0 {
+                libc::munmap(address.add(size).cast(), align - head);
+            }
+            #[cfg(target_os = "linux")]
+            if libc::madvise(address.cast(), size, libc::MADV_HUGEPAGE) != 0 {
+                let error = io::Error::last_os_error();
+                libc::munmap(address.cast(), size);
+                return Err(error);
+            }
+            Ok(address)
+        }
+    }
+
+    pub unsafe fn unmap(address: *mut u8, size: usize) {
+        // SAFETY: The caller passes memory from `map`, which isn't used again.
+        unsafe { libc::munmap(address.cast(), size) };
+    }
+}
+
+#[cfg(not(any(windows, unix)))]
+mod platform {
+    use std::alloc::{self, Layout};
+    use std::io;
+
+    pub const fn large_page_size() -> Option<usize> {
+        None
+    }
+
+    pub fn map(size: usize, _large: bool) -> io::Result<*mut u8> {
+        let layout = Layout::from_size_align(size, 4096).map_err(io::Error::other)?;
+        // SAFETY: The size isn't 0.
+        let address = unsafe { alloc::alloc_zeroed(layout) };
+        if address.is_null() {
+            Err(io::Error::from(io::ErrorKind::OutOfMemory))
+        } else {
+            Ok(address)
+        }
+    }
+
+    pub unsafe fn unmap(address: *mut u8, size: usize) {
+        // SAFETY: The caller passes memory from `map` of the same size, which isn't used again.
+        unsafe { alloc::dealloc(address, Layout::from_size_align_unchecked(size, 4096)) };
+    }
+}
+
+/// The size of a large page, if the operating system has them.
+#[must_use]
+// It's only constant on some platforms.
+#[allow(clippy::missing_const_for_fn)]
+pub fn large_page_size() -> Option<usize> {
+    platform::large_page_size()
+}
+
+/// Bytes of zeros in pages that are mapped for them, which fault when they're first touched.
+pub struct Pages {
+    address: NonNull<u8>,
+    len: usize,
+    // The size that was mapped, which is a whole number of pages.
+    size: usize,
+}
+
+impl Pages {
+    /// Map `len` bytes of zeros.
+    ///
+    /// # Errors
+    ///
+    /// If the memory can't be mapped, or there aren't large pages.
+    pub fn new(len: usize, page_size: PageSize) -> io::Result<Self> {
+        let page = match page_size {
+            PageSize::Small => 4096,
+            PageSize::Large => large_page_size().ok_or_else(|| io::Error::other("there are no large pages"))?,
+        };
+        let size = len
+            .max(1)
+            .checked_next_multiple_of(page)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::OutOfMemory))?;
+        let address = platform::map(size, page_size == PageSize::Large)?;
+        let address = NonNull::new(address).ok_or_else(|| io::Error::from(io::ErrorKind::OutOfMemory))?;
+        Ok(Self { address, len, size })
+    }
+}
+
+impl Deref for Pages {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: The mapping has at least `len` bytes, which are zeroed, and it lives as long as `self`.
+        unsafe { std::slice::from_raw_parts(self.address.as_ptr(), self.len) }
+    }
+}
+
+impl DerefMut for Pages {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        // SAFETY: As above, and `self` is borrowed mutably.
+        unsafe { std::slice::from_raw_parts_mut(self.address.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for Pages {
+    fn drop(&mut self) {
+        // SAFETY: The mapping is from `map`, with its size, and isn't used again.
+        unsafe { platform::unmap(self.address.as_ptr(), self.size) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps() {
+        let mut pages = Pages::new(10_000, PageSize::Small).unwrap();
+        assert_eq!(pages.len(), 10_000);
+        assert!(pages.iter().all(|&byte| byte == 0));
+        pages[9_999] = 7;
+        assert_eq!(pages[9_999], 7);
+        assert_eq!(pages.as_ptr().align_offset(4096), 0);
+        assert!(Pages::new(0, PageSize::Small).unwrap().is_empty());
+
+        assert_eq!("large".parse(), Ok(PageSize::Large));
+        assert!("huge".parse::<PageSize>().is_err());
+
+        #[cfg(target_os = "linux")]
+        {
+            let size = large_page_size().unwrap();
+            let mut large = Pages::new(3 * size, PageSize::Large).unwrap();
+            assert_eq!(large.as_ptr().align_offset(size), 0);
+            large.fill(1);
+            assert!(large.iter().all(|&byte| byte == 1));
+        }
+    }
+}
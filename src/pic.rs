@@ -0,0 +1,172 @@
+//! A minimal model of the 8259A programmable interrupt controller (PIC), as on the PC's ports 0x20 and 0x21.
+//!
+//! Interrupt requests (IRQs) 0 to 7 raise interrupts from a base vector, 8 by default. IRQ 0 has the highest
+//! priority. A request is delivered if it isn't masked and no request of higher or equal priority is in service.
+//! The handler ends the interrupt by writing an end-of-interrupt (EOI) command. The initialization sequence sets
+//! the base vector, and other initialization options and operation commands are ignored.
+
+const COMMAND: u16 = 0x20;
+const DATA: u16 = 0x21;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Pic {
+    /// The interrupt request register: the requests waiting to be delivered.
+    pub requests: u8,
+    /// The interrupt mask register: the requests that are not delivered.
+    pub mask: u8,
+    /// The in-service register: the requests whose handlers haven't ended.
+    pub in_service: u8,
+    /// The vector of IRQ 0.
+    pub base: u8,
+    /// The first initialization command word (ICW1), which says which words follow.
+    icw1: u8,
+    /// The number of the next initialization command word expected on the data port, or 0.
+    icw: u8,
+}
+
+impl Default for Pic {
+    fn default() -> Self {
+        Self {
+            requests: 0,
+            mask: 0,
+            in_service: 0,
+            base: 8,
+            icw1: 0,
+            icw: 0,
+        }
+    }
+}
+
+impl Pic {
+    /// Request an interrupt on an IRQ line.
+    pub const fn request(&mut self, irq: u8) {
+        self.requests |= 1 << irq;
+    }
+
+    /// The vector of the request to deliver, if any, marking it as in service.
+    pub const fn acknowledge(&mut self) -> Option<u8> {
+        let pending = self.requests & !self.mask;
+        if pending == 0 {
+            return None;
+        }
+        let irq = pending.trailing_zeros() as u8;
+        // A request in service blocks requests of lower priority, at higher IRQs.
+        if self.in_service != 0 && self.in_service.trailing_zeros() <= irq as u32 {
+            return None;
+        }
+        self.requests &= !(1 << irq);
+        self.in_service |= 1 << irq;
+        Some(self.base + irq)
+    }
+
+    /// End the highest priority request in service.
+    pub const fn end_of_interrupt(&mut self) {
+        self.in_service &= self.in_service.wrapping_sub(1);
+    }
+
+    /// The registers, including the initialization state, as bytes.
+    pub(crate) const fn to_bytes(self) -> [u8; 6] {
+        [
+            self.requests,
+            self.mask,
+            self.in_service,
+            self.base,
+            self.icw1,
+            self.icw,
+        ]
+    }
+
+    pub(crate) const fn from_bytes(bytes: [u8; 6]) -> Self {
+        let [requests, mask, in_service, base, icw1, icw] = bytes;
+        Self {
+            requests,
+            mask,
+            in_service,
+            base,
+            icw1,
+            icw,
+        }
+    }
+
+    /// Read a port, or `None` if the port isn't the PIC's.
+    #[must_use]
+    pub const fn read(&self, port: u16) -> Option<u8> {
+        match port {
+            COMMAND => Some(self.requests),
+            DATA => Some(self.mask),
+            _ => None,
+        }
+    }
+
+    /// Write a port, returning whether the port is the PIC's.
+    pub const fn write(&mut self, port: u16, value: u8) -> bool {
+        match port {
+            // ICW1 starts initialization.
+            COMMAND if value & 0x10 != 0 => {
+                self.mask = 0;
+                self.in_service = 0;
+                self.requests = 0;
+                self.icw1 = value;
+                self.icw = 2;
+            }
+            // A non-specific EOI ends the highest priority request in service.
+            COMMAND if value == 0x20 => self.end_of_interrupt(),
+            // A specific EOI ends the request in the low 3 bits.
+            COMMAND if value & 0xf8 == 0x60 => self.in_service &= !(1 << (value & 0b111)),
+            COMMAND => {}
+            // ICW2 sets the base vector. ICW3 follows unless ICW1 bit 1 (single) is set, and ICW4 follows if ICW1 bit
+            // 0 is set.
+            DATA if self.icw != 0 => {
+                if self.icw == 2 {
+                    self.base = value & 0xf8;
+                }
+                let single = self.icw1 & 0b10 != 0;
+                let icw4 = self.icw1 & 0b1 != 0;
+                self.icw = match self.icw {
+                    2 if !single => 3,
+                    2 | 3 if icw4 => 4,
+                    _ => 0,
+                };
+            }
+            DATA => self.mask = value,
+            _ => return false,
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn priority() {
+        let mut pic = Pic::default();
+        pic.request(1);
+        pic.request(0);
+        assert_eq!(pic.acknowledge(), Some(8));
+        // IRQ 1 waits for the end of IRQ 0.
+        assert_eq!(pic.acknowledge(), None);
+        pic.write(COMMAND, 0x20);
+        assert_eq!(pic.acknowledge(), Some(9));
+        pic.write(COMMAND, 0x61);
+        assert_eq!(pic.in_service, 0);
+
+        pic.write(DATA, 0b1);
+        pic.request(0);
+        assert_eq!(pic.acknowledge(), None);
+        assert_eq!(pic.read(DATA), Some(1));
+    }
+
+    #[test]
+    fn initialization() {
+        let mut pic = Pic::default();
+        // ICW1 (single, ICW4 needed), ICW2, ICW4, then OCW1.
+        for (port, value) in [(COMMAND, 0x13), (DATA, 0x70), (DATA, 0x01), (DATA, 0xfe)] {
+            assert!(pic.write(port, value));
+        }
+        assert_eq!(pic.base, 0x70);
+        assert_eq!(pic.mask, 0xfe);
+        assert!(!pic.write(0x40, 0));
+    }
+}
@@ -0,0 +1,75 @@
+//! Devices on the I/O ports that `in` and `out` access.
+
+use std::io::Write;
+
+/// A model of the devices on the I/O ports, like a timer, an interrupt controller or a serial port.
+///
+/// Word accesses default to two byte accesses, the low byte at the port and the high byte at the next port, as on
+/// the 8086 bus.
+pub trait PortIo {
+    fn read8(&mut self, port: u16) -> u8;
+
+    fn write8(&mut self, port: u16, value: u8);
+
+    fn read16(&mut self, port: u16) -> u16 {
+        u16::from_le_bytes([self.read8(port), self.read8(port.wrapping_add(1))])
+    }
+
+    fn write16(&mut self, port: u16, value: u16) {
+        let [lo, hi] = value.to_le_bytes();
+        self.write8(port, lo);
+        self.write8(port.wrapping_add(1), hi);
+    }
+}
+
+/// Log each access, with no devices attached. Reads return 0xff, as from a floating bus.
+pub struct PortLog<W> {
+    out: W,
+}
+
+impl<W: Write> PortLog<W> {
+    pub const fn new(out: W) -> Self {
+        Self { out }
+    }
+
+    // Logging is best effort: a program shouldn't fail because the log can't be written.
+    fn log(&mut self, args: std::fmt::Arguments) {
+        let _ = writeln!(self.out, "{args}");
+    }
+}
+
+impl<W: Write> PortIo for PortLog<W> {
+    fn read8(&mut self, port: u16) -> u8 {
+        self.log(format_args!("in {port:#06x} -> 0xff"));
+        0xff
+    }
+
+    fn write8(&mut self, port: u16, value: u8) {
+        self.log(format_args!("out {port:#06x} <- {value:#04x}"));
+    }
+
+    fn read16(&mut self, port: u16) -> u16 {
+        self.log(format_args!("in {port:#06x} -> 0xffff"));
+        0xffff
+    }
+
+    fn write16(&mut self, port: u16, value: u16) {
+        self.log(format_args!("out {port:#06x} <- {value:#06x}"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn log() {
+        let mut ports = PortLog::new(vec![]);
+        assert_eq!(ports.read8(0x60), 0xff);
+        ports.write16(0x3d4, 0x0a0e);
+        assert_eq!(
+            String::from_utf8(ports.out).unwrap(),
+            "in 0x0060 -> 0xff\nout 0x03d4 <- 0x0a0e\n"
+        );
+    }
+}
@@ -0,0 +1,648 @@
+//! Time blocks of code by the CPU's counter, like the course's instrumentation profiler.
+//!
+//! [`time_block!`](crate::time_block) and [`time_function!`](crate::time_function) time the rest of the scope that
+//! they're in. Each block counts its hits, the ticks spent in it including the blocks inside it, and the ticks
+//! spent in it alone. [`time_bandwidth!`](crate::time_bandwidth) also counts the bytes that a block processes, to
+//! report its throughput.
+//!
+//! A [`Report`] is printed for people, as a tree of the blocks inside the blocks that they were first hit in, or
+//! exported as CSV or JSON for other programs.
+//!
+//! Blocks are only timed with the `profile` feature. Without it, the macros expand to nothing, not even evaluating
+//! their arguments, so that instrumented code costs nothing. A block inside itself, by recursion, is only counted once.
+//! Blocks are timed on each thread separately, between [`begin`] and [`end`]. A worker thread's blocks can be added to
+//! the thread that spawned it, with [`take_thread`] and [`add_thread`], so that the report shows what each worker took
+//! and what they took together, which is more than the total if they ran at the same time.
+
+#[cfg(feature = "profile")]
+use std::cell::RefCell;
+use std::fmt;
+use std::io::{self, Write};
+#[cfg(feature = "profile")]
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::metrics;
+#[cfg(feature = "profile")]
+use crate::metrics::read_cpu_timer;
+
+// The next index of a site. Index 0 is the root, outside of any block.
+#[cfg(feature = "profile")]
+static NEXT_SITE: AtomicUsize = AtomicUsize::new(1);
+
+/// A place in the code that's timed, which has the same index on every thread.
+#[cfg(feature = "profile")]
+pub struct Site {
+    name: fn() -> &'static str,
+    index: AtomicUsize,
+}
+
+#[cfg(feature = "profile")]
+impl Site {
+    #[must_use]
+    pub const fn new(name: fn() -> &'static str) -> Self {
+        Self {
+            name,
+            index: AtomicUsize::new(0),
+        }
+    }
+
+    fn index(&self) -> usize {
+        match self.index.load(Ordering::Relaxed) {
+            0 => {
+                let next = NEXT_SITE.fetch_add(1, Ordering::Relaxed);
+                match self
+                    .index
+                    .compare_exchange(0, next, Ordering::Relaxed, Ordering::Relaxed)
+                {
+                    Ok(_) => next,
+                    // Another thread got here first.
+                    Err(index) => index,
+                }
+            }
+            index => index,
+        }
+    }
+}
+
+/// The times of a block.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Anchor {
+    pub name: &'static str,
+    pub hits: u64,
+    /// The ticks in the block and the blocks inside it.
+    pub inclusive: u64,
+    /// The ticks in the block, but not the blocks inside it.
+    pub exclusive: u64,
+    /// The bytes that the block processed, over all its hits.
+    pub bytes: u64,
+    /// The block that it was first hit inside, as an index of the report's anchors, or `None` if it was outside of any.
+    pub parent: Option<usize>,
+}
+
+impl Anchor {
+    /// The bytes per second that the block processed, over its inclusive ticks, if it counted any bytes.
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn bandwidth(&self, frequency: u64) -> Option<f64> {
+        (self.bytes > 0 && self.inclusive > 0)
+            .then(|| self.bytes as f64 / metrics::seconds(self.inclusive, frequency.max(1)))
+    }
+}
+
+#[cfg(feature = "profile")]
+#[derive(Default)]
+struct Profiler {
+    anchors: Vec<Anchor>,
+    // The block that's being timed.
+    parent: usize,
+    start: u64,
+}
+
+#[cfg(feature = "profile")]
+thread_local! {
+    static PROFILER: RefCell<Profiler> = RefCell::default();
+}
+
+/// Time until it's dropped. Use [`time_block!`](crate::time_block) rather than this.
+#[cfg(feature = "profile")]
+pub struct Block {
+    index: usize,
+    parent: usize,
+    // The block's inclusive ticks when it began, which are restored if it's inside itself.
+    inclusive: u64,
+    start: u64,
+}
+
+#[cfg(feature = "profile")]
+impl Block {
+    /// Time a block that processes `bytes`, which can be 0.
+    #[must_use]
+    pub fn new(site: &Site, bytes: u64) -> Self {
+        let index = site.index();
+        let (parent, inclusive) = PROFILER.with_borrow_mut(|profiler| {
+            if profiler.anchors.len() <= index {
+                profiler.anchors.resize(index + 1, Anchor::default());
+            }
+            let parent = profiler.parent;
+            let anchor = &mut profiler.anchors[index];
+            anchor.name = (site.name)();
+            anchor.bytes += bytes;
+            // Until the report, it's the index of a site, and the root is 0.
+            anchor.parent.get_or_insert(parent);
+            profiler.parent = index;
+            (parent, anchor.inclusive)
+        });
+        Self {
+            index,
+            parent,
+            inclusive,
+            start: read_cpu_timer(),
+        }
+    }
+}
+
+#[cfg(feature = "profile")]
+impl Drop for Block {
+    fn drop(&mut self) {
+        let elapsed = read_cpu_timer().wrapping_sub(self.start);
+        PROFILER.with_borrow_mut(|profiler| {
+            profiler.parent = self.parent;
+            // The parent's exclusive ticks wrap below zero until its own elapsed ticks are added.
+            let parent = &mut profiler.anchors[self.parent];
+            parent.exclusive = parent.exclusive.wrapping_sub(elapsed);
+            let anchor = &mut profiler.anchors[self.index];
+            anchor.exclusive = anchor.exclusive.wrapping_add(elapsed);
+            anchor.inclusive = self.inclusive + elapsed;
+            anchor.hits += 1;
+        });
+    }
+}
+
+/// The blocks that were timed between [`begin`] and [`end`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Report {
+    /// The ticks from [`begin`] to [`end`].
+    pub total: u64,
+    /// The ticks per second.
+    pub frequency: u64,
+    /// The blocks that were hit, in the order of their first hit.
+    pub anchors: Vec<Anchor>,
+}
+
+/// Start timing on this thread, forgetting any earlier times.
+#[cfg(feature = "profile")]
+pub fn begin() {
+    PROFILER.with_borrow_mut(|profiler| {
+        profiler.anchors.clear();
+        profiler.parent = 0;
+        profiler.start = read_cpu_timer();
+    });
+}
+
+/// Stop timing on this thread, and estimate the CPU's frequency to report the times in seconds.
+#[cfg(feature = "profile")]
+#[must_use]
+pub fn end() -> Report {
+    let end = read_cpu_timer();
+    let frequency = metrics::estimate_cpu_frequency(metrics::CALIBRATION);
+    PROFILER.with_borrow_mut(|profiler| {
+        let hit: Vec<usize> = (0..profiler.anchors.len())
+            .filter(|&index| profiler.anchors[index].hits > 0)
+            .collect();
+        // The index in the report of each site that was hit. The root wasn't.
+        let mut positions = vec![None; profiler.anchors.len()];
+        for (position, &index) in hit.iter().enumerate() {
+            positions[index] = Some(position);
+        }
+        Report {
+            total: end.wrapping_sub(profiler.start),
+            frequency,
+            anchors: hit
+                .iter()
+                .map(|&index| Anchor {
+                    parent: profiler.anchors[index].parent.and_then(|parent| positions[parent]),
+                    ..profiler.anchors[index].clone()
+                })
+                .collect(),
+        }
+    })
+}
+
+/// The blocks that a thread timed, to add to another thread's.
+#[derive(Debug, Default)]
+pub struct Thread {
+    #[cfg(feature = "profile")]
+    anchors: Vec<Anchor>,
+}
+
+/// Take the blocks that this thread timed, such as a worker thread at its end, outside of any block.
+#[must_use]
+pub fn take_thread() -> Thread {
+    #[cfg(feature = "profile")]
+    {
+        PROFILER.with_borrow_mut(|profiler| Thread {
+            anchors: std::mem::take(&mut profiler.anchors),
+        })
+    }
+    #[cfg(not(feature = "profile"))]
+    Thread::default()
+}
+
+/// Add the blocks that another thread timed to this thread's, such as when joining a worker thread.
+// The blocks are consumed with the feature.
+#[allow(clippy::needless_pass_by_value)]
+pub fn add_thread(thread: Thread) {
+    #[cfg(feature = "profile")]
+    PROFILER.with_borrow_mut(|profiler| {
+        if profiler.anchors.len() < thread.anchors.len() {
+            profiler.anchors.resize(thread.anchors.len(), Anchor::default());
+        }
+        // The other thread's blocks outside of any are inside this thread's block.
+        let current = profiler.parent;
+        // The root isn't a block.
+        for (anchor, other) in profiler.anchors.iter_mut().zip(thread.anchors).skip(1) {
+            if other.hits > 0 {
+                anchor.name = other.name;
+                if anchor.parent.is_none() {
+                    anchor.parent = other.parent.map(|parent| if parent == 0 { current } else { parent });
+                }
+                anchor.hits += other.hits;
+                anchor.inclusive += other.inclusive;
+                anchor.exclusive = anchor.exclusive.wrapping_add(other.exclusive);
+                anchor.bytes += other.bytes;
+            }
+        }
+    });
+    #[cfg(not(feature = "profile"))]
+    let _ = thread;
+}
+
+const MEGABYTE: f64 = 1024.0 * 1024.0;
+const GIGABYTE: f64 = MEGABYTE * 1024.0;
+
+impl Report {
+    /// Write the blocks inside `parent`, and the blocks inside them, indented by their depth.
+    fn write_tree(&self, f: &mut fmt::Formatter, parent: Option<usize>, depth: usize) -> fmt::Result {
+        // Blocks can't be inside each other, but a report that's built by hand could say so.
+        if depth > self.anchors.len() {
+            return Ok(());
+        }
+        #[allow(clippy::cast_precision_loss)]
+        let percent = |ticks: u64| 100.0 * ticks as f64 / self.total.max(1) as f64;
+        for (index, anchor) in self.anchors.iter().enumerate() {
+            if anchor.parent != parent {
+                continue;
+            }
+            let name = anchor.name;
+            write!(
+                f,
+                "{:indent$}{name}[{}]: {} ({:.2}%",
+                "",
+                anchor.hits,
+                anchor.exclusive,
+                percent(anchor.exclusive),
+                indent = 2 * (depth + 1)
+            )?;
+            if anchor.inclusive != anchor.exclusive {
+                write!(f, ", {:.2}% w/children", percent(anchor.inclusive))?;
+            }
+            write!(f, ")")?;
+            if let Some(bandwidth) = anchor.bandwidth(self.frequency) {
+                #[allow(clippy::cast_precision_loss)]
+                let megabytes = anchor.bytes as f64 / MEGABYTE;
+                write!(f, "  {megabytes:.3}mb at {:.2}gb/s", bandwidth / GIGABYTE)?;
+            }
+            writeln!(f)?;
+            self.write_tree(f, Some(index), depth + 1)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let milliseconds = 1000.0 * metrics::seconds(self.total, self.frequency.max(1));
+        writeln!(f, "Total time: {milliseconds:.4}ms (CPU freq {})", self.frequency)?;
+        self.write_tree(f, None, 0)
+    }
+}
+
+/// The formats that a [`Report`] is exported in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Export {
+    /// A row for each block, with the report's total and frequency on each row.
+    Csv,
+    /// An object with the report's total and frequency, and an array of blocks.
+    Json,
+}
+
+impl Export {
+    /// JSON if the path ends in ".json", otherwise CSV.
+    #[must_use]
+    pub fn from_path(path: &str) -> Self {
+        if path.to_ascii_lowercase().ends_with(".json") {
+            Self::Json
+        } else {
+            Self::Csv
+        }
+    }
+}
+
+/// A field of CSV, quoted if it has to be.
+fn csv_field(text: &str) -> String {
+    if text.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+impl Report {
+    /// Write the report as CSV or JSON, with ticks rather than seconds, bandwidths in gigabytes per second, and the
+    /// parent of each block as its index, if any.
+    ///
+    /// # Errors
+    ///
+    /// If writing fails.
+    pub fn export(&self, out: &mut impl Write, format: Export) -> io::Result<()> {
+        let gigabytes = |anchor: &Anchor| anchor.bandwidth(self.frequency).map(|bandwidth| bandwidth / GIGABYTE);
+        match format {
+            Export::Csv => {
+                writeln!(
+                    out,
+                    "name,hits,inclusive,exclusive,bytes,gb_per_s,parent,total,frequency"
+                )?;
+                for anchor in &self.anchors {
+                    let bandwidth = gigabytes(anchor)
+                        .map(|bandwidth| bandwidth.to_string())
+                        .unwrap_or_default();
+                    let parent = anchor.parent.map(|parent| parent.to_string()).unwrap_or_default();
+                    writeln!(
+                        out,
+                        "{},{},{},{},{},{bandwidth},{parent},{},{}",
+                        csv_field(anchor.name),
+                        anchor.hits,
+                        anchor.inclusive,
+                        anchor.exclusive,
+                        anchor.bytes,
+                        self.total,
+                        self.frequency
+                    )?;
+                }
+            }
+            Export::Json => {
+                let blocks: Vec<_> = self
+                    .anchors
+                    .iter()
+                    .map(|anchor| {
+                        serde_json::json!({
+                            "name": anchor.name,
+                            "hits": anchor.hits,
+                            "inclusive": anchor.inclusive,
+                            "exclusive": anchor.exclusive,
+                            "bytes": anchor.bytes,
+                            "gb_per_s": gigabytes(anchor),
+                            "parent": anchor.parent,
+                        })
+                    })
+                    .collect();
+                let report = serde_json::json!({
+                    "total": self.total,
+                    "frequency": self.frequency,
+                    "blocks": blocks,
+                });
+                serde_json::to_writer_pretty(&mut *out, &report)?;
+                writeln!(out)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The name of a function, from the type name of a function inside the site of a block in it.
+#[must_use]
+pub fn function_name(inner: &'static str) -> &'static str {
+    let mut name = inner.strip_suffix("::inner").unwrap_or(inner);
+    while let Some(outer) = name
+        .strip_suffix("::{{closure}}")
+        .or_else(|| name.strip_suffix("::SITE"))
+    {
+        name = outer;
+    }
+    name.rsplit("::").next().unwrap_or(name)
+}
+
+/// Time the rest of the scope as a block with a name.
+#[macro_export]
+macro_rules! time_block {
+    ($name:expr) => {
+        $crate::time_bandwidth!($name, 0);
+    };
+}
+
+/// Time the rest of the scope as a block with a name, which processes a number of bytes.
+#[cfg(feature = "profile")]
+#[macro_export]
+macro_rules! time_bandwidth {
+    ($name:expr, $bytes:expr) => {
+        let _block = {
+            static SITE: $crate::profile::Site = $crate::profile::Site::new(|| $name);
+            $crate::profile::Block::new(&SITE, ::std::convert::TryInto::try_into($bytes).unwrap_or(u64::MAX))
+        };
+    };
+}
+
+/// Nothing, without the `profile` feature.
+#[cfg(not(feature = "profile"))]
+#[macro_export]
+macro_rules! time_bandwidth {
+    ($name:expr, $bytes:expr) => {};
+}
+
+/// Time the rest of the function, named after it, which processes a number of bytes, if any.
+#[macro_export]
+macro_rules! time_function {
+    () => {
+        $crate::time_function!(0);
+    };
+    ($bytes:expr) => {
+        $crate::time_bandwidth!(
+            {
+                const fn inner() {}
+                $crate::profile::function_name(::std::any::type_name_of_val(&inner))
+            },
+            $bytes
+        );
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::json::{self, Element};
+
+    /// Spin for some ticks.
+    #[cfg(feature = "profile")]
+    fn spin(ticks: u64) {
+        let start = read_cpu_timer();
+        while read_cpu_timer().wrapping_sub(start) < ticks {}
+    }
+
+    #[cfg(feature = "profile")]
+    fn recurse(depth: u32) {
+        time_function!();
+        spin(10_000);
+        if depth > 0 {
+            recurse(depth - 1);
+        }
+    }
+
+    #[cfg(feature = "profile")]
+    #[test]
+    fn times() {
+        begin();
+        for _ in 0..3 {
+            time_block!("outer");
+            spin(100_000);
+            {
+                time_block!("inner");
+                spin(100_000);
+            }
+        }
+        recurse(4);
+        for _ in 0..2 {
+            time_bandwidth!("copy", 1024 * 1024_usize);
+            spin(100_000);
+        }
+        let report = end();
+
+        let names: Vec<_> = report.anchors.iter().map(|anchor| anchor.name).collect();
+        assert_eq!(names, ["outer", "inner", "recurse", "copy"]);
+        let [outer, inner, recurse, copy] = &report.anchors[..] else {
+            unreachable!();
+        };
+        assert_eq!((outer.bytes, copy.bytes), (0, 2 * 1024 * 1024));
+        assert_eq!(outer.bandwidth(report.frequency), None);
+        let seconds = metrics::seconds(copy.inclusive, report.frequency);
+        assert!((copy.bandwidth(report.frequency).unwrap() * seconds - 2.0 * 1024.0 * 1024.0).abs() < 1e-3);
+        assert_eq!((outer.hits, inner.hits, recurse.hits), (3, 3, 5));
+        assert_eq!(outer.inclusive, outer.exclusive + inner.inclusive);
+        assert!(outer.exclusive >= 300_000 && inner.exclusive >= 300_000);
+        // The recursion is counted once.
+        assert_eq!(recurse.inclusive, recurse.exclusive);
+        assert!(outer.inclusive + recurse.inclusive <= report.total);
+
+        let parents: Vec<_> = report.anchors.iter().map(|anchor| anchor.parent).collect();
+        assert_eq!(parents, [None, Some(0), None, None]);
+
+        let text = report.to_string();
+        assert!(text.starts_with("Total time: "));
+        assert!(text.contains("\n  outer[3]: ") && text.contains("w/children"));
+        assert!(text.contains("\n    inner[3]: ") && text.contains("\n  recurse[5]: "));
+        assert!(text.contains("  2.000mb at ") && text.contains("gb/s\n"));
+        assert_eq!(text.matches("gb/s").count(), 1);
+    }
+
+    #[cfg(feature = "profile")]
+    #[test]
+    fn threads() {
+        begin();
+        {
+            crate::time_bandwidth!("spawn", 200);
+            let threads: Vec<_> = std::thread::scope(|scope| {
+                let workers: Vec<_> = (0..2)
+                    .map(|_| {
+                        scope.spawn(|| {
+                            {
+                                crate::time_bandwidth!("work", 100);
+                                std::hint::black_box((0..1000).sum::<u64>());
+                            }
+                            take_thread()
+                        })
+                    })
+                    .collect();
+                workers.into_iter().map(|worker| worker.join().unwrap()).collect()
+            });
+            for thread in threads {
+                add_thread(thread);
+            }
+        }
+        let report = end();
+        let names: Vec<_> = report.anchors.iter().map(|anchor| (anchor.name, anchor.hits)).collect();
+        assert_eq!(names, [("spawn", 1), ("work", 2)]);
+        let (spawn, work) = (&report.anchors[0], &report.anchors[1]);
+        assert_eq!((spawn.bytes, work.bytes), (200, 200));
+        assert_eq!((spawn.parent, work.parent), (None, Some(0)));
+        assert_eq!(work.inclusive, work.exclusive);
+        assert!(spawn.inclusive <= report.total && spawn.exclusive <= spawn.inclusive);
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(
+            function_name("homework::haversine::parse::SITE::{{closure}}::inner"),
+            "parse"
+        );
+        assert_eq!(
+            function_name("homework::main::{{closure}}::SITE::{{closure}}::inner"),
+            "main"
+        );
+    }
+
+    #[test]
+    fn exports() {
+        let report = Report {
+            total: 4_000,
+            frequency: 1_000,
+            anchors: vec![
+                Anchor {
+                    name: "read",
+                    hits: 1,
+                    inclusive: 2_000,
+                    exclusive: 2_000,
+                    bytes: 1 << 30,
+                    parent: None,
+                },
+                Anchor {
+                    name: "lex, \"parse\"",
+                    hits: 3,
+                    inclusive: 1_500,
+                    exclusive: 500,
+                    bytes: 0,
+                    parent: Some(0),
+                },
+            ],
+        };
+
+        let mut csv = vec![];
+        report.export(&mut csv, Export::Csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "name,hits,inclusive,exclusive,bytes,gb_per_s,parent,total,frequency
+read,1,2000,2000,1073741824,0.5,,4000,1000
+\"lex, \"\"parse\"\"\",3,1500,500,0,,0,4000,1000
+"
+        );
+
+        let mut exported = vec![];
+        report.export(&mut exported, Export::Json).unwrap();
+        let json = json::parse(std::str::from_utf8(&exported).unwrap()).unwrap();
+        assert_eq!(json.get("frequency").and_then(Element::as_f64), Some(1000.0));
+        let blocks = json.get("blocks").and_then(Element::as_array).unwrap();
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[1].get("name").and_then(Element::as_str), Some("lex, \"parse\""));
+        assert_eq!(blocks[0].get("gb_per_s").and_then(Element::as_f64), Some(0.5));
+        assert_eq!(blocks[1].get("gb_per_s"), Some(&Element::Null));
+        assert_eq!(blocks[0].get("parent"), Some(&Element::Null));
+        assert_eq!(blocks[1].get("parent").and_then(Element::as_f64), Some(0.0));
+
+        let text = report.to_string();
+        assert!(text.contains("\n  read[1]: 2000 (50.00%)  1024.000mb at 0.50gb/s\n    lex, \"parse\"[3]: 500 (12.50%"));
+
+        assert_eq!(Export::from_path("runs/report.JSON"), Export::Json);
+        assert_eq!(Export::from_path("report.csv"), Export::Csv);
+    }
+
+    #[cfg(not(feature = "profile"))]
+    #[test]
+    fn disabled() {
+        fn instrumented() -> u32 {
+            time_function!(unreachable!("the bytes aren't evaluated"));
+            time_block!(unreachable!("the name isn't evaluated"));
+            1
+        }
+        assert_eq!(instrumented(), 1);
+
+        // Nothing that times a block is compiled into this binary. The mangled symbols are split, so that they're
+        // not in it as strings.
+        let binary = std::fs::read(std::env::current_exe().unwrap()).unwrap();
+        for symbol in ["7profile 5Block", "7profile 4Site", "7profile 8PROFILER"] {
+            let symbol = symbol.replace(' ', "");
+            assert!(
+                !binary.windows(symbol.len()).any(|window| window == symbol.as_bytes()),
+                "{symbol}"
+            );
+        }
+    }
+}
@@ -0,0 +1,73 @@
+//! A small pseudorandom number generator whose numbers for a seed are the same on every platform and with every
+//! version of Rust, so that `gen-haversine --seed` always writes the same file.
+//!
+//! It's Sebastiano Vigna's `SplitMix64` (<https://prng.di.unimi.it/splitmix64.c>): each number adds the golden ratio,
+//! as a 64-bit fraction, to the state, and mixes the state with shifts, XORs and multiplications. It passes `BigCrush`,
+//! but isn't cryptographic. It only uses integer arithmetic, which wraps the same everywhere.
+//!
+//! A float is the top 53 bits of a number, which an `f64` holds exactly, as a fraction of 2^53, and then scaled to a
+//! range with a fused multiply-add, which IEEE 754 rounds once, the same everywhere.
+
+/// A `SplitMix64` generator.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Random {
+    state: u64,
+}
+
+impl Random {
+    /// A generator whose first number is mixed from `seed` plus the golden ratio. Any seed is fine, even 0.
+    #[must_use]
+    pub const fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    /// The next 64 random bits.
+    pub const fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number from 0 up to, but not including, 1, in steps of 2^-53.
+    #[allow(clippy::cast_precision_loss)]
+    pub fn unit(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// A number from `min` up to `max`.
+    pub fn range(&mut self, min: f64, max: f64) -> f64 {
+        (max - min).mul_add(self.unit(), min)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pinned() {
+        // The reference implementation's numbers for the seed 0.
+        let mut random = Random::new(0);
+        let numbers: Vec<u64> = (0..4).map(|_| random.next_u64()).collect();
+        assert_eq!(
+            numbers,
+            [
+                0xe220_a839_7b1d_cdaf,
+                0x6e78_9e6a_a1b9_65f4,
+                0x06c4_5d18_8009_454f,
+                0xf88b_b8a8_724c_81ec
+            ]
+        );
+
+        let mut random = Random::new(1);
+        let floats: Vec<f64> = (0..3).map(|_| random.range(-180.0, 180.0)).collect();
+        assert_eq!(
+            floats,
+            [23.962_167_062_021_123, 88.481_432_614_572_41, 169.560_991_291_246_64]
+        );
+        assert_eq!(Random::new(1), Random::new(1));
+        assert!((0..1000).all(|_| (0.0..1.0).contains(&random.unit())));
+    }
+}
@@ -0,0 +1,191 @@
+//! Render a region of simulated memory as an image.
+//!
+//! Memory is read as rows of `stride` bytes from address 0, with 4 bytes (red, green, blue, alpha)
+//! per pixel, like the course's drawing listings. Alpha is ignored.
+
+use std::io::{self, Write};
+use std::str::FromStr;
+
+const BYTES_PER_PIXEL: usize = 4;
+
+/// A rectangle of pixels, with the number of bytes per row of memory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Region {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+    pub stride: usize,
+}
+
+impl FromStr for Region {
+    type Err = String;
+
+    /// Parse "x,y,w,h,stride".
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values = s
+            .split(',')
+            .map(|value| value.trim().parse::<usize>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|error| format!("invalid region {s:?}: {error}"))?;
+        let [x, y, width, height, stride] = values[..] else {
+            return Err(format!("invalid region {s:?}: expected x,y,w,h,stride"));
+        };
+        Ok(Self {
+            x,
+            y,
+            width,
+            height,
+            stride,
+        })
+    }
+}
+
+impl Region {
+    /// The red, green and blue bytes of a pixel, relative to the region. Pixels outside memory are black.
+    fn pixel(&self, memory: &[u8], column: usize, row: usize) -> [u8; 3] {
+        let address = (self.y + row) * self.stride + (self.x + column) * BYTES_PER_PIXEL;
+        match memory.get(address..address + 3) {
+            Some(&[red, green, blue]) => [red, green, blue],
+            _ => [0; 3],
+        }
+    }
+}
+
+/// Image file formats.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Ppm,
+    Bmp,
+}
+
+impl Format {
+    /// BMP if the path ends in ".bmp", otherwise PPM.
+    #[must_use]
+    pub fn from_path(path: &str) -> Self {
+        if path.to_ascii_lowercase().ends_with(".bmp") {
+            Self::Bmp
+        } else {
+            Self::Ppm
+        }
+    }
+}
+
+/// The path of a numbered frame, like `frame_00001.ppm` for `frame.ppm`.
+#[must_use]
+pub fn frame_path(path: &str, frame: usize) -> String {
+    match path.rsplit_once('.') {
+        Some((stem, extension)) if !extension.contains('/') => format!("{stem}_{frame:05}.{extension}"),
+        _ => format!("{path}_{frame:05}"),
+    }
+}
+
+/// Write the region of memory as an image.
+///
+/// # Errors
+///
+/// If writing fails.
+pub fn write<W: Write>(out: &mut W, memory: &[u8], region: &Region, format: Format) -> io::Result<()> {
+    match format {
+        Format::Ppm => write_ppm(out, memory, region),
+        Format::Bmp => write_bmp(out, memory, region),
+    }
+}
+
+// Binary PPM: https://netpbm.sourceforge.net/doc/ppm.html
+fn write_ppm<W: Write>(out: &mut W, memory: &[u8], region: &Region) -> io::Result<()> {
+    write!(out, "P6\n{} {}\n255\n", region.width, region.height)?;
+    let mut data = Vec::with_capacity(region.width * region.height * 3);
+    for row in 0..region.height {
+        for column in 0..region.width {
+            data.extend_from_slice(&region.pixel(memory, column, row));
+        }
+    }
+    out.write_all(&data)
+}
+
+// 24-bit BMP: rows are bottom-up, in blue-green-red order, and padded to a multiple of 4 bytes.
+fn write_bmp<W: Write>(out: &mut W, memory: &[u8], region: &Region) -> io::Result<()> {
+    const HEADER_SIZE: u32 = 14 + 40;
+
+    let too_large = || io::Error::new(io::ErrorKind::InvalidInput, "image is too large for BMP");
+    let width = u32::try_from(region.width).map_err(|_| too_large())?;
+    let height = u32::try_from(region.height).map_err(|_| too_large())?;
+    let row_size = (width * 3).next_multiple_of(4);
+    let image_size = row_size.checked_mul(height).ok_or_else(too_large)?;
+
+    // BITMAPFILEHEADER
+    out.write_all(b"BM")?;
+    out.write_all(&(HEADER_SIZE + image_size).to_le_bytes())?;
+    out.write_all(&[0; 4])?;
+    out.write_all(&HEADER_SIZE.to_le_bytes())?;
+    // BITMAPINFOHEADER
+    out.write_all(&40u32.to_le_bytes())?;
+    out.write_all(&width.to_le_bytes())?;
+    out.write_all(&height.to_le_bytes())?;
+    out.write_all(&1u16.to_le_bytes())?; // planes
+    out.write_all(&24u16.to_le_bytes())?; // bits per pixel
+    out.write_all(&0u32.to_le_bytes())?; // BI_RGB
+    out.write_all(&image_size.to_le_bytes())?;
+    out.write_all(&2835u32.to_le_bytes())?; // 72 DPI
+    out.write_all(&2835u32.to_le_bytes())?;
+    out.write_all(&0u32.to_le_bytes())?;
+    out.write_all(&0u32.to_le_bytes())?;
+
+    let mut data = Vec::with_capacity(image_size as usize);
+    for row in (0..region.height).rev() {
+        for column in 0..region.width {
+            let [red, green, blue] = region.pixel(memory, column, row);
+            data.extend_from_slice(&[blue, green, red]);
+        }
+        data.resize(data.len().next_multiple_of(4), 0);
+    }
+    out.write_all(&data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REGION: Region = Region {
+        x: 1,
+        y: 1,
+        width: 2,
+        height: 1,
+        stride: 16,
+    };
+
+    fn memory() -> Vec<u8> {
+        let mut memory = vec![0; 32];
+        memory[20..28].copy_from_slice(&[1, 2, 3, 255, 4, 5, 6, 255]);
+        memory
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!("1,1,2,1,16".parse(), Ok(REGION));
+        assert!("1,1,2,1".parse::<Region>().is_err());
+        assert!("1,1,2,1,x".parse::<Region>().is_err());
+    }
+
+    #[test]
+    fn frames() {
+        assert_eq!(frame_path("out/frame.ppm", 12), "out/frame_00012.ppm");
+        assert_eq!(frame_path("out.d/frame", 0), "out.d/frame_00000");
+    }
+
+    #[test]
+    fn ppm() {
+        let mut out = vec![];
+        write(&mut out, &memory(), &REGION, Format::Ppm).unwrap();
+        assert_eq!(out, b"P6\n2 1\n255\n\x01\x02\x03\x04\x05\x06");
+    }
+
+    #[test]
+    fn bmp() {
+        let mut out = vec![];
+        write(&mut out, &memory(), &REGION, Format::Bmp).unwrap();
+        assert_eq!(out.len(), 54 + 8);
+        assert_eq!(&out[54..], &[3, 2, 1, 6, 5, 4, 0, 0]);
+    }
+}
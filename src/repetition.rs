@@ -0,0 +1,349 @@
+//! Repeat a test until it stops getting faster, like the course's repetition tester, to find how fast it can run
+//! rather than how fast it ran once.
+//!
+//! Each trial times the parts of the test between [`Trial::begin`] and [`Trial::end`], and counts the bytes that it
+//! processed and the page faults that it took, which show what touching fresh memory costs. It also counts the times
+//! that the operating system switched to another thread or moved the test to another CPU, which disturb a trial, so
+//! that slow trials can be explained, or left out.
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::metrics::{self, read_cpu_timer};
+use crate::os_metrics::{read_context_switches, read_cpu, read_page_faults};
+
+/// The size of a page of memory, which page faults are reported per.
+pub const PAGE_SIZE: u64 = 4096;
+
+/// Where a test puts what it reads or parses.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Allocation {
+    /// A buffer that's allocated for each trial, whose pages fault when they're first written.
+    Fresh,
+    /// A buffer that's allocated once, whose pages only fault in the first trial.
+    Reuse,
+}
+
+impl FromStr for Allocation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fresh" => Ok(Self::Fresh),
+            "reuse" => Ok(Self::Reuse),
+            _ => Err("expected fresh or reuse".to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Allocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Fresh => "fresh",
+            Self::Reuse => "reuse",
+        })
+    }
+}
+
+/// What a trial, or the trials together, measured.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Measurement {
+    pub ticks: u64,
+    pub bytes: u64,
+    pub page_faults: u64,
+    /// The times that the operating system switched from the test to another thread.
+    pub context_switches: u64,
+    /// The times that the test ended on another CPU than it began on.
+    pub migrations: u64,
+}
+
+impl Measurement {
+    const fn add(&mut self, other: Self) {
+        self.ticks += other.ticks;
+        self.bytes += other.bytes;
+        self.page_faults += other.page_faults;
+        self.context_switches += other.context_switches;
+        self.migrations += other.migrations;
+    }
+
+    /// Whether the test was switched out or moved, so that it took longer than it would have.
+    #[must_use]
+    pub const fn is_disturbed(&self) -> bool {
+        self.context_switches > 0 || self.migrations > 0
+    }
+}
+
+// The counters when a part of a test began.
+#[derive(Debug)]
+struct Start {
+    page_faults: u64,
+    context_switches: u64,
+    cpu: Option<u32>,
+    ticks: u64,
+}
+
+/// A run of a test.
+#[derive(Debug, Default)]
+pub struct Trial {
+    measurement: Measurement,
+    started: Option<Start>,
+    unbalanced: bool,
+}
+
+impl Trial {
+    /// Start timing a part of the test.
+    pub fn begin(&mut self) {
+        self.unbalanced |= self.started.is_some();
+        self.started = Some(Start {
+            page_faults: read_page_faults(),
+            context_switches: read_context_switches(),
+            cpu: read_cpu(),
+            ticks: read_cpu_timer(),
+        });
+    }
+
+    /// Stop timing a part of the test.
+    pub fn end(&mut self) {
+        let ticks = read_cpu_timer();
+        let (page_faults, context_switches, cpu) = (read_page_faults(), read_context_switches(), read_cpu());
+        match self.started.take() {
+            Some(start) => {
+                self.measurement.ticks += ticks.wrapping_sub(start.ticks);
+                self.measurement.page_faults += page_faults.saturating_sub(start.page_faults);
+                self.measurement.context_switches += context_switches.saturating_sub(start.context_switches);
+                self.measurement.migrations += u64::from(cpu != start.cpu);
+            }
+            None => self.unbalanced = true,
+        }
+    }
+
+    /// Count bytes that the test processed.
+    pub fn count_bytes(&mut self, bytes: usize) {
+        self.measurement.bytes += u64::try_from(bytes).unwrap_or(u64::MAX);
+    }
+}
+
+/// The trials of a test.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Results {
+    /// The trials that were counted.
+    pub count: u64,
+    /// The trials that were counted, but were disturbed.
+    pub disturbed: u64,
+    /// The trials that were disturbed, and left out.
+    pub excluded: u64,
+    /// The sums of the trials.
+    pub total: Measurement,
+    /// The fastest trial.
+    pub min: Measurement,
+    /// The slowest trial.
+    pub max: Measurement,
+    /// The CPU's ticks per second.
+    pub frequency: u64,
+}
+
+/// Repeats a test that processes a number of bytes each trial.
+#[derive(Clone, Copy, Debug)]
+pub struct Tester {
+    pub bytes: u64,
+    pub frequency: u64,
+    /// How long to keep trying after the fastest trial so far.
+    pub try_for: Duration,
+    /// Whether to leave out trials that were disturbed.
+    pub exclude_disturbed: bool,
+}
+
+impl Tester {
+    #[must_use]
+    pub const fn new(bytes: u64, frequency: u64, try_for: Duration) -> Self {
+        Self {
+            bytes,
+            frequency,
+            try_for,
+            exclude_disturbed: false,
+        }
+    }
+
+    /// Run trials of `test` until none that's counted has been faster for [`try_for`](Self::try_for).
+    ///
+    /// # Errors
+    ///
+    /// If a trial doesn't end each part that it begins, or doesn't process the bytes that the tester expects.
+    pub fn run(&self, mut test: impl FnMut(&mut Trial)) -> Result<Results, String> {
+        let try_for = metrics::ticks(self.try_for, self.frequency);
+        let mut results = Results {
+            frequency: self.frequency,
+            ..Results::default()
+        };
+        let mut since = read_cpu_timer();
+        while read_cpu_timer().wrapping_sub(since) < try_for {
+            let mut trial = Trial::default();
+            test(&mut trial);
+            if trial.unbalanced || trial.started.is_some() {
+                return Err("a trial didn't end each part that it began".to_string());
+            }
+            let measurement = trial.measurement;
+            if measurement.bytes != self.bytes {
+                return Err(format!(
+                    "a trial processed {} bytes, not {}",
+                    measurement.bytes, self.bytes
+                ));
+            }
+            if measurement.is_disturbed() {
+                if self.exclude_disturbed {
+                    results.excluded += 1;
+                    continue;
+                }
+                results.disturbed += 1;
+            }
+            results.count += 1;
+            results.total.add(measurement);
+            if results.count == 1 || measurement.ticks < results.min.ticks {
+                results.min = measurement;
+                since = read_cpu_timer();
+            }
+            if measurement.ticks > results.max.ticks {
+                results.max = measurement;
+            }
+        }
+        Ok(results)
+    }
+}
+
+impl Results {
+    /// Write a measurement of `count` trials, per trial.
+    #[allow(clippy::cast_precision_loss)]
+    fn write_row(&self, f: &mut fmt::Formatter, label: &str, measurement: Measurement, count: u64) -> fmt::Result {
+        let per_trial = |value: u64| value as f64 / count as f64;
+        let (ticks, bytes, faults) = (
+            per_trial(measurement.ticks),
+            per_trial(measurement.bytes),
+            per_trial(measurement.page_faults),
+        );
+        let seconds = ticks / self.frequency.max(1) as f64;
+        write!(f, "{label}: {ticks:.0} ({:.6}ms)", seconds * 1000.0)?;
+        if bytes > 0.0 && seconds > 0.0 {
+            write!(f, " {:.6}gb/s", bytes / seconds / (1024.0 * 1024.0 * 1024.0))?;
+        }
+        if faults > 0.0 {
+            #[allow(clippy::cast_precision_loss)]
+            let pages = bytes / PAGE_SIZE as f64;
+            write!(f, " PF: {faults:.4}")?;
+            if pages > 0.0 {
+                write!(f, " ({:.4} per 4k page)", faults / pages)?;
+            }
+        }
+        if measurement.context_switches > 0 {
+            write!(f, " CS: {:.4}", per_trial(measurement.context_switches))?;
+        }
+        if measurement.migrations > 0 {
+            write!(f, " moved: {:.4}", per_trial(measurement.migrations))?;
+        }
+        writeln!(f)
+    }
+}
+
+impl fmt::Display for Results {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write_row(f, "Min", self.min, 1)?;
+        self.write_row(f, "Max", self.max, 1)?;
+        if self.count > 0 {
+            self.write_row(f, "Avg", self.total, self.count)?;
+        }
+        if self.disturbed > 0 {
+            writeln!(f, "Disturbed: {} of {} trials", self.disturbed, self.count)?;
+        }
+        if self.excluded > 0 {
+            writeln!(f, "Excluded: {} disturbed trials", self.excluded)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::hint::black_box;
+
+    const BYTES: usize = 4 << 20;
+
+    fn tester() -> Tester {
+        Tester::new(
+            BYTES as u64,
+            metrics::estimate_cpu_frequency(Duration::from_millis(10)),
+            Duration::from_millis(20),
+        )
+    }
+
+    #[test]
+    fn repeats() {
+        let results = tester()
+            .run(|trial| {
+                // The buffer isn't written until it's timed.
+                let mut buffer = vec![0u8; BYTES];
+                trial.begin();
+                for page in buffer.chunks_mut(PAGE_SIZE as usize) {
+                    page[0] = 1;
+                }
+                black_box(&buffer);
+                trial.end();
+                trial.count_bytes(buffer.len());
+            })
+            .unwrap();
+        assert!(results.count > 1);
+        assert!(results.min.ticks > 0 && results.min.ticks <= results.max.ticks);
+        assert_eq!(results.total.bytes, results.count * BYTES as u64);
+        #[cfg(any(windows, unix))]
+        assert!(results.total.page_faults > 0);
+
+        let text = results.to_string();
+        assert!(text.starts_with("Min: ") && text.contains("\nMax: ") && text.contains("\nAvg: "));
+        assert!(text.contains("gb/s"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn disturbed() {
+        // Sleeping switches to another thread.
+        let sleep = |trial: &mut Trial| {
+            trial.begin();
+            std::thread::sleep(Duration::from_millis(1));
+            trial.end();
+            trial.count_bytes(BYTES);
+        };
+        let results = tester().run(sleep).unwrap();
+        assert!(results.count > 0 && results.disturbed == results.count && results.excluded == 0);
+        assert!(results.min.context_switches > 0);
+        let text = results.to_string();
+        assert!(text.contains(" CS: ") && text.contains(&format!("\nDisturbed: {0} of {0} trials\n", results.count)));
+
+        let excluded = Tester {
+            exclude_disturbed: true,
+            ..tester()
+        }
+        .run(sleep)
+        .unwrap();
+        assert_eq!((excluded.count, excluded.disturbed), (0, 0));
+        assert!(excluded.excluded > 0);
+        assert!(excluded
+            .to_string()
+            .ends_with(&format!("\nExcluded: {} disturbed trials\n", excluded.excluded)));
+    }
+
+    #[test]
+    fn errors() {
+        let unbalanced = tester().run(|trial| {
+            trial.begin();
+            trial.count_bytes(BYTES);
+        });
+        assert!(unbalanced.unwrap_err().contains("didn't end"));
+        let short = tester().run(|trial| {
+            trial.begin();
+            trial.end();
+            trial.count_bytes(1);
+        });
+        assert!(short.unwrap_err().contains("1 bytes, not"));
+    }
+}
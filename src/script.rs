@@ -0,0 +1,313 @@
+//! Hooks for automating simulations with [Rhai](https://rhai.rs) scripts.
+//!
+//! A script defines any of these functions, which the simulator calls with the machine, `m`:
+//!
+//! - `on_instruction(m, address, text)`, before each instruction, with its physical address and its assembly.
+//! - `on_memory_write(m, address, before, after)`, after an instruction writes a byte of memory, with its physical
+//!   address and its values before and after.
+//! - `on_interrupt(m, number)`, before a software interrupt. If it returns `true`, the script handled the
+//!   interrupt, and the instruction is skipped without taking clocks.
+//!
+//! Registers are properties of the machine, like `m.ax`, `m.ds`, `m.ip` and `m.flags`, which hooks can change.
+//! `m.read(address)` and `m.read_word(address)` read memory at physical addresses, and `m.write(address, value)`
+//! and `m.write_word(address, value)` write it, without calling `on_memory_write`. `m.cycles` is the clock count,
+//! and `m.stop()` stops the simulation after the current instruction. Hooks can keep values between calls in
+//! `this`, a map, like `this.count = (this.count ?? 0) + 1`. The script's top-level statements run when it loads.
+
+// Scripts' integers are truncated to the sizes of registers and bytes.
+#![allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+
+use std::cell::RefCell;
+use std::mem;
+use std::rc::Rc;
+
+use rhai::{CallFnOptions, Dynamic, Engine, EvalAltResult, FuncArgs, Map, Scope, AST, INT};
+
+use crate::instruction::{Instruction, Operand, Operation, Register};
+use crate::sim::{Error, Machine, Registers, OF};
+use crate::state::{Assignment, Target};
+
+/// The state of the machine, which hooks share while they run.
+#[derive(Default)]
+struct State {
+    registers: Registers,
+    memory: Vec<u8>,
+    cycles: u64,
+    stop: bool,
+}
+
+impl State {
+    const fn index(&self, address: INT) -> usize {
+        address.rem_euclid(self.memory.len() as INT) as usize
+    }
+}
+
+/// The machine that hooks are called with.
+#[derive(Clone, Default)]
+struct Handle(Rc<RefCell<State>>);
+
+fn register(name: &str) -> Result<Target, Box<EvalAltResult>> {
+    name.parse::<Target>().map_err(Into::into)
+}
+
+fn engine() -> Engine {
+    let mut engine = Engine::new();
+    engine
+        .register_type_with_name::<Handle>("Machine")
+        .register_indexer_get(|m: &mut Handle, name: &str| -> Result<INT, Box<EvalAltResult>> {
+            let target = register(name)?;
+            Ok(INT::from(target.get(&m.0.borrow().registers)))
+        })
+        .register_indexer_set(
+            |m: &mut Handle, name: &str, value: INT| -> Result<(), Box<EvalAltResult>> {
+                let assignment = Assignment {
+                    target: register(name)?,
+                    value: value as u16,
+                };
+                assignment.apply(&mut m.0.borrow_mut().registers);
+                Ok(())
+            },
+        )
+        .register_get("cycles", |m: &mut Handle| m.0.borrow().cycles as INT)
+        .register_fn("read", |m: &mut Handle, address: INT| {
+            let state = m.0.borrow();
+            INT::from(state.memory[state.index(address)])
+        })
+        .register_fn("read_word", |m: &mut Handle, address: INT| {
+            let state = m.0.borrow();
+            let bytes = [
+                state.memory[state.index(address)],
+                state.memory[state.index(address + 1)],
+            ];
+            INT::from(u16::from_le_bytes(bytes))
+        })
+        .register_fn("write", |m: &mut Handle, address: INT, value: INT| {
+            let mut state = m.0.borrow_mut();
+            let index = state.index(address);
+            state.memory[index] = value as u8;
+        })
+        .register_fn("write_word", |m: &mut Handle, address: INT, value: INT| {
+            let mut state = m.0.borrow_mut();
+            for (offset, byte) in (0..).zip((value as u16).to_le_bytes()) {
+                let index = state.index(address + offset);
+                state.memory[index] = byte;
+            }
+        })
+        .register_fn("stop", |m: &mut Handle| m.0.borrow_mut().stop = true);
+    engine
+}
+
+/// A script, with the hooks that it defines.
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+    /// The values that hooks keep between calls.
+    this: Dynamic,
+    handle: Handle,
+    on_instruction: bool,
+    on_memory_write: bool,
+    on_interrupt: bool,
+}
+
+impl Script {
+    /// Compile a script, and run its top-level statements.
+    ///
+    /// # Errors
+    ///
+    /// If the script is invalid, or its statements fail.
+    pub fn new(source: &str) -> Result<Self, String> {
+        let engine = engine();
+        let ast = engine.compile(source).map_err(|error| error.to_string())?;
+        engine.run_ast(&ast).map_err(|error| error.to_string())?;
+        let defines = |name: &str, parameters: usize| {
+            ast.iter_functions()
+                .any(|function| function.name == name && function.params.len() == parameters)
+        };
+        Ok(Self {
+            on_instruction: defines("on_instruction", 3),
+            on_memory_write: defines("on_memory_write", 4),
+            on_interrupt: defines("on_interrupt", 2),
+            this: Dynamic::from_map(Map::new()),
+            handle: Handle::default(),
+            engine,
+            ast,
+        })
+    }
+
+    /// Whether a hook stopped the simulation.
+    #[must_use]
+    pub fn stopped(&self) -> bool {
+        self.handle.0.borrow().stop
+    }
+
+    /// Call a hook with the machine, and the arguments after it.
+    fn call(&mut self, machine: &mut Machine, name: &str, arguments: impl FuncArgs) -> Result<Dynamic, Error> {
+        {
+            let mut state = self.handle.0.borrow_mut();
+            state.registers = machine.registers;
+            state.memory = mem::take(&mut machine.memory);
+            state.cycles = machine.cycles;
+        }
+        let mut values = vec![Dynamic::from(self.handle.clone())];
+        arguments.parse(&mut values);
+        let options = CallFnOptions::new().eval_ast(false).bind_this_ptr(&mut self.this);
+        let result = self
+            .engine
+            .call_fn_with_options::<Dynamic>(options, &mut Scope::new(), &self.ast, name, values);
+        let mut state = self.handle.0.borrow_mut();
+        machine.registers = state.registers;
+        machine.memory = mem::take(&mut state.memory);
+        result.map_err(|error| Error::Script(format!("{name}: {error}")))
+    }
+
+    /// Execute an instruction, calling the hooks.
+    ///
+    /// # Errors
+    ///
+    /// If the instruction can't be executed, or a hook fails.
+    pub fn step(&mut self, machine: &mut Machine) -> Result<Instruction, Error> {
+        let decode = |machine: &Machine| {
+            let registers = &machine.registers;
+            machine.decode_at(registers.get(Register::Cs), registers.ip)
+        };
+        if self.on_instruction {
+            if let Some(instruction) = decode(machine) {
+                let address = machine.instruction_address() as INT;
+                let _ = self.call(machine, "on_instruction", (address, instruction.to_string()))?;
+            }
+        }
+        if self.on_interrupt {
+            if let Some(instruction) = decode(machine) {
+                let number = match (instruction.operation, instruction.operands[0]) {
+                    (Operation::Int, Operand::Immediate(number)) => Some(number as u8),
+                    (Operation::Int3, _) => Some(3),
+                    (Operation::Into, _) if machine.registers.flag(OF) => Some(4),
+                    _ => None,
+                };
+                if let Some(number) = number {
+                    let handled = self.call(machine, "on_interrupt", (INT::from(number),))?;
+                    if handled.as_bool().unwrap_or(false) {
+                        machine.registers.ip = machine.registers.ip.wrapping_add(instruction.size);
+                        return Ok(instruction);
+                    }
+                }
+            }
+        }
+        if !self.on_memory_write {
+            return machine.step();
+        }
+        machine.journal.get_or_insert_with(Vec::new);
+        let instruction = machine.step();
+        let writes = machine.journal.replace(vec![]).unwrap_or_default();
+        for (address, before) in writes {
+            let after = machine.memory[address];
+            let _ = self.call(
+                machine,
+                "on_memory_write",
+                (address as INT, INT::from(before), INT::from(after)),
+            )?;
+        }
+        instruction
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn machine(program: &[u8]) -> Machine {
+        let mut machine = Machine::new();
+        machine.load(0, program);
+        machine.registers.set(Register::Sp, 0x100);
+        machine
+    }
+
+    #[test]
+    fn instructions() {
+        let program = [
+            &[0b10111011, 0, 2][..], // mov bx, 512
+            &[0b01000011],           // inc bx
+            &[0b01000011],           // inc bx
+        ]
+        .concat();
+        let mut machine = machine(&program);
+        let mut script = Script::new(
+            r#"
+            fn on_instruction(m, address, text) {
+                this.count = (this.count ?? 0) + 1;
+                if text == "inc bx" {
+                    m.cx = this.count;
+                    m.write(0x300, m.bh);
+                    m.stop();
+                }
+            }
+            "#,
+        )
+        .unwrap();
+        script.step(&mut machine).unwrap();
+        assert!(!script.stopped());
+        script.step(&mut machine).unwrap();
+        assert!(script.stopped());
+        assert_eq!(machine.registers.get(Register::Bx), 0x201);
+        assert_eq!(machine.registers.get(Register::Cx), 2);
+        assert_eq!(machine.memory[0x300], 2);
+    }
+
+    #[test]
+    fn memory_writes() {
+        let program = [
+            &[0b10111000, 0x34, 0x12][..], // mov ax, 4660
+            &[0b01010000],                 // push ax
+        ]
+        .concat();
+        let mut machine = machine(&program);
+        let mut script = Script::new(
+            "
+            fn on_memory_write(m, address, before, after) {
+                m.write(address + 0x100, after + 1);
+            }
+            ",
+        )
+        .unwrap();
+        script.step(&mut machine).unwrap();
+        script.step(&mut machine).unwrap();
+        assert_eq!(machine.memory[0xfe..0x100], [0x34, 0x12]);
+        assert_eq!(machine.memory[0x1fe..0x200], [0x35, 0x13]);
+        assert_eq!(machine.journal, Some(vec![]));
+    }
+
+    #[test]
+    fn interrupts() {
+        let program = [
+            &[0b11001101, 0x21][..], // int 0x21
+            &[0b11001101, 0x80],     // int 0x80
+        ]
+        .concat();
+        let mut machine = machine(&program);
+        let mut script = Script::new(
+            "
+            fn on_interrupt(m, number) {
+                m.ax = m.read_word(0x40) + number;
+                number == 0x21
+            }
+            ",
+        )
+        .unwrap();
+        machine.load(0x40, &[0x00, 0x10]);
+        script.step(&mut machine).unwrap();
+        assert_eq!(machine.registers.get(Register::Ax), 0x1021);
+        assert_eq!(machine.registers.ip, 2);
+        // Interrupts that the script doesn't handle still happen.
+        assert!(matches!(script.step(&mut machine), Err(Error::Interrupt(0x80))));
+    }
+
+    #[test]
+    fn errors() {
+        assert!(Script::new("fn on_instruction(m, address, text) {").is_err());
+        assert!(Script::new("throw 1;").is_err());
+        let mut script = Script::new("fn on_instruction(m, address, text) { m.zz = 1; }").unwrap();
+        let error = script.step(&mut machine(&[0b01000011])).unwrap_err().to_string();
+        assert!(error.starts_with("script error in on_instruction: "), "{error}");
+        assert!(error.contains("unknown register \"zz\""), "{error}");
+    }
+}
@@ -0,0 +1,2159 @@
+//! Simulate the execution of 8086 machine code.
+
+// Values are reinterpreted between widths and signedness throughout.
+#![allow(clippy::cast_possible_truncation, clippy::cast_possible_wrap, clippy::cast_sign_loss)]
+
+use std::cell::Cell;
+use std::fmt::{self, Write as _};
+use std::io::{self, Read, Write};
+use std::str::FromStr;
+
+use crate::access::AccessLog;
+use crate::bios;
+use crate::clocks::Timing;
+use crate::decode::decode;
+use crate::dos;
+use crate::instruction::{Instruction, Memory, Operand, Operation, Register, Rep};
+use crate::keyboard::{self, Keyboard};
+use crate::pic::Pic;
+use crate::ports::{PortIo, PortLog};
+use crate::stats::Statistics;
+use crate::table::{self, FlagEffects};
+
+/// The 8086 addresses 1 MiB of memory.
+pub const MEMORY_SIZE: usize = 1 << 20;
+/// The least memory that a machine can have: the interrupt vectors, the BIOS data area and some room for a program.
+pub const MIN_MEMORY_SIZE: usize = 1 << 12;
+
+// The clocks for the 8086 to respond to an interrupt request from the PIC.
+const INTERRUPT_RESPONSE: u64 = 61;
+// The clocks for the 8086 to respond to the trap flag.
+const TRAP_RESPONSE: u64 = 50;
+
+// The most bytes fetched for an instruction that wraps around the end of memory or of its segment.
+const FETCH_SIZE: usize = 16;
+// The number of decoded instructions that are cached. An instruction's slot is its physical address modulo this.
+const CACHE_SIZE: usize = 1 << 14;
+
+// Bits of the flags register.
+pub const CF: u16 = 1 << 0;
+pub const PF: u16 = 1 << 2;
+pub const AF: u16 = 1 << 4;
+pub const ZF: u16 = 1 << 6;
+pub const SF: u16 = 1 << 7;
+pub const TF: u16 = 1 << 8;
+pub const IF: u16 = 1 << 9;
+pub const DF: u16 = 1 << 10;
+pub const OF: u16 = 1 << 11;
+// The flags that exist on the 8086.
+pub const FLAGS_MASK: u16 = CF | PF | AF | ZF | SF | TF | IF | DF | OF;
+// The flags that arithmetic sets.
+pub(crate) const STATUS: u16 = CF | PF | AF | ZF | SF | OF;
+
+const FLAG_LETTERS: [(u16, char); 9] = [
+    (CF, 'C'),
+    (PF, 'P'),
+    (AF, 'A'),
+    (ZF, 'Z'),
+    (SF, 'S'),
+    (TF, 'T'),
+    (IF, 'I'),
+    (DF, 'D'),
+    (OF, 'O'),
+];
+
+// The order in which the reference simulator prints registers.
+const PRINT_ORDER: [Register; 12] = [
+    Register::Ax,
+    Register::Bx,
+    Register::Cx,
+    Register::Dx,
+    Register::Sp,
+    Register::Bp,
+    Register::Si,
+    Register::Di,
+    Register::Es,
+    Register::Cs,
+    Register::Ss,
+    Register::Ds,
+];
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Registers {
+    /// AX CX DX BX SP BP SI DI, in REG field order.
+    pub general: [u16; 8],
+    /// ES CS SS DS, in SR field order.
+    pub segments: [u16; 4],
+    pub ip: u16,
+    pub flags: u16,
+}
+
+impl Registers {
+    #[must_use]
+    pub const fn get(&self, register: Register) -> u16 {
+        let field = register.field() as usize;
+        if register.is_segment() {
+            self.segments[field]
+        } else if register.is_wide() {
+            self.general[field]
+        } else if field < 4 {
+            self.general[field] & 0xff
+        } else {
+            self.general[field - 4] >> 8
+        }
+    }
+
+    pub const fn set(&mut self, register: Register, value: u16) {
+        let field = register.field() as usize;
+        if register.is_segment() {
+            self.segments[field] = value;
+        } else if register.is_wide() {
+            self.general[field] = value;
+        } else if field < 4 {
+            self.general[field] = (self.general[field] & 0xff00) | (value & 0xff);
+        } else {
+            self.general[field - 4] = (self.general[field - 4] & 0xff) | ((value & 0xff) << 8);
+        }
+    }
+
+    #[must_use]
+    pub const fn flag(&self, flag: u16) -> bool {
+        self.flags & flag != 0
+    }
+
+    pub const fn set_flag(&mut self, flag: u16, value: bool) {
+        if value {
+            self.flags |= flag;
+        } else {
+            self.flags &= !flag;
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// The bytes at this address do not encode an instruction.
+    Decode(usize),
+    /// The instruction is valid, but its simulation is not implemented.
+    Unsupported(Instruction),
+    /// DIV or IDIV divided by zero, or the quotient was too large for its register, and no handler is installed.
+    Divide(Instruction),
+    /// An interrupt has no handler in the vector table, and no built-in service.
+    Interrupt(u8),
+    /// In strict mode, the instruction reads these flags, which are undefined.
+    UndefinedFlags(Instruction, u16),
+    /// HLT stopped the processor, and no interrupt can resume it.
+    Halted,
+    /// With faulting addressing, the instruction accessed memory at this segment and offset, which is beyond memory,
+    /// or wraps around the end of its segment or of the address space.
+    Fault(Instruction, u16, u16),
+    /// The instruction waits for a key, but the console input ended.
+    InputEnded(Instruction),
+    /// A script's hook failed.
+    Script(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Decode(address) => write!(f, "invalid instruction at {address:#x}"),
+            Self::Unsupported(instruction) => write!(f, "unsupported instruction: {instruction}"),
+            Self::Divide(instruction) => write!(f, "divide error: {instruction}"),
+            Self::Interrupt(number) => write!(f, "no handler for interrupt {number:#x}"),
+            Self::UndefinedFlags(instruction, flags) => {
+                write!(f, "reads undefined flags {}: {instruction}", Flags(*flags))
+            }
+            Self::Halted => f.write_str("halted"),
+            Self::Fault(instruction, segment, offset) => {
+                write!(
+                    f,
+                    "memory access at {segment:04x}:{offset:04x} is out of range: {instruction}"
+                )
+            }
+            Self::InputEnded(instruction) => write!(f, "waiting for a key after the input ended: {instruction}"),
+            Self::Script(message) => write!(f, "script error in {message}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// How to treat the flags that the 8086 manual documents as undefined after an instruction.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UndefinedFlags {
+    /// Leave them unchanged. Logical operations clear AF, and multi-bit shifts and rotates set OF from the last
+    /// bit, though.
+    #[default]
+    Unchanged,
+    /// Approximate real hardware: MUL and IMUL set SF, ZF and PF from the low half of the product, and clear AF,
+    /// and shifts clear AF. Other undefined flags are as for `Unchanged`. The 8086's actual values depend on its
+    /// microcode, so this model is not exact.
+    Hardware,
+    /// Consider them undefined until an instruction defines them, and fail on an instruction that reads one.
+    /// PUSHF, LAHF and interrupts copy flags without reading them.
+    Strict,
+}
+
+impl FromStr for UndefinedFlags {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "unchanged" => Ok(Self::Unchanged),
+            "hardware" => Ok(Self::Hardware),
+            "strict" => Ok(Self::Strict),
+            _ => Err("expected unchanged, hardware or strict".to_string()),
+        }
+    }
+}
+
+/// What happens when an instruction accesses memory across the end of its segment, past the 1 MiB address space,
+/// or beyond the machine's memory.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Addressing {
+    /// Wrap around, like the 8086: a word at offset 0xffff continues at offset 0 of its segment, addresses past
+    /// 1 MiB continue at 0, and memory smaller than 1 MiB repeats through the address space.
+    #[default]
+    Wrap,
+    /// Fail, to catch programs that wrap by mistake. Instruction fetches, and the services of DOS and the BIOS,
+    /// still wrap.
+    Fault,
+}
+
+impl FromStr for Addressing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "wrap" => Ok(Self::Wrap),
+            "fault" => Ok(Self::Fault),
+            _ => Err("expected wrap or fault".to_string()),
+        }
+    }
+}
+
+/// How a cached instruction is dispatched to the code that executes it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Dispatch {
+    /// Call the handler and use the timing that were chosen when the instruction was decoded.
+    #[default]
+    Threaded,
+    /// Choose the handler and work out the timing again on every execution, so that only the decoding is cached. It
+    /// is kept to measure what caching them saves: `cargo bench --bench dispatch`.
+    Match,
+}
+
+/// Parse a memory size in bytes, or with a "K" or "M" suffix for KiB or MiB. It must be a power of two, from
+/// [`MIN_MEMORY_SIZE`] to [`MEMORY_SIZE`].
+///
+/// # Errors
+///
+/// If the size is invalid.
+pub fn memory_size(text: &str) -> Result<usize, String> {
+    let upper = text.to_ascii_uppercase();
+    let (digits, unit) = [("K", 1 << 10), ("M", 1 << 20)]
+        .into_iter()
+        .find_map(|(suffix, unit)| upper.strip_suffix(suffix).map(|digits| (digits, unit)))
+        .unwrap_or((&upper, 1));
+    let size = digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|size| size.checked_mul(unit))
+        .ok_or_else(|| format!("invalid memory size {text:?}"))?;
+    if !size.is_power_of_two() || !(MIN_MEMORY_SIZE..=MEMORY_SIZE).contains(&size) {
+        return Err(format!("memory size must be a power of two from 4K to 1M, not {text}"));
+    }
+    Ok(size)
+}
+
+/// The physical address of a segment and offset: the segment times 16 plus the offset. Like the 8086, addresses
+/// past 1 MiB wrap around to 0.
+#[must_use]
+pub const fn physical(segment: u16, offset: u16) -> usize {
+    (((segment as usize) << 4) + offset as usize) & (MEMORY_SIZE - 1)
+}
+
+/// A decoded instruction, its handler and its timing, with the bytes that it was decoded from, to check that the code
+/// hasn't changed.
+#[derive(Clone, Copy)]
+struct Cached {
+    address: usize,
+    bytes: [u8; FETCH_SIZE],
+    instruction: Instruction,
+    handler: Handler,
+    timing: Timing,
+}
+
+pub struct Machine {
+    pub registers: Registers,
+    pub memory: Vec<u8>,
+    /// Console output from DOS and BIOS services, for the host to display and clear.
+    pub output: Vec<u8>,
+    /// Console input for DOS services and the keyboard.
+    pub input: Box<dyn Read>,
+    pub keyboard: Keyboard,
+    /// The devices on the I/O ports, other than the PIC. By default, accesses are logged to standard error.
+    pub ports: Box<dyn PortIo>,
+    pub pic: Pic,
+    /// Request IRQ 0 every this many clocks, like the PC's timer.
+    pub timer: Option<u64>,
+    /// The estimated clocks that the executed instructions took.
+    pub cycles: u64,
+    pub undefined_flags: UndefinedFlags,
+    pub addressing: Addressing,
+    pub dispatch: Dispatch,
+    /// With faulting addressing, the segment and offset of the first access that was out of range.
+    fault: Cell<Option<(u16, u16)>>,
+    /// In strict mode, the flags that are undefined.
+    pub(crate) undefined: u16,
+    /// The exit code, once the program terminates through DOS.
+    pub exit_code: Option<u8>,
+    /// Whether HLT stopped the processor until an interrupt.
+    pub halted: bool,
+    /// If set, the address and old value of each byte that is written to memory, so writes can be undone.
+    pub journal: Option<Vec<(usize, u8)>>,
+    /// If set, statistics about the executed instructions.
+    pub statistics: Option<Statistics>,
+    /// If set, where the memory accesses of instructions are logged.
+    pub accesses: Option<AccessLog>,
+    /// The segment override prefix of the instruction being executed.
+    segment_override: Option<Register>,
+    /// Decoded instructions, so that loops aren't decoded on every iteration.
+    cache: Vec<Option<Cached>>,
+}
+
+impl Default for Machine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Machine {
+    /// A machine with 1 MiB of memory, like the 8086 can address.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::with_memory(MEMORY_SIZE)
+    }
+
+    /// A machine with less memory, for small experiments. See [`memory_size`].
+    ///
+    /// # Panics
+    ///
+    /// If the size isn't a power of two from [`MIN_MEMORY_SIZE`] to [`MEMORY_SIZE`].
+    #[must_use]
+    pub fn with_memory(size: usize) -> Self {
+        assert!(size.is_power_of_two() && (MIN_MEMORY_SIZE..=MEMORY_SIZE).contains(&size));
+        Self {
+            registers: Registers::default(),
+            memory: vec![0; size],
+            output: vec![],
+            input: Box::new(io::empty()),
+            keyboard: Keyboard::default(),
+            ports: Box::new(PortLog::new(io::stderr())),
+            pic: Pic::default(),
+            timer: None,
+            cycles: 0,
+            undefined_flags: UndefinedFlags::Unchanged,
+            addressing: Addressing::Wrap,
+            dispatch: Dispatch::Threaded,
+            fault: Cell::new(None),
+            undefined: 0,
+            exit_code: None,
+            halted: false,
+            journal: None,
+            statistics: None,
+            accesses: None,
+            segment_override: None,
+            cache: vec![None; CACHE_SIZE],
+        }
+    }
+
+    /// Copy bytes into memory at an address.
+    ///
+    /// # Panics
+    ///
+    /// If the bytes do not fit in memory.
+    pub fn load(&mut self, address: usize, bytes: &[u8]) {
+        self.memory[address..address + bytes.len()].copy_from_slice(bytes);
+    }
+
+    /// The physical address of the next instruction, at CS:IP.
+    #[must_use]
+    pub const fn instruction_address(&self) -> usize {
+        physical(
+            self.registers.segments[Register::Cs.field() as usize],
+            self.registers.ip,
+        )
+    }
+
+    /// The index in memory of a segment and offset. Memory smaller than 1 MiB repeats through the address space.
+    #[must_use]
+    pub const fn address(&self, segment: u16, offset: u16) -> usize {
+        physical(segment, offset) & (self.memory.len() - 1)
+    }
+
+    /// Decode the instruction at an address, or `None` if it is invalid.
+    #[must_use]
+    pub fn decode_at(&self, segment: u16, offset: u16) -> Option<Instruction> {
+        let address = self.address(segment, offset);
+        let mut wrapped = [0; FETCH_SIZE];
+        let code = if address + FETCH_SIZE <= self.memory.len() && usize::from(offset) + FETCH_SIZE <= 1 << 16 {
+            &self.memory[address..]
+        } else {
+            // The offset wraps around within the segment, and the address wraps around the end of memory.
+            for (i, byte) in (0..).zip(&mut wrapped) {
+                *byte = self.memory[self.address(segment, offset.wrapping_add(i))];
+            }
+            &wrapped[..]
+        };
+        decode(code)
+    }
+
+    /// Decode the instruction at an address, and choose its handler and work out its timing, or reuse them if the
+    /// instruction's bytes haven't changed, which also covers self-modifying code and writes to `memory` from outside
+    /// the machine.
+    fn fetch(&mut self, segment: u16, offset: u16) -> Option<(Instruction, Handler, Timing)> {
+        let address = self.address(segment, offset);
+        // Instructions that might wrap around aren't cached.
+        if address + FETCH_SIZE > self.memory.len() || usize::from(offset) + FETCH_SIZE > 1 << 16 {
+            return self
+                .decode_at(segment, offset)
+                .map(|instruction| (instruction, handler(&instruction), Timing::new(&instruction)));
+        }
+        let slot = address % CACHE_SIZE;
+        if let Some(cached) = &self.cache[slot] {
+            let size = usize::from(cached.instruction.size);
+            if cached.address == address && self.memory[address..address + size] == cached.bytes[..size] {
+                let instruction = cached.instruction;
+                return Some(match self.dispatch {
+                    Dispatch::Threaded => (instruction, cached.handler, cached.timing),
+                    Dispatch::Match => (instruction, handler(&instruction), Timing::new(&instruction)),
+                });
+            }
+        }
+        let instruction = decode(&self.memory[address..])?;
+        let handler = handler(&instruction);
+        let timing = Timing::new(&instruction);
+        // Redundant prefixes can make an instruction longer than the bytes that are kept.
+        if usize::from(instruction.size) <= FETCH_SIZE {
+            let mut bytes = [0; FETCH_SIZE];
+            bytes.copy_from_slice(&self.memory[address..address + FETCH_SIZE]);
+            self.cache[slot] = Some(Cached {
+                address,
+                bytes,
+                instruction,
+                handler,
+                timing,
+            });
+        }
+        Some((instruction, handler, timing))
+    }
+
+    /// Decode and execute the instruction at CS:IP, after entering the handler of a pending interrupt request, and
+    /// before entering the single-step handler if the trap flag is set.
+    ///
+    /// # Errors
+    ///
+    /// If the instruction can't be decoded or simulated. IP is left at the instruction. Or if the processor is
+    /// halted, and no interrupt resumes it.
+    pub fn step(&mut self) -> Result<Instruction, Error> {
+        if self.halted {
+            self.wait();
+        }
+        let start = self.cycles;
+        self.mark_instruction();
+        self.respond();
+        if self.halted {
+            return Err(Error::Halted);
+        }
+        // An interrupt response can change CS:IP.
+        self.mark_instruction();
+        let before = self.registers;
+        let ip = self.registers.ip;
+        let (instruction, handler, timing) = self
+            .fetch(self.registers.get(Register::Cs), ip)
+            .ok_or_else(|| Error::Decode(self.instruction_address()))?;
+        let count = match instruction.operands[1] {
+            Operand::Register(Register::Cl) => self.registers.get(Register::Cl),
+            _ => 1,
+        };
+        if self.undefined_flags == UndefinedFlags::Strict {
+            let read = flags_read(&instruction, count) & self.undefined;
+            if read != 0 {
+                return Err(Error::UndefinedFlags(instruction, read));
+            }
+        }
+        let odd = timing.depends_on_address()
+            && instruction.operands.iter().any(|operand| match operand {
+                Operand::Memory(memory) => self.offset(*memory) & 1 != 0,
+                _ => false,
+            });
+        self.registers.ip = ip.wrapping_add(instruction.size);
+        self.segment_override = instruction.segment;
+        // With faulting addressing, the instruction's writes are journaled, to undo them if it faults.
+        let journaled = self.journal.is_some();
+        if self.addressing == Addressing::Fault && !journaled {
+            self.journal = Some(vec![]);
+        }
+        let mark = self.journal.as_ref().map_or(0, Vec::len);
+        let result = handler(self, &instruction);
+        let fault = self.fault.take();
+        // The access that faults is ignored, but the instruction can write before it, like a far call that pushes CS
+        // and then IP, or each iteration of REP STOSB.
+        if let (Ok(()), Some(_), Some(journal)) = (&result, fault, &mut self.journal) {
+            for (address, value) in journal.drain(mark..).rev() {
+                self.memory[address] = value;
+            }
+        }
+        if !journaled {
+            self.journal = None;
+        }
+        if let Err(error) = result {
+            self.registers.ip = ip;
+            return Err(error);
+        }
+        if let Some((segment, offset)) = fault {
+            self.registers = before;
+            return Err(Error::Fault(instruction, segment, offset));
+        }
+        if self.undefined_flags == UndefinedFlags::Strict {
+            let (defined, undefined) = flags_written(&instruction, count, before.get(Register::Cx));
+            self.undefined = (self.undefined & !defined) | undefined;
+        }
+
+        let elapsed = timing.clocks(&instruction, &before, &self.registers, odd);
+        self.cycles += u64::from(elapsed);
+        if let Some(statistics) = &mut self.statistics {
+            // The clocks for words at odd addresses are those that the instruction would save at even addresses.
+            let mut even = before;
+            even.set(Register::Sp, before.get(Register::Sp) & !1);
+            let penalty = elapsed - timing.clocks(&instruction, &even, &self.registers, false);
+            let next = physical(self.registers.get(Register::Cs), self.registers.ip);
+            statistics.record(
+                physical(before.get(Register::Cs), ip),
+                &instruction,
+                elapsed,
+                penalty,
+                next,
+            );
+        }
+        // With TF set before the instruction, INT 1 follows it. Entering the handler clears TF, so the handler
+        // isn't traced, and IRET restores it. Without a handler, the trap is ignored.
+        if before.flag(TF) {
+            let (segment, offset) = self.vector(1);
+            if (segment, offset) != (0, 0) {
+                self.enter(segment, offset);
+                self.cycles += TRAP_RESPONSE;
+            }
+        }
+        if let Some(period) = self.timer {
+            if self.cycles / period > start / period {
+                self.pic.request(0);
+            }
+        }
+        Ok(instruction)
+    }
+
+    /// Wait in HLT until the next timer tick, unless an interrupt request is pending.
+    const fn wait(&mut self) {
+        if self.registers.flag(IF) && self.pic.requests & !self.pic.mask == 0 {
+            if let Some(period) = self.timer {
+                self.cycles = (self.cycles / period + 1) * period;
+                self.pic.request(0);
+            }
+        }
+    }
+
+    /// Enter the handler of the PIC's pending interrupt request, if interrupts are enabled.
+    fn respond(&mut self) {
+        if !self.registers.flag(IF) {
+            return;
+        }
+        let Some(number) = self.pic.acknowledge() else {
+            return;
+        };
+        match self.vector(number) {
+            // Without a handler, end the interrupt, like the BIOS's default handlers.
+            (0, 0) => self.pic.end_of_interrupt(),
+            (segment, offset) => {
+                self.enter(segment, offset);
+                self.cycles += INTERRUPT_RESPONSE;
+                self.halted = false;
+            }
+        }
+    }
+
+    /// Log the accesses that follow as made by the instruction at CS:IP.
+    fn mark_instruction(&self) {
+        if let Some(accesses) = &self.accesses {
+            accesses.instruction.set(self.instruction_address());
+        }
+    }
+
+    /// Jump to the target of a jump or loop if the condition holds.
+    fn jump_if(&mut self, instruction: &Instruction, condition: bool) {
+        if condition {
+            self.registers.ip = self.target(instruction.operands[0]);
+        }
+    }
+
+    /// Decrement CX, and jump if it isn't 0 and the condition holds.
+    fn loop_if(&mut self, instruction: &Instruction, condition: bool) {
+        let cx = self.registers.get(Register::Cx).wrapping_sub(1);
+        self.registers.set(Register::Cx, cx);
+        self.jump_if(instruction, cx != 0 && condition)
+    }
+
+    /// Shift or rotate the operand by 1 or CL bits, one bit at a time. The 8086 doesn't mask CL, so a count of 255
+    /// shifts 255 times. A count of 0 changes nothing. OF is only defined for a count of 1, but the 8086 sets it
+    /// according to the last bit shifted, as here. Shifts set SF, ZF and PF. AF is undefined.
+    fn shift(&mut self, instruction: &Instruction) {
+        let [destination, count] = instruction.operands;
+        let operation = instruction.operation;
+        let wide = instruction.wide;
+        let sign: u16 = if wide { 0x8000 } else { 0x80 };
+        let count = self.read(count, false);
+        if count == 0 {
+            return;
+        }
+
+        let mut value = self.read(destination, wide);
+        let mut carry = self.registers.flag(CF);
+        let mut overflow = false;
+        for _ in 0..count {
+            let msb = value & sign != 0;
+            let lsb = value & 1 != 0;
+            value = match operation {
+                Operation::Rol => (value << 1) | u16::from(msb),
+                Operation::Ror => (value >> 1) | if lsb { sign } else { 0 },
+                Operation::Rcl => (value << 1) | u16::from(carry),
+                Operation::Rcr => (value >> 1) | if carry { sign } else { 0 },
+                Operation::Shl => value << 1,
+                Operation::Shr => value >> 1,
+                _ => (value >> 1) | (value & sign),
+            };
+            if !wide {
+                value &= 0xff;
+            }
+            carry = match operation {
+                Operation::Rol | Operation::Rcl | Operation::Shl => msb,
+                _ => lsb,
+            };
+            overflow = match operation {
+                // The sign changed.
+                Operation::Rol | Operation::Rcl | Operation::Shl => (value & sign != 0) != carry,
+                Operation::Ror | Operation::Rcr => (value & sign != 0) != (value & (sign >> 1) != 0),
+                Operation::Shr => msb,
+                _ => false,
+            };
+        }
+
+        self.write(destination, wide, value);
+        self.registers.set_flag(CF, carry);
+        self.registers.set_flag(OF, overflow);
+        if matches!(operation, Operation::Shl | Operation::Shr | Operation::Sar) {
+            self.registers.set_flag(ZF, value == 0);
+            self.registers.set_flag(SF, value & sign != 0);
+            self.registers
+                .set_flag(PF, (value as u8).count_ones().is_multiple_of(2));
+            if self.undefined_flags == UndefinedFlags::Hardware {
+                self.registers.set_flag(AF, false);
+            }
+        }
+    }
+
+    /// Multiply the accumulator by the operand, into AX for bytes or DX:AX for words. CF and OF are set if the
+    /// upper half of the product is significant. SF, ZF, AF and PF are undefined.
+    fn multiply(&mut self, instruction: &Instruction) {
+        let wide = instruction.wide;
+        let a = self.registers.get(Register::from_field(wide, 0));
+        let b = self.read(instruction.operands[0], wide);
+        let (product, significant) = match (instruction.operation, wide) {
+            (Operation::Mul, false) => {
+                let product = u32::from(a) * u32::from(b);
+                (product, product > 0xff)
+            }
+            (Operation::Mul, true) => {
+                let product = u32::from(a) * u32::from(b);
+                (product, product > 0xffff)
+            }
+            (_, false) => {
+                let product = i32::from(a as u8 as i8) * i32::from(b as u8 as i8);
+                (product as u32, i8::try_from(product).is_err())
+            }
+            (_, true) => {
+                let product = i32::from(a as i16) * i32::from(b as i16);
+                (product as u32, i16::try_from(product).is_err())
+            }
+        };
+        self.registers.set(Register::Ax, product as u16);
+        if wide {
+            self.registers.set(Register::Dx, (product >> 16) as u16);
+        }
+        self.registers.set_flag(CF, significant);
+        self.registers.set_flag(OF, significant);
+        if self.undefined_flags == UndefinedFlags::Hardware {
+            let (low, sign) = if wide {
+                (product as u16, 0x8000)
+            } else {
+                (product as u16 & 0xff, 0x80)
+            };
+            self.registers.set_flag(SF, low & sign != 0);
+            self.registers.set_flag(ZF, low == 0);
+            self.registers.set_flag(PF, (low as u8).count_ones().is_multiple_of(2));
+            self.registers.set_flag(AF, false);
+        }
+    }
+
+    /// Divide AX for bytes or DX:AX for words by the operand, into a quotient in AL or AX and a remainder in AH or
+    /// DX. Returns false, leaving registers unchanged, if the divisor is zero or the quotient doesn't fit.
+    /// Flags are undefined and left unchanged.
+    fn divide(&mut self, instruction: &Instruction) -> bool {
+        let wide = instruction.wide;
+        let divisor = self.read(instruction.operands[0], wide);
+        if divisor == 0 {
+            return false;
+        }
+        let ax = self.registers.get(Register::Ax);
+        let dividend = if wide {
+            (u32::from(self.registers.get(Register::Dx)) << 16) | u32::from(ax)
+        } else {
+            u32::from(ax)
+        };
+        let (quotient, remainder) = if instruction.operation == Operation::Div {
+            let quotient = dividend / u32::from(divisor);
+            if quotient > if wide { 0xffff } else { 0xff } {
+                return false;
+            }
+            (quotient as u16, (dividend % u32::from(divisor)) as u16)
+        } else {
+            let (dividend, divisor) = if wide {
+                (dividend as i32, i32::from(divisor as i16))
+            } else {
+                (i32::from(dividend as u16 as i16), i32::from(divisor as u8 as i8))
+            };
+            // The remainder has the sign of the dividend. The 8086 faults on the most negative quotient, too, and
+            // i32::MIN / -1 overflows even before the quotient is checked.
+            let Some(quotient) = dividend.checked_div(divisor) else {
+                return false;
+            };
+            let limit = if wide { 0x7fff } else { 0x7f };
+            if quotient.abs() > limit {
+                return false;
+            }
+            (quotient as u16, (dividend % divisor) as u16)
+        };
+        if wide {
+            self.registers.set(Register::Ax, quotient);
+            self.registers.set(Register::Dx, remainder);
+        } else {
+            self.registers.set(Register::Al, quotient);
+            self.registers.set(Register::Ah, remainder);
+        }
+        true
+    }
+
+    /// Execute a string instruction. With a REP prefix, repeat it until CX is zero, or until ZF differs from the
+    /// prefix for CMPS and SCAS.
+    fn string(&mut self, instruction: &Instruction) {
+        let operation = instruction.operation;
+        let wide = instruction.wide;
+        let accumulator = Register::from_field(wide, 0);
+        // SI and DI are incremented, or decremented if DF is set, by the size of the operand.
+        let size: u16 = if wide { 2 } else { 1 };
+        let delta = if self.registers.flag(DF) {
+            size.wrapping_neg()
+        } else {
+            size
+        };
+
+        // The source can be overridden, but the destination is always in the extra segment.
+        let source = self.data_segment();
+        let destination = self.registers.get(Register::Es);
+        loop {
+            if instruction.rep.is_some() && self.registers.get(Register::Cx) == 0 {
+                break;
+            }
+
+            let si = self.registers.get(Register::Si);
+            let di = self.registers.get(Register::Di);
+            match operation {
+                Operation::Movs => {
+                    let value = self.read_memory(source, si, wide);
+                    self.write_memory(destination, di, wide, value);
+                }
+                Operation::Cmps => {
+                    let a = self.read_memory(source, si, wide);
+                    let b = self.read_memory(destination, di, wide);
+                    self.arithmetic(Operation::Cmp, wide, a, b);
+                }
+                Operation::Scas => {
+                    let a = self.registers.get(accumulator);
+                    let b = self.read_memory(destination, di, wide);
+                    self.arithmetic(Operation::Cmp, wide, a, b);
+                }
+                Operation::Lods => {
+                    let value = self.read_memory(source, si, wide);
+                    self.registers.set(accumulator, value);
+                }
+                _ => {
+                    let value = self.registers.get(accumulator);
+                    self.write_memory(destination, di, wide, value);
+                }
+            }
+            if matches!(operation, Operation::Movs | Operation::Cmps | Operation::Lods) {
+                self.registers.set(Register::Si, si.wrapping_add(delta));
+            }
+            if operation != Operation::Lods {
+                self.registers.set(Register::Di, di.wrapping_add(delta));
+            }
+
+            let Some(rep) = instruction.rep else {
+                break;
+            };
+            let cx = self.registers.get(Register::Cx).wrapping_sub(1);
+            self.registers.set(Register::Cx, cx);
+            if matches!(operation, Operation::Cmps | Operation::Scas) && self.registers.flag(ZF) != (rep == Rep::Rep) {
+                break;
+            }
+        }
+    }
+
+    /// Raise an interrupt, entering the handler in the vector table at address 0. IP is already past the instruction,
+    /// even for a divide error, as on the 8086.
+    ///
+    /// If the vector is 0:0, the video (10h), keyboard (16h) and DOS (20h and 21h) services are emulated instead, so
+    /// programs can install their own handlers, but can't chain to the emulated ones.
+    fn interrupt(&mut self, number: u8, instruction: &Instruction) -> Result<(), Error> {
+        let (segment, offset) = self.vector(number);
+        if (segment, offset) == (0, 0) {
+            return match number {
+                0 => Err(Error::Divide(*instruction)),
+                0x10 => bios::video(self, instruction),
+                0x16 => keyboard::interrupt(self, instruction),
+                0x20 | 0x21 => dos::interrupt(self, number, instruction),
+                _ => Err(Error::Interrupt(number)),
+            };
+        }
+        self.enter(segment, offset);
+        Ok(())
+    }
+
+    /// The segment and offset of an interrupt's handler.
+    fn vector(&self, number: u8) -> (u16, u16) {
+        let offset = u16::from(number) * 4;
+        (self.read_memory(0, offset + 2, true), self.read_memory(0, offset, true))
+    }
+
+    /// Push the flags, clear IF and TF, push CS and IP, and jump to an interrupt handler.
+    fn enter(&mut self, segment: u16, offset: u16) {
+        self.push(self.registers.flags);
+        self.registers.set_flag(IF, false);
+        self.registers.set_flag(TF, false);
+        self.push(self.registers.get(Register::Cs));
+        self.push(self.registers.ip);
+        self.registers.set(Register::Cs, segment);
+        self.registers.ip = offset;
+    }
+
+    /// Whether a conditional jump is taken, or `None` if the operation isn't a conditional jump.
+    const fn condition(&self, operation: Operation) -> Option<bool> {
+        let flags = &self.registers;
+        let less = flags.flag(SF) != flags.flag(OF);
+        Some(match operation {
+            Operation::Jo => flags.flag(OF),
+            Operation::Jno => !flags.flag(OF),
+            Operation::Jb => flags.flag(CF),
+            Operation::Jnb => !flags.flag(CF),
+            Operation::Je => flags.flag(ZF),
+            Operation::Jne => !flags.flag(ZF),
+            Operation::Jbe => flags.flag(CF) || flags.flag(ZF),
+            Operation::Jnbe => !flags.flag(CF) && !flags.flag(ZF),
+            Operation::Js => flags.flag(SF),
+            Operation::Jns => !flags.flag(SF),
+            Operation::Jp => flags.flag(PF),
+            Operation::Jnp => !flags.flag(PF),
+            Operation::Jl => less,
+            Operation::Jnl => !less,
+            Operation::Jle => less || flags.flag(ZF),
+            Operation::Jnle => !less && !flags.flag(ZF),
+            _ => return None,
+        })
+    }
+
+    /// The IP that a jump operand transfers control to. IP has already advanced past the instruction.
+    fn target(&self, operand: Operand) -> u16 {
+        match operand {
+            Operand::Relative(displacement) => self.registers.ip.wrapping_add_signed(displacement),
+            _ => self.read(operand, true),
+        }
+    }
+
+    /// Set CF, AF and OF for an arithmetic or logical operation, and ZF, SF and PF for its result.
+    fn arithmetic(&mut self, operation: Operation, wide: bool, a: u16, b: u16) -> u16 {
+        let (mask, sign) = if wide { (0xffff, 0x8000) } else { (0xff, 0x80) };
+        let a = u32::from(a);
+        let b = u32::from(b);
+        let carry = u32::from(self.registers.flag(CF));
+
+        let result = match operation {
+            Operation::Add | Operation::Adc => {
+                let carry = if operation == Operation::Adc { carry } else { 0 };
+                let result = a + b + carry;
+                self.registers.set_flag(CF, result > mask);
+                self.registers.set_flag(AF, (a ^ b ^ result) & 0x10 != 0);
+                self.registers.set_flag(OF, (a ^ result) & (b ^ result) & sign != 0);
+                result
+            }
+            Operation::Sub | Operation::Sbb | Operation::Cmp => {
+                let borrow = if operation == Operation::Sbb { carry } else { 0 };
+                let result = a.wrapping_sub(b).wrapping_sub(borrow);
+                self.registers.set_flag(CF, a < b + borrow);
+                self.registers.set_flag(AF, (a ^ b ^ result) & 0x10 != 0);
+                self.registers.set_flag(OF, (a ^ b) & (a ^ result) & sign != 0);
+                result
+            }
+            _ => {
+                self.registers.set_flag(CF, false);
+                self.registers.set_flag(AF, false);
+                self.registers.set_flag(OF, false);
+                match operation {
+                    Operation::Or => a | b,
+                    Operation::Xor => a ^ b,
+                    _ => a & b,
+                }
+            }
+        } & mask;
+
+        self.registers.set_flag(ZF, result == 0);
+        self.registers.set_flag(SF, result & sign != 0);
+        self.registers
+            .set_flag(PF, (result as u8).count_ones().is_multiple_of(2));
+        result as u16
+    }
+
+    /// The segment and offset of a direct intersegment address, or of a doubleword in memory.
+    fn far_pointer(&self, operand: Operand) -> (u16, u16) {
+        match operand {
+            Operand::Far(segment, offset) => (segment, offset),
+            Operand::Memory(memory) => {
+                let segment = self.segment(memory);
+                let offset = self.offset(memory);
+                (
+                    self.read_memory(segment, offset.wrapping_add(2), true),
+                    self.read_memory(segment, offset, true),
+                )
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn push(&mut self, value: u16) {
+        let sp = self.registers.get(Register::Sp).wrapping_sub(2);
+        self.registers.set(Register::Sp, sp);
+        self.write_memory(self.registers.get(Register::Ss), sp, true, value);
+    }
+
+    fn pop(&mut self) -> u16 {
+        let sp = self.registers.get(Register::Sp);
+        self.registers.set(Register::Sp, sp.wrapping_add(2));
+        self.read_memory(self.registers.get(Register::Ss), sp, true)
+    }
+
+    /// The segment of data: the override prefix's, or DS.
+    const fn data_segment(&self) -> u16 {
+        match self.segment_override {
+            Some(register) => self.registers.get(register),
+            None => self.registers.get(Register::Ds),
+        }
+    }
+
+    /// The segment of an effective address: the override prefix's, or SS if BP is a base register, or DS.
+    fn segment(&self, memory: Memory) -> u16 {
+        if self.segment_override.is_none() && memory.base.registers().contains(&Register::Bp) {
+            self.registers.get(Register::Ss)
+        } else {
+            self.data_segment()
+        }
+    }
+
+    /// The offset of an effective address.
+    fn offset(&self, memory: Memory) -> u16 {
+        memory
+            .base
+            .registers()
+            .iter()
+            .fold(memory.displacement as u16, |offset, &register| {
+                offset.wrapping_add(self.registers.get(register))
+            })
+    }
+
+    fn read(&self, operand: Operand, wide: bool) -> u16 {
+        match operand {
+            Operand::Register(register) => self.registers.get(register),
+            Operand::Memory(memory) => self.read_memory(self.segment(memory), self.offset(memory), wide),
+            Operand::Immediate(value) => value as u16,
+            Operand::None | Operand::Relative(_) | Operand::Far(..) => unreachable!(),
+        }
+    }
+
+    fn write(&mut self, operand: Operand, wide: bool, value: u16) {
+        match operand {
+            Operand::Register(register) => self.registers.set(register, value),
+            Operand::Memory(memory) => self.write_memory(self.segment(memory), self.offset(memory), wide, value),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Whether an access would wrap or be beyond memory, with faulting addressing. The first such access is
+    /// recorded, to fail the instruction.
+    fn faults(&self, segment: u16, offset: u16, wide: bool) -> bool {
+        if self.addressing == Addressing::Wrap {
+            return false;
+        }
+        let last = usize::from(offset) + usize::from(wide);
+        let fault = last > 0xffff || (usize::from(segment) << 4) + last >= self.memory.len();
+        if fault && self.fault.get().is_none() {
+            self.fault.set(Some((segment, offset)));
+        }
+        fault
+    }
+
+    /// Read a byte or word. Like the 8086, the high byte of a word at offset 0xffff is at offset 0 of the segment.
+    fn read_memory(&self, segment: u16, offset: u16, wide: bool) -> u16 {
+        if let Some(statistics) = &self.statistics {
+            statistics.reads.set(statistics.reads.get() + 1 + u64::from(wide));
+        }
+        if let Some(accesses) = &self.accesses {
+            accesses.log(physical(segment, offset), wide, false);
+        }
+        if self.faults(segment, offset, wide) {
+            return 0;
+        }
+        let lo = self.memory[self.address(segment, offset)];
+        if wide {
+            u16::from_le_bytes([lo, self.memory[self.address(segment, offset.wrapping_add(1))]])
+        } else {
+            u16::from(lo)
+        }
+    }
+
+    fn write_memory(&mut self, segment: u16, offset: u16, wide: bool, value: u16) {
+        if let Some(statistics) = &mut self.statistics {
+            statistics.writes += 1 + u64::from(wide);
+        }
+        if let Some(accesses) = &self.accesses {
+            accesses.log(physical(segment, offset), wide, true);
+        }
+        if self.faults(segment, offset, wide) {
+            return;
+        }
+        let [lo, hi] = value.to_le_bytes();
+        self.store(self.address(segment, offset), lo);
+        if wide {
+            self.store(self.address(segment, offset.wrapping_add(1)), hi);
+        }
+    }
+
+    /// Write a byte at a physical address, recording its old value in the journal.
+    pub(crate) fn store(&mut self, address: usize, value: u8) {
+        if let Some(journal) = &mut self.journal {
+            journal.push((address, self.memory[address]));
+        }
+        self.memory[address] = value;
+    }
+}
+
+/// Execute a decoded instruction. The handler is chosen when the instruction is decoded, so that executing a cached
+/// instruction doesn't dispatch on its operation again.
+type Handler = fn(&mut Machine, &Instruction) -> Result<(), Error>;
+
+/// The handler that executes an instruction.
+#[allow(clippy::too_many_lines)]
+fn handler(instruction: &Instruction) -> Handler {
+    match instruction.operation {
+        Operation::Mov => |machine, instruction| {
+            let [destination, source] = instruction.operands;
+            let value = machine.read(source, instruction.wide);
+            machine.write(destination, instruction.wide, value);
+            Ok(())
+        },
+        Operation::Xchg => |machine, instruction| {
+            let [destination, source] = instruction.operands;
+            let wide = instruction.wide;
+            let a = machine.read(destination, wide);
+            let b = machine.read(source, wide);
+            machine.write(destination, wide, b);
+            machine.write(source, wide, a);
+            Ok(())
+        },
+        Operation::Lea => |machine, instruction| {
+            let [destination, source] = instruction.operands;
+            let Operand::Memory(memory) = source else {
+                return Err(Error::Unsupported(*instruction));
+            };
+            let offset = machine.offset(memory);
+            machine.write(destination, instruction.wide, offset);
+            Ok(())
+        },
+        Operation::In => |machine, instruction| {
+            let [destination, source] = instruction.operands;
+            let port = machine.read(source, true);
+            let value = if instruction.wide {
+                machine.ports.read16(port)
+            } else {
+                let value = machine
+                    .pic
+                    .read(port)
+                    .or_else(|| machine.keyboard.read(port, &mut *machine.input))
+                    .unwrap_or_else(|| machine.ports.read8(port));
+                u16::from(value)
+            };
+            machine.write(destination, instruction.wide, value);
+            Ok(())
+        },
+        Operation::Out => |machine, instruction| {
+            let [destination, source] = instruction.operands;
+            let port = machine.read(destination, true);
+            let value = machine.read(source, instruction.wide);
+            if instruction.wide {
+                machine.ports.write16(port, value);
+            } else if !machine.pic.write(port, value as u8) {
+                machine.ports.write8(port, value as u8);
+            }
+            Ok(())
+        },
+        Operation::Lds | Operation::Les => |machine, instruction| {
+            let [destination, source] = instruction.operands;
+            let Operand::Memory(_) = source else {
+                return Err(Error::Unsupported(*instruction));
+            };
+            let (segment, offset) = machine.far_pointer(source);
+            machine.write(destination, true, offset);
+            let register = if instruction.operation == Operation::Lds {
+                Register::Ds
+            } else {
+                Register::Es
+            };
+            machine.registers.set(register, segment);
+            Ok(())
+        },
+        Operation::Add
+        | Operation::Adc
+        | Operation::Sub
+        | Operation::Sbb
+        | Operation::And
+        | Operation::Or
+        | Operation::Xor => |machine, instruction| {
+            let [destination, source] = instruction.operands;
+            let wide = instruction.wide;
+            let a = machine.read(destination, wide);
+            let b = machine.read(source, wide);
+            let result = machine.arithmetic(instruction.operation, wide, a, b);
+            machine.write(destination, wide, result);
+            Ok(())
+        },
+        Operation::Cmp | Operation::Test => |machine, instruction| {
+            let [destination, source] = instruction.operands;
+            let wide = instruction.wide;
+            let a = machine.read(destination, wide);
+            let b = machine.read(source, wide);
+            machine.arithmetic(instruction.operation, wide, a, b);
+            Ok(())
+        },
+        // INC and DEC leave CF unchanged.
+        Operation::Inc | Operation::Dec => |machine, instruction| {
+            let [destination, _] = instruction.operands;
+            let wide = instruction.wide;
+            let carry = machine.registers.flag(CF);
+            let operation = if instruction.operation == Operation::Inc {
+                Operation::Add
+            } else {
+                Operation::Sub
+            };
+            let a = machine.read(destination, wide);
+            let result = machine.arithmetic(operation, wide, a, 1);
+            machine.registers.set_flag(CF, carry);
+            machine.write(destination, wide, result);
+            Ok(())
+        },
+        Operation::Neg => |machine, instruction| {
+            let [destination, _] = instruction.operands;
+            let a = machine.read(destination, instruction.wide);
+            let result = machine.arithmetic(Operation::Sub, instruction.wide, 0, a);
+            machine.write(destination, instruction.wide, result);
+            Ok(())
+        },
+        Operation::Not => |machine, instruction| {
+            let [destination, _] = instruction.operands;
+            let a = machine.read(destination, instruction.wide);
+            machine.write(destination, instruction.wide, !a);
+            Ok(())
+        },
+        Operation::Rol
+        | Operation::Ror
+        | Operation::Rcl
+        | Operation::Rcr
+        | Operation::Shl
+        | Operation::Shr
+        | Operation::Sar => |machine, instruction| {
+            machine.shift(instruction);
+            Ok(())
+        },
+        Operation::Mul | Operation::Imul => |machine, instruction| {
+            machine.multiply(instruction);
+            Ok(())
+        },
+        Operation::Div | Operation::Idiv => |machine, instruction| {
+            if machine.divide(instruction) {
+                Ok(())
+            } else {
+                machine.interrupt(0, instruction)
+            }
+        },
+        Operation::Cbw => |machine, _| {
+            let al = machine.registers.get(Register::Al) as u8;
+            machine.registers.set(Register::Ax, i16::from(al as i8) as u16);
+            Ok(())
+        },
+        Operation::Cwd => |machine, _| {
+            let negative = machine.registers.get(Register::Ax) & 0x8000 != 0;
+            machine.registers.set(Register::Dx, if negative { 0xffff } else { 0 });
+            Ok(())
+        },
+        Operation::Lahf => |machine, _| {
+            machine.registers.set(Register::Ah, machine.registers.flags & 0xff);
+            Ok(())
+        },
+        Operation::Sahf => |machine, _| {
+            let ah = machine.registers.get(Register::Ah);
+            let mask = SF | ZF | AF | PF | CF;
+            machine.registers.flags = (machine.registers.flags & !mask) | (ah & mask);
+            Ok(())
+        },
+        Operation::Xlat => |machine, _| {
+            let offset = machine
+                .registers
+                .get(Register::Bx)
+                .wrapping_add(machine.registers.get(Register::Al));
+            let value = machine.read_memory(machine.data_segment(), offset, false);
+            machine.registers.set(Register::Al, value);
+            Ok(())
+        },
+        Operation::Clc => |machine, _| {
+            machine.registers.set_flag(CF, false);
+            Ok(())
+        },
+        Operation::Stc => |machine, _| {
+            machine.registers.set_flag(CF, true);
+            Ok(())
+        },
+        Operation::Cmc => |machine, _| {
+            machine.registers.set_flag(CF, !machine.registers.flag(CF));
+            Ok(())
+        },
+        Operation::Cld => |machine, _| {
+            machine.registers.set_flag(DF, false);
+            Ok(())
+        },
+        Operation::Std => |machine, _| {
+            machine.registers.set_flag(DF, true);
+            Ok(())
+        },
+        Operation::Cli => |machine, _| {
+            machine.registers.set_flag(IF, false);
+            Ok(())
+        },
+        Operation::Sti => |machine, _| {
+            machine.registers.set_flag(IF, true);
+            Ok(())
+        },
+        Operation::Hlt => |machine, _| {
+            machine.halted = true;
+            Ok(())
+        },
+        Operation::Push => |machine, instruction| {
+            let [destination, _] = instruction.operands;
+            let mut value = machine.read(destination, true);
+            // The 8086 pushes the value of SP after it is decremented.
+            if destination == Operand::Register(Register::Sp) {
+                value = value.wrapping_sub(2);
+            }
+            machine.push(value);
+            Ok(())
+        },
+        Operation::Pop => |machine, instruction| {
+            let value = machine.pop();
+            machine.write(instruction.operands[0], true, value);
+            Ok(())
+        },
+        Operation::Pushf => |machine, _| {
+            machine.push(machine.registers.flags);
+            Ok(())
+        },
+        Operation::Popf => |machine, _| {
+            machine.registers.flags = machine.pop() & FLAGS_MASK;
+            Ok(())
+        },
+        Operation::Movs | Operation::Cmps | Operation::Scas | Operation::Lods | Operation::Stos => {
+            |machine, instruction| {
+                machine.string(instruction);
+                Ok(())
+            }
+        }
+        Operation::Call | Operation::Jmp => |machine, instruction| {
+            let [destination, _] = instruction.operands;
+            let call = instruction.operation == Operation::Call;
+            if instruction.far || matches!(destination, Operand::Far(..)) {
+                // There is no far pointer in a register.
+                if let Operand::Register(_) = destination {
+                    return Err(Error::Unsupported(*instruction));
+                }
+                let (segment, offset) = machine.far_pointer(destination);
+                if call {
+                    machine.push(machine.registers.get(Register::Cs));
+                    machine.push(machine.registers.ip);
+                }
+                machine.registers.set(Register::Cs, segment);
+                machine.registers.ip = offset;
+            } else {
+                let target = machine.target(destination);
+                if call {
+                    machine.push(machine.registers.ip);
+                }
+                machine.registers.ip = target;
+            }
+            Ok(())
+        },
+        Operation::Ret | Operation::Retf => |machine, instruction| {
+            machine.registers.ip = machine.pop();
+            if instruction.operation == Operation::Retf {
+                let segment = machine.pop();
+                machine.registers.set(Register::Cs, segment);
+            }
+            // "Adding immediate to SP" discards arguments that the caller pushed.
+            if let Operand::Immediate(value) = instruction.operands[0] {
+                let sp = machine.registers.get(Register::Sp);
+                machine.registers.set(Register::Sp, sp.wrapping_add(value as u16));
+            }
+            Ok(())
+        },
+        Operation::Loop => |machine, instruction| {
+            machine.loop_if(instruction, true);
+            Ok(())
+        },
+        Operation::Loopz => |machine, instruction| {
+            machine.loop_if(instruction, machine.registers.flag(ZF));
+            Ok(())
+        },
+        Operation::Loopnz => |machine, instruction| {
+            machine.loop_if(instruction, !machine.registers.flag(ZF));
+            Ok(())
+        },
+        Operation::Int => |machine, instruction| {
+            let number = machine.read(instruction.operands[0], false) as u8;
+            machine.interrupt(number, instruction)
+        },
+        Operation::Int3 => |machine, instruction| machine.interrupt(3, instruction),
+        Operation::Into => |machine, instruction| {
+            if machine.registers.flag(OF) {
+                machine.interrupt(4, instruction)
+            } else {
+                Ok(())
+            }
+        },
+        Operation::Iret => |machine, _| {
+            machine.registers.ip = machine.pop();
+            let segment = machine.pop();
+            machine.registers.set(Register::Cs, segment);
+            machine.registers.flags = machine.pop() & FLAGS_MASK;
+            Ok(())
+        },
+        Operation::Jcxz => |machine, instruction| {
+            let zero = machine.registers.get(Register::Cx) == 0;
+            machine.jump_if(instruction, zero);
+            Ok(())
+        },
+        // Conditional jumps.
+        _ => |machine, instruction| {
+            let Some(taken) = machine.condition(instruction.operation) else {
+                return Err(Error::Unsupported(*instruction));
+            };
+            machine.jump_if(instruction, taken);
+            Ok(())
+        },
+    }
+}
+
+/// The flags that an instruction defines and leaves undefined, given its shift count and CX.
+const fn flags_written(instruction: &Instruction, count: u16, cx: u16) -> (u16, u16) {
+    let FlagEffects { defined, undefined, .. } = table::flags(instruction.operation);
+    match instruction.operation {
+        // A shift count of 0 and a REP count of 0 change nothing.
+        Operation::Shl
+        | Operation::Shr
+        | Operation::Sar
+        | Operation::Rol
+        | Operation::Ror
+        | Operation::Rcl
+        | Operation::Rcr
+            if count == 0 =>
+        {
+            (0, 0)
+        }
+        Operation::Cmps | Operation::Scas if instruction.rep.is_some() && cx == 0 => (0, 0),
+        // OF is only defined for a count of 1.
+        Operation::Shl
+        | Operation::Shr
+        | Operation::Sar
+        | Operation::Rol
+        | Operation::Ror
+        | Operation::Rcl
+        | Operation::Rcr
+            if count != 1 =>
+        {
+            (defined & !OF, undefined | OF)
+        }
+        _ => (defined, undefined),
+    }
+}
+
+/// The flags that an instruction reads, given its shift count.
+const fn flags_read(instruction: &Instruction, count: u16) -> u16 {
+    match instruction.operation {
+        Operation::Rcl | Operation::Rcr if count == 0 => 0,
+        operation => table::flags(operation).read,
+    }
+}
+
+/// Flags, displayed as the letters of those that are set, like "CPAZSO", in the reference simulator's order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Flags(pub u16);
+
+impl fmt::Display for Flags {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (flag, letter) in FLAG_LETTERS {
+            if self.0 & flag != 0 {
+                f.write_char(letter)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Write an executed instruction and the registers that it changed, like the reference simulator. The transcripts
+/// of the listings before IP was simulated leave out IP.
+///
+/// # Errors
+///
+/// If writing fails.
+pub fn write_trace<W: Write>(
+    out: &mut W,
+    instruction: &Instruction,
+    before: &Registers,
+    after: &Registers,
+    ip: bool,
+) -> io::Result<()> {
+    write!(out, "{instruction} ;")?;
+    for register in PRINT_ORDER {
+        let (old, new) = (before.get(register), after.get(register));
+        if old != new {
+            write!(out, " {register}:{old:#x}->{new:#x}")?;
+        }
+    }
+    if ip && before.ip != after.ip {
+        write!(out, " ip:{:#x}->{:#x}", before.ip, after.ip)?;
+    }
+    if before.flags != after.flags {
+        write!(out, " flags:{}->{}", Flags(before.flags), Flags(after.flags))?;
+    }
+    writeln!(out, " ")
+}
+
+/// Write the non-zero registers, like the reference simulator, with or without IP.
+///
+/// # Errors
+///
+/// If writing fails.
+pub fn write_registers<W: Write>(out: &mut W, registers: &Registers, ip: bool) -> io::Result<()> {
+    writeln!(out, "Final registers:")?;
+    for register in PRINT_ORDER {
+        let value = registers.get(register);
+        if value != 0 {
+            writeln!(out, "      {register}: {value:#06x} ({value})")?;
+        }
+    }
+    if ip && registers.ip != 0 {
+        writeln!(out, "      ip: {:#06x} ({})", registers.ip, registers.ip)?;
+    }
+    if registers.flags != 0 {
+        writeln!(out, "   flags: {}", Flags(registers.flags))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    fn simulate(path: &str) -> Machine {
+        let program = fs::read(path).unwrap();
+        let mut machine = Machine::new();
+        machine.load(0, &program);
+        while machine.instruction_address() < program.len() {
+            machine.step().unwrap();
+        }
+        machine
+    }
+
+    #[test]
+    fn registers() {
+        let mut registers = Registers::default();
+        registers.set(Register::Ax, 0x2222);
+        registers.set(Register::Al, 0x11);
+        registers.set(Register::Ah, 0x44);
+        assert_eq!(registers.get(Register::Ax), 0x4411);
+        assert_eq!(registers.get(Register::Ah), 0x44);
+    }
+
+    #[test]
+    fn flags() {
+        let machine = simulate("perfaware/part1/listing_0047_challenge_flags");
+        assert_eq!(machine.registers.get(Register::Bx), 0x9ca5);
+        assert_eq!(Flags(machine.registers.flags).to_string(), "CPAS");
+    }
+
+    #[test]
+    fn jumps() {
+        let machine = simulate("perfaware/part1/listing_0050_challenge_jumps");
+        assert_eq!(machine.registers.get(Register::Ax), 13);
+        assert_eq!(machine.registers.get(Register::Bx), 0xfffb);
+        assert_eq!(machine.registers.ip, 28);
+        assert_eq!(Flags(machine.registers.flags).to_string(), "CAS");
+    }
+
+    #[test]
+    fn call() {
+        let mut machine = Machine::new();
+        let program = [
+            &[0b10111000, 5, 0][..], // mov ax, 5
+            &[0b01010000],           // push ax
+            &[0b11101000, 3, 0],     // call $+6
+            &[0b01011011],           // pop bx
+            &[0b11101011, 3],        // jmp $+5
+            &[0b01000001],           // inc cx
+            &[0b11000011],           // ret
+        ]
+        .concat();
+        machine.load(0, &program);
+        while machine.instruction_address() < program.len() {
+            machine.step().unwrap();
+        }
+        assert_eq!(machine.registers.get(Register::Bx), 5);
+        assert_eq!(machine.registers.get(Register::Cx), 1);
+        assert_eq!(machine.registers.get(Register::Sp), 0);
+        assert_eq!(&machine.memory[0xfffc..0x10000], &[7, 0, 5, 0]);
+    }
+
+    #[test]
+    fn far_call() {
+        let mut machine = Machine::new();
+        machine.registers.set(Register::Sp, 0x100);
+        machine.registers.set(Register::Cs, 0x1234);
+        // call 0:16
+        machine.load(0x12340, &[0b10011010, 16, 0, 0, 0]);
+        // retf 4
+        machine.load(16, &[0b11001010, 4, 0]);
+        machine.step().unwrap();
+        assert_eq!(machine.registers.get(Register::Cs), 0);
+        assert_eq!(machine.registers.get(Register::Sp), 0xfc);
+        machine.step().unwrap();
+        assert_eq!(machine.registers.get(Register::Cs), 0x1234);
+        assert_eq!(machine.registers.ip, 5);
+        assert_eq!(machine.registers.get(Register::Sp), 0x104);
+    }
+
+    #[test]
+    fn push_pop() {
+        let mut machine = Machine::new();
+        machine.registers.set(Register::Sp, 0x100);
+        machine.registers.set(Register::Ds, 0x55);
+        machine.registers.flags = CF | ZF;
+        let program = [
+            &[0b00011110][..],                 // push ds
+            &[0b10011100],                     // pushf
+            &[0b01010100],                     // push sp
+            &[0b10001111, 0b00_000_110, 0, 2], // pop word [+512]
+            &[0b00000111],                     // pop es
+            &[0b10011101],                     // popf
+        ]
+        .concat();
+        machine.load(0, &program);
+        for _ in 0..4 {
+            machine.step().unwrap();
+        }
+        assert_eq!(machine.read_memory(0x55, 512, true), 0xfa);
+        machine.step().unwrap();
+        assert_eq!(machine.registers.get(Register::Es), CF | ZF);
+        machine.step().unwrap();
+        assert_eq!(machine.registers.flags, 0x55 & FLAGS_MASK);
+        assert_eq!(machine.registers.get(Register::Sp), 0x100);
+    }
+
+    #[test]
+    fn segments() {
+        let mut machine = Machine::new();
+        machine.registers.set(Register::Ds, 0x1000);
+        machine.registers.set(Register::Ss, 0x2000);
+        machine.registers.set(Register::Es, 0x3000);
+        machine.registers.set(Register::Ax, 0x5566);
+        let program = [
+            &[0b11000111, 0b01_000_111, 2, 0x34, 0x12][..], // mov word [bx+2], 4660
+            &[0b10001000, 0b01_000_110, 0],                 // mov [bp], al
+            &[0b00100110, 0b10001000, 0b01_100_110, 0],     // mov es:[bp], ah
+            &[0b11000101, 0b00_110_110, 2, 0],              // lds si, [+2]
+        ]
+        .concat();
+        machine.load(0, &program);
+        for _ in 0..3 {
+            machine.step().unwrap();
+        }
+        assert_eq!(&machine.memory[0x10002..0x10004], &[0x34, 0x12]);
+        assert_eq!(machine.memory[0x20000], 0x66);
+        assert_eq!(machine.memory[0x30000], 0x55);
+
+        machine.load(0x10004, &[0x78, 0x56]);
+        machine.step().unwrap();
+        assert_eq!(machine.registers.get(Register::Si), 0x1234);
+        assert_eq!(machine.registers.get(Register::Ds), 0x5678);
+    }
+
+    #[test]
+    fn wraparound() {
+        assert_eq!(physical(0x1234, 0x5678), 0x179b8);
+        assert_eq!(physical(0xffff, 0x10), 0);
+
+        let mut machine = Machine::new();
+        machine.registers.set(Register::Ds, 0xffff);
+        // A word at offset 0xffff wraps around within the segment.
+        machine.write_memory(0x1000, 0xffff, true, 0x1234);
+        assert_eq!(machine.memory[0x1ffff], 0x34);
+        assert_eq!(machine.memory[0x10000], 0x12);
+
+        // inc word [bx+17] at the end of memory, incrementing ds:0x20 at the start of memory.
+        machine.registers.set(Register::Bx, 0xf);
+        machine.registers.set(Register::Cs, 0xffff);
+        machine.registers.ip = 0xe;
+        machine.load(0xffffe, &[0b11111111, 0b01_000_111]);
+        machine.load(0, &[17]);
+        machine.step().unwrap();
+        assert_eq!(machine.registers.ip, 0x11);
+        assert_eq!(machine.read_memory(0, 0x10, true), 1);
+    }
+
+    #[test]
+    fn small_memory() {
+        assert_eq!(memory_size("64K"), Ok(0x10000));
+        assert_eq!(memory_size("1m"), Ok(MEMORY_SIZE));
+        assert_eq!(memory_size("4096"), Ok(0x1000));
+        assert!(memory_size("48K").is_err());
+        assert!(memory_size("2M").is_err());
+        assert!(memory_size("K").is_err());
+
+        // mov [bx], al, with 64 KiB repeating through the address space.
+        let program = [0b10001000, 0b00_000_111];
+        let mut machine = Machine::with_memory(0x10000);
+        machine.load(0, &program);
+        machine.registers.set(Register::Ds, 0x1000);
+        machine.registers.set(Register::Bx, 0x10);
+        machine.registers.set(Register::Al, 7);
+        machine.step().unwrap();
+        assert_eq!(machine.memory[0x10], 7);
+
+        // The same access faults.
+        let mut machine = Machine::with_memory(0x10000);
+        machine.addressing = Addressing::Fault;
+        machine.load(0, &program);
+        machine.registers.set(Register::Ds, 0x1000);
+        machine.registers.set(Register::Bx, 0x10);
+        let before = machine.registers;
+        let error = machine.step().unwrap_err();
+        assert!(matches!(error, Error::Fault(_, 0x1000, 0x10)), "{error}");
+        assert_eq!(machine.registers, before);
+        assert_eq!(machine.memory[0x10], 0);
+
+        // So does a word that wraps around its segment, even within memory.
+        // push ax, with SP at 1.
+        let mut machine = Machine::new();
+        machine.addressing = Addressing::Fault;
+        machine.load(0, &[0b01010000]);
+        machine.registers.set(Register::Ss, 0x100);
+        machine.registers.set(Register::Sp, 1);
+        assert!(matches!(machine.step(), Err(Error::Fault(_, 0x100, 0xffff))));
+        machine.registers.set(Register::Sp, 2);
+        machine.step().unwrap();
+
+        // The word that REP STOSW stored before the one that wraps is restored.
+        let mut machine = Machine::new();
+        machine.addressing = Addressing::Fault;
+        machine.load(0, &[0b11110011, 0b10101011]);
+        machine.registers.set(Register::Es, 0x100);
+        machine.registers.set(Register::Di, 0xfffd);
+        machine.registers.set(Register::Cx, 2);
+        machine.registers.set(Register::Ax, 0x707);
+        let before = machine.registers;
+        assert!(matches!(machine.step(), Err(Error::Fault(_, 0x100, 0xffff))));
+        assert_eq!(machine.registers, before);
+        assert_eq!(machine.memory[0x10ffd..0x11000], [0, 0, 0]);
+        assert_eq!(machine.journal, None);
+    }
+
+    #[test]
+    fn self_modifying() {
+        let program = [
+            &[0b10111000, 1, 0][..],              // mov ax, 1
+            &[0b11000110, 0b00_000_110, 1, 0, 5], // mov byte [1], 5
+        ]
+        .concat();
+        let mut machine = Machine::new();
+        machine.load(0, &program);
+        machine.step().unwrap();
+        machine.step().unwrap();
+        // The cached instruction is decoded again, after the program changes it.
+        machine.registers.ip = 0;
+        machine.step().unwrap();
+        assert_eq!(machine.registers.get(Register::Ax), 5);
+        // And after the host changes it.
+        machine.memory[2] = 1;
+        machine.registers.ip = 0;
+        machine.step().unwrap();
+        assert_eq!(machine.registers.get(Register::Ax), 0x105);
+    }
+
+    #[test]
+    fn dispatch() {
+        let program = [
+            &[0b10111001, 3, 0][..],     // mov cx, 3
+            &[0b00000011, 0b00_000_111], // add ax, [bx]
+            &[0b01000011],               // inc bx
+            &[0b11100010, 0xfb],         // loop $-3
+            &[0b11110100],               // hlt
+        ]
+        .concat();
+        let run = |dispatch| {
+            let mut machine = Machine::new();
+            machine.dispatch = dispatch;
+            machine.load(0, &program);
+            while machine.step().is_ok() {}
+            (machine.registers, machine.cycles)
+        };
+        assert_eq!(run(Dispatch::Match), run(Dispatch::Threaded));
+    }
+
+    #[test]
+    fn string() {
+        let mut machine = Machine::new();
+        machine.load(0x100, b"hello");
+        machine.registers.set(Register::Si, 0x100);
+        machine.registers.set(Register::Di, 0x200);
+        machine.registers.set(Register::Cx, 5);
+        let program = [
+            &[0b11110011, 0b10100100][..], // rep movsb
+            &[0b10111111, 0, 2],           // mov di, 512
+            &[0b10111001, 5, 0],           // mov cx, 5
+            &[0b10110000, b'l'],           // mov al, 'l'
+            &[0b11110010, 0b10101110],     // repne scasb
+            &[0b11111101],                 // std
+            &[0b01001110],                 // dec si
+            &[0b10101100],                 // lodsb
+        ]
+        .concat();
+        machine.load(0, &program);
+        while machine.instruction_address() < program.len() {
+            machine.step().unwrap();
+        }
+        assert_eq!(&machine.memory[0x200..0x205], b"hello");
+        // SCAS stopped after the first "l".
+        assert_eq!(machine.registers.get(Register::Di), 0x203);
+        assert_eq!(machine.registers.get(Register::Cx), 2);
+        assert_eq!(machine.registers.get(Register::Ax), u16::from_le_bytes([b'o', 0]));
+        assert_eq!(machine.registers.get(Register::Si), 0x103);
+    }
+
+    #[test]
+    fn string_segments() {
+        let mut machine = Machine::new();
+        machine.registers.set(Register::Ds, 0x1000);
+        machine.registers.set(Register::Es, 0x2000);
+        machine.registers.set(Register::Ss, 0x3000);
+        machine.registers.set(Register::Cx, 2);
+        machine.load(0x10000, b"ds");
+        machine.load(0x30002, b"ss");
+        let program = [
+            &[0b11110011, 0b10100100][..],         // rep movsb
+            &[0b10111001, 2, 0],                   // mov cx, 2
+            &[0b00110110, 0b11110011, 0b10100100], // rep ss:movsb
+        ]
+        .concat();
+        machine.load(0, &program);
+        while machine.instruction_address() < program.len() {
+            machine.step().unwrap();
+        }
+        assert_eq!(&machine.memory[0x20000..0x20004], b"dsss");
+    }
+
+    #[test]
+    fn repe_cmps() {
+        let mut machine = Machine::new();
+        machine.load(0x100, b"abcd");
+        machine.load(0x200, b"abxd");
+        machine.registers.set(Register::Si, 0x100);
+        machine.registers.set(Register::Di, 0x200);
+        machine.registers.set(Register::Cx, 4);
+        // repe cmpsb
+        machine.load(0, &[0b11110011, 0b10100110]);
+        machine.step().unwrap();
+        assert_eq!(machine.registers.get(Register::Cx), 1);
+        assert_eq!(machine.registers.get(Register::Si), 0x103);
+        assert!(!machine.registers.flag(ZF));
+    }
+
+    fn arithmetic(operation: Operation, wide: bool, ax: u16, dx: u16, operand: u16) -> Result<Machine, Error> {
+        let mut machine = Machine::new();
+        machine.registers.set(Register::Ax, ax);
+        machine.registers.set(Register::Dx, dx);
+        let register = Register::from_field(wide, 3); // bl or bx
+        machine.registers.set(register, operand);
+        let instruction = Instruction::new(operation, [Operand::Register(register), Operand::None], wide);
+        handler(&instruction)(&mut machine, &instruction)?;
+        Ok(machine)
+    }
+
+    #[test]
+    fn multiply() {
+        let machine = arithmetic(Operation::Mul, false, 200, 0, 3).unwrap();
+        assert_eq!(machine.registers.get(Register::Ax), 600);
+        assert!(machine.registers.flag(CF) && machine.registers.flag(OF));
+
+        let machine = arithmetic(Operation::Mul, true, 0x1234, 0, 0x100).unwrap();
+        assert_eq!(machine.registers.get(Register::Ax), 0x3400);
+        assert_eq!(machine.registers.get(Register::Dx), 0x12);
+
+        let machine = arithmetic(Operation::Imul, false, 0xfe, 0, 0x7f).unwrap();
+        assert_eq!(machine.registers.get(Register::Ax), (-254i16) as u16);
+        assert!(machine.registers.flag(CF));
+
+        let machine = arithmetic(Operation::Imul, true, (-2i16) as u16, 0, 3).unwrap();
+        assert_eq!(machine.registers.get(Register::Ax), (-6i16) as u16);
+        assert_eq!(machine.registers.get(Register::Dx), 0xffff);
+        assert!(!machine.registers.flag(CF) && !machine.registers.flag(OF));
+    }
+
+    #[test]
+    fn undefined_flags() {
+        let mut machine = Machine::new();
+        machine.undefined_flags = UndefinedFlags::Hardware;
+        machine.registers.set(Register::Ax, 0x100);
+        machine.registers.set(Register::Bx, 0x100);
+        let instruction = Instruction::new(Operation::Mul, [Operand::Register(Register::Bx), Operand::None], true);
+        handler(&instruction)(&mut machine, &instruction).unwrap();
+        assert_eq!(Flags(machine.registers.flags).to_string(), "CPZO");
+
+        let mut machine = Machine::new();
+        machine.undefined_flags = UndefinedFlags::Strict;
+        let program = [
+            &[0b11110110, 0b11_100_011][..], // mul bl
+            &[0b01110010, 0],                // jb $+2
+            &[0b01110100, 0],                // je $+2
+            &[0b00111100, 0],                // cmp al, 0
+            &[0b01110100, 0],                // je $+2
+        ]
+        .concat();
+        machine.load(0, &program);
+        machine.step().unwrap();
+        machine.step().unwrap();
+        assert!(matches!(machine.step(), Err(Error::UndefinedFlags(_, ZF))));
+        machine.registers.ip = 6;
+        machine.step().unwrap();
+        machine.step().unwrap();
+    }
+
+    #[test]
+    fn divide() {
+        let machine = arithmetic(Operation::Div, false, 1000, 0, 7).unwrap();
+        assert_eq!(machine.registers.get(Register::Al), 142);
+        assert_eq!(machine.registers.get(Register::Ah), 6);
+
+        let machine = arithmetic(Operation::Div, true, 0x0001, 0x0001, 0x10).unwrap();
+        assert_eq!(machine.registers.get(Register::Ax), 0x1000);
+        assert_eq!(machine.registers.get(Register::Dx), 1);
+
+        let machine = arithmetic(Operation::Idiv, false, (-7i16) as u16, 0, 2).unwrap();
+        assert_eq!(machine.registers.get(Register::Al), (-3i8) as u8 as u16);
+        assert_eq!(machine.registers.get(Register::Ah), (-1i8) as u8 as u16);
+
+        let machine = arithmetic(
+            Operation::Idiv,
+            true,
+            (-100_000i32) as u16,
+            (-100_000i32 >> 16) as u16,
+            7,
+        )
+        .unwrap();
+        assert_eq!(machine.registers.get(Register::Ax), (-14285i16) as u16);
+        assert_eq!(machine.registers.get(Register::Dx), (-5i16) as u16);
+    }
+
+    #[test]
+    fn divide_error() {
+        assert!(matches!(
+            arithmetic(Operation::Div, false, 1, 0, 0),
+            Err(Error::Divide(_))
+        ));
+        assert!(matches!(
+            arithmetic(Operation::Div, false, 0x100, 0, 1),
+            Err(Error::Divide(_))
+        ));
+        assert!(matches!(
+            arithmetic(Operation::Idiv, false, (-128i16) as u16, 0, 1),
+            Err(Error::Divide(_))
+        ));
+        assert!(matches!(
+            arithmetic(Operation::Idiv, true, 0, 1, 1),
+            Err(Error::Divide(_))
+        ));
+        // DX:AX = i32::MIN divided by -1.
+        assert!(matches!(
+            arithmetic(Operation::Idiv, true, 0, 0x8000, 0xffff),
+            Err(Error::Divide(_))
+        ));
+    }
+
+    // A device that reads back the last byte written to any port.
+    struct Latch(u8);
+
+    impl PortIo for Latch {
+        fn read8(&mut self, _: u16) -> u8 {
+            self.0
+        }
+
+        fn write8(&mut self, _: u16, value: u8) {
+            self.0 = value;
+        }
+    }
+
+    #[test]
+    fn ports() {
+        let mut machine = Machine::new();
+        machine.ports = Box::new(Latch(0));
+        machine.registers.set(Register::Ax, 0x1242);
+        let program = [
+            &[0b11100110, 0x70][..], // out 112, al
+            &[0b11101101],           // in ax, dx
+        ]
+        .concat();
+        machine.load(0, &program);
+        machine.step().unwrap();
+        machine.step().unwrap();
+        assert_eq!(machine.registers.get(Register::Ax), 0x4242);
+    }
+
+    #[test]
+    fn interrupts() {
+        let mut machine = Machine::new();
+        machine.registers.set(Register::Cs, 0x100);
+        machine.registers.set(Register::Sp, 0x100);
+        machine.registers.flags = IF | OF;
+        // Handlers at 0x200:0 for interrupts 0x80 and 4.
+        machine.load(0x80 * 4, &[0, 0, 0x00, 0x02]);
+        machine.load(4 * 4, &[0, 0, 0x00, 0x02]);
+        machine.load(0x2000, &[0b01000000, 0b11001111]); // inc ax; iret
+        machine.load(0x1000, &[0b11001101, 0x80, 0b11001110]); // int 128; into
+
+        machine.step().unwrap();
+        assert_eq!((machine.registers.get(Register::Cs), machine.registers.ip), (0x200, 0));
+        assert_eq!(Flags(machine.registers.flags).to_string(), "O");
+        assert_eq!(&machine.memory[0xfa..0x100], &[2, 0, 0, 1, 0, 0x0a]);
+        machine.step().unwrap();
+        machine.step().unwrap();
+        assert_eq!((machine.registers.get(Register::Cs), machine.registers.ip), (0x100, 2));
+        assert_eq!(Flags(machine.registers.flags).to_string(), "IO");
+        assert_eq!(machine.registers.get(Register::Sp), 0x100);
+
+        machine.step().unwrap();
+        assert_eq!((machine.registers.get(Register::Cs), machine.registers.ip), (0x200, 0));
+
+        machine.registers.set(Register::Cs, 0x100);
+        machine.registers.ip = 0;
+        machine.memory[0x80 * 4 + 3] = 0;
+        assert!(matches!(machine.step(), Err(Error::Interrupt(0x80))));
+    }
+
+    #[test]
+    fn timer() {
+        let mut machine = Machine::new();
+        machine.timer = Some(200);
+        machine.registers.set(Register::Cs, 0x100);
+        machine.registers.set(Register::Sp, 0x100);
+        machine.load(8 * 4, &[0, 0, 0x00, 0x02]);
+        let handler = [
+            &[0b01000011][..],   // inc bx
+            &[0b10110000, 0x20], // mov al, 32
+            &[0b11100110, 0x20], // out 32, al
+            &[0b11001111],       // iret
+        ]
+        .concat();
+        machine.load(0x2000, &handler);
+        machine.load(0x1000, &[0b11111011, 0b11101011, 0xfe]); // sti; jmp $+0
+
+        // The jumps take 15 clocks each, and the handler takes 61 to enter and 40 to run.
+        for _ in 0..40 {
+            machine.step().unwrap();
+        }
+        assert_eq!(machine.registers.get(Register::Bx), 3);
+        assert_eq!(machine.pic.in_service, 0);
+    }
+
+    #[test]
+    fn trap() {
+        let mut machine = Machine::new();
+        machine.registers.set(Register::Cs, 0x100);
+        machine.registers.set(Register::Sp, 0x100);
+        machine.registers.flags = TF;
+        machine.load(4, &[0, 0, 0x00, 0x02]);
+        machine.load(0x2000, &[0b01000011, 0b11001111]); // inc bx; iret
+        machine.load(0x1000, &[0b01000001, 0b01000001]); // inc cx; inc cx
+
+        machine.step().unwrap();
+        assert_eq!(machine.registers.get(Register::Cx), 1);
+        assert_eq!((machine.registers.get(Register::Cs), machine.registers.ip), (0x200, 0));
+        assert!(!machine.registers.flag(TF));
+        machine.step().unwrap();
+        machine.step().unwrap();
+        assert_eq!((machine.registers.get(Register::Cs), machine.registers.ip), (0x100, 1));
+        assert!(machine.registers.flag(TF));
+        machine.step().unwrap();
+        assert_eq!(machine.registers.get(Register::Cx), 2);
+        assert_eq!(machine.registers.get(Register::Bx), 1);
+        assert_eq!(machine.registers.get(Register::Cs), 0x200);
+    }
+
+    #[test]
+    fn halt() {
+        let mut machine = Machine::new();
+        machine.registers.set(Register::Cs, 0x100);
+        machine.registers.set(Register::Sp, 0x100);
+        machine.load(0x1000, &[0b11110100, 0b11111011, 0b11110100]); // hlt; sti; hlt
+        machine.step().unwrap();
+        assert!(machine.halted);
+        assert!(matches!(machine.step(), Err(Error::Halted)));
+
+        // The timer resumes the processor.
+        machine.halted = false;
+        machine.timer = Some(1000);
+        machine.load(8 * 4, &[0, 0, 0x00, 0x02]);
+        machine.load(0x2000, &[0b01000011]); // inc bx
+        machine.registers.ip = 1;
+        machine.step().unwrap();
+        machine.step().unwrap();
+        machine.step().unwrap();
+        assert!(!machine.halted);
+        assert_eq!(machine.registers.get(Register::Bx), 1);
+        assert_eq!(machine.cycles, 1000 + 61 + 2);
+        assert_eq!(machine.read_memory(0, 0xfa, true), 3);
+    }
+
+    #[test]
+    fn divide_interrupt() {
+        let mut machine = Machine::new();
+        machine.registers.set(Register::Cs, 0x100);
+        machine.registers.set(Register::Sp, 0x100);
+        machine.load(0, &[0x34, 0x12, 0, 0]);
+        machine.load(0x1000, &[0b11110110, 0b11_110_011]); // div bl
+        machine.step().unwrap();
+        assert_eq!(machine.registers.ip, 0x1234);
+        // The 8086 returns to the instruction after the division.
+        assert_eq!(machine.read_memory(0, 0xfa, true), 2);
+    }
+
+    fn shift(operation: Operation, wide: bool, value: u16, count: u8, carry: bool) -> Registers {
+        let mut machine = Machine::new();
+        machine.registers.set(Register::Ax, value);
+        machine.registers.set(Register::Cl, u16::from(count));
+        machine.registers.set_flag(CF, carry);
+        let register = Operand::Register(Register::from_field(wide, 0));
+        let count = Operand::Register(Register::Cl);
+        let instruction = Instruction::new(operation, [register, count], wide);
+        handler(&instruction)(&mut machine, &instruction).unwrap();
+        machine.registers
+    }
+
+    #[test]
+    fn shifts() {
+        let registers = shift(Operation::Shl, false, 0x81, 1, false);
+        assert_eq!(registers.get(Register::Al), 0x02);
+        assert_eq!(Flags(registers.flags).to_string(), "CO");
+
+        let registers = shift(Operation::Shr, true, 0x8001, 1, false);
+        assert_eq!(registers.get(Register::Ax), 0x4000);
+        assert_eq!(Flags(registers.flags).to_string(), "CPO");
+
+        let registers = shift(Operation::Sar, true, 0x8004, 2, true);
+        assert_eq!(registers.get(Register::Ax), 0xe001);
+        assert_eq!(Flags(registers.flags).to_string(), "S");
+
+        // The count isn't masked to 5 bits, as it is on later processors.
+        let registers = shift(Operation::Shl, true, 0xffff, 32, false);
+        assert_eq!(registers.get(Register::Ax), 0);
+        assert_eq!(Flags(registers.flags).to_string(), "PZ");
+
+        // A count of 0 changes nothing.
+        let registers = shift(Operation::Shl, true, 0xffff, 0, true);
+        assert_eq!(registers.get(Register::Ax), 0xffff);
+        assert_eq!(Flags(registers.flags).to_string(), "C");
+    }
+
+    #[test]
+    fn rotates() {
+        let registers = shift(Operation::Rol, false, 0x81, 1, false);
+        assert_eq!(registers.get(Register::Al), 0x03);
+        assert_eq!(Flags(registers.flags).to_string(), "CO");
+
+        let registers = shift(Operation::Ror, true, 0x0001, 1, false);
+        assert_eq!(registers.get(Register::Ax), 0x8000);
+        assert_eq!(Flags(registers.flags).to_string(), "CO");
+
+        let registers = shift(Operation::Rcl, false, 0x80, 1, true);
+        assert_eq!(registers.get(Register::Al), 0x01);
+        assert_eq!(Flags(registers.flags).to_string(), "CO");
+
+        // 9 bits rotate through CF and back in 9 steps.
+        let registers = shift(Operation::Rcr, false, 0x5a, 9, true);
+        assert_eq!(registers.get(Register::Al), 0x5a);
+        assert!(registers.flag(CF));
+    }
+
+    #[test]
+    fn trace() {
+        let mut machine = Machine::new();
+        // add cx, 1000
+        machine.load(0, &[0b10000001, 0b11_000_001, 0xe8, 0x03]);
+        machine.registers.set(Register::Cx, 200);
+        let before = machine.registers;
+        let instruction = machine.step().unwrap();
+        let mut out = vec![];
+        write_trace(&mut out, &instruction, &before, &machine.registers, true).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "add cx, 1000 ; cx:0xc8->0x4b0 ip:0x0->0x4 flags:->A \n"
+        );
+        let mut out = vec![];
+        write_trace(&mut out, &instruction, &before, &machine.registers, false).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "add cx, 1000 ; cx:0xc8->0x4b0 flags:->A \n"
+        );
+    }
+}
@@ -0,0 +1,479 @@
+//! The haversine formula on several pairs at once, with SSE2 or AVX, like the course's SIMD exercises.
+//!
+//! The standard library's math functions don't work on vectors, so it uses the custom ones, with the same operations
+//! in the same order on each lane as [`Custom`] does on one number. Its distances are the same bit for bit as
+//! [`Pair::distance_with`] the custom functions, for coordinates whose angles are less than 2^31 quadrants.
+
+use std::fmt;
+use std::str::FromStr;
+
+use crate::haversine::Pair;
+use crate::math::Custom;
+
+/// The instructions that compute the distances.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Simd {
+    /// Two pairs at a time, which every x86-64 processor can do.
+    Sse2,
+    /// Four pairs at a time.
+    Avx,
+}
+
+impl FromStr for Simd {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sse2" => Ok(Self::Sse2),
+            "avx" => Ok(Self::Avx),
+            _ => Err("expected sse2 or avx".to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Simd {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Sse2 => "sse2",
+            Self::Avx => "avx",
+        })
+    }
+}
+
+impl Simd {
+    /// Whether this CPU has the instructions.
+    #[must_use]
+    pub fn is_available(self) -> bool {
+        #[cfg(target_arch = "x86_64")]
+        {
+            match self {
+                Self::Sse2 => true,
+                Self::Avx => is_x86_feature_detected!("avx"),
+            }
+        }
+        #[cfg(not(target_arch = "x86_64"))]
+        {
+            let _ = self;
+            false
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+mod x86 {
+    use std::arch::x86_64::{
+        __m128d, __m256d, _mm256_add_pd, _mm256_and_pd, _mm256_andnot_pd, _mm256_castsi256_pd, _mm256_cmp_pd,
+        _mm256_cvtepi32_pd, _mm256_cvttpd_epi32, _mm256_div_pd, _mm256_loadu_pd, _mm256_mul_pd, _mm256_or_pd,
+        _mm256_set1_pd, _mm256_set_m128i, _mm256_sqrt_pd, _mm256_storeu_pd, _mm256_sub_pd, _mm256_xor_pd,
+        _mm_add_epi32, _mm_add_pd, _mm_and_pd, _mm_and_si128, _mm_andnot_pd, _mm_castsi128_pd, _mm_cmpeq_epi32,
+        _mm_cmpgt_pd, _mm_cmplt_pd, _mm_cvtepi32_pd, _mm_cvttpd_epi32, _mm_div_pd, _mm_loadu_pd, _mm_mul_pd, _mm_or_pd,
+        _mm_set1_epi32, _mm_set1_pd, _mm_sqrt_pd, _mm_storeu_pd, _mm_sub_pd, _mm_unpackhi_epi32, _mm_unpacklo_epi32,
+        _mm_xor_pd, _CMP_GT_OQ, _CMP_LT_OQ,
+    };
+    use std::f64::consts::{FRAC_2_PI, FRAC_PI_2};
+
+    use crate::haversine::{Pair, EARTH_RADIUS};
+    use crate::math::{Custom, FRAC_PI_2_HIGH, FRAC_PI_2_LOW};
+
+    // The most lanes of any vector.
+    const MAX_LANES: usize = 4;
+
+    /// Doubles in a vector register. Masks have all bits set in the lanes that are true.
+    ///
+    /// # Safety
+    ///
+    /// The CPU has the instructions of the vector. The methods are inlined, so that they're compiled with them.
+    pub trait Lanes: Copy {
+        const COUNT: usize;
+
+        unsafe fn splat(x: f64) -> Self;
+        /// The first `COUNT` values.
+        unsafe fn load(values: &[f64; MAX_LANES]) -> Self;
+        /// Into the first `COUNT` values.
+        unsafe fn store(self, out: &mut [f64; MAX_LANES]);
+        unsafe fn add(self, other: Self) -> Self;
+        unsafe fn sub(self, other: Self) -> Self;
+        unsafe fn mul(self, other: Self) -> Self;
+        unsafe fn div(self, other: Self) -> Self;
+        unsafe fn sqrt(self) -> Self;
+        unsafe fn and(self, other: Self) -> Self;
+        /// `other` without the bits of `self`.
+        unsafe fn and_not(self, other: Self) -> Self;
+        unsafe fn or(self, other: Self) -> Self;
+        unsafe fn xor(self, other: Self) -> Self;
+        unsafe fn lt(self, other: Self) -> Self;
+        unsafe fn gt(self, other: Self) -> Self;
+        /// The nearest integer, half away from zero, and masks of whether bits 0 and 1 of it plus `offset` are set.
+        unsafe fn quadrant(self, offset: i32) -> (Self, Self, Self);
+    }
+
+    // Inlined, so that they're compiled with AVX in `distances_avx`.
+    #[allow(clippy::inline_always)]
+    // SAFETY for each: The caller's CPU has SSE2, like every x86-64 processor.
+    impl Lanes for __m128d {
+        const COUNT: usize = 2;
+
+        #[inline(always)]
+        unsafe fn splat(x: f64) -> Self {
+            _mm_set1_pd(x)
+        }
+
+        #[inline(always)]
+        unsafe fn load(values: &[f64; MAX_LANES]) -> Self {
+            unsafe { _mm_loadu_pd(values.as_ptr()) }
+        }
+
+        #[inline(always)]
+        unsafe fn store(self, out: &mut [f64; MAX_LANES]) {
+            unsafe { _mm_storeu_pd(out.as_mut_ptr(), self) }
+        }
+
+        #[inline(always)]
+        unsafe fn add(self, other: Self) -> Self {
+            _mm_add_pd(self, other)
+        }
+
+        #[inline(always)]
+        unsafe fn sub(self, other: Self) -> Self {
+            _mm_sub_pd(self, other)
+        }
+
+        #[inline(always)]
+        unsafe fn mul(self, other: Self) -> Self {
+            _mm_mul_pd(self, other)
+        }
+
+        #[inline(always)]
+        unsafe fn div(self, other: Self) -> Self {
+            _mm_div_pd(self, other)
+        }
+
+        #[inline(always)]
+        unsafe fn sqrt(self) -> Self {
+            _mm_sqrt_pd(self)
+        }
+
+        #[inline(always)]
+        unsafe fn and(self, other: Self) -> Self {
+            _mm_and_pd(self, other)
+        }
+
+        #[inline(always)]
+        unsafe fn and_not(self, other: Self) -> Self {
+            _mm_andnot_pd(self, other)
+        }
+
+        #[inline(always)]
+        unsafe fn or(self, other: Self) -> Self {
+            _mm_or_pd(self, other)
+        }
+
+        #[inline(always)]
+        unsafe fn xor(self, other: Self) -> Self {
+            _mm_xor_pd(self, other)
+        }
+
+        #[inline(always)]
+        unsafe fn lt(self, other: Self) -> Self {
+            _mm_cmplt_pd(self, other)
+        }
+
+        #[inline(always)]
+        unsafe fn gt(self, other: Self) -> Self {
+            _mm_cmpgt_pd(self, other)
+        }
+
+        #[inline(always)]
+        unsafe fn quadrant(self, offset: i32) -> (Self, Self, Self) {
+            let rounded = unsafe { self.add(select(self.lt(Self::splat(0.0)), Self::splat(-0.5), Self::splat(0.5))) };
+            let quadrant = _mm_cvttpd_epi32(rounded);
+            let shifted = _mm_add_epi32(quadrant, _mm_set1_epi32(offset));
+            // Each 32-bit quadrant in both halves of its 64-bit lane.
+            let lanes = _mm_unpacklo_epi32(shifted, shifted);
+            let bit = |bit| {
+                let bit = _mm_set1_epi32(bit);
+                _mm_castsi128_pd(_mm_cmpeq_epi32(_mm_and_si128(lanes, bit), bit))
+            };
+            (_mm_cvtepi32_pd(quadrant), bit(1), bit(2))
+        }
+    }
+
+    #[allow(clippy::inline_always)]
+    // SAFETY for each: The caller's CPU has AVX.
+    impl Lanes for __m256d {
+        const COUNT: usize = 4;
+
+        #[inline(always)]
+        unsafe fn splat(x: f64) -> Self {
+            unsafe { _mm256_set1_pd(x) }
+        }
+
+        #[inline(always)]
+        unsafe fn load(values: &[f64; MAX_LANES]) -> Self {
+            unsafe { _mm256_loadu_pd(values.as_ptr()) }
+        }
+
+        #[inline(always)]
+        unsafe fn store(self, out: &mut [f64; MAX_LANES]) {
+            unsafe { _mm256_storeu_pd(out.as_mut_ptr(), self) }
+        }
+
+        #[inline(always)]
+        unsafe fn add(self, other: Self) -> Self {
+            unsafe { _mm256_add_pd(self, other) }
+        }
+
+        #[inline(always)]
+        unsafe fn sub(self, other: Self) -> Self {
+            unsafe { _mm256_sub_pd(self, other) }
+        }
+
+        #[inline(always)]
+        unsafe fn mul(self, other: Self) -> Self {
+            unsafe { _mm256_mul_pd(self, other) }
+        }
+
+        #[inline(always)]
+        unsafe fn div(self, other: Self) -> Self {
+            unsafe { _mm256_div_pd(self, other) }
+        }
+
+        #[inline(always)]
+        unsafe fn sqrt(self) -> Self {
+            unsafe { _mm256_sqrt_pd(self) }
+        }
+
+        #[inline(always)]
+        unsafe fn and(self, other: Self) -> Self {
+            unsafe { _mm256_and_pd(self, other) }
+        }
+
+        #[inline(always)]
+        unsafe fn and_not(self, other: Self) -> Self {
+            unsafe { _mm256_andnot_pd(self, other) }
+        }
+
+        #[inline(always)]
+        unsafe fn or(self, other: Self) -> Self {
+            unsafe { _mm256_or_pd(self, other) }
+        }
+
+        #[inline(always)]
+        unsafe fn xor(self, other: Self) -> Self {
+            unsafe { _mm256_xor_pd(self, other) }
+        }
+
+        #[inline(always)]
+        unsafe fn lt(self, other: Self) -> Self {
+            unsafe { _mm256_cmp_pd::<_CMP_LT_OQ>(self, other) }
+        }
+
+        #[inline(always)]
+        unsafe fn gt(self, other: Self) -> Self {
+            unsafe { _mm256_cmp_pd::<_CMP_GT_OQ>(self, other) }
+        }
+
+        #[inline(always)]
+        unsafe fn quadrant(self, offset: i32) -> (Self, Self, Self) {
+            unsafe {
+                let rounded = self.add(select(self.lt(Self::splat(0.0)), Self::splat(-0.5), Self::splat(0.5)));
+                let quadrant = _mm256_cvttpd_epi32(rounded);
+                let shifted = _mm_add_epi32(quadrant, _mm_set1_epi32(offset));
+                // AVX has no 256-bit integer instructions, so the masks are made in halves.
+                let (low, high) = (
+                    _mm_unpacklo_epi32(shifted, shifted),
+                    _mm_unpackhi_epi32(shifted, shifted),
+                );
+                let bit = |bit| {
+                    let bit = _mm_set1_epi32(bit);
+                    let mask = |lanes| _mm_cmpeq_epi32(_mm_and_si128(lanes, bit), bit);
+                    _mm256_castsi256_pd(_mm256_set_m128i(mask(high), mask(low)))
+                };
+                (_mm256_cvtepi32_pd(quadrant), bit(1), bit(2))
+            }
+        }
+    }
+
+    // The functions mirror `Custom`'s. SAFETY for each: The caller's CPU has the instructions of `L`.
+
+    /// `a` where `mask` is true, otherwise `b`.
+    #[allow(clippy::inline_always)]
+    #[inline(always)]
+    unsafe fn select<L: Lanes>(mask: L, a: L, b: L) -> L {
+        unsafe { mask.and(a).or(mask.and_not(b)) }
+    }
+
+    #[allow(clippy::inline_always)]
+    #[inline(always)]
+    unsafe fn polynomial<L: Lanes>(coefficients: &[f64], x: L) -> L {
+        unsafe {
+            coefficients
+                .iter()
+                .rev()
+                .fold(L::splat(0.0), |sum, &coefficient| sum.mul(x).add(L::splat(coefficient)))
+        }
+    }
+
+    /// The sine of `x`, or its cosine with an `offset` of one quadrant.
+    #[allow(clippy::inline_always)]
+    #[inline(always)]
+    unsafe fn sin<L: Lanes>(custom: &Custom, x: L, offset: i32) -> L {
+        unsafe {
+            let (k, odd, negative) = x.mul(L::splat(FRAC_2_PI)).quadrant(offset);
+            let r = x
+                .sub(k.mul(L::splat(FRAC_PI_2_HIGH)))
+                .sub(k.mul(L::splat(FRAC_PI_2_LOW)));
+            let squared = r.mul(r);
+            let value = select(
+                odd,
+                polynomial(&custom.cos, squared),
+                r.mul(polynomial(&custom.sin, squared)),
+            );
+            value.xor(negative.and(L::splat(-0.0)))
+        }
+    }
+
+    #[allow(clippy::inline_always)]
+    #[inline(always)]
+    unsafe fn asin<L: Lanes>(custom: &Custom, x: L) -> L {
+        unsafe {
+            let (one, two, sign) = (L::splat(1.0), L::splat(2.0), L::splat(-0.0));
+            let y = sign.and_not(x);
+            let outer = y.gt(L::splat(0.5));
+            let y = select(outer, one.sub(y).div(two).sqrt(), y);
+            let half = y.div(two.mul(one.add(one.sub(y.mul(y)).sqrt())).sqrt());
+            let angle = two.mul(half).mul(polynomial(&custom.asin, half.mul(half)));
+            let angle = select(outer, L::splat(FRAC_PI_2).sub(two.mul(angle)), angle);
+            sign.and_not(angle).or(sign.and(x))
+        }
+    }
+
+    #[allow(clippy::inline_always)]
+    #[inline(always)]
+    unsafe fn haversine<L: Lanes>(custom: &Custom, [x0, y0, x1, y1]: [L; 4]) -> L {
+        unsafe {
+            // Like `f64::to_radians`.
+            let radians = L::splat(std::f64::consts::PI / 180.0);
+            let two = L::splat(2.0);
+            let d_lat = y1.sub(y0).mul(radians);
+            let d_lon = x1.sub(x0).mul(radians);
+            let (lat0, lat1) = (y0.mul(radians), y1.mul(radians));
+            let (sin_lat, sin_lon) = (sin(custom, d_lat.div(two), 0), sin(custom, d_lon.div(two), 0));
+            let cosines = sin(custom, lat0, 1).mul(sin(custom, lat1, 1));
+            let a = sin_lat.mul(sin_lat).add(cosines.mul(sin_lon.mul(sin_lon)));
+            L::splat(EARTH_RADIUS).mul(two).mul(asin(custom, a.sqrt()))
+        }
+    }
+
+    #[allow(clippy::inline_always)]
+    #[inline(always)]
+    pub unsafe fn distances<L: Lanes>(pairs: &[Pair], custom: &Custom) -> Vec<f64> {
+        let mut distances = Vec::with_capacity(pairs.len());
+        for chunk in pairs.chunks(L::COUNT) {
+            // The coordinates in columns, with zeros in the lanes past the last pair.
+            let mut columns = [[0.0; MAX_LANES]; 4];
+            for (lane, pair) in chunk.iter().enumerate() {
+                columns[0][lane] = pair.x0;
+                columns[1][lane] = pair.y0;
+                columns[2][lane] = pair.x1;
+                columns[3][lane] = pair.y1;
+            }
+            let mut out = [0.0; MAX_LANES];
+            unsafe { haversine(custom, columns.map(|column| L::load(&column))).store(&mut out) };
+            distances.extend_from_slice(&out[..chunk.len()]);
+        }
+        distances
+    }
+
+    #[target_feature(enable = "avx")]
+    pub unsafe fn distances_avx(pairs: &[Pair], custom: &Custom) -> Vec<f64> {
+        unsafe { distances::<__m256d>(pairs, custom) }
+    }
+}
+
+/// The distances of the pairs, in order, with the custom functions of `custom`.
+///
+/// # Panics
+///
+/// If the CPU doesn't have the instructions.
+#[must_use]
+pub fn distances(pairs: &[Pair], custom: &Custom, simd: Simd) -> Vec<f64> {
+    assert!(simd.is_available(), "this CPU doesn't have {simd}");
+    #[cfg(target_arch = "x86_64")]
+    // SAFETY: The CPU has the instructions.
+    unsafe {
+        match simd {
+            Simd::Sse2 => x86::distances::<std::arch::x86_64::__m128d>(pairs, custom),
+            Simd::Avx => x86::distances_avx(pairs, custom),
+        }
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    {
+        let _ = (pairs, custom);
+        unreachable!()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::haversine::{generate, parse, Mode};
+
+    #[test]
+    fn matches() {
+        let (mut json, mut answers) = (vec![], vec![]);
+        generate(&mut json, &mut answers, 1_001, 9, Mode::Uniform).unwrap();
+        let mut pairs = parse(std::str::from_utf8(&json).unwrap()).unwrap();
+        // The edges of the domains, and the same point twice.
+        pairs.extend([
+            Pair {
+                x0: -180.0,
+                y0: -90.0,
+                x1: 180.0,
+                y1: 90.0,
+            },
+            Pair {
+                x0: 180.0,
+                y0: 0.0,
+                x1: -180.0,
+                y1: 0.0,
+            },
+            Pair {
+                x0: 0.0,
+                y0: 90.0,
+                x1: 0.0,
+                y1: -90.0,
+            },
+            Pair {
+                x0: 45.0,
+                y0: 45.0,
+                x1: 45.0,
+                y1: 45.0,
+            },
+            Pair {
+                x0: -0.0,
+                y0: -0.0,
+                x1: 0.0,
+                y1: 0.0,
+            },
+        ]);
+        for custom in [Custom::default(), Custom::new(7)] {
+            let expected: Vec<u64> = pairs.iter().map(|pair| pair.distance_with(&custom).to_bits()).collect();
+            for simd in [Simd::Sse2, Simd::Avx] {
+                if !simd.is_available() {
+                    continue;
+                }
+                for count in [0, 1, 3, pairs.len()] {
+                    let distances: Vec<u64> = distances(&pairs[..count], &custom, simd)
+                        .iter()
+                        .map(|distance| distance.to_bits())
+                        .collect();
+                    assert_eq!(distances, expected[..count], "{simd}");
+                }
+            }
+        }
+        assert_eq!("avx".parse(), Ok(Simd::Avx));
+        assert!("neon".parse::<Simd>().is_err());
+    }
+}
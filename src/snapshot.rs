@@ -0,0 +1,126 @@
+//! Save and restore the state of a simulation, so it can be paused and resumed, or shared as a test fixture.
+//!
+//! A snapshot is a signature with a format version, the registers, the cycle count, the halted state, the flags
+//! that are undefined in strict mode, the interrupt controller's registers, and all of memory, which is restored
+//! to a machine with the same size of memory. Numbers are little endian. Devices on ports, input and output aren't
+//! saved.
+
+use std::io::{self, ErrorKind, Read, Write};
+
+use crate::pic::Pic;
+use crate::sim::Machine;
+
+const SIGNATURE: &[u8; 8] = b"SIM86\0\0\x01";
+
+/// Write a snapshot of a machine.
+///
+/// # Errors
+///
+/// If the snapshot can't be written.
+pub fn save(machine: &Machine, out: &mut impl Write) -> io::Result<()> {
+    let registers = &machine.registers;
+    out.write_all(SIGNATURE)?;
+    for word in registers.general.iter().chain(&registers.segments) {
+        out.write_all(&word.to_le_bytes())?;
+    }
+    out.write_all(&registers.ip.to_le_bytes())?;
+    out.write_all(&registers.flags.to_le_bytes())?;
+    out.write_all(&machine.cycles.to_le_bytes())?;
+    out.write_all(&[u8::from(machine.halted)])?;
+    out.write_all(&machine.undefined.to_le_bytes())?;
+    out.write_all(&machine.pic.to_bytes())?;
+    out.write_all(&machine.memory)
+}
+
+/// Restore a machine from a snapshot.
+///
+/// # Errors
+///
+/// If the snapshot can't be read, or isn't a snapshot in this format.
+pub fn restore(machine: &mut Machine, input: &mut impl Read) -> io::Result<()> {
+    let mut signature = [0; SIGNATURE.len()];
+    input.read_exact(&mut signature)?;
+    if &signature != SIGNATURE {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "not a snapshot, or a different version",
+        ));
+    }
+    // Everything is read and checked before the machine changes, so that a rejected snapshot leaves it as it was.
+    let mut registers = machine.registers;
+    for word in registers.general.iter_mut().chain(&mut registers.segments) {
+        *word = u16::from_le_bytes(read(input)?);
+    }
+    registers.ip = u16::from_le_bytes(read(input)?);
+    registers.flags = u16::from_le_bytes(read(input)?);
+    let cycles = u64::from_le_bytes(read(input)?);
+    let halted = read::<1>(input)?[0] != 0;
+    let undefined = u16::from_le_bytes(read(input)?);
+    let pic = Pic::from_bytes(read(input)?);
+    let mut memory = vec![];
+    input.read_to_end(&mut memory)?;
+    if memory.len() != machine.memory.len() {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "snapshot has {} bytes of memory, but the machine has {}",
+                memory.len(),
+                machine.memory.len()
+            ),
+        ));
+    }
+    machine.registers = registers;
+    machine.cycles = cycles;
+    machine.halted = halted;
+    machine.undefined = undefined;
+    machine.pic = pic;
+    machine.memory = memory;
+    Ok(())
+}
+
+fn read<const N: usize>(input: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut bytes = [0; N];
+    input.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::instruction::Register;
+
+    #[test]
+    fn round_trip() {
+        let mut machine = Machine::new();
+        machine.registers.set(Register::Bx, 0x1234);
+        machine.registers.set(Register::Ds, 0x2000);
+        machine.registers.ip = 0x100;
+        machine.cycles = 1 << 40;
+        machine.halted = true;
+        machine.pic.request(0);
+        machine.load(0xfffff, &[0x55]);
+
+        let mut snapshot = vec![];
+        save(&machine, &mut snapshot).unwrap();
+        let mut restored = Machine::new();
+        restore(&mut restored, &mut snapshot.as_slice()).unwrap();
+        assert_eq!(restored.registers, machine.registers);
+        assert_eq!(restored.cycles, machine.cycles);
+        assert!(restored.halted);
+        assert_eq!(restored.pic, machine.pic);
+        assert_eq!(restored.memory, machine.memory);
+
+        // A rejected snapshot leaves the machine unchanged.
+        let mut fresh = Machine::new();
+        assert!(restore(&mut fresh, &mut &snapshot[..100]).is_err());
+        assert_eq!(fresh.registers, Machine::new().registers);
+        assert_eq!(fresh.cycles, 0);
+        assert!(!fresh.halted);
+        assert_eq!(fresh.pic, Pic::default());
+        assert!(fresh.memory.iter().all(|&byte| byte == 0));
+
+        snapshot[0] = b'X';
+        assert!(restore(&mut restored, &mut snapshot.as_slice()).is_err());
+    }
+}
@@ -0,0 +1,321 @@
+//! Set the initial state of a simulation: registers, and files or bytes in memory.
+//!
+//! A state file is JSON, like:
+//!
+//! ```json
+//! {
+//!     "registers": {"ax": "0x1234", "ip": 256, "flags": "0x0200"},
+//!     "memory": [{"address": "0x8000", "file": "table.bin"}, {"address": "1000:0010", "bytes": [1, 2, 3]}]
+//! }
+//! ```
+//!
+//! Numbers are decimal, or hexadecimal with a "0x" prefix. Addresses are physical, or "segment:offset" in
+//! hexadecimal. Files are relative to the state file.
+
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::instruction::Register;
+use crate::json::{self, Element};
+use crate::sim::{physical, Machine, Registers, FLAGS_MASK};
+
+const REGISTERS: [Register; 20] = [
+    Register::Al,
+    Register::Cl,
+    Register::Dl,
+    Register::Bl,
+    Register::Ah,
+    Register::Ch,
+    Register::Dh,
+    Register::Bh,
+    Register::Ax,
+    Register::Cx,
+    Register::Dx,
+    Register::Bx,
+    Register::Sp,
+    Register::Bp,
+    Register::Si,
+    Register::Di,
+    Register::Es,
+    Register::Cs,
+    Register::Ss,
+    Register::Ds,
+];
+
+/// A register, including IP and the flags.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Target {
+    Register(Register),
+    Ip,
+    Flags,
+}
+
+impl Target {
+    #[must_use]
+    pub const fn get(self, registers: &Registers) -> u16 {
+        match self {
+            Self::Register(register) => registers.get(register),
+            Self::Ip => registers.ip,
+            Self::Flags => registers.flags,
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Register(register) => write!(f, "{register}"),
+            Self::Ip => f.write_str("ip"),
+            Self::Flags => f.write_str("flags"),
+        }
+    }
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name = s.to_ascii_lowercase();
+        match name.as_str() {
+            "ip" => Ok(Self::Ip),
+            "flags" => Ok(Self::Flags),
+            _ => REGISTERS
+                .into_iter()
+                .find(|register| register.name() == name)
+                .map(Self::Register)
+                .ok_or_else(|| format!("unknown register {s:?}")),
+        }
+    }
+}
+
+/// A value for a register, like "ax=0x1234".
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Assignment {
+    pub target: Target,
+    pub value: u16,
+}
+
+impl Assignment {
+    pub const fn apply(&self, registers: &mut Registers) {
+        match self.target {
+            Target::Register(register) => registers.set(register, self.value),
+            Target::Ip => registers.ip = self.value,
+            Target::Flags => registers.flags = self.value & FLAGS_MASK,
+        }
+    }
+}
+
+impl FromStr for Assignment {
+    type Err = String;
+
+    /// Parse "register=value".
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (target, value) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected register=value, not {s:?}"))?;
+        Ok(Self {
+            target: target.trim().parse()?,
+            value: word(value.trim())?,
+        })
+    }
+}
+
+/// A file to load at an address, like "table.bin@0x8000".
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Placement {
+    pub path: PathBuf,
+    pub address: usize,
+}
+
+impl Placement {
+    /// Load the file into memory.
+    ///
+    /// # Errors
+    ///
+    /// If the file can't be read, or doesn't fit in memory.
+    pub fn apply(&self, machine: &mut Machine) -> Result<(), String> {
+        let bytes = fs::read(&self.path).map_err(|error| format!("{}: {error}", self.path.display()))?;
+        load(machine, self.address, &bytes).map_err(|error| format!("{}: {error}", self.path.display()))
+    }
+}
+
+impl FromStr for Placement {
+    type Err = String;
+
+    /// Parse "path@address".
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (path, address) = s
+            .rsplit_once('@')
+            .ok_or_else(|| format!("expected file@address, not {s:?}"))?;
+        Ok(Self {
+            path: PathBuf::from(path),
+            address: address_of(address)?,
+        })
+    }
+}
+
+/// Parse a decimal number, or a hexadecimal number with a "0x" prefix.
+///
+/// # Errors
+///
+/// If the number is invalid.
+pub fn number(text: &str) -> Result<u32, String> {
+    text.strip_prefix("0x")
+        .or_else(|| text.strip_prefix("0X"))
+        .map_or_else(|| text.parse(), |hex| u32::from_str_radix(hex, 16))
+        .map_err(|error| format!("invalid number {text:?}: {error}"))
+}
+
+fn word(text: &str) -> Result<u16, String> {
+    u16::try_from(number(text)?).map_err(|_| format!("{text} does not fit in 16 bits"))
+}
+
+/// Parse a physical address, or "segment:offset" in hexadecimal.
+///
+/// # Errors
+///
+/// If the address is invalid.
+pub fn address_of(text: &str) -> Result<usize, String> {
+    if let Some((segment, offset)) = text.split_once(':') {
+        let parse = |part: &str| {
+            u16::from_str_radix(part.trim(), 16).map_err(|error| format!("invalid address {text:?}: {error}"))
+        };
+        return Ok(physical(parse(segment)?, parse(offset)?));
+    }
+    number(text.trim()).map(|address| address as usize)
+}
+
+fn load(machine: &mut Machine, address: usize, bytes: &[u8]) -> Result<(), String> {
+    if address + bytes.len() > machine.memory.len() {
+        return Err(format!("{} bytes at {address:#x} do not fit in memory", bytes.len()));
+    }
+    machine.load(address, bytes);
+    Ok(())
+}
+
+/// Apply a JSON state file, whose files are relative to a directory.
+///
+/// # Errors
+///
+/// If the JSON or a value in it is invalid, or a file can't be loaded.
+pub fn apply_json(machine: &mut Machine, json: &str, directory: &Path) -> Result<(), String> {
+    let state = json::parse(json)?;
+    let Element::Object(members) = &state else {
+        return Err("expected an object".to_string());
+    };
+    for (key, _) in members {
+        if key != "registers" && key != "memory" {
+            return Err(format!("unknown key {key:?}"));
+        }
+    }
+
+    if let Some(registers) = state.get("registers") {
+        let Element::Object(registers) = registers else {
+            return Err("\"registers\" must be an object".to_string());
+        };
+        for (name, value) in registers {
+            let assignment = Assignment {
+                target: name.parse()?,
+                value: word(&text(value).map_err(|error| format!("{name}: {error}"))?)?,
+            };
+            assignment.apply(&mut machine.registers);
+        }
+    }
+
+    if let Some(memory) = state.get("memory") {
+        let memory = memory.as_array().ok_or("\"memory\" must be an array")?;
+        for entry in memory {
+            let address = entry.get("address").ok_or("memory entries need an \"address\"")?;
+            let address = address_of(&text(address)?)?;
+            match (entry.get("file"), entry.get("bytes")) {
+                (Some(Element::String(path)), None) => Placement {
+                    path: directory.join(path.as_ref()),
+                    address,
+                }
+                .apply(machine)?,
+                (None, Some(Element::Array(bytes))) => {
+                    let bytes = bytes
+                        .iter()
+                        .map(|byte| match byte {
+                            Element::Number(byte) if byte.fract() == 0.0 && (0.0..=255.0).contains(byte) => {
+                                Ok(*byte as u8)
+                            }
+                            _ => Err(format!("invalid byte {}", text(byte).unwrap_or_default())),
+                        })
+                        .collect::<Result<Vec<_>, _>>()?;
+                    load(machine, address, &bytes)?;
+                }
+                _ => return Err("memory entries need a \"file\" string or a \"bytes\" array".to_string()),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A number or a string, as text.
+fn text(value: &Element) -> Result<String, String> {
+    match value {
+        Element::Number(number) => Ok(number.to_string()),
+        Element::String(string) => Ok(string.to_string()),
+        _ => Err("expected a number or a string".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn assignments() {
+        let mut registers = Registers::default();
+        for assignment in ["ax=0x1234", "BL=7", "ip=256", "flags=0xffff"] {
+            assignment.parse::<Assignment>().unwrap().apply(&mut registers);
+        }
+        assert_eq!(registers.get(Register::Ax), 0x1234);
+        assert_eq!(registers.get(Register::Bx), 7);
+        assert_eq!(registers.ip, 256);
+        assert_eq!(registers.flags, FLAGS_MASK);
+
+        assert!("ax".parse::<Assignment>().is_err());
+        assert!("zx=1".parse::<Assignment>().is_err());
+        assert!("ax=0x10000".parse::<Assignment>().is_err());
+    }
+
+    #[test]
+    fn placements() {
+        let placement = "data/table.bin@0x8000".parse::<Placement>().unwrap();
+        assert_eq!(placement.path, Path::new("data/table.bin"));
+        assert_eq!(placement.address, 0x8000);
+        assert_eq!(address_of("1000:0010").unwrap(), 0x10010);
+        assert!("table.bin".parse::<Placement>().is_err());
+    }
+
+    #[test]
+    fn json() {
+        let directory = tempfile::tempdir().unwrap();
+        fs::write(directory.path().join("table.bin"), [4, 5]).unwrap();
+        let json = r#"{
+            "registers": {"cx": 3, "ds": "0x1000"},
+            "memory": [{"address": "0x8000", "file": "table.bin"}, {"address": "1000:0002", "bytes": [1, 2]}]
+        }"#;
+        let mut machine = Machine::new();
+        apply_json(&mut machine, json, directory.path()).unwrap();
+        assert_eq!(machine.registers.get(Register::Cx), 3);
+        assert_eq!(machine.registers.get(Register::Ds), 0x1000);
+        assert_eq!(machine.memory[0x8000..0x8002], [4, 5]);
+        assert_eq!(machine.memory[0x10002..0x10004], [1, 2]);
+
+        assert!(apply_json(&mut machine, r#"{"memory": [{"address": 0}]}"#, directory.path()).is_err());
+        assert!(apply_json(&mut machine, r#"{"register": {}}"#, directory.path()).is_err());
+        assert_eq!(
+            apply_json(
+                &mut machine,
+                r#"{"memory": [{"address": 0, "bytes": [256]}]}"#,
+                directory.path()
+            ),
+            Err("invalid byte 256".to_string())
+        );
+    }
+}
@@ -0,0 +1,516 @@
+//! Count what a simulated program executes: instructions by mnemonic, entries to basic blocks, the outcomes of
+//! conditional branches, and the bytes that instructions read from and write to memory.
+//!
+//! The counts also find the hottest loops, and compare the clocks that instructions took with the clocks estimated
+//! without executing them.
+//!
+//! Basic blocks are found as the program runs. A block starts at an instruction that isn't reached by falling
+//! through from the previous instruction, or that follows a control transfer, and continues until the next control
+//! transfer. Instruction fetches and the memory accesses of DOS and BIOS services aren't counted.
+//!
+//! A loop is found from a jump back to an earlier address: the loop is the instructions from that address to the
+//! jump. Jumps back to the same address are one loop, which ends at the furthest jump.
+
+// Percentages are approximate.
+#![allow(clippy::cast_precision_loss)]
+
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Write};
+
+use crate::clocks;
+use crate::instruction::{Instruction, Operation};
+
+/// The executions of an instruction, and the clocks that they took.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Executed {
+    /// The last instruction executed at the address.
+    pub instruction: Instruction,
+    pub count: u64,
+    pub cycles: u64,
+    /// The clocks estimated without executing the instruction. See [`clocks::estimate`].
+    pub estimated: u64,
+    /// The clocks for words at odd addresses.
+    pub penalties: u64,
+}
+
+/// The entries to a basic block, and the instructions and clocks executed from those entries.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Block {
+    pub entries: u64,
+    pub instructions: u64,
+    pub cycles: u64,
+}
+
+/// A loop, from the physical address of its first instruction to the address of its last jump back.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Loop {
+    pub start: usize,
+    pub end: usize,
+    /// The jumps back to the start.
+    pub iterations: u64,
+    /// The instructions executed in the loop, and their clocks.
+    pub instructions: u64,
+    pub cycles: u64,
+}
+
+/// The outcomes of a conditional branch.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Branch {
+    pub taken: u64,
+    pub not_taken: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct Statistics {
+    /// The executed instructions, by physical address.
+    pub instructions: BTreeMap<usize, Executed>,
+    pub operations: HashMap<Operation, u64>,
+    /// The basic blocks, by the physical address of their first instruction.
+    pub blocks: BTreeMap<usize, Block>,
+    /// The conditional jumps and loops, by physical address.
+    pub branches: BTreeMap<usize, Branch>,
+    /// The jumps that were taken, by the physical addresses of the jump and its target.
+    pub jumps: BTreeMap<(usize, usize), u64>,
+    /// The bytes that instructions read from memory. Reading doesn't otherwise change the machine.
+    pub reads: Cell<u64>,
+    /// The bytes that instructions wrote to memory.
+    pub writes: u64,
+    /// The first address of the block being executed, and the address that continues it.
+    block: Option<(usize, usize)>,
+}
+
+impl Statistics {
+    /// Record an instruction that was executed at a physical address, the clocks that it took, the clocks for words
+    /// at odd addresses, and the address of the next instruction.
+    pub fn record(&mut self, address: usize, instruction: &Instruction, clocks: u32, penalty: u32, next: usize) {
+        let clocks = u64::from(clocks);
+        let taken = next != address + usize::from(instruction.size);
+        let executed = self.instructions.entry(address).or_insert(Executed {
+            instruction: *instruction,
+            count: 0,
+            cycles: 0,
+            estimated: 0,
+            penalties: 0,
+        });
+        executed.instruction = *instruction;
+        executed.count += 1;
+        executed.cycles += clocks;
+        executed.estimated += u64::from(clocks::estimate(instruction));
+        executed.penalties += u64::from(penalty);
+        *self.operations.entry(instruction.operation).or_default() += 1;
+
+        let start = match self.block {
+            Some((start, next)) if next == address => start,
+            _ => {
+                self.blocks.entry(address).or_default().entries += 1;
+                address
+            }
+        };
+        let block = self.blocks.entry(start).or_default();
+        block.instructions += 1;
+        block.cycles += clocks;
+        self.block = (!transfers(instruction.operation)).then(|| (start, address + usize::from(instruction.size)));
+
+        if is_conditional(instruction.operation) {
+            let branch = self.branches.entry(address).or_default();
+            if taken {
+                branch.taken += 1;
+            } else {
+                branch.not_taken += 1;
+            }
+        }
+        if taken && (instruction.operation == Operation::Jmp || is_conditional(instruction.operation)) {
+            *self.jumps.entry((address, next)).or_default() += 1;
+        }
+    }
+
+    /// The loops, from the most to the least clocks.
+    #[must_use]
+    pub fn loops(&self) -> Vec<Loop> {
+        let mut ends = BTreeMap::new();
+        for (&(from, to), &count) in &self.jumps {
+            if to <= from {
+                let (end, iterations) = ends.entry(to).or_insert((from, 0));
+                *end = from.max(*end);
+                *iterations += count;
+            }
+        }
+        let mut loops: Vec<_> = ends
+            .into_iter()
+            .map(|(start, (end, iterations))| {
+                let body = self.instructions.range(start..=end).map(|(_, executed)| executed);
+                Loop {
+                    start,
+                    end,
+                    iterations,
+                    instructions: body.clone().map(|executed| executed.count).sum(),
+                    cycles: body.map(|executed| executed.cycles).sum(),
+                }
+            })
+            .collect();
+        loops.sort_by(|a, b| b.cycles.cmp(&a.cycles).then(a.start.cmp(&b.start)));
+        loops
+    }
+
+    /// Write the hottest loops, with the executions and clocks of each instruction.
+    ///
+    /// # Errors
+    ///
+    /// If writing fails.
+    pub fn write_loops(&self, out: &mut impl Write, labels: &BTreeMap<usize, String>, count: usize) -> io::Result<()> {
+        let total: u64 = self.instructions.values().map(|executed| executed.cycles).sum();
+        for (rank, found) in self.loops().iter().take(count).enumerate() {
+            if rank > 0 {
+                writeln!(out)?;
+            }
+            writeln!(
+                out,
+                "#{} {:05x}-{:05x}: iterations {}, instructions {}, cycles {} ({:.1}%)",
+                rank + 1,
+                found.start,
+                found.end,
+                found.iterations,
+                found.instructions,
+                found.cycles,
+                100.0 * found.cycles as f64 / total.max(1) as f64
+            )?;
+            writeln!(out, "{:>10} {:>10}", "count", "cycles")?;
+            for (address, executed) in self.instructions.range(found.start..=found.end) {
+                if let Some(label) = labels.get(address) {
+                    writeln!(out, "{:>22}{label}:", "")?;
+                }
+                writeln!(
+                    out,
+                    "{:>10} {:>10}  {address:05x}  {}",
+                    executed.count, executed.cycles, executed.instruction
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The number of instructions executed.
+    #[must_use]
+    pub fn total(&self) -> u64 {
+        self.operations.values().sum()
+    }
+
+    /// Write a report: the totals, then the mnemonics and basic blocks from most to least executed, then the
+    /// branches in address order. Addresses are physical, with their labels.
+    ///
+    /// # Errors
+    ///
+    /// If writing fails.
+    pub fn write(&self, out: &mut impl Write, labels: &BTreeMap<usize, String>) -> io::Result<()> {
+        let total = self.total();
+        let percent = |count: u64| 100.0 * count as f64 / total.max(1) as f64;
+        writeln!(out, "instructions: {total}")?;
+        writeln!(
+            out,
+            "cycles: {}",
+            self.instructions.values().map(|executed| executed.cycles).sum::<u64>()
+        )?;
+        writeln!(
+            out,
+            "memory: {} bytes read, {} bytes written",
+            self.reads.get(),
+            self.writes
+        )?;
+
+        writeln!(out, "\n{:<10} {:>10} {:>7}", "mnemonic", "count", "%")?;
+        let mut operations: Vec<_> = self.operations.iter().collect();
+        operations.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then(a.name().cmp(b.name())));
+        for (operation, &count) in operations {
+            writeln!(out, "{:<10} {count:>10} {:>6.1}%", operation.name(), percent(count))?;
+        }
+
+        writeln!(
+            out,
+            "\n{:<24} {:>10} {:>12} {:>12}",
+            "block", "entries", "instructions", "cycles"
+        )?;
+        let mut blocks: Vec<_> = self.blocks.iter().collect();
+        blocks.sort_by(|(a, a_block), (b, b_block)| b_block.instructions.cmp(&a_block.instructions).then(a.cmp(b)));
+        for (&address, block) in blocks {
+            writeln!(
+                out,
+                "{:<24} {:>10} {:>12} {:>12}",
+                describe(address, labels),
+                block.entries,
+                block.instructions,
+                block.cycles
+            )?;
+        }
+
+        if !self.branches.is_empty() {
+            writeln!(
+                out,
+                "\n{:<24} {:<16} {:>10} {:>10} {:>7}",
+                "branch", "", "taken", "not taken", "%"
+            )?;
+        }
+        for (&address, branch) in &self.branches {
+            let text = self.instructions[&address].instruction.to_string();
+            let ratio = 100.0 * branch.taken as f64 / (branch.taken + branch.not_taken) as f64;
+            writeln!(
+                out,
+                "{:<24} {text:<16} {:>10} {:>10} {ratio:>6.1}%",
+                describe(address, labels),
+                branch.taken,
+                branch.not_taken
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Write the total clocks that were estimated and simulated, then the instructions whose clocks differ from
+    /// their estimates, from the most to the least difference. The difference is split into the clocks for words at
+    /// odd addresses, and the other clocks: for jumps that were taken, repetitions and shift counts.
+    ///
+    /// # Errors
+    ///
+    /// If writing fails.
+    pub fn write_reconciliation(&self, out: &mut impl Write, labels: &BTreeMap<usize, String>) -> io::Result<()> {
+        let sum = |clocks: fn(&Executed) -> u64| self.instructions.values().map(clocks).sum::<u64>();
+        let (estimated, simulated, penalties) = (
+            sum(|executed| executed.estimated),
+            sum(|executed| executed.cycles),
+            sum(|executed| executed.penalties),
+        );
+        writeln!(
+            out,
+            "estimated {estimated} cycles, simulated {simulated} cycles: {:+} for odd addresses, {:+} other",
+            penalties,
+            simulated - estimated - penalties
+        )?;
+
+        let mut differences: Vec<_> = self
+            .instructions
+            .iter()
+            .filter(|(_, executed)| executed.cycles != executed.estimated)
+            .collect();
+        if differences.is_empty() {
+            return Ok(());
+        }
+        differences.sort_by(|(a, a_executed), (b, b_executed)| {
+            let difference = |executed: &Executed| executed.cycles - executed.estimated;
+            difference(b_executed).cmp(&difference(a_executed)).then(a.cmp(b))
+        });
+        writeln!(
+            out,
+            "\n{:<24} {:>10} {:>10} {:>10} {:>10} {:>10}  instruction",
+            "address", "count", "estimated", "simulated", "odd", "other"
+        )?;
+        for (&address, executed) in differences {
+            writeln!(
+                out,
+                "{:<24} {:>10} {:>10} {:>10} {:>+10} {:>+10}  {}",
+                describe(address, labels),
+                executed.count,
+                executed.estimated,
+                executed.cycles,
+                executed.penalties,
+                executed.cycles - executed.estimated - executed.penalties,
+                executed.instruction
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// A physical address, with its label.
+fn describe(address: usize, labels: &BTreeMap<usize, String>) -> String {
+    labels
+        .get(&address)
+        .map_or_else(|| format!("{address:05x}"), |label| format!("{address:05x} ({label})"))
+}
+
+/// Whether an operation is a conditional jump or loop.
+#[must_use]
+pub const fn is_conditional(operation: Operation) -> bool {
+    matches!(
+        operation,
+        Operation::Jo
+            | Operation::Jno
+            | Operation::Jb
+            | Operation::Jnb
+            | Operation::Je
+            | Operation::Jne
+            | Operation::Jbe
+            | Operation::Jnbe
+            | Operation::Js
+            | Operation::Jns
+            | Operation::Jp
+            | Operation::Jnp
+            | Operation::Jl
+            | Operation::Jnl
+            | Operation::Jle
+            | Operation::Jnle
+            | Operation::Loopnz
+            | Operation::Loopz
+            | Operation::Loop
+            | Operation::Jcxz
+    )
+}
+
+/// Whether an operation can transfer control, which ends a basic block.
+#[must_use]
+pub const fn transfers(operation: Operation) -> bool {
+    is_conditional(operation)
+        || matches!(
+            operation,
+            Operation::Call
+                | Operation::Jmp
+                | Operation::Ret
+                | Operation::Retf
+                | Operation::Int
+                | Operation::Int3
+                | Operation::Into
+                | Operation::Iret
+                | Operation::Hlt
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::sim::Machine;
+
+    fn run(program: &[u8]) -> Statistics {
+        let mut machine = Machine::new();
+        machine.load(0, program);
+        machine.statistics = Some(Statistics::default());
+        while machine.step().is_ok() {}
+        machine.statistics.unwrap()
+    }
+
+    #[test]
+    fn counts() {
+        let program = [
+            &[0b10111001, 3, 0][..],     // mov cx, 3
+            &[0b10111011, 0x00, 0x01],   // mov bx, 256
+            &[0b10001000, 0b00_001_111], // mov [bx], cl
+            &[0b00000010, 0b00_000_111], // add al, [bx]
+            &[0b11100010, 0b11111010],   // loop $-4
+            &[0b11110100],               // hlt
+        ]
+        .concat();
+        let statistics = run(&program);
+        assert_eq!(statistics.total(), 12);
+        assert_eq!(statistics.operations[&Operation::Mov], 5);
+        assert_eq!(statistics.operations[&Operation::Loop], 3);
+        assert_eq!(statistics.reads.get(), 3);
+        assert_eq!(statistics.writes, 3);
+        assert_eq!(statistics.instructions[&6].count, 3);
+        // The first block falls through into the loop, which is entered twice more.
+        assert_eq!(
+            (statistics.blocks[&0].entries, statistics.blocks[&0].instructions),
+            (1, 5)
+        );
+        assert_eq!(statistics.blocks[&6].entries, 2);
+        assert_eq!(statistics.blocks[&12].entries, 1);
+        assert_eq!(statistics.branches[&10], Branch { taken: 2, not_taken: 1 });
+
+        let mut out = vec![];
+        let labels = BTreeMap::from([(6, "label0".to_string())]);
+        statistics.write(&mut out, &labels).unwrap();
+        let report = String::from_utf8(out).unwrap();
+        assert!(report.starts_with("instructions: 12\n"), "{report}");
+        assert!(report.contains("\nmov                 5   41.7%\n"), "{report}");
+        assert!(
+            report.contains("\n00006 (label0)                    2            6"),
+            "{report}"
+        );
+        assert!(
+            report.contains("\n0000a                    loop $-4                  2          1   66.7%\n"),
+            "{report}"
+        );
+    }
+
+    #[test]
+    fn loops() {
+        let program = [
+            &[0b10111010, 2, 0][..], // mov dx, 2
+            &[0b10111001, 3, 0],     // mov cx, 3
+            &[0b01000000],           // inc ax
+            &[0b11100010, 0xfd],     // loop $-1
+            &[0b01001010],           // dec dx
+            &[0b01110101, 0xf7],     // jnz $-7
+            &[0b11110100],           // hlt
+        ]
+        .concat();
+        let statistics = run(&program);
+        let loops = statistics.loops();
+        assert_eq!(loops.len(), 2);
+        // The outer loop includes the inner loop, so it takes more clocks.
+        assert_eq!(
+            (loops[0].start, loops[0].end, loops[0].iterations, loops[0].instructions),
+            (3, 10, 1, 18)
+        );
+        assert_eq!(
+            (loops[1].start, loops[1].end, loops[1].iterations, loops[1].instructions),
+            (6, 7, 4, 12)
+        );
+        assert_eq!(
+            loops[1].cycles,
+            statistics.instructions[&6].cycles + statistics.instructions[&7].cycles
+        );
+
+        let mut out = vec![];
+        let labels = BTreeMap::from([(6, "label1".to_string())]);
+        statistics.write_loops(&mut out, &labels, 1).unwrap();
+        let report = String::from_utf8(out).unwrap();
+        assert!(
+            report.starts_with("#1 00003-0000a: iterations 1, instructions 18, cycles "),
+            "{report}"
+        );
+        assert!(
+            report.contains("\n                      label1:\n         6         12  00006  inc ax\n"),
+            "{report}"
+        );
+        assert!(!report.contains("#2"), "{report}");
+    }
+
+    #[test]
+    fn reconciliation() {
+        let program = [
+            &[0b10111011, 1, 0][..],     // mov bx, 1
+            &[0b10001011, 0b00_000_111], // mov ax, [bx]
+            &[0b10111001, 2, 0],         // mov cx, 2
+            &[0b11100010, 0xfe],         // loop $+0
+            &[0b11110100],               // hlt
+        ]
+        .concat();
+        let statistics = run(&program);
+        assert_eq!(statistics.instructions[&3].penalties, 4);
+        assert_eq!(
+            (
+                statistics.instructions[&8].estimated,
+                statistics.instructions[&8].cycles
+            ),
+            (10, 22)
+        );
+
+        let mut out = vec![];
+        statistics.write_reconciliation(&mut out, &BTreeMap::new()).unwrap();
+        let report = String::from_utf8(out).unwrap();
+        assert!(
+            report.starts_with("estimated 33 cycles, simulated 49 cycles: +4 for odd addresses, +12 other\n"),
+            "{report}"
+        );
+        let rows: Vec<_> = report
+            .lines()
+            .skip(3)
+            .map(|line| line.split_whitespace().collect::<Vec<_>>())
+            .collect();
+        assert_eq!(
+            rows,
+            [
+                vec!["00008", "2", "10", "22", "+0", "+12", "loop", "$+0"],
+                vec!["00003", "1", "13", "17", "+4", "+0", "mov", "ax,", "[bx]"],
+            ]
+        );
+    }
+}
@@ -0,0 +1,101 @@
+//! Decode instructions as they're read from an [`AsyncRead`], such as a socket, without blocking the executor's
+//! threads. This needs the `async` feature.
+
+use std::io;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::decode::{self, READ_AHEAD};
+use crate::instruction::Instruction;
+
+// The bytes that are read at a time.
+const BUFFER: usize = 8192;
+
+/// The instructions read from a reader, one after another, like a [`Listing`](decode::Listing).
+pub struct DecodeStream<R> {
+    reader: R,
+    buffer: Vec<u8>,
+    // The position in the buffer of the next instruction.
+    start: usize,
+    // The offset in the input of the next instruction.
+    offset: usize,
+    eof: bool,
+}
+
+/// Decode the instructions that are read from `reader`.
+pub fn decode_stream<R: AsyncRead + Unpin>(reader: R) -> DecodeStream<R> {
+    DecodeStream {
+        reader,
+        buffer: Vec::with_capacity(BUFFER),
+        start: 0,
+        offset: 0,
+        eof: false,
+    }
+}
+
+impl<R: AsyncRead + Unpin> DecodeStream<R> {
+    /// The offset of the next instruction and the instruction, or `None` for a byte that doesn't begin one, which
+    /// is skipped. Returns `Ok(None)` at the end of the input.
+    ///
+    /// # Errors
+    ///
+    /// If reading fails.
+    pub async fn next(&mut self) -> io::Result<Option<(usize, Option<Instruction>)>> {
+        loop {
+            let code = &self.buffer[self.start..];
+            if code.is_empty() && self.eof {
+                return Ok(None);
+            }
+            // An instruction is decoded once the bytes that decoding it can read are buffered, so that it's the same
+            // however the input is split into reads.
+            let instruction = decode::decode(code);
+            let needed = instruction.map_or_else(
+                || code.iter().take_while(|&&byte| decode::is_prefix(byte)).count() + READ_AHEAD,
+                |instruction| usize::from(instruction.size) + 1,
+            );
+            if self.eof || code.len() >= needed {
+                let size = instruction.map_or(1, |instruction| usize::from(instruction.size));
+                let offset = self.offset;
+                self.start += size;
+                self.offset += size;
+                return Ok(Some((offset, instruction)));
+            }
+
+            self.buffer.drain(..self.start);
+            self.start = 0;
+            self.buffer.reserve(BUFFER);
+            if self.reader.read_buf(&mut self.buffer).await? == 0 {
+                self.eof = true;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use tokio::io::AsyncWriteExt;
+
+    #[tokio::test]
+    async fn decodes() {
+        let mut code = fs::read("perfaware/part1/listing_0042_completionist_decode").unwrap();
+        // An invalid byte, and an instruction that's cut off by the end.
+        code.extend([0b11011000, 0b10111011, 0x03]);
+        let expected = decode::Listing::new(&code);
+
+        // One byte at a time, so that every instruction is split between reads.
+        let (mut writer, reader) = tokio::io::duplex(1);
+        let input = code.clone();
+        let writing = tokio::spawn(async move { writer.write_all(&input).await });
+        let mut stream = decode_stream(reader);
+        let mut entries = vec![];
+        while let Some(entry) = stream.next().await.unwrap() {
+            entries.push(entry);
+        }
+        writing.await.unwrap().unwrap();
+        assert_eq!(entries, expected.entries());
+    }
+}
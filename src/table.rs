@@ -0,0 +1,683 @@
+//! The 8086's instructions, as one table of their encodings, operands and clocks.
+//!
+//! The decoder, the encoder and the clock estimator all read the table, and the simulator reads the flags that each
+//! operation reads and writes.
+//!
+//! Each [`Form`] is a row of the manual's instruction encoding table. Its pattern is written like the manual's: the
+//! first byte's bits, where `w` is the W field, `rrr` is a register and `ss` is a segment register, and then the MOD
+//! REG R/M byte, if any, where REG is `reg` for a register operand, three bits that extend the opcode, or `---` if it
+//! is ignored, or a fixed second byte. The forms of an operation are together, in the order that the encoder prefers
+//! them, which is NASM's.
+
+// Table indices fit in bytes, which the index checks.
+#![allow(clippy::cast_possible_truncation)]
+
+use crate::encode::is_sbyte;
+use crate::instruction::{Base, Instruction, Operand, Operation, Register};
+use crate::sim::{AF, CF, OF, PF, SF, STATUS, ZF};
+
+/// The MOD REG R/M byte of a form, or its fixed second byte.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ModRm {
+    None,
+    /// REG is a register operand.
+    Reg,
+    /// REG extends the opcode.
+    Op(u8),
+    /// REG is ignored, and encoded as 000.
+    Any,
+    /// A fixed second byte.
+    Byte(u8),
+}
+
+/// Where an operand is encoded, and which operands fit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Slot {
+    None,
+    /// The R/M field: a register or memory.
+    Rm,
+    /// The R/M field, which is memory. Registers decode, but have no defined effect or assembly.
+    Mem,
+    /// The REG field: a register.
+    Reg,
+    /// The REG field: a segment register.
+    Sreg,
+    /// A register in the low bits of the opcode.
+    OpReg,
+    /// A segment register in the SR bits of the opcode.
+    OpSreg,
+    /// AL or AX, implied by the opcode.
+    Acc,
+    /// AX, implied by the opcode.
+    Ax,
+    /// CL, the count of a shift or rotate.
+    Cl,
+    /// DX, the port of IN or OUT.
+    Dx,
+    /// The count of 1 of a shift or rotate.
+    One,
+    /// Immediate data: a byte, or a word if W = 1.
+    Data,
+    /// Immediate data: a byte, sign-extended to the operation's size. The encoder only uses it for words.
+    Signed,
+    /// Immediate data: a byte, like a port or an interrupt type.
+    Byte,
+    /// Immediate data: a word, like RET's addition to SP.
+    Word,
+    /// A direct address, without a MOD REG R/M byte.
+    Direct,
+    /// A byte displacement from the next instruction.
+    Short,
+    /// A word displacement from the next instruction.
+    Near,
+    /// A direct intersegment address: offset, then segment.
+    Far,
+}
+
+/// The clocks of a form, from the manual's timing table, not counting the effective address or words at odd
+/// addresses.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Clocks {
+    /// With a register or no R/M operand.
+    pub register: u32,
+    /// With a memory R/M operand.
+    pub memory: u32,
+    /// Additional clocks for words.
+    pub word: u32,
+    /// Additional clocks if a jump or loop is taken.
+    pub taken: u32,
+    /// The clocks for each repetition of a string instruction with REP, which replace the others with 9.
+    pub repeated: u32,
+    /// The clocks for each bit of a shift by CL.
+    pub shifted: u32,
+}
+
+/// An encoding of an operation, and its operands and clocks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Form {
+    pub operation: Operation,
+    /// The fixed bits of the first byte.
+    pub opcode: u8,
+    /// Which bits of the first byte are fixed.
+    pub mask: u8,
+    /// The bit of the W field, if any.
+    pub w: Option<u8>,
+    pub modrm: ModRm,
+    pub operands: [Slot; 2],
+    /// Whether the operation is on words, if there's no W field.
+    pub wide: bool,
+    /// Whether a jump or call is indirect intersegment.
+    pub far: bool,
+    /// Whether the decoder reads the form. Forms that it doesn't read are other orders of commutative operands.
+    pub decodes: bool,
+    pub clocks: Clocks,
+}
+
+impl Form {
+    /// A form from its pattern, on bytes, with its first operand's order of encoding.
+    const fn new(operation: Operation, pattern: &str, operands: [Slot; 2]) -> Self {
+        let bytes = pattern.as_bytes();
+        let (mut opcode, mut mask, mut w) = (0, 0, None);
+        let mut i = 0;
+        while i < 8 {
+            let bit = 7 - i as u8;
+            match bytes[i] {
+                b'0' => mask |= 1 << bit,
+                b'1' => {
+                    opcode |= 1 << bit;
+                    mask |= 1 << bit;
+                }
+                b'w' => w = Some(bit),
+                b'r' | b's' => {}
+                _ => panic!("a pattern's first byte is 0, 1, w, r or s"),
+            }
+            i += 1;
+        }
+        let modrm = match bytes.len() {
+            8 => ModRm::None,
+            17 => ModRm::Byte(bits(bytes, 9)),
+            20 if bytes[9] == b'm' && bytes[13] == b'r' && bytes[14] == b'e' => ModRm::Reg,
+            20 if bytes[9] == b'm' && bytes[13] == b'-' => ModRm::Any,
+            20 if bytes[9] == b'm' => ModRm::Op(bits(bytes, 13) >> 5),
+            _ => panic!("a pattern's second byte is \"mod ... r/m\" or 8 bits"),
+        };
+        Self {
+            operation,
+            opcode,
+            mask,
+            w,
+            modrm,
+            operands,
+            wide: false,
+            far: false,
+            decodes: true,
+            clocks: Clocks {
+                register: 0,
+                memory: 0,
+                word: 0,
+                taken: 0,
+                repeated: 0,
+                shifted: 0,
+            },
+        }
+    }
+
+    /// On words, without a W field.
+    const fn wide(mut self) -> Self {
+        self.wide = true;
+        self
+    }
+
+    /// Indirect intersegment.
+    const fn far(mut self) -> Self {
+        self.far = true;
+        self
+    }
+
+    /// Only encoded, because the operands are commutative.
+    const fn encoded(mut self) -> Self {
+        self.decodes = false;
+        self
+    }
+
+    /// The same clocks for registers and memory, or for no operands.
+    const fn clocks(mut self, clocks: u32) -> Self {
+        self.clocks.register = clocks;
+        self.clocks.memory = clocks;
+        self
+    }
+
+    const fn memory(mut self, clocks: u32) -> Self {
+        self.clocks.memory = clocks;
+        self
+    }
+
+    const fn word(mut self, clocks: u32) -> Self {
+        self.clocks.word = clocks;
+        self
+    }
+
+    const fn taken(mut self, clocks: u32) -> Self {
+        self.clocks.taken = clocks;
+        self
+    }
+
+    const fn repeated(mut self, clocks: u32) -> Self {
+        self.clocks.repeated = clocks;
+        self
+    }
+
+    const fn shifted(mut self, clocks: u32) -> Self {
+        self.clocks.shifted = clocks;
+        self
+    }
+
+    /// Whether the operation is on words, given the instruction's W field.
+    #[must_use]
+    pub const fn is_wide(&self, w: bool) -> bool {
+        if self.w.is_some() {
+            w
+        } else {
+            self.wide
+        }
+    }
+
+    /// Whether the form encodes an instruction, not checking that its values fit. A sign-extended byte doesn't fit
+    /// a word immediate if `strict`.
+    #[must_use]
+    pub fn fits(&self, instruction: &Instruction, strict: bool) -> bool {
+        let wide = self.is_wide(instruction.wide);
+        // A direct intersegment address is far with or without FAR.
+        (self.far == instruction.far || self.operands[0] == Slot::Far)
+            && self
+                .operands
+                .iter()
+                .zip(instruction.operands)
+                .all(|(slot, operand)| slot.fits(operand, wide, strict))
+    }
+}
+
+impl Slot {
+    fn fits(self, operand: Operand, wide: bool, strict: bool) -> bool {
+        let general = |register: Register| !register.is_segment() && register.is_wide() == wide;
+        match (self, operand) {
+            (Self::None, Operand::None)
+            | (Self::Rm | Self::Mem, Operand::Memory(_))
+            | (Self::Data | Self::Byte | Self::Word, Operand::Immediate(_))
+            | (Self::Short | Self::Near, Operand::Relative(_))
+            | (Self::Far, Operand::Far(..))
+            | (Self::Ax, Operand::Register(Register::Ax))
+            | (Self::Cl, Operand::Register(Register::Cl))
+            | (Self::Dx, Operand::Register(Register::Dx))
+            | (Self::One, Operand::Immediate(1)) => true,
+            (Self::Rm | Self::Mem | Self::Reg | Self::OpReg, Operand::Register(register)) => general(register),
+            (Self::Sreg | Self::OpSreg, Operand::Register(register)) => register.is_segment(),
+            (Self::Acc, Operand::Register(register)) => general(register) && register.field() == 0,
+            (Self::Signed, Operand::Immediate(value)) => wide && !strict && is_sbyte(i32::from(value as u16 as i16)),
+            (Self::Direct, Operand::Memory(memory)) => memory.base == Base::Direct,
+            _ => false,
+        }
+    }
+}
+
+/// A byte from a pattern's bits, starting at an index.
+const fn bits(bytes: &[u8], start: usize) -> u8 {
+    let mut value = 0;
+    let mut i = start;
+    while i < bytes.len() && (bytes[i] == b'0' || bytes[i] == b'1') {
+        value = value << 1 | (bytes[i] - b'0');
+        i += 1;
+    }
+    value << (8 - (i - start))
+}
+
+/// The flags that an operation reads, and that it defines and leaves undefined, when it changes any.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FlagEffects {
+    pub read: u16,
+    pub defined: u16,
+    pub undefined: u16,
+}
+
+/// The flags of an operation.
+#[must_use]
+pub const fn flags(operation: Operation) -> FlagEffects {
+    use Operation as Op;
+
+    let (read, defined, undefined) = match operation {
+        Op::Add | Op::Sub | Op::Cmp | Op::Neg | Op::Cmps | Op::Scas | Op::Popf | Op::Iret => (0, STATUS, 0),
+        Op::Adc | Op::Sbb => (CF, STATUS, 0),
+        Op::Inc | Op::Dec => (0, STATUS & !CF, 0),
+        Op::And | Op::Or | Op::Xor | Op::Test => (0, STATUS & !AF, AF),
+        Op::Mul | Op::Imul => (0, CF | OF, SF | ZF | AF | PF),
+        Op::Div | Op::Idiv => (0, 0, STATUS),
+        // OF is only defined by shifts and rotates of 1.
+        Op::Shl | Op::Shr | Op::Sar => (0, CF | SF | ZF | PF | OF, AF),
+        Op::Rol | Op::Ror => (0, CF | OF, 0),
+        Op::Rcl | Op::Rcr => (CF, CF | OF, 0),
+        Op::Clc | Op::Stc => (0, CF, 0),
+        Op::Cmc => (CF, CF, 0),
+        Op::Sahf => (0, SF | ZF | AF | PF | CF, 0),
+        Op::Jo | Op::Jno | Op::Into => (OF, 0, 0),
+        Op::Jb | Op::Jnb => (CF, 0, 0),
+        Op::Je | Op::Jne | Op::Loopz | Op::Loopnz => (ZF, 0, 0),
+        Op::Jbe | Op::Jnbe => (CF | ZF, 0, 0),
+        Op::Js | Op::Jns => (SF, 0, 0),
+        Op::Jp | Op::Jnp => (PF, 0, 0),
+        Op::Jl | Op::Jnl => (SF | OF, 0, 0),
+        Op::Jle | Op::Jnle => (SF | OF | ZF, 0, 0),
+        _ => (0, 0, 0),
+    };
+    FlagEffects {
+        read,
+        defined,
+        undefined,
+    }
+}
+
+// The table names operations like the manual does.
+#[allow(clippy::enum_glob_use)]
+use Operation::*;
+use Slot::{
+    Acc, Ax, Byte, Cl, Data, Direct, Dx, Far, Mem, Near, One, OpReg, OpSreg, Reg, Rm, Short, Signed, Sreg, Word,
+};
+
+/// The forms of ADD OR ADC SBB AND SUB XOR CMP, whose field is `op`. CMP doesn't write memory.
+macro_rules! binary {
+    ($operation:ident, $op:literal, $write:literal) => {
+        [
+            Form::new($operation, concat!("1000001w mod ", $op, " r/m"), [Rm, Signed])
+                .clocks(4)
+                .memory($write + 1),
+            Form::new($operation, concat!("00", $op, "10w"), [Acc, Data]).clocks(4),
+            Form::new($operation, concat!("1000000w mod ", $op, " r/m"), [Rm, Data])
+                .clocks(4)
+                .memory($write + 1),
+            Form::new($operation, concat!("00", $op, "00w mod reg r/m"), [Rm, Reg])
+                .clocks(3)
+                .memory($write),
+            Form::new($operation, concat!("00", $op, "01w mod reg r/m"), [Reg, Rm])
+                .clocks(3)
+                .memory(9),
+        ]
+    };
+}
+
+/// The forms of a shift or rotate, whose field is `op`.
+macro_rules! shift {
+    ($operation:ident, $op:literal) => {
+        [
+            Form::new($operation, concat!("1101000w mod ", $op, " r/m"), [Rm, One])
+                .clocks(2)
+                .memory(15),
+            Form::new($operation, concat!("1101001w mod ", $op, " r/m"), [Rm, Cl])
+                .clocks(8)
+                .memory(20)
+                .shifted(4),
+        ]
+    };
+}
+
+/// The form of a short jump, which takes 4 clocks, or 16 if it's taken.
+macro_rules! jump {
+    ($operation:ident, $pattern:literal) => {
+        [Form::new($operation, $pattern, [Short, Slot::None]).clocks(4).taken(12)]
+    };
+}
+
+/// The form of an operation without operands, or with implied operands.
+macro_rules! fixed {
+    ($operation:ident, $pattern:literal, $clocks:literal) => {
+        [Form::new($operation, $pattern, [Slot::None; 2]).clocks($clocks)]
+    };
+}
+
+const N: Slot = Slot::None;
+
+/// Every form of every operation.
+pub const FORMS: &[Form] = &concat([
+    &[
+        Form::new(Mov, "1011wrrr", [OpReg, Data]).clocks(4),
+        // 10 clocks, less the direct address's 6, which these forms don't calculate.
+        Form::new(Mov, "1010000w", [Acc, Direct]).clocks(4),
+        Form::new(Mov, "1010001w", [Direct, Acc]).clocks(4),
+        Form::new(Mov, "10001110 mod reg r/m", [Sreg, Rm])
+            .wide()
+            .clocks(2)
+            .memory(8),
+        Form::new(Mov, "10001100 mod reg r/m", [Rm, Sreg])
+            .wide()
+            .clocks(2)
+            .memory(9),
+        Form::new(Mov, "1100011w mod --- r/m", [Rm, Data]).clocks(4).memory(10),
+        Form::new(Mov, "1000100w mod reg r/m", [Rm, Reg]).clocks(2).memory(9),
+        Form::new(Mov, "1000101w mod reg r/m", [Reg, Rm]).clocks(2).memory(8),
+        Form::new(Push, "000ss110", [OpSreg, N]).wide().clocks(10),
+        Form::new(Push, "01010rrr", [OpReg, N]).wide().clocks(11),
+        Form::new(Push, "11111111 mod 110 r/m", [Rm, N])
+            .wide()
+            .clocks(11)
+            .memory(16),
+        Form::new(Pop, "000ss111", [OpSreg, N]).wide().clocks(8),
+        Form::new(Pop, "01011rrr", [OpReg, N]).wide().clocks(8),
+        Form::new(Pop, "10001111 mod --- r/m", [Rm, N])
+            .wide()
+            .clocks(8)
+            .memory(17),
+        Form::new(Xchg, "10010rrr", [Ax, OpReg]).wide().clocks(3),
+        Form::new(Xchg, "10010rrr", [OpReg, Ax]).wide().encoded().clocks(3),
+        Form::new(Xchg, "1000011w mod reg r/m", [Reg, Rm]).clocks(4).memory(17),
+        Form::new(Xchg, "1000011w mod reg r/m", [Rm, Reg])
+            .encoded()
+            .clocks(4)
+            .memory(17),
+        Form::new(In, "1110010w", [Acc, Byte]).clocks(10),
+        Form::new(In, "1110110w", [Acc, Dx]).clocks(8),
+        Form::new(Out, "1110011w", [Byte, Acc]).clocks(10),
+        Form::new(Out, "1110111w", [Dx, Acc]).clocks(8),
+        Form::new(Lea, "10001101 mod reg r/m", [Reg, Mem]).wide().clocks(2),
+        Form::new(Lds, "11000101 mod reg r/m", [Reg, Mem]).wide().clocks(16),
+        Form::new(Les, "11000100 mod reg r/m", [Reg, Mem]).wide().clocks(16),
+    ],
+    &fixed!(Xlat, "11010111", 11),
+    &fixed!(Lahf, "10011111", 4),
+    &fixed!(Sahf, "10011110", 4),
+    &fixed!(Pushf, "10011100", 10),
+    &fixed!(Popf, "10011101", 8),
+    &binary!(Add, "000", 16),
+    &binary!(Or, "001", 16),
+    &binary!(Adc, "010", 16),
+    &binary!(Sbb, "011", 16),
+    &binary!(And, "100", 16),
+    &binary!(Sub, "101", 16),
+    &binary!(Xor, "110", 16),
+    &binary!(Cmp, "111", 9),
+    &[
+        Form::new(Inc, "01000rrr", [OpReg, N]).wide().clocks(2),
+        Form::new(Inc, "1111111w mod 000 r/m", [Rm, N]).clocks(3).memory(15),
+        Form::new(Dec, "01001rrr", [OpReg, N]).wide().clocks(2),
+        Form::new(Dec, "1111111w mod 001 r/m", [Rm, N]).clocks(3).memory(15),
+        Form::new(Not, "1111011w mod 010 r/m", [Rm, N]).clocks(3).memory(16),
+        Form::new(Neg, "1111011w mod 011 r/m", [Rm, N]).clocks(3).memory(16),
+        // The least clocks in the manual's ranges, which depend on the operands.
+        Form::new(Mul, "1111011w mod 100 r/m", [Rm, N])
+            .clocks(70)
+            .memory(76)
+            .word(48),
+        Form::new(Imul, "1111011w mod 101 r/m", [Rm, N])
+            .clocks(80)
+            .memory(86)
+            .word(48),
+        Form::new(Div, "1111011w mod 110 r/m", [Rm, N])
+            .clocks(80)
+            .memory(86)
+            .word(64),
+        Form::new(Idiv, "1111011w mod 111 r/m", [Rm, N])
+            .clocks(101)
+            .memory(107)
+            .word(64),
+        Form::new(Test, "1010100w", [Acc, Data]).clocks(4),
+        Form::new(Test, "1111011w mod 000 r/m", [Rm, Data]).clocks(5).memory(11),
+        Form::new(Test, "1000010w mod reg r/m", [Rm, Reg]).clocks(3).memory(9),
+        Form::new(Test, "1000010w mod reg r/m", [Reg, Rm])
+            .encoded()
+            .clocks(3)
+            .memory(9),
+    ],
+    &fixed!(Aaa, "00110111", 4),
+    &fixed!(Daa, "00100111", 4),
+    &fixed!(Aas, "00111111", 4),
+    &fixed!(Das, "00101111", 4),
+    &fixed!(Aam, "11010100 00001010", 83),
+    &fixed!(Aad, "11010101 00001010", 60),
+    &fixed!(Cbw, "10011000", 2),
+    &fixed!(Cwd, "10011001", 5),
+    &shift!(Rol, "000"),
+    &shift!(Ror, "001"),
+    &shift!(Rcl, "010"),
+    &shift!(Rcr, "011"),
+    &shift!(Shl, "100"),
+    &shift!(Shr, "101"),
+    &shift!(Sar, "111"),
+    &[
+        Form::new(Movs, "1010010w", [N; 2]).clocks(18).repeated(17),
+        Form::new(Cmps, "1010011w", [N; 2]).clocks(22).repeated(22),
+        Form::new(Stos, "1010101w", [N; 2]).clocks(11).repeated(10),
+        Form::new(Lods, "1010110w", [N; 2]).clocks(12).repeated(13),
+        Form::new(Scas, "1010111w", [N; 2]).clocks(15).repeated(15),
+        Form::new(Call, "11101000", [Near, N]).wide().clocks(19),
+        Form::new(Call, "10011010", [Far, N]).wide().clocks(28),
+        Form::new(Call, "11111111 mod 010 r/m", [Rm, N])
+            .wide()
+            .clocks(16)
+            .memory(21),
+        Form::new(Call, "11111111 mod 011 r/m", [Mem, N])
+            .wide()
+            .far()
+            .clocks(37),
+        // A JMP that isn't wide is short.
+        Form::new(Jmp, "11101011", [Short, N]).clocks(15),
+        Form::new(Jmp, "11101001", [Near, N]).wide().clocks(15),
+        Form::new(Jmp, "11101010", [Far, N]).wide().clocks(15),
+        Form::new(Jmp, "11111111 mod 100 r/m", [Rm, N])
+            .wide()
+            .clocks(11)
+            .memory(18),
+        Form::new(Jmp, "11111111 mod 101 r/m", [Mem, N]).wide().far().clocks(24),
+        Form::new(Ret, "11000011", [N; 2]).clocks(8),
+        Form::new(Ret, "11000010", [Word, N]).wide().clocks(12),
+        Form::new(Retf, "11001011", [N; 2]).clocks(18),
+        Form::new(Retf, "11001010", [Word, N]).wide().clocks(17),
+    ],
+    &jump!(Jo, "01110000"),
+    &jump!(Jno, "01110001"),
+    &jump!(Jb, "01110010"),
+    &jump!(Jnb, "01110011"),
+    &jump!(Je, "01110100"),
+    &jump!(Jne, "01110101"),
+    &jump!(Jbe, "01110110"),
+    &jump!(Jnbe, "01110111"),
+    &jump!(Js, "01111000"),
+    &jump!(Jns, "01111001"),
+    &jump!(Jp, "01111010"),
+    &jump!(Jnp, "01111011"),
+    &jump!(Jl, "01111100"),
+    &jump!(Jnl, "01111101"),
+    &jump!(Jle, "01111110"),
+    &jump!(Jnle, "01111111"),
+    &[
+        Form::new(Loopnz, "11100000", [Short, N]).clocks(5).taken(14),
+        Form::new(Loopz, "11100001", [Short, N]).clocks(6).taken(12),
+        Form::new(Loop, "11100010", [Short, N]).clocks(5).taken(12),
+        Form::new(Jcxz, "11100011", [Short, N]).clocks(6).taken(12),
+        Form::new(Int, "11001101", [Byte, N]).clocks(51),
+    ],
+    &fixed!(Int3, "11001100", 52),
+    &[Form::new(Into, "11001110", [N; 2]).clocks(4).taken(49)],
+    &fixed!(Iret, "11001111", 24),
+    &fixed!(Clc, "11111000", 2),
+    &fixed!(Cmc, "11110101", 2),
+    &fixed!(Stc, "11111001", 2),
+    &fixed!(Cld, "11111100", 2),
+    &fixed!(Std, "11111101", 2),
+    &fixed!(Cli, "11111010", 2),
+    &fixed!(Sti, "11111011", 2),
+    &fixed!(Hlt, "11110100", 2),
+    &fixed!(Wait, "10011011", 3),
+]);
+
+/// The number of forms in the table.
+const COUNT: usize = 157;
+
+/// Concatenate groups of forms.
+const fn concat<const G: usize>(groups: [&[Form]; G]) -> [Form; COUNT] {
+    let mut forms = [Form::new(Hlt, "00000000", [N; 2]); COUNT];
+    let mut length = 0;
+    let mut group = 0;
+    while group < G {
+        let mut i = 0;
+        while i < groups[group].len() {
+            assert!(length < COUNT, "COUNT is the number of forms");
+            forms[length] = groups[group][i];
+            length += 1;
+            i += 1;
+        }
+        group += 1;
+    }
+    assert!(length == COUNT, "COUNT is the number of forms");
+    forms
+}
+
+// The most forms that begin with the same byte, and of the same operation.
+const CANDIDATES: usize = 8;
+
+/// For each first byte, the forms that the decoder reads, as indices in the table, ending at 255.
+static DECODING: [[u8; CANDIDATES]; 256] = {
+    let mut index = [[u8::MAX; CANDIDATES]; 256];
+    let mut byte = 0;
+    while byte < 256 {
+        let mut count = 0;
+        let mut i = 0;
+        while i < FORMS.len() {
+            let form = &FORMS[i];
+            if form.decodes && byte as u8 & form.mask == form.opcode {
+                assert!(count < CANDIDATES, "too many forms begin with a byte");
+                index[byte][count] = i as u8;
+                count += 1;
+            }
+            i += 1;
+        }
+        byte += 1;
+    }
+    index
+};
+
+/// For each operation, the range of its forms in the table.
+static ENCODING: [(u8, u8); Operation::ALL.len()] = {
+    let mut index = [(0, 0); Operation::ALL.len()];
+    let mut i = 0;
+    while i < FORMS.len() {
+        let operation = FORMS[i].operation as usize;
+        let (start, end) = index[operation];
+        if start == end {
+            index[operation] = (i as u8, i as u8 + 1);
+        } else {
+            assert!(end as usize == i, "the forms of an operation are together");
+            index[operation].1 += 1;
+        }
+        i += 1;
+    }
+    index
+};
+
+/// The forms that the decoder reads that begin with a byte, after any prefixes.
+pub fn decoding(byte: u8) -> impl Iterator<Item = &'static Form> {
+    DECODING[usize::from(byte)]
+        .iter()
+        .take_while(|&&i| i != u8::MAX)
+        .map(|&i| &FORMS[usize::from(i)])
+}
+
+/// The forms of an operation, in the order that the encoder prefers them.
+#[must_use]
+pub fn forms(operation: Operation) -> &'static [Form] {
+    let (start, end) = ENCODING[operation as usize];
+    &FORMS[usize::from(start)..usize::from(end)]
+}
+
+/// The form that encodes an instruction. Forms without a W field are for one size, which is preferred, like a short
+/// JMP for a JMP that isn't wide.
+#[must_use]
+pub fn find(instruction: &Instruction, strict: bool) -> Option<&'static Form> {
+    let forms = forms(instruction.operation);
+    let fits = |form: &&Form| form.fits(instruction, strict);
+    forms
+        .iter()
+        .filter(|form| form.w.is_some() || form.wide == instruction.wide)
+        .find(fits)
+        .or_else(|| forms.iter().find(fits))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patterns() {
+        let add = &forms(Add)[0];
+        assert_eq!((add.opcode, add.mask, add.w), (0b10000010, 0b11111110, Some(0)));
+        assert_eq!(add.modrm, ModRm::Op(0));
+        let mov = &forms(Mov)[0];
+        assert_eq!((mov.opcode, mov.mask, mov.w), (0b10110000, 0b11110000, Some(3)));
+        assert_eq!(forms(Aam)[0].modrm, ModRm::Byte(0b00001010));
+        assert_eq!(forms(Pop)[2].modrm, ModRm::Any);
+        assert_eq!(forms(Jmp).len(), 5);
+    }
+
+    /// Each byte, with its MOD REG R/M byte or fixed second byte, is at most one form.
+    #[test]
+    fn unambiguous() {
+        for byte in 0..=u8::MAX {
+            for reg in 0..8 {
+                let forms = decoding(byte)
+                    .filter(|form| match form.modrm {
+                        ModRm::Op(op) => op == reg,
+                        ModRm::Byte(second) => second >> 3 & 0b111 == reg,
+                        _ => true,
+                    })
+                    .count();
+                assert!(forms <= 1, "{byte:08b} {reg:03b}");
+            }
+        }
+    }
+
+    #[test]
+    fn preferences() {
+        let jmp = |wide| Instruction::new(Jmp, [Operand::Relative(0), Operand::None], wide);
+        assert_eq!(find(&jmp(false), false).unwrap().operands[0], Short);
+        assert_eq!(find(&jmp(true), false).unwrap().operands[0], Near);
+        let add = Instruction::new(Add, [Operand::Register(Register::Ax), Operand::Immediate(5)], true);
+        assert_eq!(find(&add, false).unwrap().operands[1], Signed);
+        assert_eq!(find(&add, true).unwrap().operands[0], Acc);
+    }
+}
@@ -0,0 +1,120 @@
+//! Text that's written without allocating for each instruction: a line on the stack, or many lines in an arena.
+//!
+//! Mnemonics, registers and keywords are `&'static str`, which are copied into the line, so only immediates and
+//! displacements are formatted.
+
+use std::fmt;
+use std::io::{self, Write};
+
+/// The bytes of a line, which fit the longest trace of an instruction.
+pub const CAPACITY: usize = 512;
+
+/// A line of text, written with `write!` and then written out as bytes. Writes past its capacity fail.
+pub struct Line {
+    bytes: [u8; CAPACITY],
+    len: usize,
+}
+
+impl Line {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            bytes: [0; CAPACITY],
+            len: 0,
+        }
+    }
+
+    pub const fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    #[must_use]
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+impl Default for Line {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Write for Line {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let count = buf.len().min(CAPACITY - self.len);
+        self.bytes[self.len..self.len + count].copy_from_slice(&buf[..count]);
+        self.len += count;
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Lines of text in one buffer, which is cleared and reused, so that its allocations last for many lines.
+#[derive(Default)]
+pub struct Arena {
+    text: String,
+    // The end of each line in the text.
+    ends: Vec<usize>,
+}
+
+impl Arena {
+    pub fn clear(&mut self) {
+        self.text.clear();
+        self.ends.clear();
+    }
+
+    /// Add a line, and return its index.
+    pub fn push(&mut self, arguments: fmt::Arguments) -> usize {
+        // Writing to a string doesn't fail.
+        let _ = fmt::Write::write_fmt(&mut self.text, arguments);
+        self.ends.push(self.text.len());
+        self.ends.len() - 1
+    }
+
+    /// The lines, one after another.
+    #[must_use]
+    pub fn as_str(&self) -> &str {
+        &self.text
+    }
+
+    #[must_use]
+    pub fn get(&self, index: usize) -> &str {
+        let start = index.checked_sub(1).map_or(0, |previous| self.ends[previous]);
+        &self.text[start..self.ends[index]]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::instruction::Register;
+
+    #[test]
+    fn line() {
+        let mut line = Line::new();
+        write!(line, "mov {}, {}", Register::Ax, -5).unwrap();
+        assert_eq!(line.as_bytes(), b"mov ax, -5");
+
+        line.clear();
+        assert!(write!(line, "{:1$}", "", CAPACITY + 1).is_err());
+        assert_eq!(line.as_bytes().len(), CAPACITY);
+    }
+
+    #[test]
+    fn arena() {
+        let mut arena = Arena::default();
+        let first = arena.push(format_args!("label0:"));
+        let second = arena.push(format_args!("inc {}", Register::Bx));
+        assert_eq!((arena.get(first), arena.get(second)), ("label0:", "inc bx"));
+        assert_eq!(arena.as_str(), "label0:inc bx");
+
+        arena.clear();
+        let first = arena.push(format_args!("hlt"));
+        assert_eq!(arena.get(first), "hlt");
+    }
+}
@@ -0,0 +1,185 @@
+//! Write and compare simulation transcripts in the format of the course's `.txt` listings.
+//!
+//! A transcript is a header naming the program, a line per executed instruction, and the final registers, with
+//! Windows line endings, as the reference simulator writes on Windows. The transcripts of the listings before IP
+//! was simulated leave out IP.
+
+use std::fmt;
+use std::io::{self, Write};
+
+/// Write the header of a transcript. The reference simulator names the program after its file in the `test`
+/// directory, without the extension.
+///
+/// # Errors
+///
+/// If writing fails.
+pub fn write_header<W: Write>(out: &mut W, name: &str) -> io::Result<()> {
+    writeln!(out, "--- test\\{name} execution ---")
+}
+
+/// A writer that ends lines with "\r\n" instead of "\n".
+pub struct Crlf<W>(pub W);
+
+impl<W: Write> Write for Crlf<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut lines = buf.split(|&byte| byte == b'\n');
+        if let Some(first) = lines.next() {
+            self.0.write_all(first)?;
+        }
+        for line in lines {
+            self.0.write_all(b"\r\n")?;
+            self.0.write_all(line)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// The first line where a simulation's transcript differs from the reference, numbered from 1.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mismatch {
+    pub line: usize,
+    /// The reference's line, or `None` if the reference ended.
+    pub expected: Option<String>,
+    /// The simulation's line, or `None` if the simulation ended.
+    pub actual: Option<String>,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "transcript differs at line {}:", self.line)?;
+        match &self.expected {
+            Some(line) => writeln!(f, "  expected: {line}")?,
+            None => writeln!(f, "  expected: (end of reference)")?,
+        }
+        match &self.actual {
+            Some(line) => write!(f, "  actual:   {line}"),
+            None => write!(f, "  actual:   (end of simulation)"),
+        }
+    }
+}
+
+/// A reference transcript, compared with a simulation's as it runs. The header isn't compared, so that the program
+/// can be at any path.
+pub struct Reference {
+    lines: Vec<String>,
+    /// The index of the next line to compare.
+    next: usize,
+    /// Whether the reference has IP.
+    pub ip: bool,
+}
+
+impl Reference {
+    #[must_use]
+    pub fn new(text: &str) -> Self {
+        let lines: Vec<String> = text
+            .lines()
+            .map(|line| line.trim_end_matches('\r').to_string())
+            .collect();
+        let next = usize::from(lines.first().is_some_and(|line| line.starts_with("--- ")));
+        let ip = lines
+            .iter()
+            .any(|line| line.contains(" ip:") || line.starts_with("      ip: "));
+        Self { lines, next, ip }
+    }
+
+    /// Compare the next lines of the simulation's transcript.
+    ///
+    /// # Errors
+    ///
+    /// If a line differs, or the reference ended.
+    pub fn compare(&mut self, output: &[u8]) -> Result<(), Mismatch> {
+        for actual in String::from_utf8_lossy(output).lines() {
+            let expected = self.lines.get(self.next);
+            if expected.map(String::as_str) != Some(actual) {
+                return Err(Mismatch {
+                    line: self.next + 1,
+                    expected: expected.cloned(),
+                    actual: Some(actual.to_string()),
+                });
+            }
+            self.next += 1;
+        }
+        Ok(())
+    }
+
+    /// Check that the simulation's transcript ended with the reference.
+    ///
+    /// # Errors
+    ///
+    /// If the reference continues, other than with blank lines.
+    pub fn finish(&self) -> Result<(), Mismatch> {
+        let rest = &self.lines[self.next.min(self.lines.len())..];
+        rest.iter().position(|line| !line.is_empty()).map_or(Ok(()), |offset| {
+            Err(Mismatch {
+                line: self.next + offset + 1,
+                expected: Some(rest[offset].clone()),
+                actual: None,
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use crate::sim::{self, Machine};
+
+    /// Simulate a listing and write its transcript.
+    fn transcript(path: &str, ip: bool) -> Vec<u8> {
+        let program = fs::read(path).unwrap();
+        let mut machine = Machine::new();
+        machine.load(0, &program);
+        let mut out = Crlf(vec![]);
+        let name = path.rsplit('/').next().unwrap();
+        write_header(&mut out, name).unwrap();
+        while machine.instruction_address() < program.len() {
+            let before = machine.registers;
+            let instruction = machine.step().unwrap();
+            sim::write_trace(&mut out, &instruction, &before, &machine.registers, ip).unwrap();
+        }
+        writeln!(out).unwrap();
+        sim::write_registers(&mut out, &machine.registers, ip).unwrap();
+        writeln!(out).unwrap();
+        out.0
+    }
+
+    /// Check that a listing's transcript matches its reference byte for byte.
+    fn check(path: &str) {
+        let expected = fs::read_to_string(format!("{path}.txt")).unwrap();
+        let ip = Reference::new(&expected).ip;
+        assert_eq!(String::from_utf8(transcript(path, ip)).unwrap(), expected);
+    }
+
+    include!(concat!(env!("OUT_DIR"), "/transcript.include"));
+
+    #[test]
+    fn compare() {
+        let text = "--- test\\a execution ---\r\nmov cx, 1 ; cx:0x0->0x1 \r\n\r\nFinal registers:\r\n      cx: 0x0001 (1)\r\n\r\n";
+        let mut reference = Reference::new(text);
+        assert!(!reference.ip);
+        assert_eq!(reference.compare(b"mov cx, 1 ; cx:0x0->0x1 \n\n"), Ok(()));
+        assert_eq!(reference.finish().unwrap_err().line, 4);
+        assert_eq!(
+            reference.compare(b"Final registers:\n      cx: 0x0002 (2)\n"),
+            Err(Mismatch {
+                line: 5,
+                expected: Some("      cx: 0x0001 (1)".to_string()),
+                actual: Some("      cx: 0x0002 (2)".to_string()),
+            })
+        );
+
+        let mut reference = Reference::new(text);
+        reference
+            .compare(b"mov cx, 1 ; cx:0x0->0x1 \n\nFinal registers:\n      cx: 0x0001 (1)\n")
+            .unwrap();
+        assert_eq!(reference.finish(), Ok(()));
+        assert_eq!(reference.compare(b"\nhlt ;\n").unwrap_err().expected, None);
+    }
+}
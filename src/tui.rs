@@ -0,0 +1,323 @@
+//! A terminal user interface for the debugger: disassembly around CS:IP, registers, calls, memory, and the output
+//! of commands, updated after every command.
+//!
+//! Commands are the debugger's, typed at the bottom. Enter on an empty line repeats the last command. F5 continues,
+//! F10 steps over calls, F11 steps, Shift-F11 runs until the call returns, and Esc or Ctrl-C quits.
+
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use homework::debug::{self, Command, Control, Debugger};
+use homework::instruction::Register;
+use homework::ports::PortLog;
+use homework::sim::physical;
+use homework::text::Arena;
+use ratatui::backend::Backend;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Modifier, Style};
+use ratatui::text::{Line, Text};
+use ratatui::widgets::{Block, Paragraph};
+use ratatui::{Frame, Terminal};
+
+// The most lines of command output that are kept.
+const LOG_LINES: usize = 1000;
+const BYTES_PER_LINE: usize = 16;
+// The most instructions decoded from the start of the program to find the instructions before CS:IP.
+const LISTING_LIMIT: usize = 1 << 14;
+
+/// A buffer that the machine's port log and the interface share.
+#[derive(Clone, Default)]
+struct Shared(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Shared {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct App {
+    debugger: Debugger,
+    /// The command being typed.
+    input: String,
+    /// The last command that was run.
+    last: String,
+    /// The output of commands, and the program's output.
+    log: Vec<String>,
+    /// The port accesses that haven't been logged yet.
+    ports: Shared,
+    /// The physical address at the top of the memory pane.
+    memory: usize,
+    /// The text of the code pane, which is reused for each frame.
+    code: Arena,
+}
+
+/// Run the interface until the user quits.
+///
+/// # Errors
+///
+/// If the terminal fails.
+pub fn run(mut debugger: Debugger) -> io::Result<()> {
+    let ports = Shared::default();
+    // Port accesses would draw over the interface on standard error.
+    debugger.machine.ports = Box::new(PortLog::new(ports.clone()));
+    let registers = &debugger.machine.registers;
+    let memory = physical(registers.get(Register::Ds), 0);
+    let mut app = App {
+        debugger,
+        input: String::new(),
+        last: String::new(),
+        log: vec!["Type \"help\" for commands.".to_string()],
+        ports,
+        memory,
+        code: Arena::default(),
+    };
+    ratatui::run(|terminal| app.run(terminal))
+}
+
+impl App {
+    fn run<B: Backend>(&mut self, terminal: &mut Terminal<B>) -> io::Result<()>
+    where
+        io::Error: From<B::Error>,
+    {
+        loop {
+            terminal.draw(|frame| self.draw(frame))?;
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            let command = match key.code {
+                KeyCode::Esc => return Ok(()),
+                KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return Ok(()),
+                KeyCode::Char(character) => {
+                    self.input.push(character);
+                    continue;
+                }
+                KeyCode::Backspace => {
+                    self.input.pop();
+                    continue;
+                }
+                KeyCode::F(5) => "continue".to_string(),
+                KeyCode::F(10) => "next".to_string(),
+                KeyCode::F(11) if key.modifiers.contains(KeyModifiers::SHIFT) => "finish".to_string(),
+                KeyCode::F(11) => "step".to_string(),
+                KeyCode::Enter => std::mem::take(&mut self.input),
+                _ => continue,
+            };
+            if self.submit(&command)? == Control::Quit {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Run a command, or the last command if it is empty, and log its output.
+    fn submit(&mut self, line: &str) -> io::Result<Control> {
+        if !line.trim().is_empty() {
+            line.clone_into(&mut self.last);
+        }
+        if self.last.is_empty() {
+            return Ok(Control::Continue);
+        }
+        let line = self.last.clone();
+        self.log.push(format!("(sim) {line}"));
+        let mut out = vec![];
+        let control = match line.parse::<Command>() {
+            // The memory pane follows the last dump.
+            Ok(Command::Memory(address, _)) => {
+                self.memory = address.evaluate(&self.debugger.machine.registers);
+                Control::Continue
+            }
+            Ok(command) => self.debugger.run(&command, &mut out)?,
+            Err(_) => self.debugger.command(&line, &mut out)?,
+        };
+        let mut ports = self.ports.0.borrow_mut();
+        for text in [&out, &*ports] {
+            self.log
+                .extend(String::from_utf8_lossy(text).lines().map(str::to_string));
+        }
+        ports.clear();
+        let excess = self.log.len().saturating_sub(LOG_LINES);
+        self.log.drain(..excess);
+        Ok(control)
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [top, memory, log, input] = Layout::vertical([
+            Constraint::Min(8),
+            Constraint::Length(10),
+            Constraint::Length(8),
+            Constraint::Length(3),
+        ])
+        .areas(frame.area());
+        let [code, side] = Layout::horizontal([Constraint::Min(40), Constraint::Length(46)]).areas(top);
+        let [registers, calls] = Layout::vertical([Constraint::Length(6), Constraint::Min(3)]).areas(side);
+
+        let height = usize::from(code.height.saturating_sub(2));
+        frame.render_widget(
+            Paragraph::new(listing(&self.debugger, &mut self.code, height)).block(Block::bordered().title("Code")),
+            code,
+        );
+        let machine = &self.debugger.machine;
+        let text = output(|out| debug::write_registers(out, &machine.registers, machine.cycles));
+        frame.render_widget(
+            Paragraph::new(text).block(Block::bordered().title("Registers")),
+            registers,
+        );
+        let text = output(|out| self.debugger.backtrace(out));
+        frame.render_widget(Paragraph::new(text).block(Block::bordered().title("Calls")), calls);
+
+        let length = usize::from(memory.height.saturating_sub(2)) * BYTES_PER_LINE;
+        let text = output(|out| self.debugger.dump(self.memory, length, out));
+        frame.render_widget(Paragraph::new(text).block(Block::bordered().title("Memory")), memory);
+
+        let lines = usize::from(log.height.saturating_sub(2));
+        let start = self.log.len().saturating_sub(lines);
+        let text: Vec<_> = self.log[start..].iter().map(|line| Line::from(line.as_str())).collect();
+        frame.render_widget(Paragraph::new(text).block(Block::bordered().title("Output")), log);
+
+        frame.render_widget(
+            Paragraph::new(format!("(sim) {}", self.input)).block(Block::bordered().title("Command")),
+            input,
+        );
+        let column = u16::try_from(self.input.len() + 7).unwrap_or(u16::MAX);
+        frame.set_cursor_position((input.x + column.min(input.width.saturating_sub(2)), input.y + 1));
+    }
+}
+
+/// Instructions around CS:IP, with labels and breakpoints, written in the arena.
+fn listing<'a>(debugger: &Debugger, arena: &'a mut Arena, height: usize) -> Vec<Line<'a>> {
+    let machine = &debugger.machine;
+    let (cs, ip) = (machine.registers.get(Register::Cs), machine.registers.ip);
+    let base = physical(cs, 0);
+    let program = &debugger.program;
+
+    // Instructions can't be decoded backward, so decode from the start of the program, unless CS:IP isn't in it.
+    let mut offsets = vec![];
+    if program.contains(&machine.instruction_address()) && program.start >= base {
+        if let Ok(mut offset) = u16::try_from(program.start - base) {
+            while physical(cs, offset) < program.end && offsets.len() < LISTING_LIMIT {
+                offsets.push(offset);
+                let size = machine.decode_at(cs, offset).map_or(1, |instruction| instruction.size);
+                offset = offset.wrapping_add(size);
+            }
+        }
+    }
+    let index = offsets.iter().position(|&offset| offset == ip).unwrap_or_else(|| {
+        offsets.clear();
+        0
+    });
+    let start = index.saturating_sub(height / 2);
+    offsets.drain(..start);
+    offsets.truncate(height);
+    // Fill the pane with the instructions that follow.
+    let mut offset = offsets.last().map_or(ip, |&offset| {
+        offset.wrapping_add(machine.decode_at(cs, offset).map_or(1, |instruction| instruction.size))
+    });
+    while offsets.len() < height {
+        offsets.push(offset);
+        offset = offset.wrapping_add(machine.decode_at(cs, offset).map_or(1, |instruction| instruction.size));
+    }
+
+    // The index of each line in the arena, and whether it's CS:IP.
+    arena.clear();
+    let mut lines = vec![];
+    let mut current = None;
+    for offset in offsets {
+        let address = physical(cs, offset);
+        if let Some(label) = debugger.labels.get(&address) {
+            lines.push((arena.push(format_args!("{label}:")), false));
+        }
+        let marker = match (offset == ip, debugger.breakpoints.contains(&address)) {
+            (true, _) => "=>",
+            (false, true) => " *",
+            (false, false) => "  ",
+        };
+        let index = match machine.decode_at(cs, offset) {
+            Some(instruction) => arena.push(format_args!("{marker} {cs:04x}:{offset:04x}  {instruction}")),
+            None => arena.push(format_args!("{marker} {cs:04x}:{offset:04x}  (invalid)")),
+        };
+        if offset == ip {
+            current.get_or_insert(lines.len());
+        }
+        lines.push((index, offset == ip));
+    }
+    // Labels can push CS:IP down, so keep it in view.
+    lines.drain(..(current.unwrap_or(0) + 1).saturating_sub(height));
+
+    let arena: &'a Arena = arena;
+    lines
+        .into_iter()
+        .map(|(index, current)| {
+            let line = Line::from(arena.get(index));
+            if current {
+                line.style(Style::new().add_modifier(Modifier::REVERSED))
+            } else {
+                line
+            }
+        })
+        .collect()
+}
+
+/// The text that a function writes. Writing to memory doesn't fail.
+fn output(write: impl FnOnce(&mut Vec<u8>) -> io::Result<()>) -> Text<'static> {
+    let mut out = vec![];
+    write(&mut out).unwrap();
+    Text::from(String::from_utf8_lossy(&out).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use homework::sim::Machine;
+    use ratatui::backend::TestBackend;
+
+    #[test]
+    fn draw() {
+        let program = [
+            &[0b10111011, 0x34, 0x12][..], // mov bx, 4660
+            &[0b01000011],                 // inc bx
+            &[0b11110100],                 // hlt
+        ]
+        .concat();
+        let mut machine = Machine::new();
+        machine.load(0, &program);
+        let mut app = App {
+            debugger: Debugger::new(machine, 0..program.len()),
+            input: String::new(),
+            last: String::new(),
+            log: vec![],
+            ports: Shared::default(),
+            memory: 0,
+            code: Arena::default(),
+        };
+        assert_eq!(app.submit("step").unwrap(), Control::Continue);
+        // An empty line repeats the last command.
+        assert_eq!(app.submit("").unwrap(), Control::Continue);
+        assert_eq!(app.submit("x 0x10").unwrap(), Control::Continue);
+        assert_eq!(app.memory, 0x10);
+
+        let mut terminal = Terminal::new(TestBackend::new(100, 40)).unwrap();
+        terminal.draw(|frame| app.draw(frame)).unwrap();
+        let screen: Vec<String> = terminal
+            .backend()
+            .buffer()
+            .content()
+            .chunks(100)
+            .map(|row| row.iter().map(|cell| cell.symbol()).collect())
+            .collect();
+        let find = |text: &str| screen.iter().any(|row| row.contains(text));
+        assert!(find("   0000:0000  mov bx, 4660"));
+        assert!(find("=> 0000:0004  hlt"));
+        assert!(find("bx 0x1235"));
+        assert!(find("(sim) step"));
+        assert!(find("00010"));
+    }
+}
@@ -0,0 +1,180 @@
+//! Render the text video memory of a simulated machine, so programs that write directly to the screen have visible
+//! output.
+//!
+//! The screen is the 80x25 text of the color adapter at 0xB8000: a character and an attribute for each cell. The
+//! attribute's low nibble is the foreground color and its high nibble the background, with the blink bit ignored.
+//! Characters are code page 437, as on the PC.
+
+use std::io::{self, Write};
+use std::str::FromStr;
+
+use crate::sim::Machine;
+
+/// The segment of the color adapter's text memory.
+pub const SEGMENT: u16 = 0xb800;
+pub const COLUMNS: usize = 80;
+pub const ROWS: usize = 25;
+/// The bytes of a screen: a character and an attribute for each cell.
+pub const SIZE: usize = COLUMNS * ROWS * 2;
+
+// The glyphs of the control characters and the upper half of code page 437. NUL is blank.
+const CONTROL: &str = " ☺☻♥♦♣♠•◘○◙♂♀♪♫☼►◄↕‼¶§▬↨↑↓→←∟↔▲▼";
+const UPPER: &str = "ÇüéâäàåçêëèïîìÄÅÉæÆôöòûùÿÖÜ¢£¥₧ƒáíóúñÑªº¿⌐¬½¼¡«»░▒▓│┤╡╢╖╕╣║╗╝╜╛┐└┴┬├─┼╞╟╚╔╩╦╠═╬╧╨╤╥╙╘╒╓╫╪┘┌█▄▌▐▀αßΓπΣσµτΦΘΩδ∞φε∩≡±≥≤⌠⌡÷≈°∙·√ⁿ²■\u{a0}";
+// The ANSI color of each of the adapter's colors, which order red and blue the other way around.
+const ANSI_COLORS: [u8; 8] = [0, 4, 2, 6, 1, 5, 3, 7];
+
+/// When to render the screen.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Refresh {
+    /// Once, when the simulation ends.
+    #[default]
+    Exit,
+    /// Whenever the screen changes, redrawing the terminal.
+    Live,
+}
+
+impl FromStr for Refresh {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "exit" => Ok(Self::Exit),
+            "live" => Ok(Self::Live),
+            _ => Err("expected exit or live".to_string()),
+        }
+    }
+}
+
+/// The character of a byte in code page 437.
+#[must_use]
+pub fn character(byte: u8) -> char {
+    match byte {
+        0x00..0x20 => CONTROL.chars().nth(usize::from(byte)).unwrap_or(' '),
+        0x7f => '⌂',
+        0x80.. => UPPER.chars().nth(usize::from(byte - 0x80)).unwrap_or(' '),
+        _ => char::from(byte),
+    }
+}
+
+/// Copy the screen out of a machine's memory. Memory smaller than 1 MiB repeats through the address space, so the
+/// screen is wherever the text memory's addresses wrap around to.
+#[must_use]
+pub fn screen(machine: &Machine) -> Vec<u8> {
+    (0..SIZE as u16)
+        .map(|offset| machine.memory[machine.address(SEGMENT, offset)])
+        .collect()
+}
+
+/// Write a screen to a terminal. Without colors, trailing blanks are left out of each row, and blank rows out of
+/// the end of the screen. With colors, every cell is written in its attribute's ANSI colors.
+///
+/// # Errors
+///
+/// If writing fails.
+pub fn write<W: Write>(out: &mut W, screen: &[u8], colors: bool) -> io::Result<()> {
+    let blank = |cell: &[u8]| cell[0] == 0 || cell[0] == b' ';
+    let rows: Vec<&[u8]> = screen.chunks(COLUMNS * 2).collect();
+    let count = if colors {
+        rows.len()
+    } else {
+        rows.iter()
+            .rposition(|row| !row.chunks(2).all(blank))
+            .map_or(0, |last| last + 1)
+    };
+    for row in &rows[..count] {
+        let mut cells: Vec<&[u8]> = row.chunks(2).collect();
+        if !colors {
+            while cells.last().is_some_and(|cell| blank(cell)) {
+                cells.pop();
+            }
+        }
+        let mut attribute = None;
+        for cell in cells {
+            if colors && attribute != Some(cell[1]) {
+                attribute = Some(cell[1]);
+                let (foreground, background) = (cell[1] & 0x0f, (cell[1] >> 4) & 0b111);
+                let bright = if foreground & 0b1000 == 0 { 30 } else { 90 };
+                write!(
+                    out,
+                    "\x1b[{};{}m",
+                    bright + ANSI_COLORS[usize::from(foreground & 0b111)],
+                    40 + ANSI_COLORS[usize::from(background)]
+                )?;
+            }
+            write!(out, "{}", character(cell[0]))?;
+        }
+        if colors {
+            write!(out, "\x1b[0m")?;
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+/// Clear a terminal and write a screen to it, in colors.
+///
+/// # Errors
+///
+/// If writing fails.
+pub fn redraw<W: Write>(out: &mut W, screen: &[u8]) -> io::Result<()> {
+    write!(out, "\x1b[H\x1b[2J")?;
+    write(out, screen, true)?;
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn characters() {
+        assert_eq!(CONTROL.chars().count(), 0x20);
+        assert_eq!(UPPER.chars().count(), 0x80);
+        assert_eq!(character(0), ' ');
+        assert_eq!(character(0x01), '☺');
+        assert_eq!(character(b'A'), 'A');
+        assert_eq!(character(0xb3), '│');
+        assert_eq!(character(0xdb), '█');
+    }
+
+    #[test]
+    fn render() {
+        let program = [
+            &[0b10111000, 0x00, 0xb8][..], // mov ax, 0xb800
+            &[0b10001110, 0b11_000_000],   // mov es, ax
+            &[0b10111000, b'H', 0x1e],     // mov ax, 0x1e48
+            &[0b10101011],                 // stosw
+            &[0b10110000, b'i'],           // mov al, 'i'
+            &[0b10101011],                 // stosw
+            &[0b10111111, 0xa0, 0x00],     // mov di, 160
+            &[0b10111000, 0xdb, 0x0c],     // mov ax, 0x0cdb
+            &[0b10101011],                 // stosw
+        ]
+        .concat();
+        let mut machine = Machine::new();
+        machine.load(0, &program);
+        for _ in 0..9 {
+            machine.step().unwrap();
+        }
+        let screen = screen(&machine);
+        assert_eq!(&screen[..4], b"H\x1ei\x1e");
+
+        let mut out = vec![];
+        write(&mut out, &screen, false).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "Hi\n█\n");
+
+        let mut out = vec![];
+        write(&mut out, &screen[..COLUMNS * 4], true).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("\x1b[93;44mHi\x1b[30;40m "));
+        assert!(out.contains("\n\x1b[91;40m█\x1b[30;40m "));
+    }
+
+    #[test]
+    fn small_memory() {
+        let mut machine = Machine::with_memory(1 << 16);
+        let address = machine.address(SEGMENT, 0);
+        machine.memory[address] = b'x';
+        assert_eq!(screen(&machine)[0], b'x');
+    }
+}